@@ -1,15 +1,19 @@
-use bincode;
+use base64;
 use bytes::Bytes;
 use config;
-use cubes::{self, Cube};
-use database::{Context, Database};
+use cubes::{self, Cube, ResponseFn};
+use database::{Context, Database, Migration, ScanCursor};
 use metrics::{self, Meter};
 use resp::RespValue;
+use rand::{thread_rng, Rng};
 use std::convert::TryInto;
-use std::net;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::{fs, io, net, str};
 use types::*;
-use utils::{assume_str, replace_default};
+use utils::{assume_str, now_millis, replace_default, IdHashMap};
 use version_vector::*;
+use vnode::VALUE_SIZE_BUCKETS;
 
 #[derive(Debug)]
 pub enum CommandError {
@@ -31,14 +35,191 @@ pub enum CommandError {
     MultiplePartitions,
     MultipleKeyMutations,
     Unavailable,
+    ResponseTooLarge,
+    CommandDisabled,
+    RequestInProgress,
+    NotLive,
+    NotReady,
+    ContextRequired,
+    ContextOutdated,
+    HasSiblings,
+    RestartRequired,
+    ImmutableParam,
+    NotIsolated,
+    RingMismatch,
+    MigrateFailed,
+    TryAgain,
+}
+
+/// Errors as seen by clients, each rendered with a stable prefix
+#[derive(Debug)]
+pub enum ServerError {
+    Err(CommandError),
+    WrongType,
+    Moved(VNodeId, net::SocketAddr),
+    Ask(VNodeId, net::SocketAddr),
+    ClusterDown,
+    Timeout,
+    CrossSlot,
+    TryAgain,
+    // the config file couldn't be read or has an invalid value
+    Config(String),
+}
+
+impl From<CommandError> for ServerError {
+    fn from(error: CommandError) -> Self {
+        match error {
+            CommandError::TypeError => ServerError::WrongType,
+            CommandError::Unavailable => ServerError::ClusterDown,
+            CommandError::Timeout => ServerError::Timeout,
+            CommandError::MultiplePartitions => ServerError::CrossSlot,
+            CommandError::TryAgain => ServerError::TryAgain,
+            error => ServerError::Err(error),
+        }
+    }
+}
+
+impl Into<RespValue> for ServerError {
+    fn into(self) -> RespValue {
+        let error = match self {
+            ServerError::Err(CommandError::ResponseTooLarge) => {
+                "ERR value context too large, resolve the siblings with a SET using the context"
+                    .into()
+            }
+            ServerError::Err(CommandError::ImmutableParam) => {
+                "ERR parameter is immutable at runtime".into()
+            }
+            ServerError::Err(e) => format!("ERR {:?}", e),
+            ServerError::WrongType => {
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+            }
+            ServerError::Moved(vnode, addr) => format!("MOVED {} {}", vnode, addr),
+            ServerError::Ask(vnode, addr) => format!("ASK {} {}", vnode, addr),
+            ServerError::ClusterDown => "CLUSTERDOWN Not enough replicas available".into(),
+            ServerError::Timeout => "TIMEOUT Request timed out".into(),
+            ServerError::CrossSlot => {
+                "CROSSSLOT Keys in request don't hash to the same vnode".into()
+            }
+            ServerError::TryAgain => "TRYAGAIN The vnode is bootstrapping".into(),
+            // resp errors are a single line
+            ServerError::Config(error) => format!("ERR {}", error.replace(&['\r', '\n'][..], " ")),
+        };
+        RespValue::Error(error.into())
+    }
 }
 
 impl Into<RespValue> for CommandError {
     fn into(self) -> RespValue {
-        RespValue::Error(format!("{:?}", self).into())
+        ServerError::from(self).into()
     }
 }
 
+pub const SELFTEST_KEYS: usize = 10;
+pub const SELFTEST_KEY_PREFIX: &str = "sucredb-selftest:";
+#[cfg(debug_assertions)]
+pub const CORRUPT_KEY: &str = "sucredb-corrupt";
+
+struct SelfTest {
+    context: Option<Context>,
+    remaining: usize,
+    failures: Vec<String>,
+}
+
+/// A key being tested by DEBUG SELFTEST, each step issues an internal
+/// request and continues with the next once it gets the response.
+#[derive(Clone)]
+struct SelfTestKey {
+    test: Arc<Mutex<SelfTest>>,
+    key: Bytes,
+    value: Bytes,
+    read: Bytes,
+    write: Bytes,
+}
+
+impl SelfTestKey {
+    fn set(self, db: &Database) {
+        let next = self.clone();
+        db.internal_cmd(
+            &[b"SET", &self.key, &self.value, b"", &self.write],
+            Box::new(move |db, mut context| match context.take_response() {
+                RespValue::Status(_) => next.clone().get(db),
+                r => next.finish(db, Some(format!("SET failed {:?}", r))),
+            }),
+        );
+    }
+
+    fn get(self, db: &Database) {
+        let next = self.clone();
+        db.internal_cmd(
+            &[b"GET", &self.key, &self.read],
+            Box::new(move |db, mut context| match context.take_response() {
+                RespValue::Array(mut values) => {
+                    let vv = match values.pop() {
+                        Some(RespValue::Data(vv)) => vv,
+                        _ => Bytes::new(),
+                    };
+                    let failure = if values == [RespValue::Data(next.value.clone())] {
+                        None
+                    } else {
+                        Some(format!("GET returned {:?}", values))
+                    };
+                    // clean up even if it didn't match
+                    next.clone().del(db, vv, failure)
+                }
+                r => next.finish(db, Some(format!("GET failed {:?}", r))),
+            }),
+        );
+    }
+
+    fn del(self, db: &Database, vv: Bytes, failure: Option<String>) {
+        let next = self.clone();
+        let mut failure = Some(failure);
+        db.internal_cmd(
+            &[b"DEL", &self.key, &vv, &self.write],
+            Box::new(move |db, mut context| {
+                let mut failure = failure.take().unwrap_or(None);
+                match context.take_response() {
+                    RespValue::Error(e) => if failure.is_none() {
+                        failure = Some(format!("DEL failed {:?}", e));
+                    },
+                    _ => (),
+                }
+                next.finish(db, failure)
+            }),
+        );
+    }
+
+    fn finish(&self, db: &Database, failure: Option<String>) {
+        let mut test = self.test.lock().unwrap();
+        if let Some(failure) = failure {
+            let failure = format!("{}: {}", assume_str(&self.key), failure);
+            test.failures.push(failure);
+        }
+        test.remaining -= 1;
+        if test.remaining == 0 {
+            let mut context = test.context.take().unwrap();
+            if test.failures.is_empty() {
+                db.respond_ok(&mut context);
+            } else {
+                let failures = test.failures
+                    .drain(..)
+                    .map(|f| RespValue::Data(f.into()))
+                    .collect();
+                db.respond_resp(&mut context, RespValue::Array(failures));
+            }
+        }
+    }
+}
+
+/// Tally of an EXISTS request, each key is looked up with an internal TTL
+/// request and the last response replies to the client.
+struct Exists {
+    context: Option<Context>,
+    remaining: usize,
+    count: i64,
+    error: Option<RespValue>,
+}
+
 fn parse_int<T: ::std::str::FromStr + Default>(
     try: bool,
     args: &[&Bytes],
@@ -79,6 +260,10 @@ fn check_value_len(value_len: usize) -> Result<(), CommandError> {
 
 impl Database {
     pub fn handler_cmd(&self, mut context: Context) {
+        // commands not sent by a client connection, like internal ones
+        if context.request_id == 0 {
+            context.request_id = thread_rng().gen();
+        }
         let cmd = context.commands.pop().unwrap();
         if let Err(e) = self.handle_cmd(&mut context, cmd) {
             context.clear();
@@ -87,7 +272,10 @@ impl Database {
     }
 
     fn handle_cmd(&self, context: &mut Context, cmd: RespValue) -> Result<(), CommandError> {
-        debug!("Processing ({:?}) {:?}", context.token, cmd);
+        debug!(
+            "Processing ({:?}) req:{:016X} {:?}",
+            context.token, context.request_id, cmd
+        );
         let mut args = Vec::new();
         match cmd {
             RespValue::Array(ref a) => {
@@ -115,12 +303,17 @@ impl Database {
             match arg0.as_ref() {
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
                 b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
+                b"INCR" | b"incr" => self.cmd_incr(context, args, 1),
+                b"DECR" | b"decr" => self.cmd_incr(context, args, -1),
+                b"PNINCR" | b"pnincr" => self.cmd_pnincr(context, args),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
                 b"SADD" | b"sadd" => self.cmd_sadd(context, args),
                 b"SREM" | b"srem" => self.cmd_srem(context, args),
                 b"GETSET" | b"getset" => self.cmd_set(context, args, true),
+                b"CAS" | b"cas" => self.cmd_cas(context, args),
+                b"SETIFEQ" | b"setifeq" => self.cmd_setifeq(context, args),
                 b"DEL" | b"del" => self.cmd_del(context, args),
                 _ => {
                     debug!("Unknown command for multi {:?}", cmd);
@@ -138,11 +331,20 @@ impl Database {
         } else {
             match arg0.as_ref() {
                 b"GET" | b"get" => self.cmd_get(context, args),
+                b"GETEX" | b"getex" => self.cmd_getex(context, args),
+                b"EXPIRE" | b"expire" => self.cmd_expire(context, args),
+                b"PERSIST" | b"persist" => self.cmd_persist(context, args),
+                b"TTL" | b"ttl" => self.cmd_ttl(context, args),
+                b"EXISTS" | b"exists" => self.cmd_exists(context, args),
                 b"MGET" | b"mget" => self.cmd_mget(context, args),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
                 b"CGET" | b"cget" => self.cmd_cget(context, args),
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
                 b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
+                b"INCR" | b"incr" => self.cmd_incr(context, args, 1),
+                b"DECR" | b"decr" => self.cmd_incr(context, args, -1),
+                b"PNGET" | b"pnget" => self.cmd_pnget(context, args),
+                b"PNINCR" | b"pnincr" => self.cmd_pnincr(context, args),
                 b"HGETALL" | b"hgetall" => self.cmd_hgetall(context, args),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
@@ -150,9 +352,24 @@ impl Database {
                 b"SADD" | b"sadd" => self.cmd_sadd(context, args),
                 b"SREM" | b"srem" => self.cmd_srem(context, args),
                 b"GETSET" | b"getset" => self.cmd_set(context, args, true),
+                b"CAS" | b"cas" => self.cmd_cas(context, args),
+                b"SETIFEQ" | b"setifeq" => self.cmd_setifeq(context, args),
+                b"RESOLVE" | b"resolve" => self.cmd_resolve(context, args),
                 b"DEL" | b"del" => self.cmd_del(context, args),
                 b"CLUSTER" | b"cluster" => self.cmd_cluster(context, args),
+                b"CLIENT" | b"client" => self.cmd_client(context, args),
                 b"TYPE" | b"type" => self.cmd_type(context, args),
+                b"FLUSHALL" | b"flushall" => {
+                    check_arg_count(args.len(), 0, 0)?;
+                    self.flush_all(context)
+                }
+                b"SCAN" | b"scan" => self.cmd_scan(context, args),
+                b"DUMP" | b"dump" => self.cmd_dump(context, args),
+                b"RESTORE" | b"restore" => self.cmd_restore(context, args),
+                b"MIGRATE" | b"migrate" => self.cmd_migrate(context, args),
+                b"HEALTH" | b"health" => self.cmd_health(context, args),
+                b"INFO" | b"info" => self.cmd_info(context, args),
+                b"IDEMPOTENT" | b"idempotent" => self.cmd_idempotent(context, args),
                 b"MULTI" | b"multi" => self.cmd_multi(context, args),
                 b"EXEC" | b"exec" => self.cmd_exec(context, args),
                 b"ECHO" | b"echo" => Ok(self.respond_resp(context, cmd.clone())),
@@ -160,6 +377,7 @@ impl Database {
                     check_arg_count(args.len(), 0, 0).and_then(|_| Ok(self.respond_ok(context)))
                 }
                 b"CONFIG" | b"config" => self.cmd_config(context, args),
+                b"DEBUG" | b"debug" => self.cmd_debug(context, args),
                 _ => {
                     debug!("Unknown command {:?}", cmd);
                     Err(CommandError::UnknownCommand)
@@ -175,12 +393,17 @@ impl Database {
         i: usize,
     ) -> Result<VersionVector, CommandError> {
         if try && !args[i].is_empty() {
-            bincode::deserialize(args[i]).map_err(|_| CommandError::InvalidContext)
+            cubes::deserialize_context(args[i], self.config().context_encoding)
         } else {
             Ok(Default::default())
         }
     }
 
+    fn value_renderer(&self) -> ResponseFn {
+        let encoding = self.config().context_encoding;
+        Box::new(move |cube| cubes::render_value_with(cube, encoding))
+    }
+
     fn parse_consistency(
         &self,
         try: bool,
@@ -193,10 +416,109 @@ impl Database {
                 .try_into()
                 .map_err(|_| CommandError::InvalidConsistencyValue)?
         } else {
-            self.config.consistency_read
+            self.config().consistency_read
         })
     }
 
+    // like parse_consistency but defaults to the write consistency
+    fn parse_write_consistency(
+        &self,
+        try: bool,
+        args: &[&Bytes],
+        i: usize,
+    ) -> Result<ConsistencyLevel, CommandError> {
+        if try {
+            self.parse_consistency(try, args, i)
+        } else {
+            Ok(self.config().consistency_write)
+        }
+    }
+
+    /// HEALTH [LIVE|READY], for liveness and readiness probes
+    fn cmd_health(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 1)?;
+        match args.get(0).map(|a| &a[..]) {
+            Some(b"LIVE") | Some(b"live") => if self.is_live() {
+                Ok(self.respond_ok(context))
+            } else {
+                Err(CommandError::NotLive)
+            },
+            Some(b"READY") | Some(b"ready") => if self.is_ready() {
+                Ok(self.respond_ok(context))
+            } else {
+                Err(CommandError::NotReady)
+            },
+            Some(_) => Err(CommandError::UnknownCommand),
+            None => {
+                let mut resp = Vec::new();
+                for (name, value) in self.health_fields() {
+                    resp.push(RespValue::Data(name.into()));
+                    resp.push(RespValue::Int(value as _));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(resp)))
+            }
+        }
+    }
+
+    fn health_fields(&self) -> Vec<(String, u64)> {
+        vec![
+            ("live".into(), self.is_live() as u64),
+            ("ready".into(), self.is_ready() as u64),
+            ("standby".into(), self.is_standby() as u64),
+            ("bootstrapping_vnodes".into(), self.bootstrapping_vnodes() as u64),
+        ]
+    }
+
+    fn fabric_fields(&self) -> Vec<(String, u64)> {
+        let mut fields = vec![("dropped".to_owned(), self.fabric.dropped_msgs() as u64)];
+        let mut depths = self.fabric.queue_depths();
+        depths.sort();
+        for (node, depth) in depths {
+            fields.push((format!("queued_{}", node), depth as u64));
+        }
+        fields
+    }
+
+    fn cmd_info(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 0, 1)?;
+        let section = args.get(0).map(|a| String::from_utf8_lossy(a).to_lowercase());
+        let sections = [
+            ("Health", "health", self.health_fields()),
+            ("Fabric", "fabric", self.fabric_fields()),
+        ];
+        let mut info = String::new();
+        for &(title, prefix, ref fields) in &sections {
+            if section.as_ref().map_or(false, |s| s != "all" && s != prefix) {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str(&format!("# {}\r\n", title));
+            for &(ref name, value) in fields {
+                info.push_str(&format!("{}_{}:{}\r\n", prefix, name, value));
+            }
+        }
+        Ok(self.respond_resp(context, RespValue::Data(info.into())))
+    }
+
+    /// IDEMPOTENT id command [args..], the command must be a write
+    fn cmd_idempotent(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 2, usize::max_value())?;
+        match args[1].as_ref() {
+            b"SET" | b"set" | b"GETSET" | b"getset" | b"DEL" | b"del" | b"CSET" | b"cset"
+            | b"INCRBY" | b"incrby" | b"INCR" | b"incr" | b"DECR" | b"decr" | b"PNINCR"
+            | b"pnincr" | b"HSET" | b"hset" | b"HDEL" | b"hdel" | b"SADD" | b"sadd" | b"SREM"
+            | b"srem" | b"CAS" | b"cas" | b"SETIFEQ" | b"setifeq" => (),
+            _ => return Err(CommandError::InvalidCommand),
+        }
+        if !self.idempotent_begin(context, args[0]) {
+            return Ok(());
+        }
+        let cmd = RespValue::Array(args[1..].iter().map(|&a| RespValue::Data(a.clone())).collect());
+        self.handle_cmd(context, cmd)
+    }
+
     fn cmd_multi(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         assert!(!context.is_multi);
         context.is_multi = true;
@@ -209,7 +531,7 @@ impl Database {
             return Err(CommandError::InvalidExec);
         }
         check_arg_count(args.len(), 0, 1)?;
-        let consistency = self.parse_consistency(args.len() > 0, args, 0)?;
+        let consistency = self.parse_write_consistency(args.len() > 0, args, 0)?;
         assert!(!context.is_exec);
         context.is_exec = true;
         let mut cmds = replace_default(&mut context.commands);
@@ -221,8 +543,62 @@ impl Database {
         self.set_flush(context, consistency)
     }
 
-    fn cmd_config(&self, context: &mut Context, _args: &[&Bytes]) -> Result<(), CommandError> {
-        Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+    fn cmd_config(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        match args.get(0).map(|a| &a[..]) {
+            Some(b"GET") | Some(b"get") => {
+                check_arg_count(args.len(), 2, 2)?;
+                let param = assume_str(args[1]);
+                let mut result = Vec::new();
+                let value = match param {
+                    "write-quorum" => Some(self.quorum(true).to_string()),
+                    "read-quorum" => Some(self.quorum(false).to_string()),
+                    "partitions" => Some(self.dht.partitions().to_string()),
+                    "replication_factor" => Some(self.dht.replication_factor().to_string()),
+                    _ => self.config().get(param),
+                };
+                if let Some(value) = value {
+                    result.push(RespValue::Data(param.as_bytes().into()));
+                    result.push(RespValue::Data(value.as_bytes().into()));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(result)))
+            }
+            Some(b"SET") | Some(b"set") => {
+                check_arg_count(args.len(), 3, 3)?;
+                match args[1].as_ref() {
+                    b"write-quorum" => self.set_quorum(true, args[2])?,
+                    b"read-quorum" => self.set_quorum(false, args[2])?,
+                    param => self.set_config(assume_str(param), assume_str(args[2]))?,
+                }
+                Ok(self.respond_ok(context))
+            }
+            Some(b"RELOAD") | Some(b"reload") => {
+                check_arg_count(args.len(), 1, 1)?;
+                let changes = match self.reload_config() {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        self.respond_error(context, ServerError::Config(e.to_string()));
+                        return Ok(());
+                    }
+                };
+                let changes = changes
+                    .into_iter()
+                    .map(|(param, restart)| {
+                        let status: &[u8] = if restart {
+                            b"restart required"
+                        } else {
+                            b"applied"
+                        };
+                        RespValue::Array(vec![
+                            RespValue::Data(param.as_bytes().into()),
+                            RespValue::Data(status.into()),
+                        ])
+                    })
+                    .collect();
+                Ok(self.respond_resp(context, RespValue::Array(changes)))
+            }
+            // unsupported by sucredb, reply empty for client compatibility
+            _ => Ok(self.respond_resp(context, RespValue::Array(Default::default()))),
+        }
     }
 
     fn cmd_hgetall(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -241,7 +617,7 @@ impl Database {
         check_value_len(args[2].len())?;
         let hash_key = args[1].clone();
         let hash_value = args[2].clone();
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
         self.set(
             context,
             args[0],
@@ -262,7 +638,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_key_len(args[1].len())?;
         let hash_key = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -291,7 +667,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -312,7 +688,7 @@ impl Database {
         check_key_len(args[0].len())?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -332,7 +708,167 @@ impl Database {
         check_arg_count(args.len(), 1, 2)?;
         check_key_len(args[0].len())?;
         let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
-        self.get(context, args[0], consistency, Box::new(cubes::render_value))
+        self.get(context, args[0], consistency, self.value_renderer())
+    }
+
+    /// GETEX key [EX seconds | PX millis | PERSIST] [consistency]
+    fn cmd_getex(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 4)?;
+        check_key_len(args[0].len())?;
+        let (expire, next) = match args.get(1).map(|a| &a[..]) {
+            Some(b"EX") | Some(b"ex") | Some(b"PX") | Some(b"px") => {
+                check_arg_count(args.len(), 3, 4)?;
+                let ttl: u64 = parse_int(true, args, 2)?;
+                if ttl == 0 {
+                    return Err(CommandError::InvalidValue);
+                }
+                let millis = if args[1].eq_ignore_ascii_case(b"EX") {
+                    ttl.saturating_mul(1000)
+                } else {
+                    ttl
+                };
+                (Some(Some(now_millis().saturating_add(millis))), 3)
+            }
+            Some(b"PERSIST") | Some(b"persist") => (Some(None), 2),
+            _ => (None, 1),
+        };
+        check_arg_count(args.len(), next, next + 1)?;
+        let expire = match expire {
+            Some(expire) => expire,
+            None => return self.cmd_get(context, args),
+        };
+        metrics::REQUEST_SET.mark(1);
+        let consistency = self.parse_write_consistency(args.len() > next, args, next)?;
+        self.set(
+            context,
+            args[0],
+            Box::new(move |i, _v, mut c: Cube| {
+                match c {
+                    Cube::Value(_) | Cube::Void(_) => (),
+                    _ => return Err(CommandError::TypeError),
+                }
+                // missing keys stay that way
+                c.set_expire(i, expire);
+                Ok((c, None))
+            }),
+            consistency,
+            true,
+            Some(self.value_renderer()),
+        )
+    }
+
+    /// EXPIRE key seconds [consistency]
+    fn cmd_expire(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 2, 3)?;
+        let ttl: u64 = parse_int(true, args, 1)?;
+        if ttl == 0 {
+            return Err(CommandError::InvalidValue);
+        }
+        let at = now_millis().saturating_add(ttl.saturating_mul(1000));
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
+        self.set_expire(context, args[0], Some(at), consistency)
+    }
+
+    /// PERSIST key [consistency]
+    fn cmd_persist(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 1, args, 1)?;
+        self.set_expire(context, args[0], None, consistency)
+    }
+
+    // replies 1 if the expiration changed and 0 if the key is missing
+    // (or PERSIST found no expiration)
+    fn set_expire(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        at: Option<u64>,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_key_len(key.len())?;
+        self.set(
+            context,
+            key,
+            Box::new(move |i, _v, mut c: Cube| {
+                if c.is_empty() || (at.is_none() && c.expire().is_none()) {
+                    return Ok((c, Some(RespValue::Int(0))));
+                }
+                c.set_expire(i, at);
+                Ok((c, Some(RespValue::Int(1))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    /// TTL key [consistency]
+    fn cmd_ttl(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        check_key_len(args[0].len())?;
+        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        self.get(context, args[0], consistency, Box::new(cubes::render_ttl))
+    }
+
+    /// EXISTS key [key ...]
+    fn cmd_exists(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_GET.mark(1);
+        check_arg_count(args.len(), 1, usize::max_value())?;
+        let mut vnode_keys = IdHashMap::<VNodeId, Vec<&Bytes>>::default();
+        for key in args {
+            check_key_len(key.len())?;
+            vnode_keys
+                .entry(self.dht.key_vnode(key))
+                .or_insert_with(Vec::new)
+                .push(key);
+        }
+        let consistency = self.config().consistency_read;
+        // keys may live in different vnodes, so each vnode is read like an MGET of its own
+        let exists = Arc::new(Mutex::new(Exists {
+            context: Some(replace_default(context)),
+            remaining: vnode_keys.len(),
+            count: 0,
+            error: None,
+        }));
+        for (_, keys) in vnode_keys {
+            let exists = exists.clone();
+            let mut vnode_context = self.internal_context(Box::new(move |db, mut reply| {
+                let mut exists = exists.lock().unwrap();
+                match reply.take_response() {
+                    RespValue::Array(results) => for result in results {
+                        match result {
+                            RespValue::Int(found) => exists.count += found,
+                            r => if exists.error.is_none() {
+                                exists.error = Some(r);
+                            },
+                        }
+                    },
+                    r => if exists.error.is_none() {
+                        exists.error = Some(r);
+                    },
+                }
+                exists.remaining -= 1;
+                if exists.remaining == 0 {
+                    let mut context = exists.context.take().unwrap();
+                    let count = exists.count;
+                    let response = exists.error.take().unwrap_or(RespValue::Int(count));
+                    db.respond_resp(&mut context, response);
+                }
+            }));
+            vnode_context.is_multi = true;
+            vnode_context.is_exec = true;
+            let result = self.mget(
+                &mut vnode_context,
+                &keys,
+                consistency,
+                Box::new(|cube: Cube| RespValue::Int(!cube.is_empty() as i64)),
+            );
+            if let Err(e) = result {
+                self.respond_error(&mut vnode_context, e);
+            }
+        }
+        Ok(())
     }
 
     fn cmd_mget(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -350,7 +886,7 @@ impl Database {
         for key in keys {
             check_key_len(key.len())?;
         }
-        self.mget(context, keys, consistency, Box::new(cubes::render_value))
+        self.mget(context, keys, consistency, self.value_renderer())
     }
 
     fn cmd_set(
@@ -365,12 +901,24 @@ impl Database {
         check_value_len(args[1].len())?;
         let value = args[1].clone();
         let vv = self.parse_vv(args.len() > 2, args, 2)?;
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let blind_policy = if args.len() > 2 && !args[2].is_empty() {
+            None
+        } else {
+            Some(self.config().blind_write_policy)
+        };
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
         self.set(
             context,
             args[0],
             Box::new(move |i, v, c: Cube| {
                 let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                let vv = match blind_policy {
+                    Some(BlindWritePolicy::Overwrite) => cube_value.vv().clone(),
+                    Some(BlindWritePolicy::Reject) if cube_value.len() != 0 => {
+                        return Err(CommandError::ContextRequired);
+                    }
+                    _ => vv,
+                };
                 cube_value.set(i, v, Some(value), &vv);
                 let resp = if reply_result {
                     None
@@ -382,25 +930,128 @@ impl Database {
             consistency,
             reply_result,
             if reply_result {
-                Some(Box::new(cubes::render_value))
+                Some(self.value_renderer())
             } else {
                 None
             },
         )
     }
 
+    /// CAS key value token [consistency], sets the value only if the context
+    /// covers everything stored in the key, instead of creating siblings
+    fn cmd_cas(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 3, 4)?;
+        check_key_len(args[0].len())?;
+        check_value_len(args[1].len())?;
+        let value = args[1].clone();
+        // the token is always base64, wrapping a context in any encoding
+        let vv = if args[2].is_empty() {
+            Default::default()
+        } else {
+            let token = base64::decode(&args[2][..]).map_err(|_| CommandError::InvalidContext)?;
+            cubes::deserialize_context(&token, self.config().context_encoding)?
+        };
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
+        self.set(
+            context,
+            args[0],
+            Box::new(move |i, v, c: Cube| {
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                if !vv.descends(cube_value.vv()) {
+                    return Err(CommandError::ContextOutdated);
+                }
+                cube_value.set(i, v, Some(value), &vv);
+                Ok((Cube::Value(cube_value), Some(RespValue::Status("OK".into()))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    /// SETIFEQ key expected value [consistency], sets the value only if the
+    /// current one is `expected`, replies 1 if it did and 0 otherwise
+    fn cmd_setifeq(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 3, 4)?;
+        check_key_len(args[0].len())?;
+        check_value_len(args[2].len())?;
+        let expected = args[1].clone();
+        let value = args[2].clone();
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
+        self.set(
+            context,
+            args[0],
+            Box::new(move |i, v, c: Cube| {
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                let matches = {
+                    let mut present = cube_value.present();
+                    match (present.next(), present.next()) {
+                        (Some(current), None) => *current == expected,
+                        (Some(_), Some(_)) => return Err(CommandError::HasSiblings),
+                        (None, _) => false,
+                    }
+                };
+                if !matches {
+                    return Ok((Cube::Value(cube_value), Some(RespValue::Int(0))));
+                }
+                // supersedes the value that was compared
+                let vv = cube_value.vv().clone();
+                cube_value.set(i, v, Some(value), &vv);
+                Ok((Cube::Value(cube_value), Some(RespValue::Int(1))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    /// RESOLVE key index [consistency], keeps only the sibling at index
+    fn cmd_resolve(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key_len(args[0].len())?;
+        let index: usize = parse_int(true, args, 1)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
+        self.set(
+            context,
+            args[0],
+            Box::new(move |i, v, c: Cube| {
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                if cube_value.present().count() < 2 {
+                    return Err(CommandError::InvalidValue);
+                }
+                let value = cube_value
+                    .present()
+                    .nth(index)
+                    .cloned()
+                    .ok_or(CommandError::InvalidValue)?;
+                // supersedes all the siblings
+                let vv = cube_value.vv().clone();
+                cube_value.set(i, v, Some(value), &vv);
+                Ok((Cube::Value(cube_value), Some(RespValue::Status("OK".into()))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
     fn cmd_del(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 1, 3)?;
         check_key_len(args[0].len())?;
         let vv = self.parse_vv(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
             Box::new(move |i, v, mut c: Cube| {
-                let result = c.del(i, v, &vv) as i64;
-                Ok((c, Some(RespValue::Int(result))))
+                // deleted and expired keys are still stored, but they don't count
+                let existed = !c.is_empty();
+                c.del(i, v, &vv);
+                Ok((c, Some(RespValue::Int(existed as i64))))
             }),
             consistency,
             false,
@@ -413,7 +1064,7 @@ impl Database {
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
         let value: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -443,18 +1094,76 @@ impl Database {
     }
 
     fn cmd_incrby(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 2, 3)?;
+        let inc: i64 = parse_int(args.len() > 1, args, 1)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
+        self.incr(context, args[0], inc, consistency)
+    }
+
+    /// INCR/DECR key [consistency]
+    fn cmd_incr(
+        &self,
+        context: &mut Context,
+        args: &[&Bytes],
+        inc: i64,
+    ) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 1, args, 1)?;
+        self.incr(context, args[0], inc, consistency)
+    }
+
+    // counters keep a count per node, so concurrent increments add up instead of conflicting
+    fn incr(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        inc: i64,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_key_len(key.len())?;
+        self.set(
+            context,
+            key,
+            Box::new(move |i, v, c: Cube| {
+                let mut counter = c.into_counter().ok_or(CommandError::TypeError)?;
+                counter.inc(i, v, inc);
+                let total = counter.get();
+                Ok((Cube::Counter(counter), Some(RespValue::Int(total))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
+    fn cmd_pnget(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_GET.mark(1);
+        check_arg_count(args.len(), 1, 2)?;
+        check_key_len(args[0].len())?;
+        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        self.get(
+            context,
+            args[0],
+            consistency,
+            Box::new(cubes::render_pncounter),
+        )
+    }
+
+    /// PNINCR key delta [consistency]
+    fn cmd_pnincr(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 3)?;
         check_key_len(args[0].len())?;
         let inc: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
             Box::new(move |i, v, c: Cube| {
-                let mut counter = c.into_counter().ok_or(CommandError::TypeError)?;
-                counter.inc(i, v, inc);
-                Ok((Cube::Counter(counter), Some(RespValue::Status("OK".into()))))
+                let mut counter = c.into_pncounter().ok_or(CommandError::TypeError)?;
+                let total = counter.inc(i, v, inc);
+                Ok((Cube::PNCounter(counter), Some(RespValue::Int(total))))
             }),
             consistency,
             false,
@@ -468,13 +1177,272 @@ impl Database {
         self.get(context, args[0], consistency, Box::new(cubes::render_type))
     }
 
+    /// SCAN cursor [COUNT count] [TYPE type], only keys stored on this node are returned
+    fn cmd_scan(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 5)?;
+        let cursor = ScanCursor::parse(args[0])?;
+        let mut count = 10;
+        let mut type_name = None;
+        for option in args[1..].chunks(2) {
+            if option.len() != 2 {
+                return Err(CommandError::InvalidArgCount);
+            }
+            match option[0].as_ref() {
+                b"COUNT" | b"count" => count = parse_int(true, option, 1)?,
+                b"TYPE" | b"type" => type_name = Some(assume_str(option[1])),
+                _ => return Err(CommandError::InvalidCommand),
+            }
+        }
+        if count == 0 {
+            return Err(CommandError::InvalidIntValue);
+        }
+        let (cursor, keys) = self.scan(&cursor, count, type_name);
+        Ok(self.respond_resp(
+            context,
+            RespValue::Array(vec![
+                RespValue::Data(cursor.encode()),
+                RespValue::Array(keys.into_iter().map(RespValue::Data).collect()),
+            ]),
+        ))
+    }
+
+    /// DUMP key [consistency], the key value and causal context in the format RESTORE takes
+    fn cmd_dump(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_GET.mark(1);
+        check_arg_count(args.len(), 1, 2)?;
+        check_key_len(args[0].len())?;
+        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        self.get(
+            context,
+            args[0],
+            consistency,
+            Box::new(|cube: Cube| {
+                if cube.is_empty() {
+                    RespValue::Nil
+                } else {
+                    RespValue::Data(cubes::serialize_cube(&cube).into())
+                }
+            }),
+        )
+    }
+
+    /// RESTORE key payload [consistency], merges a DUMP like a replicated write
+    fn cmd_restore(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key_len(args[0].len())?;
+        check_value_len(args[1].len())?;
+        let cube = cubes::deserialize_cube_limit(args[1], config::MAX_VALUE_LEN as u64)
+            .map_err(|_| CommandError::InvalidValue)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
+        self.restore(context, args[0], cube, consistency)
+    }
+
+    /// MIGRATE host:port key [DELETE] or MIGRATE host:port CURSOR cursor [COUNT count]
+    fn cmd_migrate(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 2, 5)?;
+        let addr = str::from_utf8(args[0])
+            .ok()
+            .and_then(|a| a.to_socket_addrs().ok())
+            .and_then(|mut addrs| addrs.next())
+            .ok_or(CommandError::InvalidValue)?;
+
+        if args[1].eq_ignore_ascii_case(b"CURSOR") {
+            check_arg_count(args.len(), 3, 5)?;
+            let cursor = ScanCursor::parse(args[2])?;
+            let count = match args.get(3) {
+                Some(option) if option.eq_ignore_ascii_case(b"COUNT") => {
+                    check_arg_count(args.len(), 5, 5)?;
+                    parse_int(true, args, 4)?
+                }
+                Some(_) => return Err(CommandError::InvalidCommand),
+                None => 10,
+            };
+            if count == 0 {
+                return Err(CommandError::InvalidIntValue);
+            }
+            return Ok(self.migrate(Migration::Cursor {
+                context: replace_default(context),
+                addr: addr,
+                cursor: cursor,
+                count: count,
+            }));
+        }
+
+        check_arg_count(args.len(), 2, 3)?;
+        check_key_len(args[1].len())?;
+        let delete = match args.get(2) {
+            Some(option) if option.eq_ignore_ascii_case(b"DELETE") => true,
+            Some(_) => return Err(CommandError::InvalidCommand),
+            None => false,
+        };
+        let key = args[1].clone();
+        let mut client_context = Some(replace_default(context));
+        // the key is read like a DUMP, the migration thread then copies it
+        // and deletes it locally once the other cluster has it
+        self.internal_cmd(
+            &[b"DUMP", &args[1][..]],
+            Box::new(move |db, mut reply| {
+                let mut context = client_context.take().expect("Multiple DUMP responses");
+                let cube = match reply.take_response() {
+                    RespValue::Data(payload) => match cubes::deserialize_cube(&payload) {
+                        Ok(cube) => cube,
+                        Err(_) => return db.respond_error(&mut context, CommandError::StorageError),
+                    },
+                    RespValue::Nil => {
+                        return db.respond_resp(&mut context, RespValue::Status("NOKEY".into()))
+                    }
+                    r => return db.respond_resp(&mut context, r),
+                };
+                let delete = if delete {
+                    Some(match cube {
+                        Cube::Value(ref value) => value.vv().clone(),
+                        _ => VersionVector::new(),
+                    })
+                } else {
+                    None
+                };
+                db.migrate(Migration::Key {
+                    context: context,
+                    addr: addr,
+                    key: key.clone(),
+                    cube: cube,
+                    delete: delete,
+                });
+            }),
+        );
+        Ok(())
+    }
+
+    /// CLIENT SETNAME name, CLIENT GETNAME or CLIENT LIST
+    fn cmd_client(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 2)?;
+        match args[0].as_ref() {
+            b"SETNAME" | b"setname" => {
+                check_arg_count(args.len(), 2, 2)?;
+                // names are listed separated by spaces, an empty one clears it
+                if args[1].iter().any(|&b| b <= b' ' || b > b'~') {
+                    return Err(CommandError::InvalidValue);
+                }
+                let name = if args[1].is_empty() {
+                    None
+                } else {
+                    Some(args[1].clone())
+                };
+                self.set_client_name(context.token, name);
+                Ok(self.respond_ok(context))
+            }
+            b"GETNAME" | b"getname" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let name = self.client_name(context.token)
+                    .map_or(RespValue::Nil, RespValue::Data);
+                Ok(self.respond_resp(context, name))
+            }
+            b"LIST" | b"list" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let list = RespValue::Data(self.client_list().into());
+                Ok(self.respond_resp(context, list))
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
     fn cmd_cluster(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
-        check_arg_count(args.len(), 1, 1)?;
+        check_arg_count(args.len(), 1, 3)?;
         match args[0].as_ref() {
+            b"RETIRE" | b"retire" => {
+                check_arg_count(args.len(), 2, 2)?;
+                self.retire_node(parse_int(true, args, 1)?)?;
+                Ok(self.respond_ok(context))
+            }
+            b"REBUILD" | b"rebuild" => {
+                check_arg_count(args.len(), 2, 2)?;
+                self.vnode_rebuild(parse_int(true, args, 1)?)?;
+                Ok(self.respond_ok(context))
+            }
+            b"STANDBY" | b"standby" => {
+                check_arg_count(args.len(), 1, 2)?;
+                if args.len() > 1 {
+                    match args[1].as_ref() {
+                        b"OFF" | b"off" => {
+                            self.standby_end();
+                            return Ok(self.respond_ok(context));
+                        }
+                        _ => return Err(CommandError::UnknownCommand),
+                    }
+                }
+                let status = if self.standby_start() {
+                    "ready"
+                } else {
+                    "draining"
+                };
+                Ok(self.respond_resp(context, RespValue::Status(status.into())))
+            }
+            b"BULKLOAD" | b"bulkload" => {
+                check_arg_count(args.len(), 2, 2)?;
+                match args[1].as_ref() {
+                    b"START" | b"start" => {
+                        self.bulk_load_start();
+                        Ok(self.respond_ok(context))
+                    }
+                    b"END" | b"end" => {
+                        let count = self.bulk_load_end();
+                        Ok(self.respond_int(context, count as _))
+                    }
+                    _ => Err(CommandError::UnknownCommand),
+                }
+            }
             b"REBALANCE" | b"rebalance" => {
-                self.dht.rebalance().unwrap();
+                // migrations are throttled by the sync limits as the vnodes bootstrap
+                if self.dht.rebalance_if_unbalanced().unwrap() {
+                    Ok(self.respond_ok(context))
+                } else {
+                    Ok(self.respond_resp(context, RespValue::Status("already balanced".into())))
+                }
+            }
+            b"DUMPRING" | b"dumpring" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let ring = self.dht.save_ring();
+                Ok(self.respond_resp(context, RespValue::Data(ring.into())))
+            }
+            b"LOADRING" | b"loadring" => {
+                // replacing the ring is destructive, so it has to be confirmed explicitly
+                check_arg_count(args.len(), 3, 3)?;
+                if !args[2].eq_ignore_ascii_case(b"CONFIRM") {
+                    return Err(CommandError::InvalidCommand);
+                }
+                self.load_ring(args[1])?;
                 Ok(self.respond_ok(context))
             }
+            b"RING" | b"ring" => {
+                let ring = self.dht
+                    .ring()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(vn_no, owners)| {
+                        let owners = owners
+                            .into_iter()
+                            .map(|(node, status, ext_addr)| {
+                                RespValue::Array(vec![
+                                    RespValue::Data(node.to_string().as_bytes().into()),
+                                    RespValue::Data(ext_addr.to_string().as_bytes().into()),
+                                    RespValue::Data(
+                                        format!("{:?}", status).to_lowercase().as_bytes().into(),
+                                    ),
+                                ])
+                            })
+                            .collect();
+                        RespValue::Array(vec![RespValue::Int(vn_no as _), RespValue::Array(owners)])
+                    })
+                    .collect();
+                Ok(self.respond_resp(
+                    context,
+                    RespValue::Array(vec![
+                        RespValue::Int(self.dht.epoch() as _),
+                        RespValue::Array(ring),
+                    ]),
+                ))
+            }
             b"SLOTS" | b"slots" => {
                 let mut slots = Vec::new();
                 for (&(start, end), members) in &self.dht.slots() {
@@ -494,8 +1462,231 @@ impl Database {
         }
     }
 
+    fn cmd_debug(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        check_arg_count(args.len(), 1, 3)?;
+        match args[0].as_ref() {
+            b"VNODE" | b"vnode" => {
+                check_arg_count(args.len(), 3, 3)?;
+                self.cmd_debug_vnode(context, args[1], parse_int(true, args, 2)?)
+            }
+            b"EXPORT" | b"export" => {
+                check_arg_count(args.len(), 2, 3)?;
+                let vnode: VNodeId = parse_int(true, args, 1)?;
+                if args.len() > 2 {
+                    let path = self.export_path(assume_str(args[2]))?;
+                    let file = fs::File::create(path).map_err(|_| CommandError::InvalidValue)?;
+                    let count = self.vnode_export(vnode, io::BufWriter::new(file))?;
+                    Ok(self.respond_int(context, count as _))
+                } else {
+                    let mut buffer = Vec::new();
+                    self.vnode_export(vnode, &mut buffer)?;
+                    Ok(self.respond_resp(context, RespValue::Data(buffer.into())))
+                }
+            }
+            b"RELOAD" | b"reload" => {
+                check_arg_count(args.len(), 3, 3)?;
+                match args[1].as_ref() {
+                    b"VNODE" | b"vnode" => {
+                        self.vnode_reload(parse_int(true, args, 2)?)?;
+                        Ok(self.respond_ok(context))
+                    }
+                    _ => Err(CommandError::UnknownCommand),
+                }
+            }
+            b"LOCALGET" | b"localget" => {
+                check_arg_count(args.len(), 2, 2)?;
+                check_key_len(args[1].len())?;
+                let resp = match self.local_get(args[1])? {
+                    Some(bytes) => RespValue::Data(bytes.into()),
+                    None => RespValue::Nil,
+                };
+                Ok(self.respond_resp(context, resp))
+            }
+            b"KEYDUMP" | b"keydump" => {
+                check_arg_count(args.len(), 2, 2)?;
+                check_key_len(args[1].len())?;
+                let resp = match self.key_dump(args[1])? {
+                    Some(json) => RespValue::Data(json.into()),
+                    None => RespValue::Nil,
+                };
+                Ok(self.respond_resp(context, resp))
+            }
+            b"INFLIGHT" | b"inflight" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let ages = self.inflight_requests();
+                let fields = [
+                    ("count", ages.len() as u64),
+                    ("oldest_ms", ages.iter().cloned().max().unwrap_or(0)),
+                    ("le_10ms", ages.iter().filter(|&&a| a <= 10).count() as _),
+                    ("le_100ms", ages.iter().filter(|&&a| a <= 100).count() as _),
+                    ("le_1000ms", ages.iter().filter(|&&a| a <= 1000).count() as _),
+                    ("gt_1000ms", ages.iter().filter(|&&a| a > 1000).count() as _),
+                ];
+                let mut resp = Vec::with_capacity(fields.len() * 2);
+                for &(name, value) in &fields {
+                    resp.push(RespValue::Data(name.as_bytes().into()));
+                    resp.push(RespValue::Int(value as _));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(resp)))
+            }
+            b"FABRIC" | b"fabric" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let mut resp = Vec::new();
+                for (name, value) in self.fabric_fields() {
+                    resp.push(RespValue::Data(name.into()));
+                    resp.push(RespValue::Int(value as _));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(resp)))
+            }
+            b"VALUESTATS" | b"valuestats" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let stats = self.vnode_value_stats(parse_int(true, args, 1)?)
+                    .ok_or(CommandError::InvalidValue)?;
+                let mut fields = vec![
+                    ("keys".to_owned(), stats.keys),
+                    ("bytes".to_owned(), stats.bytes),
+                    ("max_bytes".to_owned(), stats.max_bytes),
+                ];
+                // cumulative, like the INFLIGHT ones
+                let mut le = 0;
+                for (bound, &count) in VALUE_SIZE_BUCKETS.iter().zip(&stats.buckets) {
+                    le += count;
+                    fields.push((format!("le_{}", bound), le));
+                }
+                fields.push((
+                    format!("gt_{}", VALUE_SIZE_BUCKETS[VALUE_SIZE_BUCKETS.len() - 1]),
+                    stats.buckets[VALUE_SIZE_BUCKETS.len()],
+                ));
+                let mut resp = Vec::with_capacity(fields.len() * 2);
+                for (name, value) in fields {
+                    resp.push(RespValue::Data(name.into()));
+                    resp.push(RespValue::Int(value as _));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(resp)))
+            }
+            // a single vnode is DEBUG VNODE COMPACT vnode
+            b"COMPACT" | b"compact" => {
+                check_arg_count(args.len(), 1, 1)?;
+                let count = self.compact_all();
+                Ok(self.respond_int(context, count as _))
+            }
+            b"SELFTEST" | b"selftest" => {
+                check_arg_count(args.len(), 1, 2)?;
+                self.cmd_debug_selftest(context, args.get(1).map(|a| assume_str(a)))
+            }
+            b"IMPORT" | b"import" => {
+                check_arg_count(args.len(), 2, 2)?;
+                let path = self.export_path(assume_str(args[1]))?;
+                let file = fs::File::open(path).map_err(|_| CommandError::InvalidValue)?;
+                let count = self.import_ndjson(io::BufReader::new(file))?;
+                Ok(self.respond_int(context, count as _))
+            }
+            // DEBUG CORRUPT vnode [key], exercises the recovery paths
+            #[cfg(debug_assertions)]
+            b"CORRUPT" | b"corrupt" => {
+                check_arg_count(args.len(), 2, 3)?;
+                let vnode = parse_int(true, args, 1)?;
+                let key = if args.len() > 2 {
+                    check_key_len(args[2].len())?;
+                    &args[2][..]
+                } else {
+                    CORRUPT_KEY.as_bytes()
+                };
+                self.vnode_corrupt(vnode, key)?;
+                Ok(self.respond_ok(context))
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
+    /// Writes, reads back and deletes SELFTEST_KEYS keys through the usual request path
+    /// at the configured consistency levels, replies with the mismatches if any.
+    /// The keys include the run id, random unless given, so concurrent runs don't collide.
+    fn cmd_debug_selftest(
+        &self,
+        context: &mut Context,
+        run_id: Option<&str>,
+    ) -> Result<(), CommandError> {
+        let (write, read) = {
+            let config = self.config();
+            (
+                format!("{:?}", config.consistency_write),
+                format!("{:?}", config.consistency_read),
+            )
+        };
+        let nonce: u64 = thread_rng().gen();
+        let run_id = run_id.map_or_else(|| format!("{:016X}", nonce), |id| id.to_owned());
+        let keys: Vec<Bytes> = (0..SELFTEST_KEYS)
+            .map(|i| format!("{}{}:{}", SELFTEST_KEY_PREFIX, run_id, i).into())
+            .collect();
+        for key in &keys {
+            check_key_len(key.len())?;
+        }
+        let test = Arc::new(Mutex::new(SelfTest {
+            context: Some(replace_default(context)),
+            remaining: SELFTEST_KEYS,
+            failures: Vec::new(),
+        }));
+        for (i, key) in keys.into_iter().enumerate() {
+            SelfTestKey {
+                test: test.clone(),
+                key: key,
+                value: format!("{}:{:016X}", i, nonce).into(),
+                read: read.clone().into(),
+                write: write.clone().into(),
+            }.set(self);
+        }
+        Ok(())
+    }
+
+    fn cmd_debug_vnode(
+        &self,
+        context: &mut Context,
+        sub_cmd: &Bytes,
+        vnode: VNodeId,
+    ) -> Result<(), CommandError> {
+        match sub_cmd.as_ref() {
+            b"STATS" | b"stats" => {
+                let stats = self.vnode_stats(vnode).ok_or(CommandError::InvalidValue)?;
+                let fields = [
+                    ("bytes_written", stats.bytes_written),
+                    ("live_bytes", stats.live_bytes),
+                    ("dead_bytes", stats.dead_bytes),
+                    ("compactions", stats.compactions),
+                    ("bytes_reclaimed", stats.bytes_reclaimed),
+                    ("storage_batches", stats.storage_batches),
+                    ("siblings_created", stats.siblings_created),
+                    ("cache_hits", stats.cache_hits),
+                    ("replication_lag", stats.replication_lag),
+                    ("sync_msgs_received", stats.sync_msgs_received),
+                    ("sync_keys_received", stats.sync_keys_received),
+                ];
+                let mut resp = Vec::with_capacity(fields.len() * 2);
+                for &(name, value) in &fields {
+                    resp.push(RespValue::Data(name.as_bytes().into()));
+                    resp.push(RespValue::Int(value as _));
+                }
+                Ok(self.respond_resp(context, RespValue::Array(resp)))
+            }
+            b"COMPACT" | b"compact" => {
+                if self.vnode_compact(vnode) {
+                    Ok(self.respond_ok(context))
+                } else {
+                    Err(CommandError::InvalidValue)
+                }
+            }
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+
     pub fn respond(&self, context: &mut Context) {
         debug!("Respond request ({}) {:?}", context.token, context.response);
+        if let Some(id) = context.idempotency_id.take() {
+            self.idempotent_end(id, context.response.last());
+        }
+        if self.respond_internal(context) {
+            return;
+        }
         (&self.response_fn)(replace_default(context));
     }
 
@@ -512,21 +1703,16 @@ impl Database {
         self.respond_resp(context, RespValue::Status("OK".into()));
     }
 
-    pub fn respond_error(&self, context: &mut Context, error: CommandError) {
+    pub fn respond_error<E: Into<ServerError>>(&self, context: &mut Context, error: E) {
+        let error: ServerError = error.into();
         self.respond_resp(context, error.into());
     }
 
     pub fn respond_moved(&self, context: &mut Context, vnode: VNodeId, addr: net::SocketAddr) {
-        self.respond_resp(
-            context,
-            RespValue::Error(format!("MOVED {} {}", vnode, addr).into()),
-        );
+        self.respond_error(context, ServerError::Moved(vnode, addr));
     }
 
     pub fn respond_ask(&self, context: &mut Context, vnode: VNodeId, addr: net::SocketAddr) {
-        self.respond_resp(
-            context,
-            RespValue::Error(format!("ASK {} {}", vnode, addr).into()),
-        );
+        self.respond_error(context, ServerError::Ask(vnode, addr));
     }
 }
@@ -11,7 +11,9 @@ use log4rs;
 use num_cpus;
 use serde_yaml as yaml;
 
-use types::ConsistencyLevel;
+use types::{BlindWritePolicy, BootstrapReadPolicy, CommitPolicy, ConsistencyLevel,
+            ContextEncoding, CoordinatorPolicy, NodeId, ReadRepair, RoutingPolicy,
+            SyncPeerStrategy, UnavailablePolicy};
 use utils::GenericError;
 
 // Remember to update defaults in sucredb.yaml!
@@ -28,27 +30,64 @@ pub const MAX_VALUE_LEN: usize = 10 * 1024 * 1024;
 pub struct Config {
     pub data_dir: PathBuf,
     pub cluster_name: String,
+    // if not set a random one is generated when the node is created
+    pub node_id: Option<NodeId>,
     pub listen_addr: SocketAddr,
     pub fabric_addr: SocketAddr,
     pub cmd_init: Option<InitCommand>,
     pub worker_timer: u32,
+    pub worker_flush_interval: u32,
+    pub worker_remote_set_batch: u32,
     pub worker_count: u16,
+    pub worker_vnode_affinity: bool,
+    pub worker_background_count: u16,
     pub sync_incomming_max: u16,
     pub sync_outgoing_max: u16,
+    pub sync_max: u16,
     pub sync_auto: bool,
+    pub sync_peer_strategy: SyncPeerStrategy,
     pub sync_timeout: u32,
     pub sync_msg_timeout: u32,
     pub sync_msg_inflight: u32,
+    pub sync_msg_batch: u32,
+    pub vnode_zombie_timeout: u32,
+    pub scrub_interval: u32,
+    pub scrub_sample_size: u32,
+    pub scrub_repair_max: u32,
+    pub storage_verify_on_recovery: bool,
+    pub expire_sweep_interval: u32,
+    pub expire_sweep_size: u32,
     pub dht_sync_on_connect: bool,
     pub dht_sync_aae: bool,
+    pub dht_heartbeat_interval: u32,
+    pub dht_failure_timeout: u32,
     pub fabric_timeout: u32,
     pub request_timeout: u32,
     pub client_connection_max: u32,
+    pub client_listen_backlog: u32,
+    pub client_acceptors: u32,
+    pub client_pipeline_max: u32,
     pub value_version_max: u16,
+    pub read_cache_size: u32,
+    pub read_fanout_extra: u8,
+    pub response_size_max: u32,
     pub seed_nodes: Vec<SocketAddr>,
+    // key prefixes placed by a policy instead of the key hash
+    pub routing_overrides: Vec<(String, RoutingPolicy)>,
     // TODO: these should be in the cluster config instead
     pub consistency_read: ConsistencyLevel,
     pub consistency_write: ConsistencyLevel,
+    pub unavailable_policy: UnavailablePolicy,
+    pub commit_policy: CommitPolicy,
+    pub context_encoding: ContextEncoding,
+    pub blind_write_policy: BlindWritePolicy,
+    pub coordinator_policy: CoordinatorPolicy,
+    pub read_repair: ReadRepair,
+    pub bootstrap_read_policy: BootstrapReadPolicy,
+    pub idempotency_window: u32,
+    pub flushall_enabled: bool,
+    // file the config was read from, if any, CONFIG RELOAD reads it again
+    pub config_file: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -57,30 +96,219 @@ impl Default for Config {
         Config {
             data_dir: DEFAULT_DATA_DIR.into(),
             cluster_name: DEFAULT_CLUSTER_NAME.into(),
+            node_id: None,
             listen_addr: DEFAULT_LISTEN_ADDR.parse().unwrap(),
             fabric_addr: DEFAULT_FABRIC_ADDR.parse().unwrap(),
             cmd_init: None,
             worker_timer: 500,
+            worker_flush_interval: 0,
+            worker_remote_set_batch: 100,
             worker_count: max(4, num_cpus::get() as u16 * 2),
+            worker_vnode_affinity: false,
+            worker_background_count: 2,
             sync_incomming_max: 10,
             sync_outgoing_max: 10,
+            sync_max: 5,
             sync_timeout: 10_000,
             sync_msg_timeout: 1000,
             sync_msg_inflight: 10,
+            sync_msg_batch: 1,
+            vnode_zombie_timeout: 60_000,
+            scrub_interval: 60_000,
+            scrub_sample_size: 100,
+            scrub_repair_max: 10,
+            storage_verify_on_recovery: false,
+            expire_sweep_interval: 10_000,
+            expire_sweep_size: 1_000,
             sync_auto: true,
+            sync_peer_strategy: SyncPeerStrategy::Random,
             dht_sync_on_connect: true,
             dht_sync_aae: true,
+            dht_heartbeat_interval: 1000,
+            dht_failure_timeout: 10_000,
             fabric_timeout: 1000,
             request_timeout: 1000,
             client_connection_max: 100,
+            client_listen_backlog: 1024,
+            client_acceptors: 1,
+            client_pipeline_max: 1000,
             value_version_max: 100,
+            read_cache_size: 0,
+            read_fanout_extra: 1,
+            response_size_max: 64 * 1024 * 1024,
             seed_nodes: Vec::new(),
+            routing_overrides: Vec::new(),
             consistency_read: ConsistencyLevel::One,
             consistency_write: ConsistencyLevel::One,
+            unavailable_policy: UnavailablePolicy::Fail,
+            commit_policy: CommitPolicy::Async,
+            context_encoding: ContextEncoding::Bincode,
+            blind_write_policy: BlindWritePolicy::Sibling,
+            coordinator_policy: CoordinatorPolicy::Random,
+            read_repair: ReadRepair::Off,
+            bootstrap_read_policy: BootstrapReadPolicy::Redirect,
+            idempotency_window: 60_000,
+            flushall_enabled: false,
+            config_file: None,
         }
     }
 }
 
+impl Config {
+    /// Takes the parameters that can change at runtime from `new`,
+    /// returns the changed parameters and if they require a restart.
+    pub fn update(&mut self, new: &Config) -> Vec<(&'static str, bool)> {
+        let mut changes = Vec::new();
+        macro_rules! live {
+            ($($param:ident),*) => {{ $(
+                if format!("{:?}", self.$param) != format!("{:?}", new.$param) {
+                    self.$param = new.$param.clone();
+                    changes.push((stringify!($param), false));
+                }
+            )* }};
+        }
+        // these are read once on startup
+        macro_rules! restart {
+            ($($param:ident),*) => {{ $(
+                if format!("{:?}", self.$param) != format!("{:?}", new.$param) {
+                    changes.push((stringify!($param), true));
+                }
+            )* }};
+        }
+        live!(
+            worker_timer,
+            worker_count,
+            sync_incomming_max,
+            sync_outgoing_max,
+            sync_max,
+            sync_auto,
+            sync_peer_strategy,
+            sync_timeout,
+            sync_msg_timeout,
+            sync_msg_inflight,
+            sync_msg_batch,
+            vnode_zombie_timeout,
+            scrub_interval,
+            scrub_sample_size,
+            scrub_repair_max,
+            expire_sweep_interval,
+            expire_sweep_size,
+            worker_remote_set_batch,
+            request_timeout,
+            client_connection_max,
+            client_pipeline_max,
+            value_version_max,
+            read_fanout_extra,
+            response_size_max,
+            consistency_read,
+            consistency_write,
+            unavailable_policy,
+            commit_policy,
+            context_encoding,
+            blind_write_policy,
+            coordinator_policy,
+            read_repair,
+            bootstrap_read_policy,
+            idempotency_window,
+            flushall_enabled
+        );
+        restart!(
+            data_dir,
+            cluster_name,
+            node_id,
+            listen_addr,
+            fabric_addr,
+            worker_flush_interval,
+            worker_vnode_affinity,
+            worker_background_count,
+            dht_sync_on_connect,
+            dht_sync_aae,
+            dht_heartbeat_interval,
+            dht_failure_timeout,
+            fabric_timeout,
+            client_listen_backlog,
+            client_acceptors,
+            read_cache_size,
+            storage_verify_on_recovery,
+            seed_nodes,
+            routing_overrides
+        );
+        changes
+    }
+
+    /// Value of a parameter as displayed by CONFIG GET
+    pub fn get(&self, param: &str) -> Option<String> {
+        macro_rules! get {
+            ($($param:ident),*) => {
+                match param {
+                    $(stringify!($param) => Some(format!("{:?}", self.$param)),)*
+                    _ => None,
+                }
+            };
+        }
+        get!(
+            data_dir,
+            cluster_name,
+            node_id,
+            listen_addr,
+            fabric_addr,
+            worker_timer,
+            worker_flush_interval,
+            worker_count,
+            worker_vnode_affinity,
+            worker_background_count,
+            sync_incomming_max,
+            sync_outgoing_max,
+            sync_max,
+            sync_auto,
+            sync_peer_strategy,
+            sync_timeout,
+            sync_msg_timeout,
+            sync_msg_inflight,
+            sync_msg_batch,
+            vnode_zombie_timeout,
+            scrub_interval,
+            scrub_sample_size,
+            scrub_repair_max,
+            storage_verify_on_recovery,
+            expire_sweep_interval,
+            expire_sweep_size,
+            worker_remote_set_batch,
+            dht_sync_on_connect,
+            dht_sync_aae,
+            dht_heartbeat_interval,
+            dht_failure_timeout,
+            fabric_timeout,
+            request_timeout,
+            client_connection_max,
+            client_listen_backlog,
+            client_acceptors,
+            client_pipeline_max,
+            value_version_max,
+            read_fanout_extra,
+            response_size_max,
+            read_cache_size,
+            seed_nodes,
+            routing_overrides,
+            consistency_read,
+            consistency_write,
+            unavailable_policy,
+            commit_policy,
+            context_encoding,
+            blind_write_policy,
+            coordinator_policy,
+            read_repair,
+            bootstrap_read_policy,
+            idempotency_window,
+            flushall_enabled
+        )
+    }
+}
+
+/// Parameters fixed when the cluster is created, changing them at runtime
+/// would route the keys to the wrong vnodes.
+pub const IMMUTABLE_PARAMS: &[&str] = &["partitions", "replication_factor"];
+
 #[derive(Debug, Clone)]
 pub struct InitCommand {
     pub replication_factor: u8,
@@ -120,71 +348,142 @@ pub fn parse_size(size_text: &str) -> Result<i64, GenericError> {
     number.checked_mul(scale).ok_or("Overflow error".into())
 }
 
+// each one fails with the parameter that can't be parsed
 macro_rules! cfg {
     ($yaml:ident, $target:ident, $string:ident, $method:ident) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v.$method()
-                .expect(concat!("Can't access field with", stringify!($method)));
+            let v = v.$method().ok_or(concat!(
+                "Invalid ",
+                stringify!($string),
+                ", can't access it with ",
+                stringify!($method)
+            ))?;
             $target.$string = v.into();
         }
     };
     ($yaml:ident, $target:ident, $string:ident, $method:ident,try_into) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v.$method()
-                .expect(concat!("Can't access field with", stringify!($method)));
+            let v = v.$method().ok_or(concat!(
+                "Invalid ",
+                stringify!($string),
+                ", can't access it with ",
+                stringify!($method)
+            ))?;
             $target.$string = v.try_into()
-                .expect(concat!("Can't convert ", stringify!($string)));
+                .map_err(|e| format!(concat!("Invalid ", stringify!($string), ": {:?}"), e))?;
         }
     };
     ($yaml:ident, $target:ident, $string:ident, $method:ident, $convert:expr) => {
         if let Some(v) = $yaml.get(stringify!($string)) {
-            let v = v.$method().expect(concat!(
-                "Can't access key ",
+            let v = v.$method().ok_or(concat!(
+                "Invalid ",
                 stringify!($string),
-                " with",
+                ", can't access it with ",
                 stringify!($method)
-            ));
+            ))?;
             $target.$string = $convert(v)
-                .expect(concat!(
-                    "Can't convert ",
-                    stringify!($string),
-                    " with ",
-                    stringify!($convert)
-                ))
+                .map_err(|e| format!(concat!("Invalid ", stringify!($string), ": {:?}"), e))?
                 .try_into()
-                .expect(concat!("Can't convert ", stringify!($string)));
+                .map_err(|e| format!(concat!("Invalid ", stringify!($string), ": {:?}"), e))?;
         }
     };
 }
 
+fn read_yaml_file(path: &Path) -> Result<yaml::Value, GenericError> {
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s))?;
+    Ok(yaml::from_str::<yaml::Value>(&s)?)
+}
+
 pub fn read_config_file(path: &Path, config: &mut Config) {
     debug!("Reading config file");
-    let yaml = {
-        let mut s = String::new();
-        File::open(path)
-            .and_then(|mut f| f.read_to_string(&mut s))
-            .expect("Error reading config file");
-        yaml::from_str::<yaml::Value>(&s).expect("Error parsing config file")
-    };
+    let yaml = read_yaml_file(path).expect("Error reading config file");
     debug!("Done reading config file: {:?}", config);
 
+    config.config_file = Some(path.into());
+    if let Err(e) = apply_config_yaml(&yaml, config) {
+        panic!("Error in config file: {}", e);
+    }
+
+    if let Some(config_value) = yaml.get("logging") {
+        setup_logging(config_value);
+    }
+}
+
+/// Reads the config file again on top of `config`, logging isn't reconfigured.
+pub fn reload_config_file(config: &Config) -> Result<Config, GenericError> {
+    let path = config.config_file.as_ref().ok_or("No config file")?;
+    let yaml = read_yaml_file(path)?;
+    let mut new_config = config.clone();
+    apply_config_yaml(&yaml, &mut new_config)?;
+    Ok(new_config)
+}
+
+/// Sets a single parameter on top of `config`, the value is parsed like in the config file.
+pub fn set_param(config: &Config, param: &str, value: &str) -> Result<Config, GenericError> {
+    if config.get(param).is_none() {
+        return Err(format!("Unknown parameter {}", param).into());
+    }
+    let mut yaml = yaml::Mapping::new();
+    yaml.insert(yaml::Value::String(param.to_owned()), yaml::from_str(value)?);
+    let yaml = yaml::Value::Mapping(yaml);
+    let mut new_config = config.clone();
+    apply_config_yaml(&yaml, &mut new_config)?;
+    Ok(new_config)
+}
+
+fn apply_config_yaml(yaml: &yaml::Value, config: &mut Config) -> Result<(), GenericError> {
     cfg!(yaml, config, data_dir, as_str);
     cfg!(yaml, config, cluster_name, as_str);
+    if let Some(v) = yaml.get("node_id") {
+        config.node_id = Some(v.as_u64().ok_or("Invalid node_id, not a positive integer")?);
+    }
     cfg!(yaml, config, listen_addr, as_str, SocketAddr::from_str);
     cfg!(yaml, config, fabric_addr, as_str, SocketAddr::from_str);
     // pub cmd_init: Option<InitCommand>,
     cfg!(yaml, config, worker_timer, as_str, parse_duration);
+    cfg!(yaml, config, worker_flush_interval, as_str, parse_duration);
+    cfg!(yaml, config, worker_remote_set_batch, as_u64, try_into);
     cfg!(yaml, config, worker_count, as_u64, try_into);
+    if config.worker_count == 0 {
+        return Err("Invalid worker_count, there must be at least one worker".into());
+    }
+    cfg!(yaml, config, worker_vnode_affinity, as_bool);
+    cfg!(yaml, config, worker_background_count, as_u64, try_into);
     cfg!(yaml, config, sync_incomming_max, as_u64, try_into);
     cfg!(yaml, config, sync_outgoing_max, as_u64, try_into);
+    cfg!(yaml, config, sync_max, as_u64, try_into);
     cfg!(yaml, config, sync_auto, as_bool);
+    cfg!(
+        yaml,
+        config,
+        sync_peer_strategy,
+        as_str,
+        SyncPeerStrategy::from_str
+    );
     cfg!(yaml, config, sync_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_timeout, as_str, parse_duration);
     cfg!(yaml, config, sync_msg_inflight, as_u64, try_into);
+    cfg!(yaml, config, sync_msg_batch, as_u64, try_into);
+    cfg!(yaml, config, vnode_zombie_timeout, as_str, parse_duration);
+    cfg!(yaml, config, scrub_interval, as_str, parse_duration);
+    cfg!(yaml, config, scrub_sample_size, as_u64, try_into);
+    cfg!(yaml, config, scrub_repair_max, as_u64, try_into);
+    cfg!(yaml, config, storage_verify_on_recovery, as_bool);
+    cfg!(yaml, config, expire_sweep_interval, as_str, parse_duration);
+    cfg!(yaml, config, expire_sweep_size, as_u64, try_into);
+    cfg!(yaml, config, dht_heartbeat_interval, as_str, parse_duration);
+    cfg!(yaml, config, dht_failure_timeout, as_str, parse_duration);
     cfg!(yaml, config, fabric_timeout, as_str, parse_duration);
     cfg!(yaml, config, request_timeout, as_str, parse_duration);
     cfg!(yaml, config, client_connection_max, as_u64, try_into);
+    cfg!(yaml, config, client_listen_backlog, as_u64, try_into);
+    cfg!(yaml, config, client_acceptors, as_u64, try_into);
+    cfg!(yaml, config, client_pipeline_max, as_u64, try_into);
     cfg!(yaml, config, value_version_max, as_u64, try_into);
+    cfg!(yaml, config, read_cache_size, as_u64, try_into);
+    cfg!(yaml, config, read_fanout_extra, as_u64, try_into);
+    cfg!(yaml, config, response_size_max, as_str, parse_size);
     cfg!(
         yaml,
         config,
@@ -199,23 +498,78 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
         as_str,
         ConsistencyLevel::from_str
     );
+    cfg!(
+        yaml,
+        config,
+        unavailable_policy,
+        as_str,
+        UnavailablePolicy::from_str
+    );
+    cfg!(yaml, config, commit_policy, as_str, CommitPolicy::from_str);
+    cfg!(
+        yaml,
+        config,
+        context_encoding,
+        as_str,
+        ContextEncoding::from_str
+    );
+    cfg!(
+        yaml,
+        config,
+        blind_write_policy,
+        as_str,
+        BlindWritePolicy::from_str
+    );
+    cfg!(
+        yaml,
+        config,
+        coordinator_policy,
+        as_str,
+        CoordinatorPolicy::from_str
+    );
+    cfg!(yaml, config, read_repair, as_str, ReadRepair::from_str);
+    cfg!(
+        yaml,
+        config,
+        bootstrap_read_policy,
+        as_str,
+        BootstrapReadPolicy::from_str
+    );
+    cfg!(yaml, config, idempotency_window, as_str, parse_duration);
+    cfg!(yaml, config, flushall_enabled, as_bool);
 
     if let Some(v) = yaml.get("seed_nodes") {
         config.seed_nodes = v.as_sequence()
-            .expect("seed_nodes is not a sequence")
+            .ok_or("Invalid seed_nodes, not a sequence")?
             .iter()
-            .map(|v| {
-                v.as_str()
-                    .expect("seed_nodes element is not a string")
-                    .parse()
-                    .expect("seed_nodes element can't be parsed")
+            .map(|v| -> Result<_, GenericError> {
+                let addr = v.as_str()
+                    .ok_or("Invalid seed_nodes, element is not a string")?
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Invalid seed_nodes element: {}", e))?;
+                Ok(addr)
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
     }
 
-    if let Some(config_value) = yaml.get("logging") {
-        setup_logging(config_value);
+    if let Some(v) = yaml.get("routing_overrides") {
+        config.routing_overrides = v.as_mapping()
+            .ok_or("Invalid routing_overrides, not a mapping")?
+            .iter()
+            .map(|(prefix, policy)| -> Result<_, GenericError> {
+                let prefix = prefix
+                    .as_str()
+                    .ok_or("Invalid routing_overrides, prefix is not a string")?;
+                let policy: RoutingPolicy = policy
+                    .as_str()
+                    .ok_or("Invalid routing_overrides, policy is not a string")?
+                    .parse()
+                    .map_err(|e| format!("Invalid routing_overrides policy: {:?}", e))?;
+                Ok((prefix.to_owned(), policy))
+            })
+            .collect::<Result<_, _>>()?;
     }
+    Ok(())
 }
 
 pub fn setup_logging(config_value: &yaml::Value) {
@@ -1,24 +1,32 @@
+use base64;
 use bincode;
+use byteorder::{LittleEndian, ReadBytesExt};
 use bytes::Bytes;
 use command::CommandError;
 use linear_map::{Entry as LMEntry, LinearMap};
+use msgpack;
 use resp::RespValue;
 use std::boxed::FnBox;
+use std::cmp::max;
 use std::time;
+use types::ContextEncoding;
+use utils::now_millis;
 use version_vector::*;
 
 pub type MutatorFn =
-    Box<FnBox(Id, Version, Cube) -> Result<(Cube, Option<RespValue>), CommandError> + Send>;
-pub type ResponseFn = Box<FnMut(Cube) -> RespValue + Send>;
+    Box<FnBox(Id, Version, Cube) -> Result<(Cube, Option<RespValue>), CommandError> + Send + Sync>;
+pub type ResponseFn = Box<FnMut(Cube) -> RespValue + Send + Sync>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Cube {
-    // the order is used to merge different types in a deterministic way
+    // the order is used to merge different types in a deterministic way,
+    // it's also the stored enum tag read by serialized_type_name
     Counter(Counter),
     Value(Value),
     Map(Map),
     Set(Set),
     Void(VersionVector),
+    PNCounter(PNCounter),
 }
 
 macro_rules! impl_into{
@@ -47,14 +55,148 @@ impl Cube {
             Value(ref a) => a.values.is_empty() && a.vv.contained(bvv),
             Map(ref a) => a.values.is_empty() && a.vv.contained(bvv),
             Set(ref a) => a.values.is_empty() && a.vv.contained(bvv),
+            PNCounter(ref a) => a.values.is_empty() && a.vv.contained(bvv),
             Void(_) => unreachable!(),
         }
     }
 
+    pub fn type_name(&self) -> &'static str {
+        use self::Cube::*;
+        match *self {
+            Counter(_) => "counter", // non-standard
+            Value(_) => "string",
+            Map(_) => "hash",
+            Set(_) => "set",
+            Void(_) => "none",
+            PNCounter(_) => "pncounter", // non-standard
+        }
+    }
+
+    /// Type name of a serialized cube, reading only the enum tag
+    pub fn serialized_type_name(bytes: &[u8]) -> Option<&'static str> {
+        let bytes = match format_version(bytes) {
+            Some(1) => bytes,
+            Some(2) | Some(3) | Some(FORMAT_VERSION) => &bytes[1..],
+            _ => return None,
+        };
+        // bincode encodes the variant index as a little endian u32
+        let tag = match bytes.get(..4) {
+            Some(mut tag) => tag.read_u32::<LittleEndian>().unwrap(),
+            None => return None,
+        };
+        Some(match tag {
+            0 => "counter",
+            1 => "string",
+            2 => "hash",
+            3 => "set",
+            4 => "none",
+            5 => "pncounter",
+            _ => return None,
+        })
+    }
+
+    fn expire_mut(&mut self) -> Option<&mut Option<Expire>> {
+        use self::Cube::*;
+        match *self {
+            Counter(ref mut a) => Some(&mut a.expire),
+            Value(ref mut a) => Some(&mut a.expire),
+            Map(ref mut a) => Some(&mut a.expire),
+            Set(ref mut a) => Some(&mut a.expire),
+            PNCounter(ref mut a) => Some(&mut a.expire),
+            Void(_) => None,
+        }
+    }
+
+    /// Instant the key expires at, in millis since epoch
+    pub fn expire(&self) -> Option<u64> {
+        use self::Cube::*;
+        let expire = match *self {
+            Counter(ref a) => a.expire,
+            Value(ref a) => a.expire,
+            Map(ref a) => a.expire,
+            Set(ref a) => a.expire,
+            PNCounter(ref a) => a.expire,
+            Void(_) => None,
+        };
+        expire.and_then(|e| e.at)
+    }
+
+    /// Sets or clears (with None) the expiration, voids can't expire
+    pub fn set_expire(&mut self, id: Id, at: Option<u64>) {
+        if let Some(expire) = self.expire_mut() {
+            *expire = Some(Expire {
+                at,
+                changed: (now_millis(), id),
+            });
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire().map_or(false, |at| at <= now)
+    }
+
+    /// As seen by reads, an expired cube is a void with its causal context
+    pub fn without_expired(self, now: u64) -> Self {
+        use self::Cube::*;
+        if !self.is_expired(now) {
+            return self;
+        }
+        match self {
+            Counter(a) => Void(a.vv),
+            Value(a) => Void(a.vv),
+            Map(a) => Void(a.vv),
+            Set(a) => Void(a.vv),
+            PNCounter(a) => Void(a.vv),
+            Void(vv) => Void(vv),
+        }
+    }
+
+    /// As seen by writes, an expired cube has its values removed and the expiration cleared,
+    /// so the replicas discard them when merging the write.
+    pub fn clear_expired(mut self, id: Id, now: u64) -> Self {
+        use self::Cube::*;
+        if !self.is_expired(now) {
+            return self;
+        }
+        match self {
+            Counter(ref mut a) => a.clear_values(),
+            Value(ref mut a) => a.values = Default::default(),
+            Map(ref mut a) => a.values = Default::default(),
+            Set(ref mut a) => a.values = Default::default(),
+            PNCounter(ref mut a) => a.clear_values(),
+            Void(_) => unreachable!(),
+        }
+        self.set_expire(id, None);
+        self
+    }
+
+    /// True if there's nothing to render, like missing or deleted keys
+    pub fn is_empty(&self) -> bool {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => a.is_empty(),
+            Value(ref a) => a.present().next().is_none(),
+            Map(ref a) => a.values.is_empty(),
+            Set(ref a) => a.values.is_empty(),
+            PNCounter(ref a) => a.is_empty(),
+            Void(_) => true,
+        }
+    }
+
+    /// Number of concurrent values, only registers can have more than one
+    pub fn siblings(&self) -> usize {
+        match *self {
+            Cube::Value(ref a) => a.len(),
+            Cube::Void(_) => 0,
+            _ => 1,
+        }
+    }
+
     impl_into!(into_value, Value);
     impl_into!(into_counter, Counter);
     impl_into!(into_map, Map);
     impl_into!(into_set, Set);
+    impl_into!(into_pncounter, PNCounter);
 
     // minimum set of dots required to assemble this cube
     // see comment at the bottom
@@ -65,13 +207,15 @@ impl Cube {
             Value(ref a) => a.values.iter().for_each(|(&(i, v), _)| cb(i, v)),
             Map(ref a) => a.dots.iter().for_each(|(i, v)| cb(i, v)),
             Set(ref a) => a.dots.iter().for_each(|(i, v)| cb(i, v)),
+            PNCounter(ref a) => a.values.iter().for_each(|(&i, &(v, _, _))| cb(i, v)),
             Void(_) => unreachable!(),
         }
     }
 
     pub fn new(bvv: &BitmappedVersionVector) -> Cube {
         let mut vv = VersionVector::new();
-        for (&n, bv) in bvv.iter() {
+        // ids with a 0 base only have dots past holes, they aren't in any stripped vv
+        for (&n, bv) in bvv.iter().filter(|&(_, bv)| bv.base() != 0) {
             vv.add(n, bv.base());
         }
         Cube::Void(vv)
@@ -84,6 +228,7 @@ impl Cube {
             Value(ref mut a) => a.set(id, version, None, vv),
             Map(ref mut a) => a.clear(id, version),
             Set(ref mut a) => a.clear(id, version),
+            PNCounter(ref mut a) => a.clear(id, version),
             Void(_) => return false,
         }
         true
@@ -96,11 +241,13 @@ impl Cube {
             (Value(a), Value(b)) => Value(a.merge(b)),
             (Map(a), Map(b)) => Map(a.merge(b)),
             (Set(a), Set(b)) => Set(a.merge(b)),
+            (PNCounter(a), PNCounter(b)) => PNCounter(a.merge(b)),
             (Void(vv), a) | (a, Void(vv)) => match a {
                 Counter(a) => Counter(a.merge(self::Counter::with(vv))),
                 Value(a) => Value(a.merge(self::Value::with(vv))),
                 Map(a) => Map(a.merge(self::Map::with(vv))),
                 Set(a) => Set(a.merge(self::Set::with(vv))),
+                PNCounter(a) => PNCounter(a.merge(self::PNCounter::with(vv))),
                 Void(mut o_vv) => {
                     o_vv.merge(&vv);
                     Void(o_vv)
@@ -114,6 +261,7 @@ impl Cube {
                     (Value(a), _) | (_, Value(a)) => Value(a),
                     (Map(a), _) | (_, Map(a)) => Map(a),
                     (Set(a), _) | (_, Set(a)) => Set(a),
+                    (PNCounter(a), _) | (_, PNCounter(a)) => PNCounter(a),
                     (Void(_), _) | (_, Void(_)) => unreachable!(),
                 }
             }
@@ -121,23 +269,63 @@ impl Cube {
     }
 }
 
+/// Expiration of a key, the cube types keep it as their last field
+/// so the formats before it can be decoded by appending a None (see deserialize_cube).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expire {
+    // millis since epoch, None if it was cleared
+    at: Option<u64>,
+    // millis since epoch of the change and the node that made it, the latest change wins
+    changed: (u64, Id),
+}
+
+fn merge_expire(a: Option<Expire>, b: Option<Expire>) -> Option<Expire> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.changed > a.changed { b } else { a }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
 // RWCounter
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Counter {
     values: LinearMap<Id, (Version, i64)>,
+    // version and count of each node observed by deletes, the count is subtracted
+    cleared: LinearMap<Id, (Version, i64)>,
     vv: VersionVector,
+    expire: Option<Expire>,
 }
 
 impl Counter {
     fn with(vv: VersionVector) -> Self {
         Counter {
             values: Default::default(),
+            cleared: Default::default(),
             vv,
+            expire: None,
         }
     }
 
     pub fn get(&self) -> i64 {
-        self.values.values().map(|&(_, c)| c).sum()
+        self.values.iter().fold(0i64, |sum, (id, &(_, c))| {
+            let cleared = self.cleared.get(id).map_or(0, |&(_, cc)| cc);
+            sum.wrapping_add(c.wrapping_sub(cleared))
+        })
+    }
+
+    /// True if every count was deleted at its latest version, or there are none
+    pub fn is_empty(&self) -> bool {
+        self.values
+            .iter()
+            .all(|(id, &(v, _))| self.cleared.get(id).map(|&(cv, _)| cv) == Some(v))
+    }
+
+    // like for PNCounter the counts are kept so merges can't revive what was deleted
+    fn clear_values(&mut self) {
+        for (&id, &count) in &self.values {
+            self.cleared.insert(id, count);
+        }
     }
 
     pub fn inc(&mut self, node: Id, version: Version, by: i64) -> i64 {
@@ -149,11 +337,14 @@ impl Counter {
     }
 
     pub fn clear(&mut self, node: Id, version: Version) {
-        self.values.clear();
+        self.clear_values();
         self.vv.add(node, version);
     }
 
+    // a node's count is only changed by the node, so the latest version of it wins,
+    // the same goes for the ones observed by deletes
     fn merge(mut self, other: Self) -> Self {
+        self.expire = merge_expire(self.expire, other.expire);
         for (id, other) in other.values {
             match self.values.entry(id) {
                 LMEntry::Occupied(mut oc) => if other.0 > oc.get().0 {
@@ -164,6 +355,102 @@ impl Counter {
                 }
             }
         }
+        for (id, other) in other.cleared {
+            match self.cleared.entry(id) {
+                LMEntry::Occupied(mut oc) => if other.0 > oc.get().0 {
+                    *oc.get_mut() = other;
+                },
+                LMEntry::Vacant(va) => {
+                    va.insert(other);
+                }
+            }
+        }
+        self
+    }
+}
+
+// PNCounter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PNCounter {
+    // version of the latest change of each node with its increments and decrements totals
+    values: LinearMap<Id, (Version, u64, u64)>,
+    // totals of each node observed by deletes, they're subtracted from the values
+    cleared: LinearMap<Id, (u64, u64)>,
+    vv: VersionVector,
+    expire: Option<Expire>,
+}
+
+impl PNCounter {
+    fn with(vv: VersionVector) -> Self {
+        PNCounter {
+            values: Default::default(),
+            cleared: Default::default(),
+            vv,
+            expire: None,
+        }
+    }
+
+    pub fn get(&self) -> i64 {
+        self.values.iter().fold(0i64, |sum, (id, &(_, p, n))| {
+            let (cp, cn) = self.cleared.get(id).cloned().unwrap_or((0, 0));
+            let total = p.wrapping_sub(n).wrapping_sub(cp.wrapping_sub(cn));
+            sum.wrapping_add(total as i64)
+        })
+    }
+
+    /// True if every total was deleted, or there are none
+    pub fn is_empty(&self) -> bool {
+        self.values
+            .iter()
+            .all(|(id, &(_, p, n))| self.cleared.get(id) == Some(&(p, n)))
+    }
+
+    // the totals are kept so concurrent changes can't revive what was deleted
+    fn clear_values(&mut self) {
+        for (&id, &(_, p, n)) in &self.values {
+            self.cleared.insert(id, (p, n));
+        }
+    }
+
+    pub fn inc(&mut self, node: Id, version: Version, by: i64) -> i64 {
+        self.vv.add(node, version);
+        {
+            let totals = self.values.entry(node).or_insert((0, 0, 0));
+            totals.0 = version;
+            if by >= 0 {
+                totals.1 = totals.1.wrapping_add(by as u64);
+            } else {
+                totals.2 = totals.2.wrapping_add(by.wrapping_neg() as u64);
+            }
+        }
+        self.get()
+    }
+
+    pub fn clear(&mut self, node: Id, version: Version) {
+        self.clear_values();
+        self.vv.add(node, version);
+    }
+
+    // the totals of a node only grow, so its latest version has their max,
+    // and the deleted totals are the max observed by either side.
+    fn merge(mut self, other: Self) -> Self {
+        for (id, totals) in other.values {
+            match self.values.entry(id) {
+                LMEntry::Occupied(mut oc) => if totals.0 > oc.get().0 {
+                    *oc.get_mut() = totals;
+                },
+                LMEntry::Vacant(va) => {
+                    va.insert(totals);
+                }
+            }
+        }
+        for (id, (p, n)) in other.cleared {
+            let cleared = self.cleared.entry(id).or_insert((0, 0));
+            cleared.0 = max(cleared.0, p);
+            cleared.1 = max(cleared.1, n);
+        }
+        self.vv.merge(&other.vv);
+        self.expire = merge_expire(self.expire, other.expire);
         self
     }
 }
@@ -173,6 +460,7 @@ impl Counter {
 pub struct Value {
     values: DotMap<Option<Bytes>>,
     vv: VersionVector,
+    expire: Option<Expire>,
 }
 
 impl Value {
@@ -180,6 +468,7 @@ impl Value {
         Value {
             values: Default::default(),
             vv,
+            expire: None,
         }
     }
 
@@ -187,6 +476,15 @@ impl Value {
         self.values.len()
     }
 
+    pub fn vv(&self) -> &VersionVector {
+        &self.vv
+    }
+
+    /// The values that aren't deletes, in the order they're rendered
+    pub fn present(&self) -> impl Iterator<Item = &Bytes> {
+        self.values.values().filter_map(|v| v.as_ref())
+    }
+
     pub fn set(&mut self, node: Id, version: Version, value: Option<Bytes>, vv: &VersionVector) {
         self.values.discard(vv);
         self.values.insert(node, version, value);
@@ -196,6 +494,7 @@ impl Value {
     fn merge(mut self, mut other: Self) -> Self {
         self.values.merge(&mut other.values, &self.vv, &other.vv);
         self.vv.merge(&other.vv);
+        self.expire = merge_expire(self.expire, other.expire);
         self
     }
 }
@@ -207,6 +506,7 @@ pub struct Set {
     values: CausalMap<Bytes, DotSet>,
     dots: VersionVector,
     vv: VersionVector,
+    expire: Option<Expire>,
 }
 
 impl Set {
@@ -215,6 +515,7 @@ impl Set {
             values: Default::default(),
             dots: Default::default(),
             vv,
+            expire: None,
         }
     }
 
@@ -244,17 +545,19 @@ impl Set {
         self.values.merge(&mut other.values, &self.vv, &other.vv);
         self.vv.merge(&other.vv);
         self.dots.merge(&other.dots);
+        self.expire = merge_expire(self.expire, other.expire);
         self
     }
 }
 
 // Actor Observed removal
-// LWW on value conflict (max as tiebreaker)
+// LWW on value conflict (highest dot as tiebreaker)
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Map {
     values: CausalMap<Bytes, MapValue>,
     dots: VersionVector,
     vv: VersionVector,
+    expire: Option<Expire>,
 }
 
 impl Map {
@@ -263,6 +566,7 @@ impl Map {
             values: Default::default(),
             dots: Default::default(),
             vv,
+            expire: None,
         }
     }
 
@@ -292,6 +596,7 @@ impl Map {
         self.values.merge(&mut other.values, &self.vv, &other.vv);
         self.vv.merge(&other.vv);
         self.dots.merge(&other.dots);
+        self.expire = merge_expire(self.expire, other.expire);
         self
     }
 }
@@ -301,6 +606,7 @@ struct MapValue {
     dots: DotSet,
     value: Bytes,
     timestamp: u64, // millis since epoch
+    dot: (Id, Version), // dot of the write that set value
 }
 
 impl MapValue {
@@ -310,6 +616,7 @@ impl MapValue {
             dots: DotSet::from_dot(dot),
             value,
             timestamp: timestamp.as_secs() * 1_000 + (timestamp.subsec_nanos() / 1_000_000) as u64,
+            dot,
         }
     }
 }
@@ -318,11 +625,11 @@ impl CausalValue for MapValue {
     fn merge<VV: AbsVersionVector>(&mut self, other: &mut Self, s_vv: &VV, o_vv: &VV) {
         self.dots.merge(&mut other.dots, s_vv, o_vv);
         // resolve possible value collision
-        // if timestamps are equal value becomes max(a, b)
-        if self.timestamp > other.timestamp {
-            // nothing to do
-        } else if other.timestamp > self.timestamp || other.value > self.value {
+        // if timestamps are equal the write with the highest dot (node, version) wins,
+        // so every replica picks the same value regardless of the merge order
+        if (other.timestamp, other.dot) > (self.timestamp, self.dot) {
             self.timestamp = other.timestamp;
+            self.dot = other.dot;
             ::std::mem::swap(&mut self.value, &mut other.value);
         }
     }
@@ -332,20 +639,212 @@ impl CausalValue for MapValue {
     }
 }
 
+// format versions before 4 stored the map values without the dot of their write and
+// the counters without the deleted counts, these mirror that layout (variants included,
+// in the same order) to upgrade them
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+enum CubeV3 {
+    Counter(CounterV3),
+    Value(Value),
+    Map(MapV3),
+    Set(Set),
+    Void(VersionVector),
+    PNCounter(PNCounter),
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct CounterV3 {
+    values: LinearMap<Id, (Version, i64)>,
+    vv: VersionVector,
+    expire: Option<Expire>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MapV3 {
+    values: LinearMap<Bytes, MapValueV3>,
+    dots: VersionVector,
+    vv: VersionVector,
+    expire: Option<Expire>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MapValueV3 {
+    dots: DotSet,
+    value: Bytes,
+    timestamp: u64,
+}
+
+impl From<CubeV3> for Cube {
+    fn from(cube: CubeV3) -> Self {
+        match cube {
+            CubeV3::Counter(a) => Cube::Counter(Counter {
+                values: a.values,
+                cleared: Default::default(),
+                vv: a.vv,
+                expire: a.expire,
+            }),
+            CubeV3::Value(a) => Cube::Value(a),
+            CubeV3::Map(a) => {
+                let mut values = CausalMap::new();
+                for (k, v) in a.values {
+                    // the write is unknown, the highest dot still breaks ties
+                    // the same way in every replica
+                    let dot = v.dots.iter().max().unwrap_or_default();
+                    values.insert(
+                        k,
+                        MapValue {
+                            dots: v.dots,
+                            value: v.value,
+                            timestamp: v.timestamp,
+                            dot,
+                        },
+                    );
+                }
+                Cube::Map(Map {
+                    values,
+                    dots: a.dots,
+                    vv: a.vv,
+                    expire: a.expire,
+                })
+            }
+            CubeV3::Set(a) => Cube::Set(a),
+            CubeV3::Void(a) => Cube::Void(a),
+            CubeV3::PNCounter(a) => Cube::PNCounter(a),
+        }
+    }
+}
+
+// stored cubes start with a byte with the format version, the high bit set tells
+// them apart from the unversioned ones (version 1), starting with a small bincode enum tag
+const FORMAT_VERSION_FLAG: u8 = 0x80;
+pub const FORMAT_VERSION: u8 = 4;
+
+fn format_version(bytes: &[u8]) -> Option<u8> {
+    match bytes.first() {
+        Some(&b) if b & FORMAT_VERSION_FLAG == 0 => Some(1),
+        Some(&b) => Some(b & !FORMAT_VERSION_FLAG),
+        None => None,
+    }
+}
+
+/// Serializes the cube for storage in the current format version
+pub fn serialize_cube(cube: &Cube) -> Vec<u8> {
+    let size = bincode::serialized_size(cube).expect("Can't serialize Cube");
+    let mut bytes = Vec::with_capacity(1 + size as usize);
+    bytes.push(FORMAT_VERSION_FLAG | FORMAT_VERSION);
+    bincode::serialize_into(&mut bytes, cube).expect("Can't serialize Cube");
+    bytes
+}
+
+/// Deserializes a stored cube of any format version up to the current one,
+/// older ones are upgraded once written again.
+pub fn deserialize_cube(bytes: &[u8]) -> Result<Cube, bincode::Error> {
+    deserialize_cube_limit(bytes, u64::max_value())
+}
+
+/// Like `deserialize_cube` but fails instead of reading (or allocating for)
+/// more than `limit` bytes, for cubes sent by clients.
+pub fn deserialize_cube_limit(bytes: &[u8], limit: u64) -> Result<Cube, bincode::Error> {
+    let decode = |bytes: &[u8]| -> Result<Cube, bincode::Error> {
+        bincode::config().limit(limit).deserialize(bytes)
+    };
+    let decode_v3 = |bytes: &[u8]| -> Result<Cube, bincode::Error> {
+        bincode::config()
+            .limit(limit)
+            .deserialize::<CubeV3>(bytes)
+            .map(Cube::from)
+    };
+    // versions 1 and 2 are the same as 3 without the expiration
+    let without_expire = |bytes: &[u8]| {
+        let mut upgraded = Vec::with_capacity(bytes.len() + 1);
+        upgraded.extend_from_slice(bytes);
+        upgraded.push(0);
+        decode_v3(&upgraded)
+    };
+    match format_version(bytes) {
+        Some(1) => without_expire(bytes),
+        Some(2) => without_expire(&bytes[1..]),
+        Some(3) => decode_v3(&bytes[1..]),
+        Some(FORMAT_VERSION) => decode(&bytes[1..]),
+        version => Err(bincode::ErrorKind::Custom(format!(
+            "Unknown cube format version {:?}",
+            version
+        )).into()),
+    }
+}
+
+pub fn serialize_context(vv: &VersionVector, encoding: ContextEncoding) -> Bytes {
+    match encoding {
+        ContextEncoding::Bincode => bincode::serialize(vv).unwrap().into(),
+        ContextEncoding::MsgPack => msgpack::serialize_vv(vv).into(),
+        ContextEncoding::Base64 => base64::encode(&bincode::serialize(vv).unwrap()).into(),
+    }
+}
+
+/// Deserializes a context in any encoding, trying `encoding` first. The encodings
+/// can't be mistaken for each other, so contexts issued before `context_encoding`
+/// changed still decode.
+pub fn deserialize_context(
+    bytes: &[u8],
+    encoding: ContextEncoding,
+) -> Result<VersionVector, CommandError> {
+    let decode = |encoding| match encoding {
+        ContextEncoding::Bincode => deserialize_bincode_context(bytes),
+        ContextEncoding::MsgPack => msgpack::deserialize_vv(bytes).ok(),
+        ContextEncoding::Base64 => base64::decode(bytes)
+            .ok()
+            .and_then(|b| deserialize_bincode_context(&b)),
+    };
+    let encodings = [
+        ContextEncoding::Bincode,
+        ContextEncoding::MsgPack,
+        ContextEncoding::Base64,
+    ];
+    decode(encoding)
+        .or_else(|| {
+            encodings
+                .iter()
+                .filter(|&&e| e != encoding)
+                .filter_map(|&e| decode(e))
+                .next()
+        })
+        .ok_or(CommandError::InvalidContext)
+}
+
+// a bincode context is the number of entries followed by the (id, version) of each,
+// the length is checked upfront so other encodings are never read as one
+fn deserialize_bincode_context(bytes: &[u8]) -> Option<VersionVector> {
+    let mut head = bytes.get(..8)?;
+    let entries = head.read_u64::<LittleEndian>().ok()?;
+    let expected_len = entries.checked_mul(16).and_then(|l| l.checked_add(8));
+    if expected_len != Some(bytes.len() as u64) {
+        return None;
+    }
+    bincode::deserialize(bytes).ok()
+}
+
 pub fn render_value(cube: Cube) -> RespValue {
+    render_value_with(cube, ContextEncoding::Bincode)
+}
+
+pub fn render_value_with(cube: Cube, encoding: ContextEncoding) -> RespValue {
     match cube {
         Cube::Value(v) => {
-            let serialized_vv = bincode::serialize(&v.vv).unwrap();
+            let serialized_vv = serialize_context(&v.vv, encoding);
             let mut values: Vec<_> = v.values
                 .into_iter()
                 .filter_map(|(_, ov)| ov.map(RespValue::Data))
                 .collect();
-            values.push(RespValue::Data(serialized_vv.into()));
+            values.push(RespValue::Data(serialized_vv));
             RespValue::Array(values)
         }
         Cube::Void(vv) => {
-            let serialized_vv = bincode::serialize(&vv).unwrap();
-            RespValue::Array(vec![RespValue::Data(serialized_vv.into())])
+            let serialized_vv = serialize_context(&vv, encoding);
+            RespValue::Array(vec![RespValue::Data(serialized_vv)])
         }
         _ => CommandError::TypeError.into(),
     }
@@ -359,16 +858,27 @@ pub fn render_counter(cube: Cube) -> RespValue {
     }
 }
 
+pub fn render_pncounter(cube: Cube) -> RespValue {
+    match cube {
+        Cube::PNCounter(ref c) if !c.is_empty() => RespValue::Int(c.get()),
+        Cube::PNCounter(_) | Cube::Void(_) => RespValue::Nil,
+        _ => CommandError::TypeError.into(),
+    }
+}
+
 pub fn render_type(cube: Cube) -> RespValue {
-    use self::Cube::*;
-    let ty = match cube {
-        Counter(_) => "counter", // non-standard
-        Value(_) => "string",
-        Map(_) => "hash",
-        Set(_) => "set",
-        Void(_) => "none",
-    };
-    RespValue::Data(ty.into())
+    RespValue::Data(cube.type_name().into())
+}
+
+/// Seconds until the key expires, -1 if it doesn't and -2 if it's missing
+pub fn render_ttl(cube: Cube) -> RespValue {
+    if cube.is_empty() {
+        return RespValue::Int(-2);
+    }
+    match cube.expire() {
+        Some(at) => RespValue::Int((at.saturating_sub(now_millis()) as i64 + 500) / 1000),
+        None => RespValue::Int(-1),
+    }
 }
 
 pub fn render_map(cube: Cube) -> RespValue {
@@ -400,6 +910,111 @@ pub fn render_set(cube: Cube) -> RespValue {
     }
 }
 
+/// Readable description of a cube, for diagnostics only
+#[derive(Debug, Serialize)]
+pub struct CubeDump {
+    #[serde(rename = "type")]
+    pub type_name: &'static str,
+    pub values: Vec<ValueDump>,
+    pub vv: Vec<(Id, Version)>,
+    // millis since epoch
+    pub expire: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValueDump {
+    pub dots: Vec<(Id, Version)>,
+    // the field of hashes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    // None for deletes
+    pub value: Option<String>,
+}
+
+fn dump_bytes(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+impl Cube {
+    pub fn dump(&self) -> CubeDump {
+        use self::Cube::*;
+        let (values, vv) = match *self {
+            Counter(ref a) => (
+                a.values
+                    .iter()
+                    .map(|(&i, &(v, c))| ValueDump {
+                        dots: vec![(i, v)],
+                        field: None,
+                        value: Some(
+                            c.wrapping_sub(a.cleared.get(&i).map_or(0, |&(_, cc)| cc))
+                                .to_string(),
+                        ),
+                    })
+                    .collect(),
+                &a.vv,
+            ),
+            Value(ref a) => (
+                a.values
+                    .iter()
+                    .map(|(&dot, value)| ValueDump {
+                        dots: vec![dot],
+                        field: None,
+                        value: value.as_ref().map(|v| dump_bytes(v)),
+                    })
+                    .collect(),
+                &a.vv,
+            ),
+            Map(ref a) => (
+                a.values
+                    .iter()
+                    .map(|(field, value)| ValueDump {
+                        dots: value.dots.iter().collect(),
+                        field: Some(dump_bytes(field)),
+                        value: Some(dump_bytes(&value.value)),
+                    })
+                    .collect(),
+                &a.vv,
+            ),
+            Set(ref a) => (
+                a.values
+                    .iter()
+                    .map(|(item, dots)| ValueDump {
+                        dots: dots.iter().collect(),
+                        field: None,
+                        value: Some(dump_bytes(item)),
+                    })
+                    .collect(),
+                &a.vv,
+            ),
+            PNCounter(ref a) => (
+                a.values
+                    .iter()
+                    .map(|(&i, &(v, p, n))| {
+                        let (cp, cn) = a.cleared.get(&i).cloned().unwrap_or((0, 0));
+                        ValueDump {
+                            dots: vec![(i, v)],
+                            field: None,
+                            value: Some(format!(
+                                "+{} -{}",
+                                p.wrapping_sub(cp),
+                                n.wrapping_sub(cn)
+                            )),
+                        }
+                    })
+                    .collect(),
+                &a.vv,
+            ),
+            Void(ref vv) => (Vec::new(), vv),
+        };
+        CubeDump {
+            type_name: self.type_name(),
+            values,
+            vv: vv.iter().collect(),
+            expire: self.expire(),
+        }
+    }
+}
+
 /*
 Using the vv from cubes to track key dots (the latest version from each node) doesn't work, example:
 
@@ -476,3 +1091,327 @@ What if n3 asks to sync dots n1-101 n1-102 ... with n2?
 Same problem and fix as the above.
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_with(node: Id, version: Version, key: &str, value: &str, timestamp: u64) -> Map {
+        let mut map = Map::with(VersionVector::new());
+        map.insert(node, version, key.into(), value.into());
+        map.values.insert(
+            key.into(),
+            MapValue {
+                dots: DotSet::from_dot((node, version)),
+                value: value.into(),
+                timestamp,
+                dot: (node, version),
+            },
+        );
+        map
+    }
+
+    #[test]
+    fn test_map_lww_tiebreak() {
+        let key = Bytes::from("key");
+        for &(a, b) in &[((1, 1), (2, 1)), ((1, 2), (2, 1))] {
+            let map_a = map_with(a.0, a.1, "key", "a", 1000);
+            let map_b = map_with(b.0, b.1, "key", "b", 1000);
+            let ab = map_a.clone().merge(map_b.clone());
+            let ba = map_b.merge(map_a);
+            // the highest node then version wins in every replica
+            assert_eq!(ab.values.get(&key).unwrap().value, "b");
+            assert_eq!(ba.values.get(&key).unwrap().value, "b");
+        }
+    }
+
+    #[test]
+    fn test_set_add_wins() {
+        let mut a = Set::with(VersionVector::new());
+        a.insert(1, 1, "item".into());
+        let mut b = a.clone();
+        // the remove only covers the dots it observed
+        b.remove(2, 1, b"item");
+        a.insert(1, 2, "item".into());
+        for merged in &[a.clone().merge(b.clone()), b.clone().merge(a.clone())] {
+            assert_eq!(merged.values.len(), 1);
+            assert!(merged.values.get(&b"item"[..]).is_some());
+        }
+        // removes that saw all adds win
+        let mut c = a.clone().merge(b.clone());
+        c.remove(2, 2, b"item");
+        assert!(c.clone().merge(a.clone()).values.is_empty());
+        assert!(a.merge(c).values.is_empty());
+    }
+
+    #[test]
+    fn test_pncounter_merge() {
+        let mut a = PNCounter::with(VersionVector::new());
+        let mut b = PNCounter::with(VersionVector::new());
+        a.inc(1, 1, 5);
+        a.inc(1, 2, -2);
+        b.inc(2, 1, -4);
+        let b_seen = b.clone().merge(a.clone());
+        b.inc(2, 2, 10);
+        // each node's latest totals win, in any merge order
+        assert_eq!(a.clone().merge(b.clone()).get(), 9);
+        assert_eq!(b.clone().merge(a.clone()).get(), 9);
+        assert_eq!(b.clone().merge(b_seen.clone()).get(), 9);
+
+        // clears only erase the observed totals
+        let mut cleared = b_seen;
+        cleared.clear(3, 1);
+        assert_eq!(cleared.get(), 0);
+        assert_eq!(cleared.clone().merge(a.clone()).get(), 0);
+        assert_eq!(a.clone().merge(cleared.clone()).get(), 0);
+        // the concurrent increment survives, not the decrement the clear saw
+        assert_eq!(cleared.clone().merge(b.clone()).get(), 10);
+        assert_eq!(b.clone().merge(cleared.clone()).get(), 10);
+        assert!(cleared.is_empty() && !b.merge(cleared).is_empty());
+    }
+
+    #[test]
+    fn test_counter_clear_merge() {
+        let mut a = Counter::with(VersionVector::new());
+        a.inc(1, 1, 5);
+        a.inc(1, 2, 2);
+        let b = a.clone();
+        let mut b_inc = b.clone();
+        b_inc.inc(2, 1, 3);
+
+        // ex: an expiration sweep, replicas still holding the counts can't revive them
+        let mut cleared = a.clone();
+        cleared.clear(3, 1);
+        assert_eq!(cleared.get(), 0);
+        assert!(cleared.is_empty() && !a.is_empty());
+        assert_eq!(cleared.clone().merge(b.clone()).get(), 0);
+        assert_eq!(b.clone().merge(cleared.clone()).get(), 0);
+        assert!(b.clone().merge(cleared.clone()).is_empty());
+        // the concurrent increment survives
+        assert_eq!(cleared.clone().merge(b_inc.clone()).get(), 3);
+        assert_eq!(b_inc.clone().merge(cleared.clone()).get(), 3);
+
+        // later increments count from the clear
+        cleared.inc(1, 3, 1);
+        assert_eq!(cleared.get(), 1);
+        assert_eq!(b.merge(cleared.clone()).get(), 1);
+        let mut expired = Cube::Counter(a);
+        expired.set_expire(1, Some(1_000));
+        let expired = expired.clear_expired(2, 1_000);
+        assert!(expired.is_empty());
+        assert_eq!(expired.into_counter().unwrap().get(), 0);
+    }
+
+    #[test]
+    fn test_cube_format_counter() {
+        // counters before version 4 didn't have the deleted counts
+        let mut values = LinearMap::new();
+        values.insert(1, (2, 7i64));
+        let legacy = CubeV3::Counter(CounterV3 {
+            values,
+            vv: VersionVector::new(),
+            expire: None,
+        });
+        let mut v3 = vec![FORMAT_VERSION_FLAG | 3];
+        v3.extend_from_slice(&bincode::serialize(&legacy).unwrap());
+        let mut v1 = bincode::serialize(&legacy).unwrap();
+        v1.pop();
+        for bytes in &[&v1, &v3] {
+            assert_eq!(Cube::serialized_type_name(bytes), Some("counter"));
+            let counter = deserialize_cube(bytes).unwrap().into_counter().unwrap();
+            assert_eq!(counter.get(), 7);
+            assert!(counter.cleared.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_serialized_type_name() {
+        let vv = VersionVector::new();
+        let cubes = [
+            Cube::Counter(Counter::with(vv.clone())),
+            Cube::Value(Value::with(vv.clone())),
+            Cube::Map(Map::with(vv.clone())),
+            Cube::Set(Set::with(vv.clone())),
+            Cube::Void(vv.clone()),
+            Cube::PNCounter(PNCounter::with(vv.clone())),
+        ];
+        for cube in &cubes {
+            let bytes = bincode::serialize(cube).unwrap();
+            assert_eq!(Cube::serialized_type_name(&bytes), Some(cube.type_name()));
+            let bytes = serialize_cube(cube);
+            assert_eq!(Cube::serialized_type_name(&bytes), Some(cube.type_name()));
+        }
+        assert_eq!(Cube::serialized_type_name(&[1, 0]), None);
+        assert_eq!(Cube::serialized_type_name(&[9, 0, 0, 0]), None);
+        assert_eq!(Cube::serialized_type_name(&[0xff, 1, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_cube_serialized_type_name() {
+        // pins the enum tags, every variant must be listed
+        let cubes = vec![
+            Cube::Counter(Counter::with(VersionVector::new())),
+            Cube::Value(Value::with(VersionVector::new())),
+            Cube::Map(Map::with(VersionVector::new())),
+            Cube::Set(Set::with(VersionVector::new())),
+            Cube::Void(VersionVector::new()),
+            Cube::PNCounter(PNCounter::with(VersionVector::new())),
+        ];
+        for cube in &cubes {
+            match *cube {
+                Cube::Counter(_)
+                | Cube::Value(_)
+                | Cube::Map(_)
+                | Cube::Set(_)
+                | Cube::Void(_)
+                | Cube::PNCounter(_) => (),
+            }
+            let bytes = serialize_cube(cube);
+            assert_eq!(Cube::serialized_type_name(&bytes), Some(cube.type_name()));
+        }
+        assert_eq!(Cube::serialized_type_name(&[FORMAT_VERSION_FLAG | FORMAT_VERSION]), None);
+        assert_eq!(
+            Cube::serialized_type_name(&[FORMAT_VERSION_FLAG | FORMAT_VERSION, 6, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cube_format_versions() {
+        let mut value = Value::with(VersionVector::new());
+        value.set(1, 1, Some("value".into()), &VersionVector::new());
+        let cube = Cube::Value(value);
+        let v4 = serialize_cube(&cube);
+        assert_eq!(v4[0], FORMAT_VERSION_FLAG | FORMAT_VERSION);
+        assert_eq!(&v4[1..], &bincode::serialize(&cube).unwrap()[..]);
+        // values are the same in version 3, the previous ones didn't have the expiration
+        let mut v3 = v4.clone();
+        v3[0] = FORMAT_VERSION_FLAG | 3;
+        let v1 = v4[1..v4.len() - 1].to_vec();
+        let mut v2 = vec![FORMAT_VERSION_FLAG | 2];
+        v2.extend_from_slice(&v1);
+        // all decode to the same cube, written back in the current version
+        for bytes in &[&v1, &v2, &v3, &v4] {
+            let decoded = deserialize_cube(bytes).unwrap();
+            assert_eq!(decoded.siblings(), 1);
+            assert_eq!(decoded.expire(), None);
+            assert_eq!(Cube::serialized_type_name(bytes), Some("string"));
+            assert_eq!(serialize_cube(&decoded), v4);
+        }
+        assert!(deserialize_cube(&[]).is_err());
+        assert!(deserialize_cube(&[FORMAT_VERSION_FLAG | 5, 1, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_cube_format_map_dot() {
+        // map values before version 4 didn't have the dot of their write
+        let mut dots = DotSet::from_dot((1, 1));
+        dots.merge(&mut DotSet::from_dot((2, 3)), &VersionVector::new(), &VersionVector::new());
+        let mut values = LinearMap::new();
+        values.insert(
+            Bytes::from("field"),
+            MapValueV3 {
+                dots,
+                value: "value".into(),
+                timestamp: 1,
+            },
+        );
+        let legacy = CubeV3::Map(MapV3 {
+            values,
+            dots: VersionVector::new(),
+            vv: VersionVector::new(),
+            expire: None,
+        });
+        let mut v3 = vec![FORMAT_VERSION_FLAG | 3];
+        v3.extend_from_slice(&bincode::serialize(&legacy).unwrap());
+        let mut v1 = bincode::serialize(&legacy).unwrap();
+        v1.pop();
+        for bytes in &[&v1, &v3] {
+            assert_eq!(Cube::serialized_type_name(bytes), Some("hash"));
+            let map = deserialize_cube(bytes).unwrap().into_map().unwrap();
+            let value = map.values.get(&b"field"[..]).unwrap();
+            assert_eq!(value.value, Bytes::from("value"));
+            assert_eq!(value.timestamp, 1);
+            assert_eq!(value.dot, (2, 3));
+        }
+    }
+
+    #[test]
+    fn test_cube_deserialize_limit() {
+        let mut value = Value::with(VersionVector::new());
+        value.set(1, 1, Some("value".into()), &VersionVector::new());
+        let bytes = serialize_cube(&Cube::Value(value));
+        assert!(deserialize_cube_limit(&bytes, bytes.len() as u64).is_ok());
+        assert!(deserialize_cube_limit(&bytes, 4).is_err());
+    }
+
+    #[test]
+    fn test_cube_subsumed_round_trip() {
+        // subsumed cubes aren't stored, reads rebuild them from the vnode clocks
+        let mut vv = VersionVector::new();
+        vv.add(1, 3);
+        vv.add(2, 1);
+        let mut bvv = BitmappedVersionVector::new();
+        for v in 1..4 {
+            bvv.add(1, v);
+        }
+        bvv.add(2, 1);
+        // only a dot past a hole
+        bvv.add(3, 5);
+        // not in the cube vv
+        bvv.add_all(4, 7);
+
+        let cubes = vec![
+            Cube::Counter(Counter::with(vv.clone())),
+            Cube::Value(Value::with(vv.clone())),
+            Cube::Map(Map::with(vv.clone())),
+            Cube::Set(Set::with(vv.clone())),
+            Cube::PNCounter(PNCounter::with(vv.clone())),
+        ];
+        for cube in cubes {
+            assert!(cube.is_subsumed(&bvv));
+            let filled = match Cube::new(&bvv) {
+                Cube::Void(filled) => filled,
+                c => panic!("unexpected cube {:?}", c),
+            };
+            assert!(filled.descends(&vv));
+            let mut versions: Vec<_> = filled.iter().collect();
+            versions.sort();
+            assert_eq!(versions, vec![(1, 3), (2, 1), (4, 7)]);
+        }
+
+        // the clocks no longer cover the vv
+        let mut other = vv.clone();
+        other.add(3, 5);
+        assert!(!Cube::Value(Value::with(other)).is_subsumed(&bvv));
+    }
+
+    #[test]
+    fn test_cube_expire() {
+        let mut a = Value::with(VersionVector::new());
+        a.set(1, 1, Some("value".into()), &VersionVector::new());
+        let mut a = Cube::Value(a);
+        let mut b = a.clone();
+        a.set_expire(1, Some(1_000));
+        assert_eq!(a.expire(), Some(1_000));
+        assert!(!a.is_expired(999) && a.is_expired(1_000));
+        // the latest change wins, regardless of the merge order
+        ::std::thread::sleep(::std::time::Duration::from_millis(2));
+        b.set_expire(2, None);
+        assert_eq!(a.clone().merge(b.clone()).expire(), None);
+        assert_eq!(b.clone().merge(a.clone()).expire(), None);
+        assert_eq!(a.clone().merge(Cube::default()).expire(), Some(1_000));
+
+        // reads see a void, writes an empty cube with the expiration cleared
+        match a.clone().without_expired(1_000) {
+            Cube::Void(vv) => assert!(vv.contains(1, 1)),
+            c => panic!("unexpected cube {:?}", c),
+        }
+        assert_eq!(a.clone().without_expired(999).siblings(), 1);
+        let cleared = a.clone().clear_expired(2, 1_000);
+        assert_eq!(cleared.siblings(), 0);
+        assert!(cleared.is_empty() && !a.is_empty());
+        assert_eq!(cleared.expire(), None);
+        assert_eq!(a.merge(cleared).expire(), None);
+    }
+}
@@ -1,30 +1,50 @@
 use bytes::Bytes;
-use command::CommandError;
-use config::Config;
+use command::{CommandError, ServerError};
+use config::{self, Config};
 use cubes::*;
 use dht::{RingDescription, DHT};
 use fabric::*;
+use hash::stable_hash;
+use inflightmap::InFlightMap;
 use metrics::{self, Gauge};
 use rand::{thread_rng, Rng};
-use resp::RespValue;
-use std::sync::{Arc, Mutex, RwLock};
-use std::{net, time};
+use resp::{self, RespValue};
+use serde_json;
+use std::collections::hash_map::Entry as HMEntry;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock, RwLockReadGuard};
+use std::{fs, io, net, path, str, thread, time};
 use storage::{Storage, StorageManager};
 pub use types::*;
 use utils::LoggerExt;
-use utils::{assume_str, is_dir_empty_or_absent, join_u64, replace_default, split_u64, IdHashMap};
-use version_vector::Version;
+use utils::{assume_str, duration_millis, is_dir_empty_or_absent, join_u64, replace_default,
+            split_u64, GenericError, IdHashMap, IdHashSet};
+use version_vector::{Version, VersionVector};
 use vnode::*;
 use vnode_sync::SyncDirection;
 use workers::*;
 
 // require sync as it can be called from any worker thread
 pub type DatabaseResponseFn = Box<Fn(Context) + Send + Sync>;
+// called with the response of a request issued by the database itself
+pub type InternalResponseFn = Box<FnMut(&Database, Context) + Send>;
+
+// tokens of the requests issued by the database itself
+pub const INTERNAL_TOKEN_FLAG: Token = 1 << 63;
+
+// ticks the workers can miss before the node is no longer live
+const LIVENESS_MISSED_TICKS: u64 = 10;
+
+// dir under the data dir with the files of DEBUG EXPORT/IMPORT
+const EXPORT_DIR: &str = "export";
 
 #[derive(Default)]
 struct Stats {
     incomming_syncs: u16,
     outgoing_syncs: u16,
+    // anti-entropy syncs in either direction
+    syncs: u16,
 }
 
 pub struct ContextRead {
@@ -58,6 +78,10 @@ pub struct Context {
     pub commands: Vec<RespValue>,
     pub reads: Vec<ContextRead>,
     pub writes: Vec<ContextWrite>,
+    // hash of the IDEMPOTENT id of the command, its result is kept once responded
+    pub idempotency_id: Option<u64>,
+    // id of the command being processed, the cookies of its fabric msgs start with it
+    pub request_id: RequestId,
 }
 
 impl Context {
@@ -70,6 +94,8 @@ impl Context {
             commands: Default::default(),
             writes: Default::default(),
             reads: Default::default(),
+            idempotency_id: None,
+            request_id: 0,
         }
     }
 
@@ -110,12 +136,122 @@ pub struct Database {
     pub meta_storage: Storage,
     pub storage_manager: StorageManager,
     pub response_fn: DatabaseResponseFn,
-    pub config: Config,
+    config: RwLock<Config>,
     stats: Mutex<Stats>,
-    vnodes: RwLock<IdHashMap<VNodeId, Mutex<VNode>>>,
+    vnodes: RwLock<IdHashMap<VNodeId, RwLock<VNode>>>,
+    // vnodes with writes staged for the next storage batch
+    pending_flush: Mutex<IdHashSet<VNodeId>>,
+    // epoch of the last applied dht change
+    dht_epoch: Mutex<u64>,
+    // writes are only applied locally until the bulk load ends
+    bulk_load: AtomicBool,
+    // client writes are redirected to the other replicas ahead of a restart
+    standby: AtomicBool,
+    // when the workers last handled a tick
+    last_tick: Mutex<time::Instant>,
+    internal_requests: Mutex<IdHashMap<Token, InternalResponseFn>>,
+    internal_token: AtomicUsize,
+    // FLUSHALL requests coordinated by this node waiting for acks
+    broadcasts: Mutex<InFlightMap<Cookie, BroadcastState, time::Instant>>,
+    // results of IDEMPOTENT writes coordinated by this node, by id hash
+    idempotent_writes: Mutex<InFlightMap<u64, IdempotentWrite, time::Instant>>,
+    // client connections by token, as listed by CLIENT LIST
+    clients: Mutex<IdHashMap<Token, ClientInfo>>,
+    // MIGRATE requests waiting for the migration thread
+    migrations: Mutex<mpsc::Sender<Migration>>,
     workers: Mutex<WorkerManager>,
 }
 
+/// A MIGRATE request, handled by the migration thread as it blocks on the other cluster
+pub enum Migration {
+    Key {
+        context: Context,
+        addr: net::SocketAddr,
+        key: Bytes,
+        cube: Cube,
+        // the local key is deleted with this context once it's migrated
+        delete: Option<VersionVector>,
+    },
+    Cursor {
+        context: Context,
+        addr: net::SocketAddr,
+        cursor: ScanCursor,
+        count: usize,
+    },
+}
+
+/// Where a SCAN continues, the vnode and the last key returned from it.
+/// Encoded as `vnode` or `vnode:hex key` so it can be resumed from any connection,
+/// the first and the completed cursors are both `0`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanCursor {
+    vnode: u32,
+    after: Option<Bytes>,
+}
+
+impl ScanCursor {
+    pub fn parse(bytes: &[u8]) -> Result<Self, CommandError> {
+        let mut parts = bytes.splitn(2, |&b| b == b':');
+        let vnode = str::from_utf8(parts.next().unwrap_or_default())
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or(CommandError::InvalidValue)?;
+        let after = match parts.next() {
+            Some(hex) if hex.len() % 2 == 0 && hex.len() <= 2 * config::MAX_KEY_LEN => {
+                let key = hex.chunks(2)
+                    .map(|h| {
+                        str::from_utf8(h)
+                            .ok()
+                            .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or(CommandError::InvalidValue)?;
+                Some(key.into())
+            }
+            Some(_) => return Err(CommandError::InvalidValue),
+            None => None,
+        };
+        Ok(ScanCursor { vnode, after })
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut cursor = self.vnode.to_string();
+        if let Some(ref after) = self.after {
+            cursor.push(':');
+            for b in after.iter() {
+                cursor.push_str(&format!("{:02x}", b));
+            }
+        }
+        cursor.into()
+    }
+}
+
+struct ClientInfo {
+    addr: Option<net::SocketAddr>,
+    // set with CLIENT SETNAME
+    name: Option<Bytes>,
+    connected: time::Instant,
+    // vnodes that coordinated requests for it, checked once it disconnects
+    vnodes: IdHashSet<VNodeId>,
+}
+
+struct BroadcastState {
+    context: Context,
+    pending: usize,
+    // the first error acked by a node, responded once all acked
+    error: Option<CommandError>,
+}
+
+struct IdempotentWrite {
+    id: Bytes,
+    // None while the write is in flight
+    result: Option<RespValue>,
+}
+
+fn idempotency_hash(id: &[u8]) -> u64 {
+    stable_hash(&[id])
+}
+
 macro_rules! fabric_send_error {
     ($db:expr, $to:expr, $msg:expr, $emsg:ident, $err:expr) => {
         $db.fabric.send_msg(
@@ -141,12 +277,53 @@ macro_rules! fabric_send_error {
 
 macro_rules! vnode {
     ($s:expr, $k:expr, $ok:expr) => {{
-        let vnodes = $s.vnodes.read().unwrap();
-        let mut locked_vnode = vnodes[&$k].lock().unwrap();
+        let vnodes = $s.vnodes_with($k);
+        let mut locked_vnode = vnodes[&$k].write().unwrap();
         Some(&mut *locked_vnode).map($ok).unwrap()
     }};
 }
 
+// for read only operations, these can run concurrently in the same vnode
+macro_rules! vnode_read {
+    ($s:expr, $k:expr, $ok:expr) => {{
+        let vnodes = $s.vnodes_with($k);
+        let locked_vnode = vnodes[&$k].read().unwrap();
+        Some(&*locked_vnode).map($ok).unwrap()
+    }};
+}
+
+/// Sends a key to another cluster with RESTORE, which merges it there.
+/// A MOVED or ASK redirection to a replica of the key is followed once.
+fn restore_remote(
+    client: &mut resp::Client,
+    key: &[u8],
+    cube: &Cube,
+    timeout: time::Duration,
+) -> Result<(), CommandError> {
+    let payload = serialize_cube(cube);
+    let args = [&b"RESTORE"[..], key, &payload[..]];
+    let result = match client.cmd(&args) {
+        Ok(RespValue::Error(ref e)) if e.starts_with(b"MOVED ") || e.starts_with(b"ASK ") => {
+            let addr = str::from_utf8(e)
+                .ok()
+                .and_then(|e| e.rsplit(' ').next())
+                .and_then(|a| a.parse().ok());
+            match addr {
+                Some(addr) => resp::Client::connect(&addr, timeout).and_then(|mut c| c.cmd(&args)),
+                None => Err(io::ErrorKind::InvalidData.into()),
+            }
+        }
+        result => result,
+    };
+    match result {
+        Ok(RespValue::Status(ref status)) if &status[..] == b"OK" => Ok(()),
+        result => {
+            warn!("Can't migrate key {:?}: {:?}", key, result);
+            Err(CommandError::MigrateFailed)
+        }
+    }
+}
+
 impl Database {
     pub fn new(config: &Config, response_fn: DatabaseResponseFn) -> Arc<Database> {
         info!("Initializing database");
@@ -176,12 +353,22 @@ impl Database {
 
         let (old_node, node) = if let Some(s_node) = meta_node {
             let prev_node: NodeId = String::from_utf8(s_node).unwrap().parse().unwrap();
+            if let Some(config_node) = config.node_id {
+                if split_u64(config_node).0 != split_u64(prev_node).0 {
+                    panic!(
+                        "Node id differs! Expected `{}` got `{}`",
+                        config_node, prev_node
+                    );
+                }
+            }
             if meta_clean_shutdown.is_some() {
                 (None, prev_node)
             } else {
                 let node = join_u64(split_u64(prev_node).0, thread_rng().gen());
                 (Some(prev_node), node)
             }
+        } else if let Some(config_node) = config.node_id {
+            (None, config_node)
         } else {
             (None, thread_rng().gen::<i64>().abs() as NodeId)
         };
@@ -242,12 +429,15 @@ impl Database {
             .expect("Can't save ring");
         meta_storage.sync().expect("Can't sync storage");
 
-        let workers = WorkerManager::new(
+        let mut workers = WorkerManager::new(
             node,
             config.worker_count as _,
             time::Duration::from_millis(config.worker_timer as _),
         );
+        workers.set_vnode_affinity(config.worker_vnode_affinity);
+        workers.set_background_threads(config.worker_background_count as _);
 
+        let (migrations_tx, migrations_rx) = mpsc::channel();
         let db = Arc::new(Database {
             fabric: fabric,
             dht: dht,
@@ -255,32 +445,97 @@ impl Database {
             meta_storage: meta_storage,
             response_fn: response_fn,
             vnodes: Default::default(),
+            pending_flush: Default::default(),
+            dht_epoch: Default::default(),
+            bulk_load: Default::default(),
+            standby: Default::default(),
+            last_tick: Mutex::new(time::Instant::now()),
+            internal_requests: Default::default(),
+            internal_token: Default::default(),
+            broadcasts: Mutex::new(InFlightMap::new()),
+            idempotent_writes: Mutex::new(InFlightMap::new()),
+            clients: Default::default(),
+            migrations: Mutex::new(migrations_tx),
             workers: Mutex::new(workers),
-            config: config.clone(),
+            config: RwLock::new(config.clone()),
             stats: Default::default(),
         });
 
-        db.workers.lock().unwrap().start(|| {
-            let cdb = Arc::downgrade(&db);
+        let flush_interval = time::Duration::from_millis(config.worker_flush_interval as _);
+        // kept by the manager to start more workers on a resize
+        let wdb = Arc::downgrade(&db);
+        db.workers.lock().unwrap().start(move || {
+            let cdb = wdb.clone();
             Box::new(move |chan| {
-                for wm in chan {
+                let mut chan = SignaledChan::with_interval(chan, flush_interval);
+                // a message received while batching remote sets, handled next
+                let mut next = None;
+                loop {
+                    let wm = match next.take() {
+                        Some(wm) => Some(wm),
+                        None => match chan.next() {
+                            Some(wm) => wm,
+                            None => break,
+                        },
+                    };
                     let db = if let Some(db) = cdb.upgrade() {
                         db
                     } else {
                         break;
                     };
+                    let wm = if let Some(wm) = wm {
+                        wm
+                    } else {
+                        // queue drained, commit the staged writes
+                        db.handler_drained();
+                        continue;
+                    };
                     match wm {
+                        WorkerMsg::Fabric(from, FabricMsg::RemoteSet(m)) => {
+                            // apply the remote sets already queued together
+                            let batch_max = db.config().worker_remote_set_batch as usize;
+                            let mut msgs = vec![(from, m)];
+                            while msgs.len() < batch_max {
+                                match chan.try_recv() {
+                                    Some(WorkerMsg::Fabric(from, FabricMsg::RemoteSet(m))) => {
+                                        msgs.push((from, m))
+                                    }
+                                    Some(wm) => {
+                                        next = Some(wm);
+                                        break;
+                                    }
+                                    None => break,
+                                }
+                            }
+                            db.handler_remote_sets(msgs);
+                        }
                         WorkerMsg::Fabric(from, m) => db.handler_fabric_msg(from, m),
                         WorkerMsg::Command(context) => db.handler_cmd(context),
                         WorkerMsg::Tick(time) => db.handler_tick(time),
                         WorkerMsg::DHTFabric(from, m) => db.dht.handler_fabric_msg(from, m),
-                        WorkerMsg::DHTChange => db.handler_dht_change(),
+                        WorkerMsg::DHTChange(epoch) => {
+                            db.handler_dht_change(epoch);
+                        }
                         WorkerMsg::Exit => break,
                     }
                 }
             })
         });
 
+        // the migration thread exits once the database is dropped, along with the sender
+        let cdb = Arc::downgrade(&db);
+        thread::Builder::new()
+            .name(format!("Migration:{}", db.dht.node()))
+            .spawn(move || {
+                for migration in migrations_rx.iter() {
+                    match cdb.upgrade() {
+                        Some(db) => db.handler_migration(migration),
+                        None => break,
+                    }
+                }
+            })
+            .unwrap();
+
         // register dht nodes into fabric
         db.fabric.set_nodes(db.dht.members().into_iter());
         // fabric dht messages
@@ -293,17 +548,15 @@ impl Database {
 
         // setup dht change callback
         let sender = Mutex::new(db.sender());
-        let callback = move || {
-            sender.lock().unwrap().send(WorkerMsg::DHTChange);
+        let callback = move |epoch| {
+            sender.lock().unwrap().send(WorkerMsg::DHTChange(epoch));
         };
         db.dht.set_callback(Box::new(callback));
 
         // other types of fabric msgs
         for &msg_type in &[FabricMsgType::Crud, FabricMsgType::Synch] {
             let mut sender = db.sender();
-            let callback = move |f, m| {
-                sender.send(WorkerMsg::Fabric(f, m));
-            };
+            let callback = move |f, m| Self::route_fabric_msg(&mut sender, f, m);
             db.fabric.register_msg_handler(msg_type, Box::new(callback));
         }
 
@@ -313,16 +566,19 @@ impl Database {
             let mut vnodes = db.vnodes.write().unwrap();
             let (ready_vnodes, pending_vnodes) = db.dht.vnodes_for_node(db.dht.node());
             // TODO: this can be done in parallel
+            // absent vnodes are created once used, unless they've state left to clean up
             *vnodes = (0..db.dht.partitions() as VNodeId)
-                .map(|i| {
+                .filter_map(|i| {
                     let vn = if ready_vnodes.contains(&i) {
                         VNode::new(&db, i, VNodeStatus::Ready)
                     } else if pending_vnodes.contains(&i) {
                         VNode::new(&db, i, VNodeStatus::Bootstrap)
-                    } else {
+                    } else if VNode::has_saved_state(&db, i) {
                         VNode::new(&db, i, VNodeStatus::Absent)
+                    } else {
+                        return None;
                     };
-                    (i, Mutex::new(vn))
+                    Some((i, RwLock::new(vn)))
                 })
                 .collect();
         }
@@ -330,9 +586,26 @@ impl Database {
         db
     }
 
+    /// Read lock of the vnodes with `vnode` in it, creating it as Absent if needed
+    fn vnodes_with(&self, vnode: VNodeId) -> RwLockReadGuard<IdHashMap<VNodeId, RwLock<VNode>>> {
+        {
+            let vnodes = self.vnodes.read().unwrap();
+            if vnodes.contains_key(&vnode) {
+                return vnodes;
+            }
+        }
+        assert!(vnode < self.dht.partitions() as VNodeId, "Invalid vnode {}", vnode);
+        self.vnodes
+            .write()
+            .unwrap()
+            .entry(vnode)
+            .or_insert_with(|| RwLock::new(VNode::new(self, vnode, VNodeStatus::Absent)));
+        self.vnodes.read().unwrap()
+    }
+
     pub fn save(&self, shutdown: bool) {
         for vn in self.vnodes.read().unwrap().values() {
-            vn.lock().unwrap().save(self, shutdown);
+            vn.write().unwrap().save(self, shutdown);
         }
         if shutdown {
             self.meta_storage
@@ -342,12 +615,198 @@ impl Database {
         self.meta_storage.sync().expect("Can't sync storage");
     }
 
+    pub fn config(&self) -> RwLockReadGuard<Config> {
+        self.config.read().unwrap()
+    }
+
+    /// Reads the config file again and applies the parameters that can change at runtime,
+    /// returns the changed parameters and if they require a restart.
+    pub fn reload_config(&self) -> Result<Vec<(&'static str, bool)>, GenericError> {
+        let mut current = self.config.write().unwrap();
+        let new_config = config::reload_config_file(&current).map_err(|e| {
+            warn!("Can't reload config file: {}", e);
+            e
+        })?;
+        let changes = current.update(&new_config);
+        info!("Reloaded config file, changes: {:?}", changes);
+        self.config_changed(&current, &changes);
+        Ok(changes)
+    }
+
+    /// Sets a parameter that can change at runtime, as CONFIG SET does
+    pub fn set_config(&self, param: &str, value: &str) -> Result<(), CommandError> {
+        if config::IMMUTABLE_PARAMS.contains(&param) {
+            return Err(CommandError::ImmutableParam);
+        }
+        let mut current = self.config.write().unwrap();
+        let new_config = config::set_param(&current, param, value).map_err(|e| {
+            debug!("Can't set config: {}", e);
+            CommandError::InvalidValue
+        })?;
+        let mut updated = current.clone();
+        let changes = updated.update(&new_config);
+        if changes.iter().any(|&(_, restart)| restart) {
+            return Err(CommandError::RestartRequired);
+        }
+        info!("Set config {} to {}", param, value);
+        *current = updated;
+        self.config_changed(&current, &changes);
+        Ok(())
+    }
+
+    // applies the live changes that aren't just read from the config when needed
+    fn config_changed(&self, config: &Config, changes: &[(&'static str, bool)]) {
+        if changes.iter().any(|&(param, _)| param == "worker_timer") {
+            let worker_timer = time::Duration::from_millis(config.worker_timer as _);
+            self.workers
+                .lock()
+                .unwrap()
+                .set_ticker_interval(worker_timer);
+        }
+        if changes.iter().any(|&(param, _)| param == "worker_count") {
+            self.workers
+                .lock()
+                .unwrap()
+                .resize(config.worker_count as _);
+        }
+    }
+
+    /// Acks required by default for writes or reads, as shown by CONFIG GET write-quorum
+    pub fn quorum(&self, write: bool) -> u8 {
+        let replicas = self.dht.replication_factor() as u8;
+        let config = self.config();
+        if write {
+            config.consistency_write.required(replicas)
+        } else {
+            config.consistency_read.required(replicas)
+        }
+    }
+
+    /// Sets the default consistency for writes or reads, either a level or the number
+    /// of acks it requires, which can't exceed the replication factor.
+    /// Takes effect for the next requests.
+    pub fn set_quorum(&self, write: bool, value: &[u8]) -> Result<(), CommandError> {
+        let replicas = self.dht.replication_factor() as u8;
+        let consistency = match assume_str(value).parse::<u8>() {
+            Ok(required) => ConsistencyLevel::with_required(required, replicas),
+            Err(_) => value.try_into().ok(),
+        }.ok_or(CommandError::InvalidConsistencyValue)?;
+        let mut config = self.config.write().unwrap();
+        if write {
+            config.consistency_write = consistency;
+        } else {
+            config.consistency_read = consistency;
+        }
+        info!(
+            "Set default consistency write:{:?} read:{:?}",
+            config.consistency_write, config.consistency_read
+        );
+        Ok(())
+    }
+
+    // syncs take the background lane, both lanes keep the vnode affinity if enabled
+    fn route_fabric_msg(sender: &mut WorkerSender, from: NodeId, msg: FabricMsg) {
+        match (msg.get_type(), msg.vnode()) {
+            (FabricMsgType::Synch, Some(vnode)) => {
+                sender.send_background_for_vnode(vnode, WorkerMsg::Fabric(from, msg))
+            }
+            (FabricMsgType::Synch, None) => sender.send_background(WorkerMsg::Fabric(from, msg)),
+            (_, Some(vnode)) => sender.send_for_vnode(vnode, WorkerMsg::Fabric(from, msg)),
+            (_, None) => sender.send(WorkerMsg::Fabric(from, msg)),
+        }
+    }
+
+    /// Stops the workers once they handle the messages already queued, then saves
+    /// as a clean shutdown. Messages sent afterwards are refused.
+    pub fn shutdown(&self) {
+        info!("Shutting down database");
+        // joined without holding the lock, draining workers may still get senders
+        let threads = self.workers.lock().unwrap().stop(true);
+        for t in threads {
+            let _ = t.join();
+        }
+        self.handler_drained();
+        self.save(true);
+    }
+
     // Gets a Sender handle that allows sending work to the database worker pool
     pub fn sender(&self) -> WorkerSender {
         self.workers.lock().unwrap().sender()
     }
 
-    fn handler_dht_change(&self) {
+    /// Issues a command like a client would, `callback` is called with the response.
+    pub fn internal_cmd(&self, args: &[&[u8]], callback: InternalResponseFn) {
+        let mut context = self.internal_context(callback);
+        context.commands.push(RespValue::Array(
+            args.iter().map(|&a| RespValue::Data(a.into())).collect(),
+        ));
+        self.send_cmd(&mut self.sender(), context);
+    }
+
+    /// A context for an internal request, `callback` is called with it once responded
+    pub fn internal_context(&self, callback: InternalResponseFn) -> Context {
+        let token =
+            INTERNAL_TOKEN_FLAG | self.internal_token.fetch_add(1, Ordering::Relaxed) as Token;
+        self.internal_requests
+            .lock()
+            .unwrap()
+            .insert(token, callback);
+        Context::new(token)
+    }
+
+    /// Sends a command to the workers, with the vnode affinity enabled
+    /// to the thread of the vnode of its key.
+    pub fn send_cmd(&self, sender: &mut WorkerSender, context: Context) {
+        let vnode = if sender.vnode_affinity() {
+            self.cmd_vnode(&context)
+        } else {
+            None
+        };
+        match vnode {
+            Some(vnode) => sender.send_for_vnode(vnode, WorkerMsg::Command(context)),
+            None => sender.send(WorkerMsg::Command(context)),
+        }
+    }
+
+    // the vnode of the key when the command takes one (as its first argument)
+    fn cmd_vnode(&self, context: &Context) -> Option<VNodeId> {
+        match context.commands.last() {
+            Some(&RespValue::Array(ref args)) if args.len() > 1 => match args[1] {
+                RespValue::Data(ref key) => Some(self.dht.key_vnode(key)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Routes the response of internal requests to their callback,
+    /// returns false if the context isn't from an internal request.
+    pub fn respond_internal(&self, context: &mut Context) -> bool {
+        if context.token & INTERNAL_TOKEN_FLAG == 0 {
+            return false;
+        }
+        let callback_opt = self.internal_requests
+            .lock()
+            .unwrap()
+            .remove(&context.token);
+        if let Some(mut callback) = callback_opt {
+            callback(self, replace_default(context));
+        }
+        true
+    }
+
+    // returns false if the change was older than the last one applied
+    fn handler_dht_change(&self, epoch: u64) -> bool {
+        {
+            // changes can be delivered out of order by different workers
+            let mut last_epoch = self.dht_epoch.lock().unwrap();
+            if epoch < *last_epoch {
+                debug!("Ignoring stale dht change {} < {}", epoch, *last_epoch);
+                return false;
+            }
+            *last_epoch = epoch;
+        }
+
         // save dht
         self.meta_storage
             .set(b"ring", &self.dht.save_ring())
@@ -356,7 +815,7 @@ impl Database {
         // register nodes
         self.fabric.set_nodes(self.dht.members().into_iter());
 
-        for (&i, vn) in self.vnodes.read().unwrap().iter() {
+        for i in 0..self.dht.partitions() as VNodeId {
             let final_status = if self.dht
                 .nodes_for_vnode(i, true, true)
                 .contains(&self.dht.node())
@@ -365,28 +824,58 @@ impl Database {
             } else {
                 VNodeStatus::Absent
             };
-            vn.lock().unwrap().handler_dht_change(self, final_status);
+            let vnodes = if final_status == VNodeStatus::Ready {
+                self.vnodes_with(i)
+            } else {
+                self.vnodes.read().unwrap()
+            };
+            if let Some(vn) = vnodes.get(&i) {
+                vn.write().unwrap().handler_dht_change(self, final_status);
+            }
         }
+        true
     }
 
     fn handler_tick(&self, time: time::Instant) {
+        *self.last_tick.lock().unwrap() = time::Instant::now();
         self.dht.handler_tick(time);
+        loop {
+            let expired = self.broadcasts.lock().unwrap().pop_expired(time);
+            match expired {
+                Some((cookie, mut broadcast)) => {
+                    debug!("broadcast {:?} timed out", cookie);
+                    self.respond_error(&mut broadcast.context, CommandError::Timeout);
+                }
+                None => break,
+            }
+        }
+        {
+            let mut idempotent_writes = self.idempotent_writes.lock().unwrap();
+            while idempotent_writes.pop_expired(time).is_some() {}
+        }
 
         let mut incomming_syncs = 0usize;
         let vnodes = self.vnodes.read().unwrap();
         for vn in vnodes.values() {
-            let mut vn = vn.lock().unwrap();
+            let mut vn = vn.write().unwrap();
             vn.handler_tick(self, time);
             incomming_syncs += vn.syncs_inflight().0;
         }
         // auto start sync in random vnodes
-        if self.config.sync_auto && incomming_syncs < self.config.sync_incomming_max as usize {
-            let vnodes_len = vnodes.len() as u16;
-            let rnd = thread_rng().gen::<u16>() % vnodes_len;
-            for vnode in (0..vnodes_len).map(|i| vnodes.get(&((i + rnd) % vnodes_len))) {
-                incomming_syncs +=
-                    vnode.unwrap().lock().unwrap().start_sync_if_ready(self) as usize;
-                if incomming_syncs >= self.config.sync_incomming_max as usize {
+        let (sync_auto, sync_incomming_max, sync_max) = {
+            let config = self.config();
+            (config.sync_auto, config.sync_incomming_max as usize, config.sync_max)
+        };
+        let syncs = self.stats.lock().unwrap().syncs;
+        if sync_auto && incomming_syncs < sync_incomming_max && syncs < sync_max
+            && !vnodes.is_empty()
+        {
+            let mut nums: Vec<_> = vnodes.keys().cloned().collect();
+            let rnd = thread_rng().gen::<usize>() % nums.len();
+            nums.rotate_left(rnd);
+            for vnode in nums.iter().map(|i| &vnodes[i]) {
+                incomming_syncs += vnode.write().unwrap().start_sync_if_ready(self) as usize;
+                if incomming_syncs >= sync_incomming_max {
                     break;
                 }
             }
@@ -396,7 +885,7 @@ impl Database {
     fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
         match msg {
             FabricMsg::RemoteGet(m) => {
-                vnode!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m));
+                vnode_read!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m));
             }
             FabricMsg::RemoteGetAck(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_get_remote_ack(self, from, m));
@@ -419,569 +908,4777 @@ impl Database {
             FabricMsg::SyncFin(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_fin(self, from, m));
             }
+            FabricMsg::Flush(m) => self.handler_flush(from, m),
+            FabricMsg::FlushAck(m) => self.handler_flush_ack(from, m),
             msg => unreachable!("Can't handle {:?}", msg),
         }
     }
 
+    /// Applies the remote sets grouped by vnode, locking each vnode once
+    fn handler_remote_sets(&self, msgs: Vec<(NodeId, MsgRemoteSet)>) {
+        let mut by_vnode: IdHashMap<VNodeId, Vec<(NodeId, MsgRemoteSet)>> = Default::default();
+        for (from, msg) in msgs {
+            by_vnode
+                .entry(msg.vnode)
+                .or_insert_with(Vec::new)
+                .push((from, msg));
+        }
+        for (vnode, msgs) in by_vnode {
+            vnode!(self, vnode, |vn| vn.handler_set_remote_batch(self, msgs));
+        }
+    }
+
+    fn handler_drained(&self) {
+        let pending = replace_default(&mut *self.pending_flush.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        let vnodes = self.vnodes.read().unwrap();
+        for vn in pending {
+            if let Some(vn) = vnodes.get(&vn) {
+                vn.write().unwrap().storage_persist_staged(self);
+            }
+        }
+    }
+
+    pub fn signal_pending_flush(&self, vnode: VNodeId) {
+        self.pending_flush.lock().unwrap().insert(vnode);
+    }
+
     fn syncs_inflight(&self) -> usize {
         self.vnodes
             .read()
             .unwrap()
             .values()
             .map(|vn| {
-                let inf = vn.lock().unwrap().syncs_inflight();
+                let inf = vn.read().unwrap().syncs_inflight();
                 inf.0 + inf.1
             })
             .sum()
     }
 
-    #[cfg(test)]
-    fn _start_sync(&self, vnode: VNodeId) -> bool {
+    pub fn vnode_stats(&self, vnode: VNodeId) -> Option<VNodeStats> {
         let vnodes = self.vnodes.read().unwrap();
-        let mut vnode = vnodes.get(&vnode).unwrap().lock().unwrap();
-        vnode._start_sync(self)
+        vnodes.get(&vnode).map(|vn| vn.read().unwrap().stats(self))
     }
 
-    pub fn signal_sync_start(&self, direction: SyncDirection) -> bool {
-        let mut stats = self.stats.lock().unwrap();
-        match direction {
-            SyncDirection::Incomming => if stats.incomming_syncs < self.config.sync_incomming_max {
-                stats.incomming_syncs += 1;
-                metrics::SYNC_INCOMING.inc();
-                true
-            } else {
-                false
-            },
-            SyncDirection::Outgoing => if stats.outgoing_syncs < self.config.sync_outgoing_max {
-                stats.outgoing_syncs += 1;
-                metrics::SYNC_OUTGOING.inc();
-                true
-            } else {
-                false
+    pub fn client_connected(&self, token: Token, addr: Option<net::SocketAddr>) {
+        self.clients.lock().unwrap().insert(
+            token,
+            ClientInfo {
+                addr,
+                name: None,
+                connected: time::Instant::now(),
+                vnodes: Default::default(),
             },
+        );
+    }
+
+    /// Names the client connection, None clears the name
+    pub fn set_client_name(&self, token: Token, name: Option<Bytes>) {
+        info!("Token {} named {:?}", token, name);
+        // a client that already disconnected isn't added back
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&token) {
+            client.name = name;
         }
     }
 
-    pub fn signal_sync_end(&self, direction: SyncDirection) {
-        let mut stats = self.stats.lock().unwrap();
-        match direction {
-            SyncDirection::Incomming => {
-                stats.incomming_syncs = stats.incomming_syncs.checked_sub(1).unwrap();
-                metrics::SYNC_INCOMING.dec();
-            }
-            SyncDirection::Outgoing => {
-                stats.outgoing_syncs = stats.outgoing_syncs.checked_sub(1).unwrap();
-                metrics::SYNC_OUTGOING.dec();
-            }
+    pub fn client_name(&self, token: Token) -> Option<Bytes> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&token).and_then(|c| c.name.clone())
+    }
+
+    /// One line per client connection, like `id=1 addr=127.0.0.1:50000 name=app age=10`
+    pub fn client_list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut tokens: Vec<_> = clients.keys().cloned().collect();
+        tokens.sort();
+        let mut list = String::new();
+        for token in tokens {
+            let client = &clients[&token];
+            list.push_str(&format!(
+                "id={} addr={} name={} age={}\n",
+                token,
+                client.addr.map_or_else(String::new, |a| a.to_string()),
+                client.name.as_ref().map_or("", |n| assume_str(n)),
+                client.connected.elapsed().as_secs()
+            ));
         }
+        list
     }
 
-    // CLIENT CRUD
-    pub fn set_flush(
-        &self,
-        context: &mut Context,
-        consistency: ConsistencyLevel,
-    ) -> Result<(), CommandError> {
-        debug_assert!(context.is_multi && context.is_exec);
-        let mut multi_vnode = None;
-        for (wi, w) in context.writes.iter().enumerate() {
-            // vnode can't changes mid way a batch we need to error
-            let write_vnode = self.dht.key_vnode(&w.key);
-            if multi_vnode.is_none() {
-                multi_vnode = Some(write_vnode);
-            } else if multi_vnode != Some(write_vnode) {
-                return Err(CommandError::MultiplePartitions);
+    /// Cleans up after a client disconnects, its requests still waiting for replies
+    /// are dropped instead of responding to a gone connection.
+    /// Writes already sent to the replicas aren't undone.
+    pub fn client_disconnected(&self, token: Token) {
+        let client = self.clients.lock().unwrap().remove(&token);
+        let client_vnodes = match client {
+            Some(client) => {
+                if let Some(name) = client.name {
+                    info!("Token {} ({}) disconnected", token, assume_str(&name));
+                }
+                client.vnodes
             }
-
-            // make sure key doesn't appear in the rest of the commands
-            for w2 in &context.writes[wi + 1..] {
-                if w.key == w2.key {
-                    return Err(CommandError::MultipleKeyMutations);
+            None => Default::default(),
+        };
+        let mut contexts = Vec::new();
+        {
+            let vnodes = self.vnodes.read().unwrap();
+            for vnode in &client_vnodes {
+                if let Some(vn) = vnodes.get(vnode) {
+                    contexts.extend(vn.write().unwrap().cancel_requests(token));
                 }
             }
         }
+        let mut cancelled = contexts.len();
+        {
+            let mut broadcasts = self.broadcasts.lock().unwrap();
+            let before = broadcasts.len();
+            broadcasts.retain(|_, b| b.context.token != token);
+            cancelled += before - broadcasts.len();
+        }
+        // like a timed out one, the IDEMPOTENT id of a cancelled write can be retried
+        for context in &contexts {
+            if let Some(hash) = context.idempotency_id {
+                self.idempotent_end(hash, None);
+            }
+        }
+        if cancelled != 0 {
+            debug!("Cancelled {} requests of token {}", cancelled, token);
+        }
+    }
 
-        if let Some(vnode) = multi_vnode {
-            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
-        } else {
-            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+    /// Notes that `vnode` coordinates a request of the client with `token`
+    pub fn client_request(&self, token: Token, vnode: VNodeId) {
+        if token & INTERNAL_TOKEN_FLAG != 0 {
+            return;
+        }
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&token) {
+            client.vnodes.insert(vnode);
         }
     }
 
-    pub fn set(
-        &self,
-        context: &mut Context,
-        key: &Bytes,
-        mutator_fn: MutatorFn,
-        consistency: ConsistencyLevel,
-        reply_result: bool,
-        response_fn: Option<ResponseFn>,
-    ) -> Result<(), CommandError> {
-        debug_assert!(!context.is_exec);
-        context.writes.push(ContextWrite {
-            version: 0,
-            mutator_fn: Some(mutator_fn),
-            key: key.clone(),
-            cube: Default::default(),
-            reply_result: reply_result,
-            response: None,
-            response_fn: response_fn,
-        });
+    /// Age in millis of the requests coordinated by this node still waiting for replies
+    pub fn inflight_requests(&self) -> Vec<u64> {
+        let now = time::Instant::now();
+        let vnodes = self.vnodes.read().unwrap();
+        vnodes
+            .values()
+            .flat_map(|vn| vn.read().unwrap().requests_ages(now))
+            .collect()
+    }
 
-        if context.is_multi {
-            Ok(())
-        } else {
-            debug_assert_eq!(context.writes.len(), 1);
-            let vnode = self.dht.key_vnode(key);
-            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
-        }
+    /// Whether the workers are handling the ticks, if not the node needs a restart
+    pub fn is_live(&self) -> bool {
+        let worker_timer = self.config().worker_timer as u64;
+        let elapsed = self.last_tick.lock().unwrap().elapsed();
+        duration_millis(elapsed) <= worker_timer * LIVENESS_MISSED_TICKS
     }
 
-    pub fn get(
-        &self,
-        context: &mut Context,
-        key: &Bytes,
-        consistency: ConsistencyLevel,
-        response_fn: ResponseFn,
-    ) -> Result<(), CommandError> {
-        debug_assert!(!context.is_multi && !context.is_exec);
+    /// Number of vnodes still getting their data from the other nodes
+    pub fn bootstrapping_vnodes(&self) -> usize {
+        let vnodes = self.vnodes.read().unwrap();
+        vnodes
+            .values()
+            .filter(|vn| vn.read().unwrap().status() == VNodeStatus::Bootstrap)
+            .count()
+    }
+
+    /// Whether the node can take client traffic: it's live, not in standby
+    /// and all its vnodes bootstrapped.
+    pub fn is_ready(&self) -> bool {
+        self.is_live() && !self.is_standby() && self.bootstrapping_vnodes() == 0
+    }
+
+    /// The serialized cube for `key` stored in this node, if any
+    pub fn local_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CommandError> {
         let vnode = self.dht.key_vnode(key);
-        vnode!(self, vnode, |vn| vn.do_get(
-            self,
-            context,
-            &[key],
-            consistency,
-            response_fn
-        ))
+        let vnodes = self.vnodes.read().unwrap();
+        match vnodes.get(&vnode) {
+            Some(vn) => vn.read()
+                .unwrap()
+                .local_get(key)
+                .map_err(|_| CommandError::StorageError),
+            None => Ok(None),
+        }
     }
 
-    pub fn mget(
-        &self,
-        context: &mut Context,
-        keys: &[&Bytes],
-        consistency: ConsistencyLevel,
-        response_fn: ResponseFn,
-    ) -> Result<(), CommandError> {
-        debug_assert!(context.is_multi && context.is_exec);
-        let mut multi_vnode = None;
-        for key in keys {
-            let write_vnode = self.dht.key_vnode(key);
-            if multi_vnode.is_none() {
-                multi_vnode = Some(write_vnode);
-            } else if multi_vnode != Some(write_vnode) {
-                return Err(CommandError::MultiplePartitions);
-            }
+    /// JSON description of a key as stored in this node, None if the vnode isn't local
+    pub fn key_dump(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CommandError> {
+        let vnode = self.dht.key_vnode(key);
+        let vnodes = self.vnodes.read().unwrap();
+        let dump = match vnodes.get(&vnode) {
+            Some(vn) => vn.read()
+                .unwrap()
+                .dump_key(key)
+                .map_err(|_| CommandError::StorageError)?,
+            None => return Ok(None),
+        };
+        let json = serde_json::to_vec_pretty(&dump).map_err(|_| CommandError::StorageError)?;
+        Ok(Some(json))
+    }
+
+    pub fn vnode_export<W: io::Write>(
+        &self,
+        vnode: VNodeId,
+        writer: W,
+    ) -> Result<usize, CommandError> {
+        let vnodes = self.vnodes.read().unwrap();
+        let mut locked_vnode = vnodes
+            .get(&vnode)
+            .ok_or(CommandError::InvalidValue)?
+            .write()
+            .unwrap();
+        locked_vnode.export_ndjson(writer).map_err(|e| {
+            error!("Can't export vnode {}: {}", vnode, e);
+            CommandError::StorageError
+        })
+    }
+
+    /// Path of a DEBUG EXPORT/IMPORT file, these are kept in the export dir under
+    /// the data dir so clients can't read or write anywhere else
+    pub fn export_path(&self, name: &str) -> Result<path::PathBuf, CommandError> {
+        let mut components = path::Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(path::Component::Normal(_)), None) => (),
+            _ => return Err(CommandError::InvalidValue),
         }
-        if let Some(vnode) = multi_vnode {
-            vnode!(self, vnode, |vn| vn.do_get(
-                self,
-                context,
-                keys,
-                consistency,
-                response_fn
-            ))
-        } else {
-            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+        let dir = self.config().data_dir.join(EXPORT_DIR);
+        fs::create_dir_all(&dir).map_err(|e| {
+            error!("Can't create export dir {:?}: {}", dir, e);
+            CommandError::StorageError
+        })?;
+        Ok(dir.join(name))
+    }
+
+    fn reset_vnodes(&self) {
+        warn!("Deleting all data in the node");
+        let vnodes = self.vnodes.read().unwrap();
+        for vn in vnodes.values() {
+            vn.write().unwrap().reset(self);
         }
     }
-}
 
-impl Drop for Database {
-    fn drop(&mut self) {
-        debug!("Droping database");
-        // force dropping vnodes before other components
-        let _ = self.vnodes.write().map(|mut vns| vns.clear());
+    /// Deletes all data in the cluster, responds once all reachable nodes did it
+    pub fn flush_all(&self, context: &mut Context) -> Result<(), CommandError> {
+        if !self.config().flushall_enabled {
+            return Err(CommandError::CommandDisabled);
+        }
+        self.reset_vnodes();
+        self.broadcast(context, |node, cookie| {
+            self.fabric.send_msg(node, &MsgFlush { cookie })
+        });
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bincode;
-    use config;
-    use env_logger;
-    use resp::RespValue;
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use std::{fs, net, ops};
-    use utils::sleep_ms;
-    use version_vector::VersionVector;
+    /// Sends a msg built by `send` to all other nodes, responds once all reachable nodes acked
+    fn broadcast<F>(&self, context: &mut Context, send: F)
+    where
+        F: Fn(NodeId, Cookie) -> Result<(), FabricError>,
+    {
+        let mut rng = thread_rng();
+        let cookie = Cookie::new(rng.gen(), rng.gen());
+        let nodes: Vec<_> = self.dht
+            .members()
+            .into_iter()
+            .map(|(node, _)| node)
+            .filter(|&node| node != self.dht.node())
+            .collect();
+        if nodes.is_empty() {
+            return self.respond_ok(context);
+        }
+        let expire =
+            time::Instant::now() + time::Duration::from_millis(self.config().request_timeout as _);
+        let broadcast = BroadcastState {
+            context: replace_default(context),
+            pending: nodes.len(),
+            error: None,
+        };
+        self.broadcasts.lock().unwrap().insert(cookie, broadcast, expire);
+        for node in nodes {
+            if send(node, cookie).is_err() {
+                // unreachable nodes aren't waited for
+                self.broadcast_ack(cookie, None);
+            }
+        }
+    }
 
-    #[allow(non_upper_case_globals)]
-    const One: &[u8] = b"One";
-    #[allow(non_upper_case_globals)]
-    const Quorum: &[u8] = b"Quorum";
-    #[allow(non_upper_case_globals)]
-    const All: &[u8] = b"All";
+    fn broadcast_ack(&self, cookie: Cookie, error: Option<CommandError>) {
+        let done = {
+            let mut broadcasts = self.broadcasts.lock().unwrap();
+            let done = match broadcasts.entry(cookie) {
+                HMEntry::Occupied(mut o) => {
+                    let broadcast = o.get_mut();
+                    broadcast.pending -= 1;
+                    if broadcast.error.is_none() {
+                        broadcast.error = error;
+                    }
+                    broadcast.pending == 0
+                }
+                HMEntry::Vacant(_) => false,
+            };
+            if done {
+                broadcasts.remove(&cookie)
+            } else {
+                None
+            }
+        };
+        if let Some(mut broadcast) = done {
+            match broadcast.error.take() {
+                Some(error) => self.respond_error(&mut broadcast.context, error),
+                None => self.respond_ok(&mut broadcast.context),
+            }
+        }
+    }
 
-    struct TestDatabase {
-        db: Arc<Database>,
-        responses: Arc<Mutex<HashMap<Token, RespValue>>>,
+    fn handler_flush(&self, from: NodeId, msg: MsgFlush) {
+        // each node must allow it, not only the one receiving the command
+        let result = if self.config().flushall_enabled {
+            self.reset_vnodes();
+            Ok(())
+        } else {
+            warn!("Refusing FLUSHALL from node {}, it's disabled", from);
+            Err(FabricError::Disabled)
+        };
+        let _ = self.fabric.send_msg(
+            from,
+            &MsgFlushAck {
+                cookie: msg.cookie,
+                result,
+            },
+        );
     }
 
-    const PARTITIONS: usize = 64;
+    fn handler_flush_ack(&self, _from: NodeId, msg: MsgFlushAck) {
+        let error = msg.result.err().map(|_| CommandError::CommandDisabled);
+        self.broadcast_ack(msg.cookie, error);
+    }
 
-    impl TestDatabase {
-        fn new(fabric_addr: net::SocketAddr, data_dir: &str, create: bool) -> Self {
-            let responses1 = Arc::new(Mutex::new(HashMap::new()));
-            let responses2 = responses1.clone();
-            let config = config::Config {
-                data_dir: data_dir.into(),
-                fabric_addr: fabric_addr,
-                cluster_name: "test".into(),
-                sync_incomming_max: 100,
-                sync_outgoing_max: 100,
-                sync_auto: false,
-                cmd_init: if create {
-                    Some(config::InitCommand {
-                        replication_factor: 3,
-                        partitions: PARTITIONS as _,
-                    })
-                } else {
-                    None
-                },
-                seed_nodes: vec!["127.0.0.1:9000".parse().unwrap()],
-                ..Default::default()
+    /// Removes the permanently dead node `node` from the ring, the other owners keep
+    /// serving its vnodes until a rebalance gives them new replicas. Its versions stay in
+    /// the clocks and cubes, pruning them could bring back values it deleted, and the
+    /// node replacing it writes under its own id.
+    pub fn retire_node(&self, node: NodeId) -> Result<(), CommandError> {
+        if node == self.dht.node() || !self.dht.members().contains_key(&node) {
+            return Err(CommandError::InvalidValue);
+        }
+        if self.fabric.connections().contains(&node) {
+            warn!("Can't retire node {}, it's still connected", node);
+            return Err(CommandError::InvalidValue);
+        }
+        self.dht.remove_node(node).map_err(|e| {
+            warn!("Can't retire node {}: {}", node, e);
+            CommandError::InvalidValue
+        })
+    }
+
+    /// Registers a write with the IDEMPOTENT `id`, returns false if it's a retry
+    /// of a recent one, in which case the original result was already responded.
+    pub fn idempotent_begin(&self, context: &mut Context, id: &Bytes) -> bool {
+        let window = self.config().idempotency_window;
+        if window == 0 {
+            return true;
+        }
+        let hash = idempotency_hash(id);
+        let previous = {
+            let mut idempotent_writes = self.idempotent_writes.lock().unwrap();
+            let previous = match idempotent_writes.entry(hash) {
+                // a hash collision, the write just isn't tracked
+                HMEntry::Occupied(ref o) if o.get().id != *id => return true,
+                HMEntry::Occupied(o) => Some(o.get().result.clone()),
+                HMEntry::Vacant(_) => None,
             };
-            let db = Database::new(
-                &config,
-                Box::new(move |mut ctx| {
-                    info!("response for {}", ctx.token);
-                    let r = responses1
-                        .lock()
-                        .unwrap()
-                        .insert(ctx.token, ctx.take_response());
-                    assert!(r.is_none(), "replaced a result");
-                }),
-            );
-            TestDatabase {
-                db: db,
-                responses: responses2,
+            if previous.is_none() {
+                let expire = time::Instant::now() + time::Duration::from_millis(window as _);
+                let write = IdempotentWrite {
+                    id: id.clone(),
+                    result: None,
+                };
+                idempotent_writes.insert(hash, write, expire);
+            }
+            previous
+        };
+        match previous {
+            Some(Some(result)) => {
+                debug!("Replying idempotent write {:?} again", id);
+                self.respond_resp(context, result);
+                false
+            }
+            Some(None) => {
+                self.respond_error(context, CommandError::RequestInProgress);
+                false
+            }
+            None => {
+                context.idempotency_id = Some(hash);
+                true
             }
         }
+    }
 
-        fn force_syncs(&self) {
-            for i in 0..PARTITIONS as u16 {
-                while !self._start_sync(i) {
-                    sleep_ms(1);
-                }
+    /// Keeps the result of an IDEMPOTENT write, failures are forgotten so they can be retried
+    pub fn idempotent_end(&self, hash: u64, result: Option<&RespValue>) {
+        let mut idempotent_writes = self.idempotent_writes.lock().unwrap();
+        match result {
+            Some(&RespValue::Error(_)) | None => {
+                idempotent_writes.remove(&hash);
             }
-            self.wait_syncs();
+            Some(result) => if let HMEntry::Occupied(mut o) = idempotent_writes.entry(hash) {
+                o.get_mut().result = Some(result.clone());
+            },
         }
+    }
 
-        fn wait_syncs(&self) {
-            // wait a bit so syncs can actually start after requested
-            sleep_ms(200);
-            while self.syncs_inflight() != 0 {
-                //warn!("waiting for syncs to finish");
-                sleep_ms(1);
+    /// Up to `count` keys stored locally, following `cursor` and continuing
+    /// through the following vnodes. Each call seeks past the last key returned,
+    /// so keys added or deleted meanwhile don't shift the rest.
+    /// Returns the cursor to continue from, the default one once all vnodes are scanned.
+    pub fn scan(
+        &self,
+        cursor: &ScanCursor,
+        count: usize,
+        type_name: Option<&str>,
+    ) -> (ScanCursor, Vec<Bytes>) {
+        let partitions = self.dht.partitions() as u32;
+        let mut vnode = cursor.vnode;
+        let mut after = cursor.after.clone();
+        let vnodes = self.vnodes.read().unwrap();
+        let mut keys = Vec::new();
+        while vnode < partitions && keys.len() < count {
+            let filled = vnodes.get(&(vnode as VNodeId)).map_or(false, |vn| {
+                // only flushing takes the vnode exclusively, the scan shares it
+                if vn.read().unwrap().has_pending_writes() {
+                    vn.write().unwrap().storage_flush();
+                }
+                vn.read().unwrap().scan_keys(
+                    after.as_ref().map(|a| &a[..]),
+                    count,
+                    type_name,
+                    &mut keys,
+                )
+            });
+            if filled {
+                after = keys.last().cloned();
+            } else {
+                vnode += 1;
+                after = None;
             }
         }
-
-        fn response_resp(&self, token: Token) -> RespValue {
-            (0..1000)
-                .filter_map(|_| {
-                    sleep_ms(1);
-                    self.responses.lock().unwrap().remove(&token)
-                })
-                .next()
-                .unwrap()
+        if vnode >= partitions {
+            return (Default::default(), keys);
         }
+        (ScanCursor { vnode, after }, keys)
+    }
 
-        fn response_values(&self, token: Token) -> (Vec<Vec<u8>>, VersionVector) {
-            decode_values(self.response_resp(token))
+    /// Imports newline delimited json as exported by vnode_export,
+    /// each key is merged into the replicas of the vnode that owns it.
+    pub fn import_ndjson<R: io::BufRead>(&self, reader: R) -> Result<usize, CommandError> {
+        let mut count = 0;
+        for line in reader.split(b'\n') {
+            let line = line.map_err(|_| CommandError::InvalidValue)?;
+            if line.is_empty() {
+                continue;
+            }
+            let ExportRecord { key, cube } =
+                serde_json::from_slice(&line).map_err(|_| CommandError::InvalidValue)?;
+            let vnode = self.dht.key_vnode(&key);
+            vnode!(self, vnode, |vn| vn.do_import(self, key, cube))?;
+            count += 1;
         }
+        // the imported keys are in the storage once it returns
+        self.handler_drained();
+        Ok(count)
+    }
 
-        fn do_cmd(&self, token: Token, args: &[&[u8]]) {
-            let mut context = Context::new(token);
-            context.commands.push(RespValue::Array(
-                args.iter().map(|&x| RespValue::Data(x.into())).collect(),
-            ));
-            self.handler_cmd(context)
-        }
+    /// Merges a key into the replicas of the vnode that owns it, concurrent values
+    /// become siblings. Responds once `consistency` replicas acknowledged it.
+    pub fn restore(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        cube: Cube,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        debug_assert!(!context.is_multi && !context.is_exec);
+        context.writes.push(ContextWrite {
+            version: 0,
+            mutator_fn: None,
+            key: key.clone(),
+            cube: cube,
+            reply_result: false,
+            response: Some(RespValue::Status("OK".into())),
+            response_fn: None,
+        });
+        let vnode = self.dht.key_vnode(key);
+        vnode!(self, vnode, |vn| vn.do_restore(self, context, consistency))
     }
 
-    impl ops::Deref for TestDatabase {
-        type Target = Database;
-        fn deref(&self) -> &Self::Target {
-            &self.db
-        }
+    /// Queues a MIGRATE request, the remote I/O is kept off the workers
+    pub fn migrate(&self, migration: Migration) {
+        let _ = self.migrations.lock().unwrap().send(migration);
     }
 
-    fn decode_values(value: RespValue) -> (Vec<Vec<u8>>, VersionVector) {
-        if let RespValue::Array(ref arr) = value {
-            let mut values: Vec<_> = arr[0..arr.len() - 1]
-                .iter()
-                .map(|d| {
-                    if let RespValue::Data(ref d) = *d {
-                        d[..].to_owned()
-                    } else {
-                        panic!("cant decode values from {:?}", value);
+    fn handler_migration(&self, migration: Migration) {
+        let timeout = time::Duration::from_millis(self.config().request_timeout as _);
+        match migration {
+            Migration::Key {
+                mut context,
+                addr,
+                key,
+                cube,
+                delete,
+            } => {
+                let result = resp::Client::connect(&addr, timeout)
+                    .map_err(|e| {
+                        warn!("Can't connect to {} for migration: {}", addr, e);
+                        CommandError::MigrateFailed
+                    })
+                    .and_then(|mut client| restore_remote(&mut client, &key, &cube, timeout));
+                match (result, delete) {
+                    // a write of its own, values written after the DUMP are kept
+                    (Ok(()), Some(vv)) => {
+                        let consistency = self.config().consistency_write;
+                        let result = self.set(
+                            &mut context,
+                            &key,
+                            Box::new(move |i, v, mut c: Cube| {
+                                c.del(i, v, &vv);
+                                Ok((c, Some(RespValue::Status("OK".into()))))
+                            }),
+                            consistency,
+                            false,
+                            None,
+                        );
+                        if let Err(e) = result {
+                            context.clear();
+                            self.respond_error(&mut context, e);
+                        }
                     }
-                })
-                .collect();
-            // sort so we have a deterministic sibling order for tests
-            values.sort();
-            let vv = if let RespValue::Data(ref d) = arr[arr.len() - 1] {
-                bincode::deserialize(&d[..]).unwrap()
-            } else {
-                panic!("cant decode values from {:?}", value);
-            };
-            return (values, vv);
+                    (Ok(()), None) => self.respond_ok(&mut context),
+                    (Err(e), _) => self.respond_error(&mut context, e),
+                }
+            }
+            Migration::Cursor {
+                mut context,
+                addr,
+                cursor,
+                count,
+            } => match self.migrate_keys(&addr, &cursor, count) {
+                Ok((cursor, migrated)) => self.respond_resp(
+                    &mut context,
+                    RespValue::Array(vec![
+                        RespValue::Data(cursor.encode()),
+                        RespValue::Int(migrated as _),
+                    ]),
+                ),
+                Err(e) => self.respond_error(&mut context, e),
+            },
         }
-        panic!("Can't decode response {:?}", value);
     }
 
-    fn encode_vv(vv: &VersionVector) -> Vec<u8> {
-        bincode::serialize(vv).unwrap()
+    /// Copies the local keys returned by a scan from `cursor` into another cluster,
+    /// returns the next cursor and the number of keys copied.
+    /// Copying the same keys again is harmless, so a failed batch can be retried.
+    pub fn migrate_keys(
+        &self,
+        addr: &net::SocketAddr,
+        cursor: &ScanCursor,
+        count: usize,
+    ) -> Result<(ScanCursor, usize), CommandError> {
+        let timeout = time::Duration::from_millis(self.config().request_timeout as _);
+        let mut client = resp::Client::connect(addr, timeout).map_err(|e| {
+            warn!("Can't connect to {} for migration: {}", addr, e);
+            CommandError::MigrateFailed
+        })?;
+        let (next, keys) = self.scan(cursor, count, None);
+        let mut migrated = 0;
+        for key in keys {
+            let cube = match self.local_get(&key)? {
+                Some(bytes) => deserialize_cube(&bytes).map_err(|_| CommandError::StorageError)?,
+                None => continue,
+            };
+            // tombstones are only meaningful to this cluster
+            if cube.is_empty() {
+                continue;
+            }
+            restore_remote(&mut client, &key, &cube, timeout)?;
+            migrated += 1;
+        }
+        Ok((next, migrated))
     }
 
-    fn test_reload_stub(shutdown: bool) {
-        let _ = fs::remove_dir_all("t/");
-        let _ = env_logger::try_init();
-        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
-        let prev_node = db.dht.node();
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert!(db.response_values(1).0.len() == 0);
+    pub fn is_bulk_loading(&self) -> bool {
+        self.bulk_load.load(Ordering::Acquire)
+    }
 
-        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", One]);
-        assert!(db.response_values(1).0.len() == 1);
+    pub fn bulk_load_start(&self) {
+        info!("Starting bulk load");
+        self.bulk_load.store(true, Ordering::Release);
+    }
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db.response_values(1).0, [b"value1"]);
+    /// Ends the bulk load and replicates the keys written during it,
+    /// returns the number of keys replicated.
+    pub fn bulk_load_end(&self) -> usize {
+        self.bulk_load.store(false, Ordering::Release);
+        let count: usize = self.vnodes
+            .read()
+            .unwrap()
+            .values()
+            .map(|vn| vn.write().unwrap().bulk_load_replicate(self))
+            .sum();
+        info!("Finished bulk load, replicated {} keys", count);
+        count
+    }
 
-        db.save(shutdown);
-        drop(db);
-        db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Acquire)
+    }
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db.response_values(1).0, [b"value1"]);
+    /// Stops coordinating client writes and flushes the vnodes storage,
+    /// returns whether the requests in flight are done so the node can be restarted.
+    /// The vnodes stay owned by the node, the standby ends with the restart.
+    pub fn standby_start(&self) -> bool {
+        if !self.standby.swap(true, Ordering::AcqRel) {
+            info!("Entering standby");
+        }
+        self.save(false);
+        self.inflight_requests().is_empty()
+    }
 
-        if shutdown {
-            assert_eq!(db.dht.node(), prev_node);
-        } else {
-            assert_ne!(db.dht.node(), prev_node);
+    pub fn standby_end(&self) {
+        if self.standby.swap(false, Ordering::AcqRel) {
+            info!("Leaving standby");
         }
+    }
 
-        assert_eq!(
-            1,
-            db.vnodes
-                .read()
-                .unwrap()
-                .values()
-                .map(|vn| vn.lock().unwrap()._log_len(prev_node))
-                .sum::<usize>()
-        );
+    /// Replaces the ring with one dumped by CLUSTER DUMPRING, only permitted while
+    /// the node has no peers so it can't override the ring of a live cluster.
+    /// A ring with other partitions or replication factor can only replace that of
+    /// a node without any data, as its keys would belong to other vnodes.
+    pub fn load_ring(&self, serialized_ring: &[u8]) -> Result<(), CommandError> {
+        // checked before the ring lock as the vnodes take it while locked,
+        // an isolated node doesn't get the writes of other nodes meanwhile
+        let storage_empty = self.vnodes
+            .read()
+            .unwrap()
+            .values()
+            .all(|vn| vn.write().unwrap().is_storage_empty());
+        // the loaded ring may have a lower epoch than the one it replaces
+        let mut last_epoch = self.dht_epoch.lock().unwrap();
+        self.dht
+            .load_ring(serialized_ring, |alone, same_layout| {
+                if !alone || !self.fabric.connections().is_empty() {
+                    Err(CommandError::NotIsolated)
+                } else if !same_layout && !storage_empty {
+                    Err(CommandError::RingMismatch)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| {
+                warn!("Can't load ring: {}", e);
+                CommandError::InvalidValue
+            })??;
+        *last_epoch = 0;
+        // vnodes past the loaded partitions have no data
+        let partitions = self.dht.partitions() as VNodeId;
+        self.vnodes
+            .write()
+            .unwrap()
+            .retain(|&vnode, _| vnode < partitions);
+        Ok(())
+    }
+
+    /// Drops the local data of `vnode` and fetches it again from another replica
+    pub fn vnode_rebuild(&self, vnode: VNodeId) -> Result<(), CommandError> {
+        let connections = self.fabric.connections();
+        let replicas = self.dht
+            .nodes_for_vnode(vnode, false, true)
+            .into_iter()
+            .filter(|n| *n != self.dht.node() && connections.contains(n))
+            .count();
+        if replicas == 0 {
+            return Err(CommandError::Unavailable);
+        }
+        let vnodes = self.vnodes.read().unwrap();
+        let rebuilding = vnodes
+            .get(&vnode)
+            .map_or(false, |vn| vn.write().unwrap().rebuild(self));
+        if rebuilding {
+            Ok(())
+        } else {
+            Err(CommandError::InvalidValue)
+        }
+    }
+
+    pub fn vnode_reload(&self, vnode: VNodeId) -> Result<(), CommandError> {
+        let vnodes = self.vnodes.read().unwrap();
+        let mut locked_vnode = vnodes
+            .get(&vnode)
+            .ok_or(CommandError::InvalidValue)?
+            .write()
+            .unwrap();
+        if locked_vnode.reload(self) {
+            Ok(())
+        } else {
+            warn!("Can't reload vnode {}, it isn't idle", vnode);
+            Err(CommandError::InvalidValue)
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn vnode_corrupt(&self, vnode: VNodeId, key: &[u8]) -> Result<(), CommandError> {
+        let vnodes = self.vnodes.read().unwrap();
+        vnodes
+            .get(&vnode)
+            .ok_or(CommandError::InvalidValue)?
+            .write()
+            .unwrap()
+            .corrupt(key)
+            .map_err(|_| CommandError::StorageError)
+    }
+
+    pub fn vnode_value_stats(&self, vnode: VNodeId) -> Option<ValueStats> {
+        let vnodes = self.vnodes.read().unwrap();
+        vnodes
+            .get(&vnode)
+            .map(|vn| vn.write().unwrap().value_stats())
+    }
+
+    /// Compacts the storage of every local vnode, returns how many were compacted
+    pub fn compact_all(&self) -> usize {
+        let vnodes = self.vnodes.read().unwrap();
+        for vn in vnodes.values() {
+            vn.write().unwrap().compact();
+        }
+        vnodes.len()
+    }
+
+    pub fn vnode_compact(&self, vnode: VNodeId) -> bool {
+        let vnodes = self.vnodes.read().unwrap();
+        vnodes
+            .get(&vnode)
+            .map(|vn| vn.write().unwrap().compact())
+            .is_some()
+    }
+
+    #[cfg(test)]
+    fn _start_sync(&self, vnode: VNodeId) -> bool {
+        let vnodes = self.vnodes.read().unwrap();
+        let mut vnode = vnodes.get(&vnode).unwrap().write().unwrap();
+        vnode._start_sync(self)
+    }
+
+    /// Reserves a slot for a sync or bootstrap, false if the limits don't allow it to start.
+    /// Anti-entropy syncs also count towards `sync_max`, so they can't starve the bootstraps.
+    pub fn signal_sync_start(&self, direction: SyncDirection, bootstrap: bool) -> bool {
+        let config = self.config();
+        let mut stats = self.stats.lock().unwrap();
+        if !bootstrap && stats.syncs >= config.sync_max {
+            return false;
+        }
+        let started = match direction {
+            SyncDirection::Incomming => if stats.incomming_syncs < config.sync_incomming_max {
+                stats.incomming_syncs += 1;
+                metrics::SYNC_INCOMING.inc();
+                true
+            } else {
+                false
+            },
+            SyncDirection::Outgoing => if stats.outgoing_syncs < config.sync_outgoing_max {
+                stats.outgoing_syncs += 1;
+                metrics::SYNC_OUTGOING.inc();
+                true
+            } else {
+                false
+            },
+        };
+        if started && !bootstrap {
+            stats.syncs += 1;
+        }
+        started
+    }
+
+    pub fn signal_sync_end(&self, direction: SyncDirection, bootstrap: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        if !bootstrap {
+            stats.syncs = stats.syncs.checked_sub(1).unwrap();
+        }
+        match direction {
+            SyncDirection::Incomming => {
+                stats.incomming_syncs = stats.incomming_syncs.checked_sub(1).unwrap();
+                metrics::SYNC_INCOMING.dec();
+            }
+            SyncDirection::Outgoing => {
+                stats.outgoing_syncs = stats.outgoing_syncs.checked_sub(1).unwrap();
+                metrics::SYNC_OUTGOING.dec();
+            }
+        }
+    }
+
+    // CLIENT CRUD
+    pub fn set_flush(
+        &self,
+        context: &mut Context,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        debug_assert!(context.is_multi && context.is_exec);
+        let mut multi_vnode = None;
+        for (wi, w) in context.writes.iter().enumerate() {
+            // vnode can't changes mid way a batch we need to error
+            let write_vnode = self.dht.key_vnode(&w.key);
+            if multi_vnode.is_none() {
+                multi_vnode = Some(write_vnode);
+            } else if multi_vnode != Some(write_vnode) {
+                return Err(CommandError::MultiplePartitions);
+            }
+
+            // make sure key doesn't appear in the rest of the commands
+            for w2 in &context.writes[wi + 1..] {
+                if w.key == w2.key {
+                    return Err(CommandError::MultipleKeyMutations);
+                }
+            }
+        }
+
+        if let Some(vnode) = multi_vnode {
+            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
+        } else {
+            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+        }
+    }
+
+    pub fn set(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        mutator_fn: MutatorFn,
+        consistency: ConsistencyLevel,
+        reply_result: bool,
+        response_fn: Option<ResponseFn>,
+    ) -> Result<(), CommandError> {
+        debug_assert!(!context.is_exec);
+        context.writes.push(ContextWrite {
+            version: 0,
+            mutator_fn: Some(mutator_fn),
+            key: key.clone(),
+            cube: Default::default(),
+            reply_result: reply_result,
+            response: None,
+            response_fn: response_fn,
+        });
+
+        if context.is_multi {
+            Ok(())
+        } else {
+            debug_assert_eq!(context.writes.len(), 1);
+            let vnode = self.dht.key_vnode(key);
+            vnode!(self, vnode, |vn| vn.do_flush(self, context, consistency))
+        }
+    }
+
+    pub fn get(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        consistency: ConsistencyLevel,
+        response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        debug_assert!(!context.is_multi && !context.is_exec);
+        let vnode = self.dht.key_vnode(key);
+        vnode!(self, vnode, |vn| vn.do_get(
+            self,
+            context,
+            &[key],
+            consistency,
+            response_fn
+        ))
+    }
+
+    pub fn mget(
+        &self,
+        context: &mut Context,
+        keys: &[&Bytes],
+        consistency: ConsistencyLevel,
+        response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        debug_assert!(context.is_multi && context.is_exec);
+        let mut multi_vnode = None;
+        for key in keys {
+            let write_vnode = self.dht.key_vnode(key);
+            if multi_vnode.is_none() {
+                multi_vnode = Some(write_vnode);
+            } else if multi_vnode != Some(write_vnode) {
+                return Err(CommandError::MultiplePartitions);
+            }
+        }
+        if let Some(vnode) = multi_vnode {
+            vnode!(self, vnode, |vn| vn.do_get(
+                self,
+                context,
+                keys,
+                consistency,
+                response_fn
+            ))
+        } else {
+            Ok(self.respond_resp(context, RespValue::Array(Default::default())))
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        debug!("Droping database");
+        // force dropping vnodes before other components
+        let _ = self.vnodes.write().map(|mut vns| vns.clear());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+    use config;
+    use env_logger;
+    use resp::{self, RespValue};
+    use serde_json;
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::{fs, net, ops, thread};
+    use utils::{join_u64, now_millis, sleep_ms};
+    use version_vector::VersionVector;
+
+    #[allow(non_upper_case_globals)]
+    const One: &[u8] = b"One";
+    #[allow(non_upper_case_globals)]
+    const Quorum: &[u8] = b"Quorum";
+    #[allow(non_upper_case_globals)]
+    const All: &[u8] = b"All";
+
+    struct TestDatabase {
+        db: Arc<Database>,
+        responses: Arc<Mutex<HashMap<Token, RespValue>>>,
+    }
+
+    const PARTITIONS: usize = 64;
+
+    impl TestDatabase {
+        fn new(fabric_addr: net::SocketAddr, data_dir: &str, create: bool) -> Self {
+            Self::with_config(fabric_addr, data_dir, create, |_| ())
+        }
+
+        fn with_config<F: FnOnce(&mut config::Config)>(
+            fabric_addr: net::SocketAddr,
+            data_dir: &str,
+            create: bool,
+            config_fn: F,
+        ) -> Self {
+            let responses1 = Arc::new(Mutex::new(HashMap::new()));
+            let responses2 = responses1.clone();
+            let mut config = config::Config {
+                data_dir: data_dir.into(),
+                fabric_addr: fabric_addr,
+                cluster_name: "test".into(),
+                sync_incomming_max: 100,
+                sync_outgoing_max: 100,
+                sync_max: 100,
+                sync_auto: false,
+                cmd_init: if create {
+                    Some(config::InitCommand {
+                        replication_factor: 3,
+                        partitions: PARTITIONS as _,
+                    })
+                } else {
+                    None
+                },
+                seed_nodes: vec!["127.0.0.1:9000".parse().unwrap()],
+                ..Default::default()
+            };
+            config_fn(&mut config);
+            let db = Database::new(
+                &config,
+                Box::new(move |mut ctx| {
+                    info!("response for {}", ctx.token);
+                    let r = responses1
+                        .lock()
+                        .unwrap()
+                        .insert(ctx.token, ctx.take_response());
+                    assert!(r.is_none(), "replaced a result");
+                }),
+            );
+            TestDatabase {
+                db: db,
+                responses: responses2,
+            }
+        }
+
+        fn force_syncs(&self) {
+            for i in 0..PARTITIONS as u16 {
+                while !self._start_sync(i) {
+                    sleep_ms(1);
+                }
+            }
+            self.wait_syncs();
+        }
+
+        fn wait_syncs(&self) {
+            // wait a bit so syncs can actually start after requested
+            sleep_ms(200);
+            while self.syncs_inflight() != 0 {
+                //warn!("waiting for syncs to finish");
+                sleep_ms(1);
+            }
+        }
+
+        fn response_resp(&self, token: Token) -> RespValue {
+            (0..1000)
+                .filter_map(|_| {
+                    sleep_ms(1);
+                    self.responses.lock().unwrap().remove(&token)
+                })
+                .next()
+                .unwrap()
+        }
+
+        fn response_values(&self, token: Token) -> (Vec<Vec<u8>>, VersionVector) {
+            decode_values(self.response_resp(token))
+        }
+
+        fn do_cmd(&self, token: Token, args: &[&[u8]]) {
+            let mut context = Context::new(token);
+            context.commands.push(RespValue::Array(
+                args.iter().map(|&x| RespValue::Data(x.into())).collect(),
+            ));
+            self.handler_cmd(context)
+        }
+
+        // the context doesn't survive across do_cmd calls, so queue the
+        // commands like MULTI would and send the EXEC
+        fn do_multi(&self, token: Token, cmds: &[&[&[u8]]], consistency: &[u8]) {
+            let mut context = Context::new(token);
+            context.is_multi = true;
+            let exec: &[&[u8]] = &[b"EXEC", consistency];
+            for args in cmds.iter().chain(Some(&exec)) {
+                context.commands.push(RespValue::Array(
+                    args.iter().map(|&x| RespValue::Data(x.into())).collect(),
+                ));
+            }
+            self.handler_cmd(context)
+        }
+    }
+
+    impl ops::Deref for TestDatabase {
+        type Target = Database;
+        fn deref(&self) -> &Self::Target {
+            &self.db
+        }
+    }
+
+    fn decode_values(value: RespValue) -> (Vec<Vec<u8>>, VersionVector) {
+        if let RespValue::Array(ref arr) = value {
+            let mut values: Vec<_> = arr[0..arr.len() - 1]
+                .iter()
+                .map(|d| {
+                    if let RespValue::Data(ref d) = *d {
+                        d[..].to_owned()
+                    } else {
+                        panic!("cant decode values from {:?}", value);
+                    }
+                })
+                .collect();
+            // sort so we have a deterministic sibling order for tests
+            values.sort();
+            let vv = if let RespValue::Data(ref d) = arr[arr.len() - 1] {
+                bincode::deserialize(&d[..]).unwrap()
+            } else {
+                panic!("cant decode values from {:?}", value);
+            };
+            return (values, vv);
+        }
+        panic!("Can't decode response {:?}", value);
+    }
+
+    fn encode_vv(vv: &VersionVector) -> Vec<u8> {
+        bincode::serialize(vv).unwrap()
+    }
+
+    fn test_reload_stub(shutdown: bool) {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let prev_node = db.dht.node();
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert!(db.response_values(1).0.len() == 0);
+
+        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", One]);
+        assert!(db.response_values(1).0.len() == 1);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+
+        db.do_cmd(1, &[b"EXPIRE", b"test", b"1000", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        db.save(shutdown);
+        drop(db);
+        db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        db.do_cmd(1, &[b"TTL", b"test", One]);
+        match db.response_resp(1) {
+            RespValue::Int(ttl) => assert!(ttl > 990 && ttl <= 1000),
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        if shutdown {
+            assert_eq!(db.dht.node(), prev_node);
+        } else {
+            assert_ne!(db.dht.node(), prev_node);
+        }
+
+        // the set and the expire
+        assert_eq!(
+            2,
+            db.vnodes
+                .read()
+                .unwrap()
+                .values()
+                .map(|vn| vn.write().unwrap()._log_len(prev_node))
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_reload_clean_shutdown() {
+        test_reload_stub(true);
+    }
+
+    #[test]
+    fn test_reload_dirty_shutdown() {
+        test_reload_stub(false);
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let prev_node = db.dht.node();
+        let mut sender = db.sender();
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let mut context = Context::new(i);
+            context.commands.push(RespValue::Array(vec![
+                RespValue::Data("SET".into()),
+                RespValue::Data(key.into()),
+                RespValue::Data("value".into()),
+                RespValue::Data(One.into()),
+            ]));
+            sender.send(WorkerMsg::Command(context));
+        }
+        db.shutdown();
+        // every queued command was handled before the workers exited
+        assert_eq!(db.responses.lock().unwrap().len(), 100);
+        drop(db);
+
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+        assert_eq!(db.dht.node(), prev_node);
+        db.do_cmd(1, &[b"GET", b"key99", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_route_fabric_msg() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 2, time::Duration::from_secs(60));
+        manager.set_background_threads(2);
+        manager.set_vnode_affinity(true);
+        manager.start(move || {
+            let tx = tx.clone();
+            Box::new(move |chan| {
+                let name = thread::current().name().unwrap().to_owned();
+                for wm in chan {
+                    match wm {
+                        WorkerMsg::Fabric(_, m) => {
+                            tx.send((m.vnode(), m.get_type(), name.clone())).unwrap()
+                        }
+                        WorkerMsg::Exit => break,
+                        _ => (),
+                    }
+                }
+            })
+        });
+
+        let mut sender = manager.sender();
+        for _ in 0..5 {
+            for vnode in 0..4 {
+                let fin = MsgSyncFin {
+                    vnode: vnode,
+                    cookie: Cookie::new(0, 0),
+                    result: Err(FabricError::SyncInterrupted),
+                };
+                Database::route_fabric_msg(&mut sender, 1, FabricMsg::SyncFin(fin));
+                let get = MsgRemoteGet {
+                    vnode: vnode,
+                    cookie: Cookie::new(0, 0),
+                    keys: vec![],
+                    deadline: None,
+                };
+                Database::route_fabric_msg(&mut sender, 1, FabricMsg::RemoteGet(get));
+            }
+        }
+        manager.shutdown(true);
+
+        let mut threads = HashMap::new();
+        for (vnode, msg_type, name) in rx.try_iter() {
+            match msg_type {
+                FabricMsgType::Synch => assert!(name.starts_with("BackgroundWorker:")),
+                _ => assert!(name.starts_with("Worker:")),
+            }
+            let key = (vnode.unwrap(), msg_type as u8);
+            assert_eq!(*threads.entry(key).or_insert_with(|| name.clone()), name);
+        }
+        assert_eq!(threads.len(), 8);
+    }
+
+    #[test]
+    fn test_commit_policy_sync() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.commit_policy = CommitPolicy::Sync,
+        );
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        // crash before any pending write is flushed
+        for vn in db.vnodes.read().unwrap().values() {
+            vn.write().unwrap()._discard_pending();
+        }
+        drop(db);
+        db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_debug_corrupt() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let new_db = |create: bool| {
+            TestDatabase::with_config(
+                "127.0.0.1:9000".parse().unwrap(),
+                "t/db",
+                create,
+                |config| config.storage_verify_on_recovery = true,
+            )
+        };
+        let mut db = new_db(true);
+        db.do_cmd(1, &[b"SET", b"{t}test", b"value", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db.dht.key_vnode(b"{t}").to_string();
+        db.do_cmd(1, &[b"DEBUG", b"CORRUPT", vnode.as_bytes(), b"{t}corrupt"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"{t}corrupt"]);
+        match db.response_resp(1) {
+            RespValue::Data(bytes) => assert!(deserialize_cube(&bytes).is_err()),
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        // crash, the vnode storage is verified when recovering
+        drop(db);
+        db = new_db(false);
+        db.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"{t}corrupt"]);
+        assert_eq!(db.response_resp(1), RespValue::Nil);
+        db.do_cmd(1, &[b"GET", b"{t}test", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_corrupt_key_rebuild() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let new_db2 = |create: bool| {
+            TestDatabase::with_config(
+                "127.0.0.1:9001".parse().unwrap(),
+                "t/db2",
+                create,
+                |config| config.storage_verify_on_recovery = true,
+            )
+        };
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = new_db2(false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db1.dht.key_vnode(b"test").to_string();
+        db2.do_cmd(1, &[b"DEBUG", b"CORRUPT", vnode.as_bytes(), b"test"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+
+        // the clocks of db2 still have the dots of the key, so it's rebuilt from db1
+        drop(db2);
+        db2 = new_db2(false);
+        let mut rebuilt = false;
+        for _ in 0..50 {
+            db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+            if let RespValue::Data(bytes) = db2.response_resp(1) {
+                rebuilt = deserialize_cube(&bytes).is_ok();
+            }
+            if rebuilt {
+                break;
+            }
+            sleep_ms(100);
+        }
+        assert!(rebuilt);
+        db2.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_blind_write_policy() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        // sibling, the default
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1", b"value2"]);
+
+        db.config.write().unwrap().blind_write_policy = BlindWritePolicy::Overwrite;
+        db.do_cmd(1, &[b"SET", b"test", b"value3", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value3"]);
+
+        db.config.write().unwrap().blind_write_policy = BlindWritePolicy::Reject;
+        db.do_cmd(1, &[b"SET", b"test", b"value4", b"", One]);
+        let required: RespValue = CommandError::ContextRequired.into();
+        assert_eq!(db.response_resp(1), required);
+        // empty keys and writes with context are fine
+        db.do_cmd(1, &[b"SET", b"other", b"value", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (_, vv) = db.response_values(1);
+        db.do_cmd(1, &[b"SET", b"test", b"value4", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value4"]);
+    }
+
+    #[test]
+    fn test_getex() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let expire = |db: &TestDatabase| {
+            db.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+            match db.response_resp(1) {
+                RespValue::Data(bytes) => deserialize_cube(&bytes).unwrap().expire(),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+
+        db.do_cmd(1, &[b"SET", b"test", b"value", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let before = now_millis();
+        db.do_cmd(1, &[b"GETEX", b"test", b"EX", b"100", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        let at = expire(&db).unwrap();
+        assert!(at >= before + 100_000 && at <= now_millis() + 100_000);
+
+        // a plain read
+        db.do_cmd(1, &[b"GETEX", b"test"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        assert_eq!(expire(&db), Some(at));
+
+        db.do_cmd(1, &[b"GETEX", b"test", b"PERSIST"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        assert_eq!(expire(&db), None);
+
+        // expired keys read as missing and writes start over
+        db.do_cmd(1, &[b"GETEX", b"test", b"PX", b"50"]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        sleep_ms(100);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert!(db.response_values(1).0.is_empty());
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+        assert_eq!(expire(&db), None);
+
+        db.do_cmd(1, &[b"GETEX", b"test", b"EX", b"0"]);
+        let invalid: RespValue = CommandError::InvalidValue.into();
+        assert_eq!(db.response_resp(1), invalid);
+        db.do_cmd(1, &[b"GETEX", b"missing", b"EX", b"10"]);
+        assert!(db.response_values(1).0.is_empty());
+    }
+
+    #[test]
+    fn test_expire_ttl() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.expire_sweep_interval = 100,
+        );
+        let ttl = |key: &[u8]| {
+            db.do_cmd(1, &[b"TTL", key, One]);
+            db.response_resp(1)
+        };
+
+        assert_eq!(ttl(b"test"), RespValue::Int(-2));
+        db.do_cmd(1, &[b"EXPIRE", b"test", b"10", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"SET", b"test", b"value", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(ttl(b"test"), RespValue::Int(-1));
+        db.do_cmd(1, &[b"PERSIST", b"test", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+
+        db.do_cmd(1, &[b"EXPIRE", b"test", b"100", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        assert_eq!(ttl(b"test"), RespValue::Int(100));
+        db.do_cmd(1, &[b"PERSIST", b"test", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        assert_eq!(ttl(b"test"), RespValue::Int(-1));
+
+        // any type can expire
+        db.do_cmd(1, &[b"SADD", b"set", b"member", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"EXPIRE", b"set", b"20", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        assert_eq!(ttl(b"set"), RespValue::Int(20));
+
+        let invalid: RespValue = CommandError::InvalidValue.into();
+        db.do_cmd(1, &[b"EXPIRE", b"test", b"0", One]);
+        assert_eq!(db.response_resp(1), invalid);
+
+        // expired keys read as missing until the sweep deletes them
+        let stored_expire = || {
+            db.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+            match db.response_resp(1) {
+                RespValue::Data(bytes) => deserialize_cube(&bytes).unwrap().expire(),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+        db.do_cmd(1, &[b"GETEX", b"test", b"PX", b"50", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        assert!(stored_expire().is_some());
+        sleep_ms(60);
+        assert_eq!(ttl(b"test"), RespValue::Int(-2));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert!(db.response_values(1).0.is_empty());
+        for _ in 0..100 {
+            if stored_expire().is_none() {
+                break;
+            }
+            sleep_ms(50);
+        }
+        assert_eq!(stored_expire(), None);
+        assert_eq!(ttl(b"test"), RespValue::Int(-2));
+        assert_eq!(ttl(b"set"), RespValue::Int(20));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let invalid: RespValue = CommandError::InvalidValue.into();
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"RESOLVE", b"test", b"0", One]);
+        assert_eq!(db.response_resp(1), invalid);
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1", b"value2"]);
+
+        db.do_cmd(1, &[b"RESOLVE", b"test", b"2", One]);
+        assert_eq!(db.response_resp(1), invalid);
+        db.do_cmd(1, &[b"RESOLVE", b"test", b"0", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        // missing keys start at 0
+        db.do_cmd(1, &[b"INCR", b"counter", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd(1, &[b"DECR", b"counter", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"DECR", b"counter", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(-1));
+        db.do_cmd(1, &[b"INCRBY", b"counter", b"5", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(4));
+        db.do_cmd(1, &[b"CGET", b"counter", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(4));
+
+        db.do_cmd(1, &[b"SET", b"string", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"INCR", b"string", One]);
+        match db.response_resp(1) {
+            RespValue::Error(ref e) if e.starts_with(b"WRONGTYPE") => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        let invalid_args: RespValue = CommandError::InvalidArgCount.into();
+        db.do_cmd(1, &[b"DECR"]);
+        assert_eq!(db.response_resp(1), invalid_args);
+    }
+
+    #[test]
+    fn test_incr_concurrent() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // both coordinators increment before seeing each other's writes
+        db1.do_cmd(1, &[b"INCR", b"counter", One]);
+        db2.do_cmd(1, &[b"INCR", b"counter", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        assert_eq!(db2.response_resp(1), RespValue::Int(1));
+        sleep_ms(100);
+
+        // neither increment is lost
+        db1.do_cmd(1, &[b"CGET", b"counter", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(2));
+        db2.do_cmd(1, &[b"INCRBY", b"counter", b"3", All]);
+        assert_eq!(db2.response_resp(1), RespValue::Int(5));
+        db1.do_cmd(1, &[b"CGET", b"counter", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(5));
+    }
+
+    #[test]
+    fn test_pncounter_concurrent() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // both coordinators change the counter before seeing each other's writes
+        db1.do_cmd(1, &[b"PNINCR", b"counter", b"5", One]);
+        db2.do_cmd(1, &[b"PNINCR", b"counter", b"-2", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(5));
+        assert_eq!(db2.response_resp(1), RespValue::Int(-2));
+        db1.do_cmd(1, &[b"PNINCR", b"counter", b"-1", One]);
+        db2.do_cmd(1, &[b"PNINCR", b"counter", b"7", One]);
+        db1.response_resp(1);
+        db2.response_resp(1);
+        sleep_ms(100);
+
+        // every replica converges to the sum of all changes
+        for db in &[&db1, &db2] {
+            db.do_cmd(1, &[b"PNGET", b"counter", All]);
+            assert_eq!(db.response_resp(1), RespValue::Int(9));
+        }
+        db2.do_cmd(1, &[b"PNINCR", b"counter", b"1", All]);
+        assert_eq!(db2.response_resp(1), RespValue::Int(10));
+        db1.do_cmd(1, &[b"PNGET", b"counter", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(10));
+        db1.do_cmd(1, &[b"TYPE", b"counter"]);
+        assert_eq!(db1.response_resp(1), RespValue::Data("pncounter".into()));
+
+        // the types don't mix
+        db1.do_cmd(1, &[b"INCR", b"counter", One]);
+        match db1.response_resp(1) {
+            RespValue::Error(ref e) if e.starts_with(b"WRONGTYPE") => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        db1.do_cmd(1, &[b"PNGET", b"missing"]);
+        assert_eq!(db1.response_resp(1), RespValue::Nil);
+        db1.do_cmd(1, &[b"DEL", b"counter"]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        db1.do_cmd(1, &[b"PNGET", b"counter", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Nil);
+    }
+
+    #[test]
+    fn test_set_add_wins() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"SADD", b"set", b"member", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        db1.do_cmd(1, &[b"SADD", b"set", b"other", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+
+        // db2 removes the member it saw while db1 adds it again
+        db2.do_cmd(1, &[b"SREM", b"set", b"member", One]);
+        db1.do_cmd(1, &[b"SADD", b"set", b"member", One]);
+        db2.do_cmd(2, &[b"SREM", b"set", b"other", One]);
+        assert_eq!(db2.response_resp(1), RespValue::Int(1));
+        db1.response_resp(1);
+        assert_eq!(db2.response_resp(2), RespValue::Int(1));
+        sleep_ms(100);
+
+        // the remove only erased the add it observed
+        for db in &[&db1, &db2] {
+            db.do_cmd(1, &[b"SMEMBERS", b"set", All]);
+            assert_eq!(
+                db.response_resp(1),
+                RespValue::Array(vec![RespValue::Data("member".into())])
+            );
+        }
+    }
+
+    #[test]
+    fn test_context_encoding_msgpack() {
+        use msgpack;
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.context_encoding = ContextEncoding::MsgPack,
+        );
+        let get_context = |db: &TestDatabase| -> (usize, Vec<u8>) {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            match db.response_resp(1) {
+                RespValue::Array(mut values) => match values.pop() {
+                    Some(RespValue::Data(d)) => (values.len(), d.to_vec()),
+                    x => panic!("unexpected context {:?}", x),
+                },
+                x => panic!("unexpected response {:?}", x),
+            }
+        };
+
+        let (count, context) = get_context(&db);
+        assert_eq!(count, 0);
+        assert_eq!(msgpack::deserialize_vv(&context).unwrap(), VersionVector::new());
+
+        // two writes without context become siblings
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        db.response_resp(1);
+        let (count, context) = get_context(&db);
+        assert_eq!(count, 2);
+        let vv = msgpack::deserialize_vv(&context).unwrap();
+        assert!(vv.contains(db.dht.node(), 2));
+        assert_eq!(context, msgpack::serialize_vv(&vv));
+
+        // the msgpack context resolves them
+        db.do_cmd(1, &[b"SET", b"test", b"value3", &context, One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(get_context(&db).0, 1);
+        db.do_cmd(1, &[b"SET", b"test", b"value4", b"garbage", One]);
+        let invalid: RespValue = CommandError::InvalidContext.into();
+        assert_eq!(db.response_resp(1), invalid);
+
+        // a bincode context issued before the encoding changed still decodes
+        db.do_cmd(1, &[b"SET", b"test", b"value4", b"", One]);
+        db.response_resp(1);
+        let (count, context) = get_context(&db);
+        assert_eq!(count, 2);
+        let bincode_context = bincode::serialize(&msgpack::deserialize_vv(&context).unwrap());
+        db.do_cmd(1, &[b"SET", b"test", b"value5", &bincode_context.unwrap(), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(get_context(&db).0, 1);
+    }
+
+    #[test]
+    fn test_cas() {
+        use base64;
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let outdated: RespValue = CommandError::ContextOutdated.into();
+        let token = |vv: &VersionVector| base64::encode(&encode_vv(vv)).into_bytes();
+
+        // an empty context only works for a key that never had a value
+        db.do_cmd(1, &[b"CAS", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CAS", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), outdated);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv1) = db.response_values(1);
+        assert_eq!(values, [b"value1"]);
+        db.do_cmd(1, &[b"CAS", b"test", b"value2", &token(&vv1), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv2) = db.response_values(1);
+        assert_eq!(values, [b"value2"]);
+
+        // the key advanced past vv1, the write is rejected instead of becoming a sibling
+        db.do_cmd(1, &[b"CAS", b"test", b"value3", &token(&vv1), One]);
+        assert_eq!(db.response_resp(1), outdated);
+        db.do_cmd(1, &[b"SET", b"test", b"value3", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CAS", b"test", b"value4", &token(&vv2), One]);
+        assert_eq!(db.response_resp(1), outdated);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv3) = db.response_values(1);
+        assert_eq!(values, [b"value2", b"value3"]);
+
+        // a context covering both siblings resolves them
+        db.do_cmd(1, &[b"CAS", b"test", b"value4", &token(&vv3), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value4"]);
+
+        db.do_cmd(1, &[b"CAS", b"test", b"value5", b"garbage", One]);
+        let invalid: RespValue = CommandError::InvalidContext.into();
+        assert_eq!(db.response_resp(1), invalid);
+        db.do_cmd(1, &[b"CSET", b"counter", b"1"]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"CAS", b"counter", b"value", b"", One]);
+        let wrong_type: RespValue = CommandError::TypeError.into();
+        assert_eq!(db.response_resp(1), wrong_type);
+    }
+
+    #[test]
+    fn test_setifeq() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        // a missing key never matches
+        db.do_cmd(1, &[b"SETIFEQ", b"test", b"", b"value1", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert!(db.response_values(1).0.is_empty());
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SETIFEQ", b"test", b"value1", b"value2", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+        db.do_cmd(1, &[b"SETIFEQ", b"test", b"value1", b"value3", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+
+        // siblings have to be resolved first
+        db.do_cmd(1, &[b"SET", b"test", b"value3", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SETIFEQ", b"test", b"value2", b"value4", One]);
+        let siblings: RespValue = CommandError::HasSiblings.into();
+        assert_eq!(db.response_resp(1), siblings);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2", b"value3"]);
+    }
+
+    #[test]
+    fn test_context_encoding_base64() {
+        use base64;
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.context_encoding = ContextEncoding::Base64,
+        );
+        let get_context = |db: &TestDatabase| -> Vec<u8> {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            match db.response_resp(1) {
+                RespValue::Array(mut values) => match values.pop() {
+                    Some(RespValue::Data(d)) => d.to_vec(),
+                    x => panic!("unexpected context {:?}", x),
+                },
+                x => panic!("unexpected response {:?}", x),
+            }
+        };
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        db.response_resp(1);
+        let token = get_context(&db);
+        let vv: VersionVector = bincode::deserialize(&base64::decode(&token).unwrap()).unwrap();
+        assert!(vv.contains(db.dht.node(), 1));
+        assert_eq!(token, base64::encode(&encode_vv(&vv)).into_bytes());
+
+        // the token is accepted back
+        db.do_cmd(1, &[b"CAS", b"test", b"value2", b"not base64!", One]);
+        let invalid: RespValue = CommandError::InvalidContext.into();
+        assert_eq!(db.response_resp(1), invalid);
+        db.do_cmd(1, &[b"CAS", b"test", b"value2", &token, One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CAS", b"test", b"value3", &token, One]);
+        let outdated: RespValue = CommandError::ContextOutdated.into();
+        assert_eq!(db.response_resp(1), outdated);
+    }
+
+    #[test]
+    fn test_reload_clocks() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let vnode = db.dht.key_vnode(b"test");
+        let clocks = |db: &TestDatabase| db.vnodes.read().unwrap()[&vnode].read().unwrap()._clocks();
+
+        for &shutdown in &[true, false] {
+            for i in 0..10 {
+                db.do_cmd(1, &[b"SET", b"test", i.to_string().as_bytes(), b"", One]);
+                db.response_resp(1);
+            }
+            let before = clocks(&db);
+            assert!(before.get(db.dht.node()).is_some());
+
+            // clean shutdowns load the saved clocks, dirty ones also recover from the log
+            db.save(shutdown);
+            drop(db);
+            db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+            assert_eq!(clocks(&db), before);
+        }
+    }
+
+    #[test]
+    fn test_clock_regression() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let new_db = |create: bool| {
+            TestDatabase::with_config(
+                "127.0.0.1:9000".parse().unwrap(),
+                "t/db",
+                create,
+                |config| config.flushall_enabled = true,
+            )
+        };
+        let mut db = new_db(true);
+        let node = db.dht.node();
+        let vnode = db.dht.key_vnode(b"test");
+        // the latest version of the local node seen by the key
+        let version = |db: &TestDatabase| -> u64 {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            let (_, vv) = db.response_values(1);
+            vv.iter()
+                .filter(|&(n, _)| n == node)
+                .map(|(_, v)| v)
+                .max()
+                .unwrap()
+        };
+        let set = |db: &TestDatabase, value: &[u8]| {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            let (_, vv) = db.response_values(1);
+            db.do_cmd(1, &[b"SET", b"test", value, &encode_vv(&vv), One]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        };
+
+        for _ in 0..3 {
+            set(&db, b"old");
+        }
+        db.save(true);
+        let snapshot = db.meta_storage
+            .get(vnode.to_string().as_bytes(), |bytes| bytes.to_vec())
+            .unwrap()
+            .unwrap();
+        for _ in 0..2 {
+            set(&db, b"new");
+        }
+        assert_eq!(version(&db), 5);
+
+        // restore the vnode state saved before the last writes
+        db.save(true);
+        db.meta_storage
+            .set(vnode.to_string().as_bytes(), &snapshot)
+            .unwrap();
+        drop(db);
+        db = new_db(false);
+        assert_eq!(db.dht.node(), node);
+        set(&db, b"restored");
+        assert_eq!(version(&db), 6);
+
+        // import an export of the key into the flushed vnode, its dots
+        // are ahead of the reset clocks
+        let path = "clock.ndjson";
+        db.do_cmd(
+            1,
+            &[b"DEBUG", b"EXPORT", vnode.to_string().as_bytes(), path.as_bytes()],
+        );
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd(1, &[b"FLUSHALL"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"DEBUG", b"IMPORT", path.as_bytes()]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        db.do_cmd(1, &[b"SET", b"test", b"concurrent", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 2);
+        assert_eq!(version(&db), 7);
+    }
+
+    #[test]
+    fn test_node_id_persisted() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let mut db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let node = db.dht.node();
+        assert_eq!(
+            db.meta_storage.get_vec(b"node").unwrap(),
+            Some(node.to_string().into_bytes())
+        );
+        for _ in 0..2 {
+            db.save(true);
+            drop(db);
+            db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", false);
+            assert_eq!(db.dht.node(), node);
+        }
+    }
+
+    #[test]
+    fn test_one() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 0);
+
+        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_values(1).0.len(), 1);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+
+        db.do_cmd(1, &[b"GETSET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_values(1).0.len(), 2);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values, [b"value1", b"value2"]);
+
+        db.do_cmd(1, &[b"GETSET", b"test", b"value12", &encode_vv(&vv), One]);
+        assert_eq!(db.response_values(1).0.len(), 1);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values, [b"value12"]);
+
+        db.do_cmd(1, &[b"DEL", b"test", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 0);
+    }
+
+    #[test]
+    fn test_error_prefixes() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let error_prefix = |resp: RespValue| match resp {
+            RespValue::Error(e) => e.split(|&b| b == b' ').next().unwrap().to_vec(),
+            r => panic!("Unexpected response {:?}", r),
+        };
+
+        db.do_cmd(1, &[b"FOO"]);
+        assert_eq!(error_prefix(db.response_resp(1)), b"ERR");
+
+        db.do_cmd(1, &[b"GET"]);
+        assert_eq!(error_prefix(db.response_resp(1)), b"ERR");
+
+        db.do_cmd(1, &[b"SET", b"test", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"HSET", b"test", b"field", b"value", One]);
+        assert_eq!(error_prefix(db.response_resp(1)), b"WRONGTYPE");
+
+        let render = |error: ServerError| -> RespValue { error.into() };
+        let addr = "127.0.0.1:9001".parse().unwrap();
+        assert_eq!(
+            render(ServerError::Moved(1, addr)),
+            RespValue::Error("MOVED 1 127.0.0.1:9001".into())
+        );
+        assert_eq!(
+            render(CommandError::Unavailable.into()),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        assert_eq!(
+            render(CommandError::Timeout.into()),
+            RespValue::Error("TIMEOUT Request timed out".into())
+        );
+    }
+
+    #[test]
+    fn test_malformed_commands() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.worker_count = 1,
+        );
+        let data = |v: &[u8]| RespValue::Data(v.into());
+        let mut many_args = vec![data(b"GET"), data(b"test")];
+        many_args.extend((0..100).map(|i| data(i.to_string().as_bytes())));
+        let garbage = vec![
+            data(b"GET"),
+            RespValue::Int(1),
+            RespValue::Nil,
+            RespValue::Array(vec![]),
+            RespValue::Array(vec![data(b"GET"), RespValue::Int(1)]),
+            RespValue::Array(vec![RespValue::Array(vec![data(b"GET")]), data(b"test")]),
+            RespValue::Array(vec![data(b"FOO"), data(b"test")]),
+            RespValue::Array(vec![data(b"")]),
+            RespValue::Array(many_args),
+        ];
+
+        // sent through the worker so a panic would take it down
+        let mut sender = db.sender();
+        for (i, cmd) in garbage.into_iter().enumerate() {
+            let mut context = Context::new(i as Token);
+            context.commands.push(cmd);
+            sender.send(WorkerMsg::Command(context));
+            match db.response_resp(i as Token) {
+                RespValue::Error(ref e) if e.starts_with(b"ERR ") => (),
+                r => panic!("Unexpected response {:?} to malformed command {}", r, i),
+            }
+        }
+
+        let mut context = Context::new(1);
+        context.commands.push(RespValue::Array(vec![
+            data(b"SET"),
+            data(b"test"),
+            data(b"value"),
+            data(b""),
+        ]));
+        sender.send(WorkerMsg::Command(context));
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+    }
+    #[test]
+    fn test_scan_type() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for i in 0..10 {
+            let key = |ty: &str| format!("{}{}", ty, i).into_bytes();
+            db.do_cmd(1, &[b"SET", &key("string"), b"value", b"", One]);
+            db.response_resp(1);
+            db.do_cmd(1, &[b"INCRBY", &key("counter"), b"1", One]);
+            db.response_resp(1);
+            db.do_cmd(1, &[b"HSET", &key("hash"), b"field", b"value", One]);
+            db.response_resp(1);
+            db.do_cmd(1, &[b"SADD", &key("set"), b"member", One]);
+            db.response_resp(1);
+        }
+        // deleted keys are skipped
+        db.do_cmd(1, &[b"DEL", b"counter0", b"", One]);
+        db.response_resp(1);
+
+        let scan = |args: &[&[u8]]| {
+            let mut keys = Vec::new();
+            let mut cursor = b"0".to_vec();
+            loop {
+                {
+                    let mut cmd = vec![&b"SCAN"[..], &cursor[..]];
+                    cmd.extend_from_slice(args);
+                    db.do_cmd(1, &cmd);
+                }
+                let (next, page) = match db.response_resp(1) {
+                    RespValue::Array(mut r) => match (r.pop(), r.pop()) {
+                        (Some(RespValue::Array(page)), Some(RespValue::Data(next))) => {
+                            (next.to_vec(), page)
+                        }
+                        r => panic!("Unexpected response {:?}", r),
+                    },
+                    r => panic!("Unexpected response {:?}", r),
+                };
+                keys.extend(page.into_iter().map(|k| match k {
+                    RespValue::Data(k) => String::from_utf8(k.to_vec()).unwrap(),
+                    r => panic!("Unexpected key {:?}", r),
+                }));
+                if next == b"0" {
+                    break;
+                }
+                cursor = next;
+            }
+            keys.sort();
+            keys
+        };
+
+        let expected: Vec<_> = (1..10).map(|i| format!("counter{}", i)).collect();
+        assert_eq!(scan(&[b"TYPE", b"counter"]), expected);
+        assert_eq!(scan(&[b"TYPE", b"counter", b"COUNT", b"1000"]), expected);
+        assert_eq!(scan(&[b"TYPE", b"set"]).len(), 10);
+        assert_eq!(scan(&[b"TYPE", b"none"]).len(), 0);
+        assert_eq!(scan(&[b"COUNT", b"1"]).len(), 39);
+        // a scan returning nothing would never advance
+        db.do_cmd(1, &[b"SCAN", b"0", b"COUNT", b"0"]);
+        let invalid: RespValue = CommandError::InvalidIntValue.into();
+        assert_eq!(db.response_resp(1), invalid);
+    }
+
+    #[test]
+    fn test_scan_cursor() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        for i in 0..100 {
+            db.do_cmd(1, &[b"SET", format!("key{}", i).as_bytes(), b"value", b"", One]);
+            db.response_resp(1);
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor = ScanCursor::default();
+        let mut pages = 0;
+        loop {
+            let (next, page) = db.scan(&cursor, 7, None);
+            pages += 1;
+            keys.extend(page.iter().map(|k| String::from_utf8(k.to_vec()).unwrap()));
+            if next == ScanCursor::default() {
+                assert!(page.len() <= 7);
+                break;
+            }
+            assert_eq!(page.len(), 7);
+            // the cursor points past the last key, resuming from it is stable
+            assert!(next.vnode < db.dht.partitions() as u32);
+            assert_eq!(next.after.as_ref(), page.last());
+            assert_eq!(ScanCursor::parse(&next.encode()).unwrap(), next);
+            assert_eq!(db.scan(&next, 7, None), db.scan(&next, 7, None));
+            // deleting the keys already returned doesn't shift the rest
+            for key in &page {
+                db.do_cmd(1, &[b"DEL", &key[..], b"", One]);
+                db.response_resp(1);
+            }
+            cursor = next;
+        }
+        assert!(pages >= 100 / 7);
+        keys.sort();
+        let mut expected: Vec<_> = (0..100).map(|i| format!("key{}", i)).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        // a cursor past the last vnode completes the scan
+        let past = ScanCursor::parse(db.dht.partitions().to_string().as_bytes()).unwrap();
+        assert_eq!(db.scan(&past, 7, None), (Default::default(), vec![]));
+        for invalid in &[&b"x"[..], b"1:x", b"1:abc"] {
+            assert!(ScanCursor::parse(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let assert_wrong_type = |resp: RespValue| match resp {
+            RespValue::Error(ref e) if e.starts_with(b"WRONGTYPE") => (),
+            r => panic!("Unexpected response {:?}", r),
+        };
+
+        db.do_cmd(1, &[b"SET", b"string", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SADD", b"set", b"member", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"HSET", b"hash", b"field", b"value", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"INCRBY", b"counter", b"1", One]);
+        db.response_resp(1);
+
+        let cmds: &[&[&[u8]]] = &[
+            &[b"SADD", b"string", b"member", One],
+            &[b"HSET", b"string", b"field", b"value", One],
+            &[b"INCRBY", b"string", b"1", One],
+            &[b"GET", b"set", One],
+            &[b"SET", b"set", b"value", b"", One],
+            &[b"HGETALL", b"set", One],
+            &[b"SMEMBERS", b"hash", One],
+            &[b"SREM", b"hash", b"field", One],
+            &[b"CGET", b"hash", One],
+            &[b"HDEL", b"counter", b"field", One],
+        ];
+        for cmd in cmds {
+            db.do_cmd(1, cmd);
+            assert_wrong_type(db.response_resp(1));
+        }
+
+        // nothing was overwritten
+        db.do_cmd(1, &[b"GET", b"string", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+        db.do_cmd(1, &[b"SMEMBERS", b"set", One]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![RespValue::Data("member".into())])
+        );
+        db.do_cmd(1, &[b"TYPE", b"hash", One]);
+        assert_eq!(db.response_resp(1), RespValue::Data("hash".into()));
+        db.do_cmd(1, &[b"CGET", b"counter", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+    }
+
+    #[test]
+    fn test_del_count() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"{k}:string", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SADD", b"{k}:set", b"member", One]);
+        db.response_resp(1);
+
+        db.do_cmd(1, &[b"DEL", b"{k}:string", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        // the tombstone is stored but the key is gone
+        db.do_cmd(1, &[b"DEL", b"{k}:string", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"DEL", b"{k}:missing", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+
+        // a batch replies the count of each key
+        db.do_cmd(1, &[b"SET", b"{k}:string", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"MULTI"]);
+        db.response_resp(1);
+        db.do_multi(
+            1,
+            &[
+                &[b"DEL", b"{k}:string", b""],
+                &[b"DEL", b"{k}:set", b""],
+                &[b"DEL", b"{k}:missing", b""],
+            ],
+            One,
+        );
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![RespValue::Int(1), RespValue::Int(1), RespValue::Int(0)])
+        );
+        db.do_cmd(1, &[b"SMEMBERS", b"{k}:set", One]);
+        assert_eq!(db.response_resp(1), RespValue::Array(vec![]));
+    }
+
+    #[test]
+    fn test_exists() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"string", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SADD", b"set", b"member", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"SET", b"deleted", b"value", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"DEL", b"deleted", b"", One]);
+        db.response_resp(1);
+
+        db.do_cmd(1, &[b"EXISTS", b"string"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        db.do_cmd(1, &[b"EXISTS", b"missing"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        db.do_cmd(1, &[b"EXISTS", b"deleted"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(0));
+        // the keys don't need to share a vnode
+        db.do_cmd(1, &[b"EXISTS", b"string", b"set", b"missing", b"deleted"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(2));
+        // repeated keys count each time
+        db.do_cmd(1, &[b"EXISTS", b"string", b"string", b"set"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(3));
+        // keys of the same vnode are read together, without a limit on their number
+        db.do_cmd(1, &[b"SET", b"{tag}:a", b"value", b"", One]);
+        db.response_resp(1);
+        let mut keys: Vec<&[u8]> = vec![b"EXISTS", b"{tag}:a", b"{tag}:b"];
+        keys.extend((0..200).map(|_| &b"string"[..]));
+        db.do_cmd(1, &keys);
+        assert_eq!(db.response_resp(1), RespValue::Int(201));
+        db.do_cmd(1, &[b"EXISTS"]);
+        let invalid_args: RespValue = CommandError::InvalidArgCount.into();
+        assert_eq!(db.response_resp(1), invalid_args);
+    }
+
+    #[test]
+    fn test_multi_exec() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let ok = || RespValue::Status("OK".into());
+        assert_eq!(db.dht.key_vnode(b"{user1}:a"), db.dht.key_vnode(b"{user1}:b"));
+
+        db.do_cmd(1, &[b"MULTI"]);
+        assert_eq!(db.response_resp(1), ok());
+        db.do_multi(
+            1,
+            &[
+                &[b"SET", b"{user1}:a", b"1", b""],
+                &[b"SET", b"{user1}:b", b"2", b""],
+            ],
+            One,
+        );
+        assert_eq!(db.response_resp(1), RespValue::Array(vec![ok(), ok()]));
+        db.do_cmd(1, &[b"GET", b"{user1}:a", One]);
+        assert_eq!(db.response_values(1).0, [b"1"]);
+        db.do_cmd(1, &[b"GET", b"{user1}:b", One]);
+        assert_eq!(db.response_values(1).0, [b"2"]);
+
+        // a failing command aborts the whole batch
+        db.do_multi(
+            1,
+            &[
+                &[b"SET", b"{user1}:a", b"3", b""],
+                &[b"SADD", b"{user1}:b", b"3"],
+            ],
+            One,
+        );
+        match db.response_resp(1) {
+            RespValue::Error(ref e) if e.starts_with(b"WRONGTYPE") => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        db.do_cmd(1, &[b"GET", b"{user1}:a", One]);
+        assert_eq!(db.response_values(1).0, [b"1"]);
+
+        // keys in different vnodes can't be in the same batch
+        let other = (0..)
+            .map(|i: u32| format!("{{user{}}}:a", i).into_bytes())
+            .find(|k| db.dht.key_vnode(k) != db.dht.key_vnode(b"{user1}:a"))
+            .unwrap();
+        db.do_multi(
+            1,
+            &[&[b"SET", b"{user1}:a", b"4", b""], &[b"SET", &other, b"4", b""]],
+            One,
+        );
+        match db.response_resp(1) {
+            RespValue::Error(ref e) if e.starts_with(b"CROSSSLOT") => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        db.do_cmd(1, &[b"GET", b"{user1}:a", One]);
+        assert_eq!(db.response_values(1).0, [b"1"]);
+        db.do_cmd(1, &[b"GET", &other, One]);
+        assert!(db.response_values(1).0.is_empty());
+    }
+
+    #[test]
+    fn test_config_reload() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        fs::create_dir_all("t/").unwrap();
+        let config_file = "t/sucredb.yaml";
+        fs::write(config_file, "request_timeout: \"1000ms\"\n").unwrap();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.config_file = Some(config_file.into()),
+        );
+        let config_get = |param: &[u8]| {
+            db.do_cmd(1, &[b"CONFIG", b"GET", param]);
+            db.response_resp(1)
+        };
+        let data = |v: &str| RespValue::Data(v.as_bytes().into());
+
+        assert_eq!(
+            config_get(b"request_timeout"),
+            RespValue::Array(vec![data("request_timeout"), data("1000")])
+        );
+
+        fs::write(
+            config_file,
+            "request_timeout: \"2000ms\"\nworker_count: 2\nworker_background_count: 1\n",
+        ).unwrap();
+        db.do_cmd(1, &[b"CONFIG", b"RELOAD"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Array(vec![
+                RespValue::Array(vec![data("worker_count"), data("applied")]),
+                RespValue::Array(vec![data("request_timeout"), data("applied")]),
+                RespValue::Array(vec![data("worker_background_count"), data("restart required")]),
+            ])
+        );
+
+        assert_eq!(
+            config_get(b"request_timeout"),
+            RespValue::Array(vec![data("request_timeout"), data("2000")])
+        );
+        assert_eq!(
+            config_get(b"worker_count"),
+            RespValue::Array(vec![data("worker_count"), data("2")])
+        );
+        // left unchanged until a restart
+        assert_ne!(
+            config_get(b"worker_background_count"),
+            RespValue::Array(vec![data("worker_background_count"), data("1")])
+        );
+
+        // the reply tells what's wrong with the file, which isn't applied
+        fs::write(
+            config_file,
+            "request_timeout: \"3000ms\"\nsync_msg_inflight: many\n",
+        ).unwrap();
+        db.do_cmd(1, &[b"CONFIG", b"RELOAD"]);
+        match db.response_resp(1) {
+            RespValue::Error(e) => assert!(e.starts_with(b"ERR Invalid sync_msg_inflight")),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        assert_eq!(
+            config_get(b"request_timeout"),
+            RespValue::Array(vec![data("request_timeout"), data("2000")])
+        );
+    }
+
+    #[test]
+    fn test_config_set_quorum() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let config_set = |param: &[u8], value: &[u8]| {
+            db1.do_cmd(1, &[b"CONFIG", b"SET", param, value]);
+            db1.response_resp(1)
+        };
+        let config_get = |param: &[u8]| {
+            db1.do_cmd(1, &[b"CONFIG", b"GET", param]);
+            match db1.response_resp(1) {
+                RespValue::Array(mut kv) => match kv.pop() {
+                    Some(RespValue::Data(v)) => v,
+                    r => panic!("Unexpected value {:?}", r),
+                },
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+        let ok = RespValue::Status("OK".into());
+        let invalid: RespValue = CommandError::InvalidConsistencyValue.into();
+
+        // more acks than the 3 replicas, or none at all
+        assert_eq!(config_set(b"write-quorum", b"4"), invalid);
+        assert_eq!(config_set(b"write-quorum", b"0"), invalid);
+        assert_eq!(config_get(b"write-quorum"), &b"1"[..]);
+
+        assert_eq!(config_set(b"write-quorum", b"3"), ok);
+        assert_eq!(config_get(b"write-quorum"), &b"3"[..]);
+        assert_eq!(config_get(b"consistency_write"), &b"All"[..]);
+        assert_eq!(config_set(b"read-quorum", b"quorum"), ok);
+        assert_eq!(config_get(b"read-quorum"), &b"2"[..]);
+
+        drop(db2);
+        sleep_ms(200);
+
+        // the defaults apply to the next requests
+        db1.do_cmd(1, &[b"SET", b"key", b"value", b""]);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        db1.do_cmd(1, &[b"GET", b"key"]);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        assert_eq!(config_set(b"write-quorum", b"1"), ok);
+        db1.do_cmd(1, &[b"SET", b"key", b"value", b""]);
+        assert_eq!(db1.response_resp(1), ok);
+        db1.do_cmd(1, &[b"GET", b"key", One]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_down_node_unavailable() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let config_fn = |config: &mut config::Config| {
+            config.worker_timer = 50;
+            config.dht_heartbeat_interval = 50;
+            config.dht_failure_timeout = 500;
+        };
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            config_fn,
+        );
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            config_fn,
+        );
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        // without ticks db2 stops heartbeating, but it's still connected and serving
+        db2.do_cmd(1, &[b"CONFIG", b"SET", b"worker_timer", b"1h"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        let node2 = db2.dht.node();
+        for _ in 0..100 {
+            if !db1.dht.is_node_up(node2) {
+                break;
+            }
+            sleep_ms(50);
+        }
+        assert!(!db1.dht.is_node_up(node2));
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        db1.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+
+        // a heartbeat brings it back
+        db2.dht.handler_tick(time::Instant::now());
+        for _ in 0..100 {
+            if db1.dht.is_node_up(node2) {
+                break;
+            }
+            sleep_ms(50);
+        }
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_config_set() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let config_set = |param: &[u8], value: &[u8]| {
+            db.do_cmd(1, &[b"CONFIG", b"SET", param, value]);
+            db.response_resp(1)
+        };
+        let config_get = |param: &[u8]| {
+            db.do_cmd(1, &[b"CONFIG", b"GET", param]);
+            match db.response_resp(1) {
+                RespValue::Array(mut kv) => match kv.pop() {
+                    Some(RespValue::Data(v)) => v,
+                    r => panic!("Unexpected value {:?}", r),
+                },
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+        let ok = RespValue::Status("OK".into());
+        let invalid: RespValue = CommandError::InvalidValue.into();
+
+        assert_eq!(config_set(b"worker_timer", b"50ms"), ok);
+        assert_eq!(config_get(b"worker_timer"), &b"50"[..]);
+        assert_eq!(config_set(b"sync_msg_inflight", b"5"), ok);
+        assert_eq!(config_get(b"sync_msg_inflight"), &b"5"[..]);
+        assert_eq!(config_set(b"consistency_read", b"quorum"), ok);
+        assert_eq!(config_get(b"consistency_read"), &b"Quorum"[..]);
+        assert_eq!(db.quorum(false), 2);
+
+        // unknown parameters and invalid values
+        assert_eq!(config_set(b"foo", b"1"), invalid);
+        assert_eq!(config_set(b"sync_msg_inflight", b"many"), invalid);
+        assert_eq!(config_set(b"worker_timer", b"50"), invalid);
+        assert_eq!(config_get(b"sync_msg_inflight"), &b"5"[..]);
+        let restart: RespValue = CommandError::RestartRequired.into();
+        assert_eq!(config_set(b"worker_background_count", b"3"), restart);
+        assert_ne!(config_get(b"worker_background_count"), &b"3"[..]);
+        // the workers are resized right away
+        assert_eq!(config_set(b"worker_count", b"0"), invalid);
+        assert_eq!(config_set(b"worker_count", b"2"), ok);
+        assert_eq!(config_get(b"worker_count"), &b"2"[..]);
+        // the ring layout can't change at all
+        let immutable = RespValue::Error("ERR parameter is immutable at runtime".into());
+        assert_eq!(config_set(b"partitions", b"128"), immutable);
+        assert_eq!(config_get(b"partitions"), PARTITIONS.to_string().as_bytes());
+        assert_eq!(config_set(b"replication_factor", b"1"), immutable);
+        assert_eq!(config_set(b"sync_msg_inflight", b"6"), ok);
+        assert_eq!(config_get(b"sync_msg_inflight"), &b"6"[..]);
+
+        // unsupported subcommands don't take down the worker
+        db.do_cmd(1, &[b"CONFIG", b"FOO"]);
+        assert_eq!(db.response_resp(1), RespValue::Array(vec![]));
+        db.do_cmd(1, &[b"CONFIG"]);
+        assert_eq!(db.response_resp(1), RespValue::Array(vec![]));
+        assert_eq!(config_get(b"worker_timer"), &b"50"[..]);
+        // the ticks follow the new interval once the pending one elapses
+        sleep_ms(700);
+        assert!(db.is_live());
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.response_size_max = 1024,
+        );
+        let value = [b'a'; 100];
+        db.do_cmd(1, &[b"SET", b"small", &value, b"", One]);
+        db.response_resp(1);
+        // writes without context become siblings
+        for _ in 0..20 {
+            db.do_cmd(1, &[b"SET", b"test", &value, b"", One]);
+            db.response_resp(1);
+        }
+
+        db.do_cmd(1, &[b"GET", b"small", One]);
+        assert_eq!(db.response_values(1).0.len(), 1);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        match db.response_resp(1) {
+            RespValue::Error(e) => assert!(e.starts_with(b"ERR value context too large")),
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_reload_vnode() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        // find two keys living in different vnodes
+        let other = (0..)
+            .map(|i: u32| format!("key{}", i).into_bytes())
+            .find(|k| db.dht.key_vnode(k) != db.dht.key_vnode(b"key"))
+            .unwrap();
+        let keys: &[&[u8]] = &[b"key", &other];
+        for &key in keys {
+            db.do_cmd(1, &[b"SET", key, key, b"", One]);
+            db.response_resp(1);
+        }
+
+        let vnode = db.dht.key_vnode(keys[0]);
+        let log_len = |db: &TestDatabase| {
+            db.vnodes.read().unwrap()[&vnode]
+                .write()
+                .unwrap()
+                ._log_len(db.dht.node())
+        };
+        let log_len_before = log_len(&db);
+        db.do_cmd(1, &[b"DEBUG", b"RELOAD", b"VNODE", vnode.to_string().as_bytes()]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(log_len(&db), log_len_before);
+
+        for &key in keys {
+            db.do_cmd(1, &[b"GET", key, One]);
+            assert_eq!(db.response_values(1).0, [key]);
+        }
+    }
+
+    #[test]
+    fn test_vnode_stats() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let vnode = db.dht.key_vnode(b"test").to_string();
+
+        let stats = |db: &TestDatabase| -> HashMap<Vec<u8>, i64> {
+            db.do_cmd(1, &[b"DEBUG", b"VNODE", b"STATS", vnode.as_bytes()]);
+            match db.response_resp(1) {
+                RespValue::Array(fields) => fields
+                    .chunks(2)
+                    .map(|kv| match (&kv[0], &kv[1]) {
+                        (&RespValue::Data(ref k), &RespValue::Int(v)) => (k.to_vec(), v),
+                        _ => panic!("Can't decode stats {:?}", kv),
+                    })
+                    .collect(),
+                r => panic!("Can't decode stats {:?}", r),
+            }
+        };
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let before = stats(&db);
+        assert!(before[&b"bytes_written"[..]] > 0);
+        assert_eq!(before[&b"live_bytes"[..]], before[&b"bytes_written"[..]]);
+        assert_eq!(before[&b"dead_bytes"[..]], 0);
+
+        for _ in 0..10 {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            let (_, vv) = db.response_values(1);
+            db.do_cmd(1, &[b"SET", b"test", b"value2", &encode_vv(&vv), One]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+        let overwritten = stats(&db);
+        assert!(overwritten[&b"bytes_written"[..]] > before[&b"bytes_written"[..]]);
+        assert!(overwritten[&b"dead_bytes"[..]] > 0);
+        assert_eq!(overwritten[&b"compactions"[..]], 0);
+
+        db.do_cmd(1, &[b"DEBUG", b"VNODE", b"COMPACT", vnode.as_bytes()]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let compacted = stats(&db);
+        assert_eq!(compacted[&b"dead_bytes"[..]], 0);
+        assert_eq!(compacted[&b"compactions"[..]], 1);
+        assert_eq!(
+            compacted[&b"bytes_reclaimed"[..]],
+            overwritten[&b"dead_bytes"[..]]
+        );
+        assert_eq!(
+            compacted[&b"live_bytes"[..]],
+            overwritten[&b"live_bytes"[..]]
+        );
+    }
+
+    #[test]
+    fn test_debug_compact() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let keys: Vec<_> = (0..10).map(|i| i.to_string()).collect();
+        for round in 0..5 {
+            for (i, key) in keys.iter().enumerate() {
+                db.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+                let (_, vv) = db.response_values(1);
+                let value = format!("{}:{}", key, round);
+                db.do_cmd(
+                    1,
+                    &[b"SET", key.as_bytes(), value.as_bytes(), &encode_vv(&vv), One],
+                );
+                assert_eq!(db.response_resp(1), RespValue::Status("OK".into()), "{}", i);
+            }
+        }
+        let vnodes: Vec<_> = keys.iter().map(|k| db.dht.key_vnode(k.as_bytes())).collect();
+        let dead_bytes = |db: &TestDatabase| -> u64 {
+            vnodes
+                .iter()
+                .map(|&vn| db.vnode_stats(vn).unwrap().dead_bytes)
+                .sum()
+        };
+        assert!(dead_bytes(&db) > 0);
+
+        // a single vnode
+        let vnode = vnodes[0];
+        db.do_cmd(
+            1,
+            &[b"DEBUG", b"VNODE", b"COMPACT", vnode.to_string().as_bytes()],
+        );
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(db.vnode_stats(vnode).unwrap().dead_bytes, 0);
+        assert_eq!(db.vnode_stats(vnode).unwrap().compactions, 1);
+
+        // all of them
+        db.do_cmd(1, &[b"DEBUG", b"COMPACT"]);
+        assert_eq!(db.response_resp(1), RespValue::Int(PARTITIONS as _));
+        assert_eq!(dead_bytes(&db), 0);
+        for &vn in &vnodes {
+            assert!(db.vnode_stats(vn).unwrap().bytes_reclaimed > 0);
+        }
+        for key in &keys {
+            db.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+            assert_eq!(db.response_values(1).0, [format!("{}:4", key).as_bytes()]);
+        }
+
+        db.do_cmd(1, &[b"DEBUG", b"COMPACT", b"1"]);
+        let invalid_args: RespValue = CommandError::InvalidArgCount.into();
+        assert_eq!(db.response_resp(1), invalid_args);
+    }
+
+    #[test]
+    fn test_siblings_created() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let vnode = db1.dht.key_vnode(b"test").to_string();
+        let siblings_created = |db: &TestDatabase| -> i64 {
+            db.do_cmd(1, &[b"DEBUG", b"VNODE", b"STATS", vnode.as_bytes()]);
+            match db.response_resp(1) {
+                RespValue::Array(fields) => match fields.get(13) {
+                    Some(&RespValue::Int(v)) => {
+                        assert_eq!(fields[12], RespValue::Data("siblings_created".into()));
+                        v
+                    }
+                    r => panic!("Can't decode stats {:?}", r),
+                },
+                r => panic!("Can't decode stats {:?}", r),
+            }
+        };
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(siblings_created(&db1) + siblings_created(&db2), 0);
+
+        // without the context the write is concurrent to value1
+        db2.do_cmd(1, &[b"SET", b"test", b"value2", b"", All]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        let (values, vv) = db1.response_values(1);
+        assert_eq!(values.len(), 2);
+        assert_eq!(siblings_created(&db1) + siblings_created(&db2), 1);
+
+        // resolving the siblings doesn't count
+        db1.do_cmd(1, &[b"SET", b"test", b"value3", &encode_vv(&vv), All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(siblings_created(&db1) + siblings_created(&db2), 1);
+    }
+
+    #[test]
+    fn test_key_dump() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let (n1, n2) = (db1.dht.node(), db2.dht.node());
+        let dump = |key: &[u8]| -> serde_json::Value {
+            db1.do_cmd(1, &[b"DEBUG", b"KEYDUMP", key]);
+            match db1.response_resp(1) {
+                RespValue::Data(json) => serde_json::from_slice(&json).unwrap(),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+        let dots = |dump: &serde_json::Value, i: usize| -> Vec<(u64, u64)> {
+            serde_json::from_value(dump["cube"]["values"][i]["dots"].clone()).unwrap()
+        };
+
+        let missing = dump(b"test");
+        assert_eq!(missing["cube"]["type"].as_str(), Some("none"));
+        assert!(missing["size"].is_null());
+
+        // concurrent writes coordinated by each node
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db2.do_cmd(1, &[b"SET", b"test", b"value2", b"", All]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"EXPIRE", b"test", b"100", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+
+        let key_dump = dump(b"test");
+        assert_eq!(key_dump["key"].as_str(), Some("test"));
+        let vnode = db1.dht.key_vnode(b"test") as u64;
+        assert_eq!(key_dump["vnode"].as_u64(), Some(vnode));
+        assert_eq!(key_dump["cube"]["type"].as_str(), Some("string"));
+        assert!(key_dump["size"].as_u64().unwrap() > 0);
+        assert!(key_dump["ttl_ms"].as_u64().unwrap() > 99_000);
+        let values = key_dump["cube"]["values"].as_array().unwrap();
+        assert_eq!(values.len(), 2);
+        let mut found: Vec<_> = (0..2)
+            .map(|i| (dots(&key_dump, i), values[i]["value"].as_str().unwrap().to_owned()))
+            .collect();
+        found.sort();
+        let mut expected = vec![
+            (vec![(n1, 1)], "value1".to_owned()),
+            (vec![(n2, 1)], "value2".to_owned()),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+        // the expire doesn't add a value, the vv only has both writes
+        let mut vv: Vec<(u64, u64)> =
+            serde_json::from_value(key_dump["cube"]["vv"].clone()).unwrap();
+        vv.sort();
+        let mut expected_vv = vec![(n1, 1), (n2, 1)];
+        expected_vv.sort();
+        assert_eq!(vv, expected_vv);
+    }
+
+    #[test]
+    fn test_health() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        db1.do_cmd(1, &[b"HEALTH", b"READY"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        // db2 can't bootstrap while db1 refuses to send
+        db1.config.write().unwrap().sync_outgoing_max = 0;
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        while db2.bootstrapping_vnodes() == 0 {
+            sleep_ms(10);
+        }
+        db2.do_cmd(1, &[b"HEALTH", b"LIVE"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db2.do_cmd(1, &[b"HEALTH", b"READY"]);
+        assert_eq!(db2.response_resp(1), RespValue::Error("ERR NotReady".into()));
+        db2.do_cmd(1, &[b"HEALTH"]);
+        match db2.response_resp(1) {
+            RespValue::Array(ref fields) => {
+                assert_eq!(fields[1], RespValue::Int(1));
+                assert_eq!(fields[3], RespValue::Int(0));
+                assert_eq!(fields[6], RespValue::Data("bootstrapping_vnodes".into()));
+                assert!(fields[7] != RespValue::Int(0));
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        // ready once the migrations finish
+        db1.config.write().unwrap().sync_outgoing_max = 100;
+        db1.wait_syncs();
+        db2.wait_syncs();
+        assert_eq!(db2.bootstrapping_vnodes(), 0);
+        db2.do_cmd(1, &[b"HEALTH", b"READY"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_info() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let info = |db: &TestDatabase, args: &[&[u8]]| -> String {
+            db.do_cmd(1, args);
+            match db.response_resp(1) {
+                RespValue::Data(d) => String::from_utf8(d.to_vec()).unwrap(),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        };
+
+        let all = info(&db1, &[b"INFO"]);
+        assert!(all.starts_with("# Health\r\nhealth_live:1\r\n"));
+        assert!(all.contains("\r\n# Fabric\r\nfabric_dropped:"));
+        assert!(all.contains(&format!("fabric_queued_{}:", db2.dht.node())));
+        assert_eq!(info(&db1, &[b"INFO", b"ALL"]), all);
+
+        let fabric = info(&db1, &[b"INFO", b"fabric"]);
+        assert!(fabric.starts_with("# Fabric\r\n"));
+        assert!(!fabric.contains("health_"));
+        assert_eq!(info(&db1, &[b"INFO", b"unknown"]), "");
+    }
+
+    #[test]
+    fn test_bootstrap_read_policy() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+
+        // db2 can't bootstrap while db1 refuses to send
+        db1.config.write().unwrap().sync_outgoing_max = 0;
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        while db2.bootstrapping_vnodes() == 0 {
+            sleep_ms(10);
+        }
+        let key = (0..TEST_JOIN_SIZE)
+            .map(|i| i.to_string())
+            .find(|k| {
+                let vnodes = db2.vnodes.read().unwrap();
+                let vnode = db2.dht.key_vnode(k.as_bytes());
+                let bootstrapping = vnodes
+                    .get(&vnode)
+                    .map_or(false, |vn| vn.read().unwrap().status() == VNodeStatus::Bootstrap);
+                bootstrapping
+            })
+            .unwrap();
+
+        // redirect, the default, and serve_if_present without the key locally
+        for &policy in &[
+            BootstrapReadPolicy::Redirect,
+            BootstrapReadPolicy::ServeIfPresent,
+        ] {
+            db2.config.write().unwrap().bootstrap_read_policy = policy;
+            db2.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+            assert_eq!(db2.response_values(1).0, [b"value"]);
+        }
+
+        db2.config.write().unwrap().bootstrap_read_policy = BootstrapReadPolicy::Error;
+        db2.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+        let try_again: RespValue = CommandError::TryAgain.into();
+        assert_eq!(db2.response_resp(1), try_again);
+
+        db1.config.write().unwrap().sync_outgoing_max = 100;
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db2.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_lazy_absent_vnodes() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let partitions = 512;
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| {
+                config.cmd_init = Some(config::InitCommand {
+                    replication_factor: 1,
+                    partitions: partitions as _,
+                })
+            },
+        );
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(200);
+        assert_eq!(db1.vnodes.read().unwrap().len(), partitions);
+        // db2 doesn't own any vnode yet
+        assert_eq!(db2.vnodes.read().unwrap().len(), 0);
+
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let owned = |db: &TestDatabase| {
+            let (ready, pending) = db.dht.vnodes_for_node(db.dht.node());
+            ready.len() + pending.len()
+        };
+        assert!(owned(&db2) > 0 && owned(&db2) < partitions);
+        assert_eq!(db2.vnodes.read().unwrap().len(), owned(&db2));
+
+        // only the owned ones are created on restart
+        drop(db2);
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        assert_eq!(db2.vnodes.read().unwrap().len(), owned(&db2));
+
+        // others are created on demand
+        let key = (0..)
+            .map(|i: u64| i.to_string())
+            .find(|k| {
+                let vnode = db2.dht.key_vnode(k.as_bytes());
+                !db2.vnodes.read().unwrap().contains_key(&vnode)
+            })
+            .unwrap();
+        db2.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+        assert_eq!(db2.response_values(1).0.len(), 0);
+        assert_eq!(db2.vnodes.read().unwrap().len(), owned(&db2) + 1);
+    }
+
+    #[test]
+    fn test_cluster_standby() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        db2.do_cmd(1, &[b"CLUSTER", b"STANDBY"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("ready".into()));
+        // writes are redirected, reads and replicated writes still work
+        db2.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        match db2.response_resp(1) {
+            RespValue::Error(ref e) if e.starts_with(b"ASK ") => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        db2.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(1).0, [b"value1"]);
+        db1.do_cmd(1, &[b"SET", b"test2", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        // restart it, writes made meanwhile are caught up by the syncs
+        drop(db2);
+        db1.do_cmd(1, &[b"SET", b"test3", b"value", b"", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(200); // wait for fabric to reconnect
+        db2.force_syncs();
+        for &key in &[&b"test"[..], b"test2", b"test3"] {
+            db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", key]);
+            assert!(db2.response_resp(1) != RespValue::Nil);
+        }
+        db2.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+
+        db1.do_cmd(1, &[b"CLUSTER", b"STANDBY"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("ready".into()));
+        db1.do_cmd(1, &[b"CLUSTER", b"STANDBY", b"OFF"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"SET", b"test", b"value3", b"", One]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_cluster_retire() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let node1 = db1.dht.node();
+        let id1 = node1.to_string();
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        // not itself or a node that is still around
+        let invalid = RespValue::Error("ERR InvalidValue".into());
+        db1.do_cmd(1, &[b"CLUSTER", b"RETIRE", id1.as_bytes()]);
+        assert_eq!(db1.response_resp(1), invalid);
+        db2.do_cmd(1, &[b"CLUSTER", b"RETIRE", id1.as_bytes()]);
+        assert_eq!(db2.response_resp(1), invalid);
+        db2.do_cmd(1, &[b"CLUSTER", b"RETIRE", b"12345"]);
+        assert_eq!(db2.response_resp(1), invalid);
+
+        drop(db1);
+        sleep_ms(200);
+        db2.do_cmd(1, &[b"CLUSTER", b"RETIRE", id1.as_bytes()]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        assert!(!db2.dht.members().contains_key(&node1));
+
+        // the versions of the retired node are kept, its writes are superseded as usual
+        db2.do_cmd(1, &[b"GET", b"test", All]);
+        let (values, vv) = db2.response_values(1);
+        assert_eq!(values, [b"value1"]);
+        db2.do_cmd(1, &[b"SET", b"test", b"value2", &encode_vv(&vv), All]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db2.do_cmd(1, &[b"GET", b"test", All]);
+        assert_eq!(db2.response_values(1).0, [b"value2"]);
+        let vnode = db2.dht.key_vnode(b"test");
+        let clocks = db2.vnodes.read().unwrap()[&vnode].read().unwrap()._clocks();
+        assert!(clocks.get(node1).is_some());
+    }
+
+    #[test]
+    fn test_debug_valuestats() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let small = vec![b'a'; 100];
+        let medium = vec![b'b'; 2000];
+        let large = vec![b'c'; 20000];
+        for (key, value) in vec![
+            (&b"{v}small"[..], &small[..]),
+            (&b"{v}medium"[..], &medium[..]),
+            (&b"{v}medium2"[..], &medium[..]),
+            (&b"{v}large"[..], &large[..]),
+        ] {
+            db.do_cmd(1, &[b"SET", key, value, b"", One]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+
+        let vnode = db.dht.key_vnode(b"{v}").to_string();
+        db.do_cmd(1, &[b"DEBUG", b"VALUESTATS", vnode.as_bytes()]);
+        let fields = match db.response_resp(1) {
+            RespValue::Array(fields) => fields,
+            r => panic!("Can't decode value stats {:?}", r),
+        };
+        let stats: HashMap<_, _> = fields
+            .chunks(2)
+            .map(|f| match (&f[0], &f[1]) {
+                (&RespValue::Data(ref name), &RespValue::Int(v)) => (name.clone(), v),
+                r => panic!("Can't decode value stats {:?}", r),
+            })
+            .collect();
+        let stat = |name: &str| stats[&Bytes::from(name)];
+        assert_eq!(stat("keys"), 4);
+        assert!(stat("max_bytes") > 20000 && stat("max_bytes") <= 65536);
+        assert!(stat("bytes") > 24100);
+        assert_eq!(stat("le_64"), 0);
+        assert_eq!(stat("le_256"), 1);
+        assert_eq!(stat("le_1024"), 1);
+        assert_eq!(stat("le_4096"), 3);
+        assert_eq!(stat("le_16384"), 3);
+        assert_eq!(stat("le_65536"), 4);
+        assert_eq!(stat("gt_65536"), 0);
+
+        db.do_cmd(1, &[b"DEBUG", b"VALUESTATS", b"100000"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Error("ERR InvalidValue".into())
+        );
+    }
+
+    #[test]
+    fn test_cube_format_upgrade() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let vnode = db.dht.key_vnode(b"test");
+        let stored = |db: &TestDatabase| {
+            let vnodes = db.vnodes.read().unwrap();
+            let mut vn = vnodes[&vnode].write().unwrap();
+            vn.storage_flush();
+            let bytes = vn._storage_get_vec(b"test").unwrap();
+            bytes
+        };
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        let current = stored(&db);
+        assert_eq!(current[0], 0x80 | FORMAT_VERSION);
+        // rewrite it as it was stored before the format had a version,
+        // without the version byte and the trailing None expiration
+        let v1 = current[1..current.len() - 1].to_vec();
+        db.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._storage_set_vec(b"test", &v1);
+        assert_eq!(stored(&db), v1);
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values, [b"value1"]);
+        db.do_cmd(1, &[b"SET", b"test", b"value2", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(stored(&db)[0], 0x80 | FORMAT_VERSION);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.read_cache_size = 10,
+        );
+        let vnode = db.dht.key_vnode(b"test");
+        let flush = |db: &TestDatabase| {
+            db.vnodes.read().unwrap()[&vnode].write().unwrap().storage_flush()
+        };
+        let cache_hits = |db: &TestDatabase| db.vnode_stats(vnode).unwrap().cache_hits;
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        flush(&db);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+        assert_eq!(cache_hits(&db), 0);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values, [b"value1"]);
+        assert_eq!(cache_hits(&db), 1);
+
+        // the write invalidates the cached value
+        db.do_cmd(1, &[b"SET", b"test", b"value2", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        flush(&db);
+        let hits = cache_hits(&db);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value2"]);
+        assert_eq!(cache_hits(&db), hits);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (values, vv) = db.response_values(1);
+        assert_eq!(values, [b"value2"]);
+        assert_eq!(cache_hits(&db), hits + 1);
+
+        // so does a delete
+        db.do_cmd(1, &[b"DEL", b"test", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        flush(&db);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 0);
+    }
+
+    #[test]
+    fn test_export_vnode() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let vnode = db.dht.key_vnode(b"key0");
+        let mut keys: Vec<String> = (0..1000)
+            .map(|i| format!("key{}", i))
+            .filter(|k| db.dht.key_vnode(k.as_bytes()) == vnode)
+            .take(3)
+            .collect();
+        keys.sort();
+        for key in &keys {
+            db.do_cmd(1, &[b"SET", key.as_bytes(), b"value", b"", One]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+
+        db.do_cmd(1, &[b"DEBUG", b"EXPORT", vnode.to_string().as_bytes()]);
+        let exported = match db.response_resp(1) {
+            RespValue::Data(d) => d,
+            r => panic!("Unexpected export response {:?}", r),
+        };
+        let mut exported_keys = Vec::new();
+        for line in exported.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let record: ExportRecord = serde_json::from_slice(line).unwrap();
+            // exported values and version vectors are the same as returned by GET
+            db.do_cmd(1, &[b"GET", &record.key[..], One]);
+            assert_eq!(db.response_resp(1), render_value(record.cube));
+            exported_keys.push(String::from_utf8(record.key.to_vec()).unwrap());
+        }
+        exported_keys.sort();
+        assert_eq!(exported_keys, keys);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let keys: Vec<String> = (0..TEST_JOIN_SIZE).map(|i| i.to_string()).collect();
+        let mut expected = Vec::new();
+        {
+            let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+            for key in &keys {
+                db.do_cmd(1, &[b"SET", key.as_bytes(), b"value1", b"", One]);
+                assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+            }
+            // make some siblings
+            for key in &keys[..10] {
+                db.do_cmd(1, &[b"SET", key.as_bytes(), b"value2", b"", One]);
+                assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+            }
+            for key in &keys {
+                db.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+                expected.push(db.response_resp(1));
+            }
+
+            let mut exported = 0;
+            for vnode in 0..PARTITIONS {
+                let path = format!("{}.ndjson", vnode);
+                db.do_cmd(
+                    1,
+                    &[b"DEBUG", b"EXPORT", vnode.to_string().as_bytes(), path.as_bytes()],
+                );
+                match db.response_resp(1) {
+                    RespValue::Int(count) => exported += count,
+                    r => panic!("Unexpected export response {:?}", r),
+                }
+            }
+            assert_eq!(exported, TEST_JOIN_SIZE as i64);
+        }
+
+        // the exports are copied over to a new node
+        fs::rename("t/db/export", "t/export").unwrap();
+        let _ = fs::remove_dir_all("t/db");
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        fs::rename("t/export", "t/db/export").unwrap();
+        for key in &keys {
+            db.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+            assert_eq!(db.response_values(1).0.len(), 0);
+        }
+
+        let mut imported = 0;
+        for vnode in 0..PARTITIONS {
+            let path = format!("{}.ndjson", vnode);
+            db.do_cmd(1, &[b"DEBUG", b"IMPORT", path.as_bytes()]);
+            match db.response_resp(1) {
+                RespValue::Int(count) => imported += count,
+                r => panic!("Unexpected import response {:?}", r),
+            }
+        }
+        assert_eq!(imported, TEST_JOIN_SIZE as i64);
+
+        for (key, expected) in keys.iter().zip(expected) {
+            db.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+            assert_eq!(db.response_resp(1), expected);
+        }
+
+        // files outside the export dir are refused
+        let invalid: RespValue = CommandError::InvalidValue.into();
+        for path in &["../0.ndjson", "/tmp/0.ndjson", "t/db/export/0.ndjson", ".."] {
+            db.do_cmd(1, &[b"DEBUG", b"EXPORT", b"0", path.as_bytes()]);
+            assert_eq!(db.response_resp(1), invalid);
+            db.do_cmd(1, &[b"DEBUG", b"IMPORT", path.as_bytes()]);
+            assert_eq!(db.response_resp(1), invalid);
+        }
+    }
+
+    #[test]
+    fn test_restore_replicated() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"DUMP", b"test", One]);
+        let payload = match db1.response_resp(1) {
+            RespValue::Data(payload) => payload,
+            r => panic!("Unexpected response {:?}", r),
+        };
+
+        // replies once all replicas have it
+        db1.do_cmd(1, &[b"RESTORE", b"restored", &payload, All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        for db in &[&db1, &db2] {
+            db.do_cmd(1, &[b"GET", b"restored", One]);
+            assert_eq!(db.response_values(1).0, [b"value"]);
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        use std::io::{Read, Write};
+        use std::time::Duration;
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let remote = TestDatabase::with_config(
+            "127.0.0.1:9100".parse().unwrap(),
+            "t/remote",
+            true,
+            |config| {
+                config.cluster_name = "remote".into();
+                config.seed_nodes = vec![];
+            },
+        );
+        let remote_addr = "127.0.0.1:9101";
+
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"SET", b"test", b"value2", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"SET", b"moved", b"value", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        // the other cluster isn't reachable, the key is kept
+        db.do_cmd(1, &[b"MIGRATE", remote_addr.as_bytes(), b"moved", b"DELETE"]);
+        let failed: RespValue = CommandError::MigrateFailed.into();
+        assert_eq!(db.response_resp(1), failed);
+        db.do_cmd(1, &[b"GET", b"moved", One]);
+        assert_eq!(db.response_values(1).0, [b"value"]);
+
+        // serves the remote database over RESP until it gets a QUIT
+        let server = thread::spawn(move || {
+            let listener = net::TcpListener::bind(remote_addr).unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buffer = Vec::new();
+                let mut chunk = [0u8; 4096];
+                loop {
+                    let parsed = resp::Parser::new(&buffer)
+                        .and_then(|mut p| p.parse().map(|cmd| (p.consumed(), cmd)));
+                    let cmd = match parsed {
+                        Ok((consumed, cmd)) => {
+                            buffer.drain(..consumed);
+                            cmd
+                        }
+                        Err(_) => {
+                            let read = stream.read(&mut chunk).unwrap();
+                            if read == 0 {
+                                break;
+                            }
+                            buffer.extend_from_slice(&chunk[..read]);
+                            continue;
+                        }
+                    };
+                    if cmd == RespValue::Array(vec![RespValue::Data("QUIT".into())]) {
+                        return remote;
+                    }
+                    let mut context = Context::new(1);
+                    context.commands.push(cmd);
+                    remote.handler_cmd(context);
+                    let mut response = Vec::new();
+                    remote.response_resp(1).serialize_into(&mut response).unwrap();
+                    stream.write_all(&response).unwrap();
+                }
+            }
+            unreachable!();
+        });
+        sleep_ms(100);
+
+        db.do_cmd(1, &[b"MIGRATE", remote_addr.as_bytes(), b"test"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"MIGRATE", remote_addr.as_bytes(), b"moved", b"DELETE"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"MIGRATE", remote_addr.as_bytes(), b"missing"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("NOKEY".into()));
+        db.do_cmd(1, &[b"GET", b"moved", One]);
+        assert_eq!(db.response_values(1).0.len(), 0);
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        let (mut values, vv) = db.response_values(1);
+        assert_eq!(values.len(), 2);
+        values.sort();
+
+        // bulk copy following a scan cursor
+        for i in 0..20 {
+            let key = format!("bulk{}", i);
+            db.do_cmd(1, &[b"SET", key.as_bytes(), key.as_bytes(), b"", One]);
+            assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        }
+        let mut cursor = b"0".to_vec();
+        let mut migrated = 0;
+        loop {
+            db.do_cmd(
+                1,
+                &[b"MIGRATE", remote_addr.as_bytes(), b"CURSOR", &cursor, b"COUNT", b"5"],
+            );
+            match db.response_resp(1) {
+                RespValue::Array(ref reply) => match (&reply[0], &reply[1]) {
+                    (&RespValue::Data(ref next), &RespValue::Int(count)) => {
+                        cursor = next.to_vec();
+                        migrated += count;
+                    }
+                    _ => panic!("Unexpected reply {:?}", reply),
+                },
+                r => panic!("Unexpected response {:?}", r),
+            }
+            if cursor == b"0" {
+                break;
+            }
+        }
+        // the 20 keys and test, moved was deleted
+        assert_eq!(migrated, 21);
+
+        let mut client =
+            resp::Client::connect(&remote_addr.parse().unwrap(), Duration::from_secs(1)).unwrap();
+        assert!(client.cmd(&[b"QUIT"]).is_err());
+        let remote = server.join().unwrap();
+
+        // siblings and context are kept
+        remote.do_cmd(1, &[b"GET", b"test", One]);
+        let (mut remote_values, remote_vv) = remote.response_values(1);
+        remote_values.sort();
+        assert_eq!((remote_values, remote_vv), (values, vv));
+        remote.do_cmd(1, &[b"GET", b"moved", One]);
+        assert_eq!(remote.response_values(1).0, [b"value"]);
+        for i in 0..20 {
+            let key = format!("bulk{}", i);
+            remote.do_cmd(1, &[b"GET", key.as_bytes(), One]);
+            assert_eq!(remote.response_values(1).0, [key.as_bytes()]);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_vnode_reads() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            // no ticks, they'd queue for the vnode write lock
+            |config| config.worker_timer = 3_600_000,
+        );
+        let vnode = db.dht.key_vnode(b"test");
+        let vnodes = db.vnodes.read().unwrap();
+        let _locked = vnodes[&vnode].read().unwrap();
+
+        // other readers of the same vnode aren't blocked by the one above
+        let (tx, rx) = mpsc::channel();
+        let db2 = db.db.clone();
+        thread::spawn(move || {
+            tx.send(db2.vnode_stats(vnode).is_some()).unwrap();
+            let (cursor, keys) = db2.scan(&Default::default(), 10, None);
+            tx.send(cursor == Default::default() && keys.is_empty()).unwrap();
+        });
+        for _ in 0..2 {
+            assert!(rx.recv_timeout(::std::time::Duration::from_secs(1)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_remote_get_shares_vnode() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.worker_timer = 3_600_000,
+        );
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+
+        // db2 still replies while something else reads its vnode
+        let vnode = db1.dht.key_vnode(b"test");
+        let vnodes = db2.vnodes.read().unwrap();
+        let _locked = vnodes[&vnode].read().unwrap();
+        let timeout = time::Duration::from_millis(db1.config().request_timeout as _);
+        for i in 0..10 {
+            let start = time::Instant::now();
+            db1.do_cmd(i, &[b"GET", b"test", All]);
+            assert_eq!(db1.response_values(i).0, [b"value"]);
+            assert!(start.elapsed() < timeout);
+        }
+    }
+
+    #[test]
+    fn test_batched_flush() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| config.worker_count = 1,
+        );
+        let vnode = db.dht.key_vnode(b"test");
+        let mut sender = db.sender();
+        const N: usize = 50;
+        {
+            // hold the vnode so the commands pile up in the worker queue
+            let vnodes = db.vnodes.read().unwrap();
+            let _locked = vnodes[&vnode].write().unwrap();
+            for i in 0..N {
+                let mut context = Context::new(i as Token);
+                context.commands.push(RespValue::Array(
+                    [&b"SET"[..], b"test", format!("value{}", i).as_bytes(), b"", One]
+                        .iter()
+                        .map(|&x| RespValue::Data(x.into()))
+                        .collect(),
+                ));
+                sender.send(WorkerMsg::Command(context));
+            }
+        }
+        for i in 0..N {
+            assert_eq!(db.response_resp(i as Token), RespValue::Status("OK".into()));
+        }
+
+        // the writes were acked once the queue drained and their batch was written
+        let in_storage = db.vnodes.read().unwrap()[&vnode]
+            .read()
+            .unwrap()
+            ._storage_get_vec(b"test")
+            .unwrap();
+        let cube = deserialize_cube(&in_storage).unwrap();
+        assert_eq!(cube.into_value().unwrap().len(), N);
+
+        let batches = db.vnode_stats(vnode).unwrap().storage_batches;
+        assert!(batches > 0 && batches < N as u64, "{} batches", batches);
+    }
+
+    #[test]
+    fn test_remote_set_batch() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db",
+            true,
+            |config| {
+                config.commit_policy = CommitPolicy::Sync;
+                config.worker_timer = 3_600_000;
+            },
+        );
+        const N: u64 = 100;
+        let remote_sets = |from_version: u64| -> Vec<(NodeId, MsgRemoteSet)> {
+            (from_version..from_version + N)
+                .map(|i| {
+                    let key = Bytes::from(format!("test{}", i % N));
+                    let mut value = Cube::Void(VersionVector::new()).into_value().unwrap();
+                    value.set(99, i, Some(Bytes::from("value")), &VersionVector::new());
+                    let msg = MsgRemoteSet {
+                        vnode: db.dht.key_vnode(&key),
+                        cookie: Default::default(),
+                        writes: vec![(key, Cube::Value(value), false)],
+                        reply: false,
+                        repair: false,
+                        deadline: None,
+                    };
+                    (99, msg)
+                })
+                .collect()
+        };
+        let commits = || -> u64 {
+            let vnodes: Vec<VNodeId> = db.vnodes.read().unwrap().keys().cloned().collect();
+            vnodes
+                .into_iter()
+                .map(|vn| db.vnode_stats(vn).unwrap().storage_batches)
+                .sum()
+        };
+
+        // one commit per vnode once the queue drains
+        let msgs = remote_sets(1);
+        let mut vnodes: Vec<VNodeId> = msgs.iter().map(|&(_, ref m)| m.vnode).collect();
+        vnodes.sort();
+        vnodes.dedup();
+        let before = commits();
+        db.handler_remote_sets(msgs);
+        db.handler_drained();
+        assert_eq!(commits() - before, vnodes.len() as u64);
+        assert!((vnodes.len() as u64) < N);
+
+        // also if the messages weren't applied together
+        let before = commits();
+        for (from, msg) in remote_sets(N + 1) {
+            db.handler_fabric_msg(from, FabricMsg::RemoteSet(msg));
+        }
+        db.handler_drained();
+        assert_eq!(commits() - before, vnodes.len() as u64);
+
+        for i in 0..N {
+            let key = format!("test{}", i);
+            let vnode = db.dht.key_vnode(key.as_bytes());
+            let vnodes = db.vnodes.read().unwrap();
+            let stored = vnodes[&vnode]
+                .read()
+                .unwrap()
+                ._storage_get_vec(key.as_bytes())
+                .unwrap();
+            let value = deserialize_cube(&stored).unwrap().into_value().unwrap();
+            assert_eq!(value.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_client_disconnected() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.request_timeout = 60_000,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // db2 stops answering, so requests needing it stay inflight
+        db2.fabric
+            .register_msg_handler(FabricMsgType::Crud, Box::new(|_, _| ()));
+        for token in 1..5 {
+            db1.client_connected(token, None);
+        }
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        db1.do_cmd(2, &[b"GET", b"test", All]);
+        db1.do_cmd(3, &[b"IDEMPOTENT", b"id", b"SET", b"test", b"value", b"", All]);
+        let wait_inflight = |count| {
+            for _ in 0..1000 {
+                if db1.inflight_requests().len() == count {
+                    break;
+                }
+                sleep_ms(1);
+            }
+            assert_eq!(db1.inflight_requests().len(), count);
+        };
+        wait_inflight(3);
+
+        // only the requests of the disconnected client are dropped
+        db1.client_disconnected(1);
+        assert_eq!(db1.inflight_requests().len(), 2);
+        db1.client_disconnected(2);
+        assert_eq!(db1.inflight_requests().len(), 1);
+        // the cancelled write isn't in progress anymore, so it can be retried
+        db1.client_disconnected(3);
+        assert!(db1.inflight_requests().is_empty());
+        db1.do_cmd(4, &[b"IDEMPOTENT", b"id", b"SET", b"test", b"value", b"", All]);
+        wait_inflight(1);
+        db1.client_disconnected(4);
+        assert!(db1.inflight_requests().is_empty());
+        sleep_ms(100);
+        assert!(db1.responses.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client_name() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.client_connected(1, Some("127.0.0.1:50000".parse().unwrap()));
+
+        db.do_cmd(1, &[b"CLIENT", b"GETNAME"]);
+        assert_eq!(db.response_resp(1), RespValue::Nil);
+        db.do_cmd(1, &[b"CLIENT", b"SETNAME", b"app"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CLIENT", b"GETNAME"]);
+        assert_eq!(db.response_resp(1), RespValue::Data("app".into()));
+        db.do_cmd(1, &[b"CLIENT", b"SETNAME", b"app name"]);
+        assert_eq!(db.response_resp(1), RespValue::Error("ERR InvalidValue".into()));
+        db.do_cmd(1, &[b"CLIENT", b"LIST"]);
+        assert_eq!(
+            db.response_resp(1),
+            RespValue::Data("id=1 addr=127.0.0.1:50000 name=app age=0\n".into())
+        );
+
+        // an empty name clears it and disconnected clients aren't listed
+        db.do_cmd(1, &[b"CLIENT", b"SETNAME", b""]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        db.do_cmd(1, &[b"CLIENT", b"GETNAME"]);
+        assert_eq!(db.response_resp(1), RespValue::Nil);
+        db.client_disconnected(1);
+        db.do_cmd(2, &[b"CLIENT", b"LIST"]);
+        assert_eq!(db.response_resp(2), RespValue::Data("".into()));
+
+        // naming a gone client doesn't list it again
+        db.set_client_name(1, Some("app".into()));
+        assert_eq!(db.client_name(1), None);
+        db.do_cmd(2, &[b"CLIENT", b"LIST"]);
+        assert_eq!(db.response_resp(2), RespValue::Data("".into()));
+    }
+
+    #[test]
+    fn test_broadcast_timeout() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| {
+                config.request_timeout = 200;
+                config.flushall_enabled = true;
+            },
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // db2 drops the flush, so its ack never arrives
+        db2.fabric
+            .register_msg_handler(FabricMsgType::Crud, Box::new(|_, _| ()));
+        db1.do_cmd(1, &[b"FLUSHALL"]);
+        assert_eq!(db1.broadcasts.lock().unwrap().len(), 1);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error("TIMEOUT Request timed out".into())
+        );
+        assert!(db1.broadcasts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // record the request id of the crud msgs each node receives
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for db in &[&db1, &db2] {
+            let seen = seen.clone();
+            let node = db.dht.node();
+            let mut sender = db.sender();
+            db.fabric.register_msg_handler(
+                FabricMsgType::Crud,
+                Box::new(move |from, msg| {
+                    let cookie = match msg {
+                        FabricMsg::RemoteGet(ref m) => Some(m.cookie),
+                        FabricMsg::RemoteGetAck(ref m) => Some(m.cookie),
+                        FabricMsg::RemoteSet(ref m) => Some(m.cookie),
+                        FabricMsg::RemoteSetAck(ref m) => Some(m.cookie),
+                        _ => None,
+                    };
+                    if let Some(cookie) = cookie {
+                        seen.lock().unwrap().push((node, cookie.request_id()));
+                    }
+                    sender.send(WorkerMsg::Fabric(from, msg));
+                }),
+            );
+        }
+
+        let cmds: &[(RequestId, &[&[u8]])] = &[
+            (0x5e7, &[b"SET", b"test", b"value", b"", All]),
+            (0x6e7, &[b"GET", b"test", All]),
+        ];
+        for &(request_id, cmd) in cmds {
+            let mut context = Context::new(1);
+            context.request_id = request_id;
+            context.commands.push(RespValue::Array(
+                cmd.iter().map(|&x| RespValue::Data(x.into())).collect(),
+            ));
+            db1.handler_cmd(context);
+            db1.response_resp(1);
+            // the replica got the request and the coordinator its ack, both with the id
+            let seen = seen.lock().unwrap();
+            assert!(seen.contains(&(db2.dht.node(), request_id)));
+            assert!(seen.contains(&(db1.dht.node(), request_id)));
+        }
+
+        // commands without one get a new id
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        db1.response_resp(1);
+        let seen = seen.lock().unwrap();
+        let last = seen.iter().rev().find(|s| s.0 == db2.dht.node()).unwrap();
+        assert!(last.1 != 0 && last.1 != 0x5e7 && last.1 != 0x6e7);
+    }
+
+    #[test]
+    fn test_two() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db1.response_values(1).0.len(), 0);
+
+        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_values(1).0, &[b"value1"]);
+
+        for &db in &[&db1, &db2] {
+            db.do_cmd(1, &[b"GET", b"test", One]);
+            assert_eq!(db.response_values(1).0, [b"value1"]);
+        }
+    }
+
+    const TEST_JOIN_SIZE: u64 = 100;
+
+    #[test]
+    fn test_bootstrap() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // boostrap node 2 from 1
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        warn!("will check data before balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        db2.dht.rebalance().unwrap();
+
+        warn!("will check data during balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        db2.wait_syncs();
+
+        warn!("will check data after balancing");
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+
+        // remove first node
+        db2.dht.remove_node(db1.dht.node()).unwrap();
+        sleep_ms(100);
+
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+
+        db3.dht.rebalance().unwrap();
+        db3.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db2, &db3] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_2() {
+        // similar to the previous, but values in n1 are rewritten + sibling
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
+            let (_, vv) = db1.response_values(i);
+
+            // rewrite key
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+
+            // add sibling
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    &encode_vv(&vv),
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // boostrap node 2 from 1
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+
+        // remove first node so node 3 boostrap from 1
+        db2.dht.remove_node(db1.dht.node()).unwrap();
+        sleep_ms(100);
+
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+
+        db3.dht.rebalance().unwrap();
+        db3.wait_syncs();
+
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db2, &db3] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(
+                    db.response_values(i).0,
+                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_incomplete() {
+        use std::env;
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        // all keys received by db2 are lost, so bootstraps can't complete
+        env::set_var("SYNC_DROP_RECV", "1");
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        sleep_ms(1000);
+        let db2_owns_all = || {
+            (0..PARTITIONS as VNodeId).all(|i| {
+                db2.dht
+                    .nodes_for_vnode(i, false, false)
+                    .contains(&db2.dht.node())
+            })
+        };
+        assert!(db2.syncs_inflight() != 0);
+        assert!(!db2_owns_all());
+
+        env::set_var("SYNC_DROP_RECV", "0");
+        db2.wait_syncs();
+        assert!(db2_owns_all());
+
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [i.to_string().as_bytes()]);
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_sync_msg_batch() {
+        let _ = env_logger::try_init();
+        const N: u64 = 1000;
+        // returns the sync messages and keys received by the joining node
+        let bootstrap = |batch: &[u8]| -> (u64, u64) {
+            let _ = fs::remove_dir_all("t/");
+            let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+            for i in 0..N {
+                db1.do_cmd(
+                    i,
+                    &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", One],
+                );
+                assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+            }
+            db1.do_cmd(1, &[b"CONFIG", b"SET", b"sync_msg_batch", batch]);
+            assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+            db1.do_cmd(1, &[b"CONFIG", b"GET", b"sync_msg_batch"]);
+            assert_eq!(
+                db1.response_resp(1),
+                RespValue::Array(vec![
+                    RespValue::Data("sync_msg_batch".into()),
+                    RespValue::Data(batch.into()),
+                ])
+            );
+
+            let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+            db2.dht.rebalance().unwrap();
+            db2.wait_syncs();
+            for i in 0..N {
+                db2.do_cmd(i, &[b"DEBUG", b"LOCALGET", i.to_string().as_bytes()]);
+                assert!(db2.response_resp(i) != RespValue::Nil);
+            }
+            (0..PARTITIONS as VNodeId)
+                .filter_map(|vn| db2.vnode_stats(vn))
+                .fold((0, 0), |(msgs, keys), stats| {
+                    (msgs + stats.sync_msgs_received, keys + stats.sync_keys_received)
+                })
+        };
+
+        let (single_msgs, single_keys) = bootstrap(b"1");
+        let (batch_msgs, batch_keys) = bootstrap(b"10");
+        assert_eq!(single_msgs, single_keys);
+        assert!(single_keys >= N && batch_keys >= N);
+        assert!(
+            batch_msgs < single_msgs / 2,
+            "{} vs {} msgs",
+            batch_msgs,
+            single_msgs
+        );
+    }
+
+    #[test]
+    fn test_cluster_rebalance() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes()]);
+            db1.response_resp(i);
+        }
+        // the ring isn't changed when already balanced
+        let ring = db1.dht.save_ring();
+        db1.do_cmd(0, &[b"CLUSTER", b"REBALANCE"]);
+        assert_eq!(
+            db1.response_resp(0),
+            RespValue::Status("already balanced".into())
+        );
+        assert_eq!(db1.dht.save_ring(), ring);
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(100);
+        db2.do_cmd(0, &[b"CLUSTER", b"REBALANCE"]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+        db2.wait_syncs();
+
+        db2.do_cmd(0, &[b"CLUSTER", b"REBALANCE"]);
+        assert_eq!(
+            db2.response_resp(0),
+            RespValue::Status("already balanced".into())
+        );
+        for i in 0..TEST_JOIN_SIZE {
+            for &db in &[&db1, &db2] {
+                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(0, &[b"CLUSTER", b"BULKLOAD", b"START"]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", All]);
+            db1.response_resp(i);
+        }
+        // nothing was replicated yet
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0.len(), 0);
+        }
+
+        db1.do_cmd(0, &[b"CLUSTER", b"BULKLOAD", b"END"]);
+        assert_eq!(db1.response_resp(0), RespValue::Int(TEST_JOIN_SIZE as _));
+        sleep_ms(200);
+        for i in 0..TEST_JOIN_SIZE {
+            db2.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
+            assert_eq!(db2.response_values(i).0, [b"value"]);
+        }
     }
 
     #[test]
-    fn test_reload_clean_shutdown() {
-        test_reload_stub(true);
-    }
+    fn test_cluster_ring() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
 
-    #[test]
-    fn test_reload_dirty_shutdown() {
-        test_reload_stub(false);
+        let ring = |db: &TestDatabase| -> (i64, Vec<RespValue>) {
+            db.do_cmd(1, &[b"CLUSTER", b"RING"]);
+            match db.response_resp(1) {
+                RespValue::Array(mut a) => match (a.remove(0), a.remove(0)) {
+                    (RespValue::Int(epoch), RespValue::Array(vnodes)) => (epoch, vnodes),
+                    r => panic!("Unexpected ring {:?}", r),
+                },
+                r => panic!("Unexpected ring {:?}", r),
+            }
+        };
+
+        let (epoch1, vnodes) = ring(&db1);
+        assert_eq!(vnodes.len(), PARTITIONS);
+        for (i, vnode) in vnodes.into_iter().enumerate() {
+            match vnode {
+                RespValue::Array(ref a) => {
+                    assert_eq!(a[0], RespValue::Int(i as _));
+                    assert_eq!(a[1], RespValue::Array(vec![RespValue::Array(vec![
+                        RespValue::Data(db1.dht.node().to_string().as_bytes().into()),
+                        RespValue::Data(db1.config().listen_addr.to_string().as_bytes().into()),
+                        RespValue::Data(b"owner"[..].into()),
+                    ])]));
+                }
+                r => panic!("Unexpected vnode {:?}", r),
+            }
+        }
+
+        let _db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(200);
+        let (epoch2, vnodes) = ring(&db1);
+        assert_eq!(vnodes.len(), PARTITIONS);
+        assert!(epoch2 > epoch1, "{} > {}", epoch2, epoch1);
     }
 
     #[test]
-    fn test_one() {
+    fn test_cluster_load_ring() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(200);
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db.response_values(1).0.len(), 0);
+        let ring = |db: &TestDatabase| {
+            db.do_cmd(1, &[b"CLUSTER", b"RING"]);
+            db.response_resp(1)
+        };
+        let load_ring = |db: &TestDatabase, dump: &[u8]| {
+            db.do_cmd(1, &[b"CLUSTER", b"LOADRING", dump, b"CONFIRM"]);
+            db.response_resp(1)
+        };
+        db1.do_cmd(1, &[b"CLUSTER", b"DUMPRING"]);
+        let dump = match db1.response_resp(1) {
+            RespValue::Data(dump) => dump,
+            r => panic!("Unexpected response {:?}", r),
+        };
+        let topology = ring(&db1);
+        let not_isolated: RespValue = CommandError::NotIsolated.into();
+        assert_eq!(load_ring(&db1, &dump), not_isolated);
+        drop(db1);
+        drop(db2);
 
-        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", One]);
-        assert_eq!(db.response_values(1).0.len(), 1);
+        // a fresh node takes the ring as is
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", true);
+        let invalid: RespValue = CommandError::InvalidValue.into();
+        assert_eq!(load_ring(&db3, b"garbage"), invalid);
+        assert_ne!(ring(&db3), topology);
+        // it has to be confirmed
+        db3.do_cmd(1, &[b"CLUSTER", b"LOADRING", &dump]);
+        let arg_count: RespValue = CommandError::InvalidArgCount.into();
+        assert_eq!(db3.response_resp(1), arg_count);
+        assert_eq!(load_ring(&db3, &dump), RespValue::Status("OK".into()));
+        assert_eq!(ring(&db3), topology);
+        assert_eq!(load_ring(&db3, &dump), not_isolated);
+        drop(db3);
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db.response_values(1).0, [b"value1"]);
+        // another layout is only taken by a node without data
+        let create_other = |addr: &str, dir: &str| {
+            TestDatabase::with_config(addr.parse().unwrap(), dir, true, |config| {
+                config.cmd_init = Some(config::InitCommand {
+                    replication_factor: 1,
+                    partitions: PARTITIONS as _,
+                })
+            })
+        };
+        let db4 = create_other("127.0.0.1:9003", "t/db4");
+        db4.do_cmd(1, &[b"SET", b"test", b"value", b"", One]);
+        assert_eq!(db4.response_resp(1), RespValue::Status("OK".into()));
+        let mismatch: RespValue = CommandError::RingMismatch.into();
+        assert_eq!(load_ring(&db4, &dump), mismatch);
+        assert_ne!(ring(&db4), topology);
+        drop(db4);
+        let db5 = create_other("127.0.0.1:9004", "t/db5");
+        assert_eq!(load_ring(&db5, &dump), RespValue::Status("OK".into()));
+        assert_eq!(ring(&db5), topology);
+    }
 
-        db.do_cmd(1, &[b"GETSET", b"test", b"value2", b"", One]);
-        assert_eq!(db.response_values(1).0.len(), 2);
+    #[test]
+    fn test_dht_change_stale_epoch() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let epoch1 = db1.dht.epoch();
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        let (values, vv) = db.response_values(1);
-        assert_eq!(values, [b"value1", b"value2"]);
+        let _db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        sleep_ms(200);
+        let epoch2 = db1.dht.epoch();
+        assert!(epoch2 > epoch1);
+        assert_eq!(*db1.dht_epoch.lock().unwrap(), epoch2);
+
+        // an older membership is ignored
+        assert!(!db1.handler_dht_change(epoch1));
+        assert_eq!(*db1.dht_epoch.lock().unwrap(), epoch2);
+        // the current one can be reapplied
+        assert!(db1.handler_dht_change(epoch2));
+    }
 
-        db.do_cmd(1, &[b"GETSET", b"test", b"value12", &encode_vv(&vv), One]);
-        assert_eq!(db.response_values(1).0.len(), 1);
+    #[test]
+    fn test_zombie_handoff() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.vnode_zombie_timeout = 0,
+        );
+        db2.dht.rebalance().unwrap();
+        db2.wait_syncs();
+        for i in 0..TEST_JOIN_SIZE {
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), i.to_string().as_bytes(), b"", All]);
+            db1.response_resp(i);
+        }
+        let db2_log_len = || {
+            db2.vnodes
+                .read()
+                .unwrap()
+                .values()
+                .map(|vn| vn.write().unwrap()._log_len(db1.dht.node()))
+                .sum::<usize>()
+        };
+        assert_eq!(db2_log_len(), TEST_JOIN_SIZE as usize);
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        let (values, vv) = db.response_values(1);
-        assert_eq!(values, [b"value12"]);
+        // a node that never finishes bootstrapping takes over from db2
+        // (random node ids never have the highest bit set)
+        let fake_node = join_u64(u32::max_value(), 0);
+        let fake_addr: net::SocketAddr = "127.0.0.1:9009".parse().unwrap();
+        db2.dht.join_node(fake_node, fake_addr, fake_addr).unwrap();
+        db2.dht.rebalance().unwrap();
+        db2.dht.remove_node(db2.dht.node()).unwrap();
 
-        db.do_cmd(1, &[b"DEL", b"test", &encode_vv(&vv), One]);
-        assert_eq!(db.response_resp(1), RespValue::Int(1));
+        // db2 vnodes stay zombies past the timeout as the fake node is still pending
+        sleep_ms(2000);
+        assert_eq!(db2_log_len(), TEST_JOIN_SIZE as usize);
 
-        db.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db.response_values(1).0.len(), 0);
+        // and keep accepting its syncs, as it may still bootstrap from them
+        let vnode = db2.dht.key_vnode(b"0");
+        {
+            let vnodes = db2.vnodes.read().unwrap();
+            let mut vn = vnodes[&vnode].write().unwrap();
+            assert_eq!(vn.status(), VNodeStatus::Zombie);
+            let cookie = Cookie::new(1, 1);
+            vn.handler_sync_start(
+                &db2,
+                fake_node,
+                MsgSyncStart {
+                    vnode,
+                    cookie,
+                    clocks_in_peer: Default::default(),
+                    target: None,
+                },
+            );
+            assert_eq!(vn.syncs_inflight(), (0, 1));
+            vn.handler_sync_fin(
+                &db2,
+                fake_node,
+                MsgSyncFin {
+                    vnode,
+                    cookie,
+                    result: Err(FabricError::NotReady),
+                },
+            );
+            assert_eq!(vn.syncs_inflight(), (0, 0));
+        }
+
+        // once there's nothing pending the zombies can go absent
+        db2.dht.remove_node(fake_node).unwrap();
+        sleep_ms(2000);
+        assert_eq!(db2_log_len(), 0);
     }
 
     #[test]
-    fn test_two() {
+    fn test_scrub_repair() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.scrub_interval = 100,
+        );
         let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
-        db2.dht.rebalance().unwrap();
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
 
         db1.wait_syncs();
         db2.wait_syncs();
+        db3.wait_syncs();
 
-        db1.do_cmd(1, &[b"GET", b"test", One]);
-        assert_eq!(db1.response_values(1).0.len(), 0);
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
 
-        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
-        assert_eq!(db1.response_values(1).0, &[b"value1"]);
+        // silently lose the key in one replica, its clocks still have the dots
+        let vnode = db1.dht.key_vnode(b"test");
+        let lost = || {
+            db3.vnodes.read().unwrap()[&vnode]
+                .read()
+                .unwrap()
+                ._storage_get_vec(b"test")
+                .is_none()
+        };
+        db3.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._storage_del(b"test");
+        assert!(lost());
 
-        for &db in &[&db1, &db2] {
-            db.do_cmd(1, &[b"GET", b"test", One]);
-            assert_eq!(db.response_values(1).0, [b"value1"]);
+        for _ in 0..100 {
+            if !lost() {
+                break;
+            }
+            sleep_ms(50);
         }
+        assert!(!lost());
+        db3.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db3.response_values(1).0, [b"value1"]);
     }
 
-    const TEST_JOIN_SIZE: u64 = 100;
-
     #[test]
-    fn test_bootstrap() {
+    fn test_scrub_keeps_deletes() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
         let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        for i in 0..TEST_JOIN_SIZE {
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    b"",
-                    One,
-                ],
-            );
-            db1.response_values(i);
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.scrub_interval = 100,
+        );
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+        for db in &[&db1, &db2, &db3] {
+            db.config.write().unwrap().sync_auto = false;
         }
 
-        // boostrap node 2 from 1
-        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
-        warn!("will check data before balancing");
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
-            }
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"GET", b"test", One]);
+        let (_, vv) = db1.response_values(1);
+        db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        let stored = match db2.response_resp(1) {
+            RespValue::Data(bytes) => bytes,
+            r => panic!("{:?}", r),
+        };
+        db1.do_cmd(1, &[b"DEL", b"test", &encode_vv(&vv), All]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+
+        // the delete is undone in two of the three replicas, outvoting db1
+        let vnode = db1.dht.key_vnode(b"test");
+        for db in &[&db2, &db3] {
+            db.vnodes.read().unwrap()[&vnode]
+                .write()
+                .unwrap()
+                ._storage_set_vec(b"test", &stored);
         }
 
-        db2.dht.rebalance().unwrap();
+        // db2 scrubs a few times, finding the key missing in db1 and present in db3
+        sleep_ms(1_000);
+        db1.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        assert_eq!(db1.response_resp(1), RespValue::Nil);
+    }
 
-        warn!("will check data during balancing");
+    #[test]
+    fn test_sync_max() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let config_fn = |config: &mut config::Config| config.sync_max = 2;
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            config_fn,
+        );
+        let mut db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            config_fn,
+        );
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // give the syncs something to send
+        drop(db2);
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
-            }
+            db1.do_cmd(i, &[b"SET", i.to_string().as_bytes(), b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
         }
+        db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            config_fn,
+        );
+        sleep_ms(200); // wait for fabric to reconnect
 
+        let mut started = 0;
+        for i in 0..PARTITIONS as u16 {
+            while !db2._start_sync(i) {
+                assert!(db2.syncs_inflight() <= 2);
+                assert!(db1.syncs_inflight() <= 2);
+                sleep_ms(1);
+            }
+            started += 1;
+            assert!(db2.syncs_inflight() <= 2);
+        }
+        assert_eq!(started, PARTITIONS);
         db2.wait_syncs();
+        db1.wait_syncs();
 
-        warn!("will check data after balancing");
         for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
-            }
+            db2.do_cmd(i, &[b"DEBUG", b"LOCALGET", i.to_string().as_bytes()]);
+            assert!(db2.response_resp(i) != RespValue::Nil);
         }
+    }
 
-        // remove first node
-        db2.dht.remove_node(db1.dht.node()).unwrap();
-        sleep_ms(100);
-
-        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
-
-        db3.dht.rebalance().unwrap();
+    #[test]
+    fn test_sync_peer_round_robin() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let config_fn = |config: &mut config::Config| {
+            config.sync_auto = false;
+            config.sync_peer_strategy = SyncPeerStrategy::RoundRobin;
+        };
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            config_fn,
+        );
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            config_fn,
+        );
+        let db3 = TestDatabase::with_config(
+            "127.0.0.1:9002".parse().unwrap(),
+            "t/db3",
+            false,
+            config_fn,
+        );
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
         db3.wait_syncs();
 
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db2, &db3] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(db.response_values(i).0, [i.to_string().as_bytes()]);
-            }
+        // each scheduling round of the same vnode moves on to the next peer
+        let mut peers = Vec::new();
+        for _ in 0..4 {
+            assert!(db1._start_sync(0));
+            let nodes = db1.vnodes.read().unwrap()[&0].read().unwrap()._sync_nodes();
+            assert_eq!(nodes.len(), 1);
+            peers.push(nodes[0]);
+            db1.wait_syncs();
         }
+        assert!(peers.contains(&db2.dht.node()));
+        assert!(peers.contains(&db3.dht.node()));
+        assert!(peers.windows(2).all(|w| w[0] != w[1]));
     }
 
     #[test]
-    fn test_bootstrap_2() {
-        // similar to the previous, but values in n1 are rewritten + sibling
+    fn test_replication_lag() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
         let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        for i in 0..TEST_JOIN_SIZE {
-            db1.do_cmd(i, &[b"GETSET", i.to_string().as_bytes(), b"", b"", One]);
-            let (_, vv) = db1.response_values(i);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let vnode = db1.dht.key_vnode(b"test");
+        let lag = |db: &TestDatabase| db.vnode_stats(vnode).unwrap().replication_lag;
 
-            // rewrite key
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
+        // db2 has the write but didn't confirm it with a sync yet
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        assert_eq!(lag(&db1), 1);
+        db2.force_syncs();
+        db1.wait_syncs();
+        assert_eq!(lag(&db1), 0);
 
-            // add sibling
-            db1.do_cmd(
-                i,
-                &[
-                    b"GETSET",
-                    i.to_string().as_bytes(),
-                    i.to_string().as_bytes(),
-                    &encode_vv(&vv),
-                    One,
-                ],
-            );
-            db1.response_values(i);
+        // db2 misses the writes while it's down
+        drop(db2);
+        for i in 0..10 {
+            db1.do_cmd(i, &[b"SET", b"test", b"value", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
         }
+        assert_eq!(lag(&db1), 10);
 
-        // boostrap node 2 from 1
-        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        sleep_ms(200); // wait for fabric to reconnect
+        db2.force_syncs();
+        db1.wait_syncs();
+        assert_eq!(lag(&db1), 0);
 
-        db2.dht.rebalance().unwrap();
+        // the clocks of db2 are forgotten once it leaves the ring
+        let peers = |db: &TestDatabase| {
+            db.vnodes.read().unwrap()[&vnode]
+                .write()
+                .unwrap()
+                ._peer_clocks_nodes()
+        };
+        assert_eq!(peers(&db1), [db2.dht.node()]);
+        db1.dht.remove_node(db2.dht.node()).unwrap();
+        sleep_ms(100);
+        assert!(peers(&db1).is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_cluster_rebuild() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
         db2.wait_syncs();
 
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db1, &db2] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
+        db1.do_cmd(1, &[b"SET", b"{t}test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db2.dht.key_vnode(b"{t}").to_string();
+        db2.do_cmd(1, &[b"DEBUG", b"CORRUPT", vnode.as_bytes(), b"{t}test"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+
+        db2.do_cmd(1, &[b"CLUSTER", b"REBUILD", vnode.as_bytes()]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db2.wait_syncs();
+        db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"{t}test"]);
+        match db2.response_resp(1) {
+            RespValue::Data(bytes) => assert!(deserialize_cube(&bytes).is_ok()),
+            r => panic!("Unexpected response {:?}", r),
         }
+        db2.do_cmd(1, &[b"GET", b"{t}test", One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
 
-        // remove first node so node 3 boostrap from 1
-        db2.dht.remove_node(db1.dht.node()).unwrap();
-        sleep_ms(100);
+        // nothing to rebuild from
+        drop(db1);
+        sleep_ms(200);
+        db2.do_cmd(1, &[b"CLUSTER", b"REBUILD", vnode.as_bytes()]);
+        assert_eq!(
+            db2.response_resp(1),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+    }
 
-        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+    #[test]
+    fn test_stale_sync_generation() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db2.dht.key_vnode(b"test");
+        let generation = |db: &TestDatabase| {
+            let vnodes = db.vnodes.read().unwrap();
+            let generation = vnodes[&vnode].read().unwrap()._generation();
+            generation
+        };
+        let before = generation(&db2);
+
+        // db1 misses the fins, so its sender outlives the sync db2 finished
+        let mut sender = db1.sender();
+        db1.fabric.register_msg_handler(
+            FabricMsgType::Synch,
+            Box::new(move |from, msg| match msg {
+                FabricMsg::SyncFin(..) => (),
+                msg => Database::route_fabric_msg(&mut sender, from, msg),
+            }),
+        );
+        assert!(db2.vnodes.read().unwrap()[&vnode].write().unwrap()._start_sync(&db2));
+        db2.wait_syncs();
+        let cookies = db1.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._sync_cookies();
+        assert_eq!(cookies.len(), 1);
+        let cookie = cookies[0];
+        assert_eq!(cookie.generation(), before);
 
-        db3.dht.rebalance().unwrap();
-        db3.wait_syncs();
+        db2.do_cmd(1, &[b"CLUSTER", b"REBUILD", vnode.to_string().as_bytes()]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db2.wait_syncs();
+        assert!(generation(&db2) != before);
 
-        for i in 0..TEST_JOIN_SIZE {
-            for &db in &[&db2, &db3] {
-                db.do_cmd(i, &[b"GET", i.to_string().as_bytes(), One]);
-                assert_eq!(
-                    db.response_values(i).0,
-                    [i.to_string().as_bytes(), i.to_string().as_bytes()]
-                );
-            }
-        }
+        // a late message of the sender still registered from before the rebuild
+        db1.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        let cube = match db1.response_resp(1) {
+            RespValue::Data(bytes) => deserialize_cube(&bytes).unwrap(),
+            r => panic!("Unexpected response {:?}", r),
+        };
+        let msg = MsgSyncSend {
+            vnode,
+            cookie: cookie,
+            seq: 0,
+            writes: vec![(Bytes::from_static(b"stale"), cube)],
+        };
+        db2.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            .handler_sync_send(&db2, db1.dht.node(), msg);
+        db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"stale"]);
+        assert_eq!(db2.response_resp(1), RespValue::Nil);
+        db2.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
     }
 
     #[test]
@@ -1091,6 +5788,571 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_expired_deadline() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        // requests from db1 are already expired once they reach db2
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.request_timeout = 0,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let vnode = db1.dht.key_vnode(b"test");
+        // db2 applies the writes that aren't replied
+        db1.do_cmd(0, &[b"SET", b"test", b"value", b"", One]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+        sleep_ms(100);
+        db2.do_cmd(0, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(0).0, [b"value"]);
+
+        // the others are only skipped once past the skew margin
+        {
+            let vnodes = db2.vnodes.read().unwrap();
+            let _locked = vnodes[&vnode].write().unwrap();
+            db1.do_cmd(0, &[b"SET", b"test", b"value2", b"", All]);
+            db1.do_cmd(1, &[b"GET", b"test", All]);
+            sleep_ms(DEADLINE_SKEW_MARGIN + 100);
+        }
+        // so db2 skipped the set, the requests failed either way
+        for token in 0..2 {
+            match db1.response_resp(token) {
+                RespValue::Error(_) => (),
+                r => panic!("Unexpected response {:?}", r),
+            }
+        }
+        db2.do_cmd(0, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(0).0, [b"value"]);
+    }
+
+    #[test]
+    fn test_speculative_read() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+
+        // db3 can't reply while its vnode is locked
+        let vnode = db1.dht.key_vnode(b"test");
+        let vnodes = db3.vnodes.read().unwrap();
+        let _locked = vnodes[&vnode].write().unwrap();
+        let timeout = time::Duration::from_millis(db1.config().request_timeout as _);
+        for i in 0..10 {
+            let start = time::Instant::now();
+            db1.do_cmd(i, &[b"GET", b"test", Quorum]);
+            assert_eq!(db1.response_values(i).0, [b"value"]);
+            assert!(start.elapsed() < timeout);
+        }
+    }
+
+    #[test]
+    fn test_consistency_one_single_replica() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        db1.do_cmd(0, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+
+        // only db1 can reply while the other replicas are locked
+        let vnode = db1.dht.key_vnode(b"test");
+        let timeout = time::Duration::from_millis(db1.config().request_timeout as _);
+        {
+            let vnodes2 = db2.vnodes.read().unwrap();
+            let _locked2 = vnodes2[&vnode].write().unwrap();
+            let vnodes3 = db3.vnodes.read().unwrap();
+            let _locked3 = vnodes3[&vnode].write().unwrap();
+            let start = time::Instant::now();
+            db1.do_cmd(0, &[b"GET", b"test", One]);
+            let (values, vv) = db1.response_values(0);
+            assert_eq!(values, [b"value"]);
+            db1.do_cmd(0, &[b"SET", b"test", b"value2", &encode_vv(&vv), One]);
+            assert_eq!(db1.response_resp(0), RespValue::Status("OK".into()));
+            assert!(start.elapsed() < timeout);
+        }
+
+        // the write was still sent to all replicas
+        sleep_ms(100);
+        for &db in &[&db2, &db3] {
+            db.do_cmd(0, &[b"GET", b"test", One]);
+            assert_eq!(db.response_values(0).0, [b"value2"]);
+        }
+    }
+
+    #[test]
+    fn test_coordinator_policy() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.listen_addr = "127.0.0.1:6380".parse().unwrap(),
+        );
+        let db2 = TestDatabase::with_config(
+            "127.0.0.1:9001".parse().unwrap(),
+            "t/db2",
+            false,
+            |config| config.listen_addr = "127.0.0.1:6381".parse().unwrap(),
+        );
+        let db3 = TestDatabase::with_config(
+            "127.0.0.1:9002".parse().unwrap(),
+            "t/db3",
+            false,
+            |config| config.listen_addr = "127.0.0.1:6382".parse().unwrap(),
+        );
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        // db1 can't coordinate writes to the vnode and redirects the clients
+        let vnode = db1.dht.key_vnode(b"test");
+        db1.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db1, VNodeStatus::Absent);
+        let redirects = |count| {
+            let mut addrs = HashMap::new();
+            for _ in 0..count {
+                db1.do_cmd(1, &[b"SET", b"test", b"value", b"", One]);
+                match db1.response_resp(1) {
+                    RespValue::Error(ref e) if e.starts_with(b"MOVED ") => {
+                        let addr = e.rsplit(|&b| b == b' ').next().unwrap().to_vec();
+                        *addrs.entry(addr).or_insert(0) += 1;
+                    }
+                    r => panic!("Unexpected response {:?}", r),
+                }
+            }
+            addrs
+        };
+
+        let addrs = redirects(40);
+        assert_eq!(addrs.len(), 2);
+        assert!(!addrs.contains_key(&b"127.0.0.1:6380"[..]));
+
+        db1.config.write().unwrap().coordinator_policy = CoordinatorPolicy::First;
+        assert_eq!(redirects(10).len(), 1);
+
+        // nothing is queued to the replicas, so they're equally loaded
+        db1.config.write().unwrap().coordinator_policy = CoordinatorPolicy::LeastLoaded;
+        assert_eq!(redirects(40).len(), 2);
+    }
+
+    #[test]
+    fn test_flushall() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        for i in 0..10 {
+            db1.do_cmd(1, &[b"SET", i.to_string().as_bytes(), b"value", b"", All]);
+            assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        }
+
+        // disabled by default
+        db1.do_cmd(1, &[b"FLUSHALL"]);
+        let disabled: RespValue = CommandError::CommandDisabled.into();
+        assert_eq!(db1.response_resp(1), disabled);
+        db2.do_cmd(1, &[b"GET", b"0", One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
+
+        // every node has to allow it
+        db1.config.write().unwrap().flushall_enabled = true;
+        db1.do_cmd(1, &[b"FLUSHALL"]);
+        assert_eq!(db1.response_resp(1), disabled);
+        db2.do_cmd(1, &[b"GET", b"0", One]);
+        assert_eq!(db2.response_values(1).0, [b"value"]);
+
+        db2.config.write().unwrap().flushall_enabled = true;
+        db1.do_cmd(1, &[b"FLUSHALL"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        for db in &[&db1, &db2] {
+            for i in 0..10 {
+                db.do_cmd(1, &[b"DEBUG", b"LOCALGET", i.to_string().as_bytes()]);
+                assert_eq!(db.response_resp(1), RespValue::Nil);
+            }
+        }
+        // the clocks were reset as well, new writes don't carry the old history
+        db2.do_cmd(1, &[b"SET", b"0", b"new", b"", All]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"GET", b"0", All]);
+        let (values, vv) = db1.response_values(1);
+        assert_eq!(values, [b"new"]);
+        assert_eq!(vv.iter().map(|(_, v)| v).sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn test_debug_localget() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        let vnode = db1.dht.key_vnode(b"test");
+        db2.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._storage_del(b"test");
+
+        db1.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        match db1.response_resp(1) {
+            RespValue::Data(bytes) => {
+                let cube = deserialize_cube(&bytes).unwrap();
+                let (values, _) = decode_values(render_value(cube));
+                assert_eq!(values, [b"value"]);
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+        // db2 lost its copy, LOCALGET doesn't look for it in db1
+        db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        assert_eq!(db2.response_resp(1), RespValue::Nil);
+        db2.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"missing"]);
+        assert_eq!(db2.response_resp(1), RespValue::Nil);
+    }
+
+    #[test]
+    fn test_get_skips_bad_vnode_status() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.read_fanout_extra = 0,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        // db2 replies BadVNodeStatus, quorum reads ask only one of the other
+        // two replicas so half of them have to fallback to db3
+        let vnode = db1.dht.key_vnode(b"test");
+        db2.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db2, VNodeStatus::Absent);
+        for _ in 0..20 {
+            db1.do_cmd(1, &[b"GET", b"test", Quorum]);
+            assert_eq!(db1.response_values(1).0, [b"value"]);
+        }
+    }
+
+    #[test]
+    fn test_read_repair_sync() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.read_repair = ReadRepair::Sync,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+        // syncs would eventually repair db3 as well
+        for db in &[&db1, &db2, &db3] {
+            db.config.write().unwrap().sync_auto = false;
+        }
+
+        // db3 misses the write, All fails only after it replied
+        let vnode = db1.dht.key_vnode(b"test");
+        db3.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db3, VNodeStatus::Absent);
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", All]);
+        match db1.response_resp(1) {
+            RespValue::Error(_) => (),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        db3.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db3, VNodeStatus::Ready);
+        db3.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        assert_eq!(db3.response_resp(1), RespValue::Nil);
+
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+        // the write-back was acknowledged before the GET responded
+        db3.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+        match db3.response_resp(1) {
+            RespValue::Data(bytes) => {
+                let cube = deserialize_cube(&bytes).unwrap();
+                let (values, _) = decode_values(render_value(cube));
+                assert_eq!(values, [b"value"]);
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_read_repair_async() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.read_repair = ReadRepair::Async,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+        for db in &[&db1, &db2, &db3] {
+            db.config.write().unwrap().sync_auto = false;
+        }
+
+        // db3 misses the write
+        let vnode = db1.dht.key_vnode(b"test");
+        db3.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db3, VNodeStatus::Absent);
+        db1.do_cmd(1, &[b"SET", b"test", b"value", b"", Quorum]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db3.vnodes.read().unwrap()[&vnode]
+            .write()
+            .unwrap()
+            ._set_status(&db3, VNodeStatus::Ready);
+
+        // the GET doesn't wait for the write-back but db3 eventually gets it
+        db1.do_cmd(1, &[b"GET", b"test", All]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+        let mut repaired = false;
+        for _ in 0..100 {
+            db3.do_cmd(1, &[b"DEBUG", b"LOCALGET", b"test"]);
+            if let RespValue::Data(bytes) = db3.response_resp(1) {
+                let cube = deserialize_cube(&bytes).unwrap();
+                let (values, _) = decode_values(render_value(cube));
+                assert_eq!(values, [b"value"]);
+                repaired = true;
+                break;
+            }
+            sleep_ms(10);
+        }
+        assert!(repaired);
+    }
+
+    #[test]
+    fn test_idempotent_set() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        db1.dht.rebalance().unwrap();
+
+        // without a context each SET adds a sibling, a retry would duplicate the value
+        for _ in 0..2 {
+            db1.do_cmd(1, &[b"IDEMPOTENT", b"req1", b"SET", b"test", b"value"]);
+            assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        }
+        db1.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db1.response_values(1).0, [b"value"]);
+
+        db1.do_cmd(1, &[b"IDEMPOTENT", b"req2", b"SET", b"test", b"value"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+        db1.do_cmd(1, &[b"GET", b"test"]);
+        assert_eq!(db1.response_values(1).0, [b"value", b"value"]);
+
+        // only writes can be idempotent and failures aren't kept
+        db1.do_cmd(1, &[b"IDEMPOTENT", b"req3", b"GET", b"test"]);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error("ERR InvalidCommand".into())
+        );
+        db1.do_cmd(1, &[b"IDEMPOTENT", b"req4", b"INCRBY", b"test", b"1"]);
+        assert_eq!(
+            db1.response_resp(1),
+            RespValue::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+            )
+        );
+        db1.do_cmd(1, &[b"IDEMPOTENT", b"req4", b"INCRBY", b"counter", b"1"]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        db1.do_cmd(1, &[b"IDEMPOTENT", b"req4", b"INCRBY", b"counter", b"1"]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+        db1.do_cmd(1, &[b"CGET", b"counter"]);
+        assert_eq!(db1.response_resp(1), RespValue::Int(1));
+    }
+
+    #[test]
+    fn test_debug_inflight() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::with_config(
+            "127.0.0.1:9000".parse().unwrap(),
+            "t/db1",
+            true,
+            |config| config.request_timeout = 200,
+        );
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        let inflight = |db: &TestDatabase| -> HashMap<Vec<u8>, i64> {
+            db.do_cmd(1, &[b"DEBUG", b"INFLIGHT"]);
+            match db.response_resp(1) {
+                RespValue::Array(fields) => fields
+                    .chunks(2)
+                    .map(|kv| match (&kv[0], &kv[1]) {
+                        (&RespValue::Data(ref k), &RespValue::Int(v)) => (k.to_vec(), v),
+                        _ => panic!("Can't decode inflight {:?}", kv),
+                    })
+                    .collect(),
+                r => panic!("Can't decode inflight {:?}", r),
+            }
+        };
+        assert_eq!(inflight(&db1)[&b"count"[..]], 0);
+
+        // the replicas can't reply while their vnodes are locked
+        let vnode = db1.dht.key_vnode(b"test");
+        let vnodes2 = db2.vnodes.read().unwrap();
+        let vnodes3 = db3.vnodes.read().unwrap();
+        let _locked2 = vnodes2[&vnode].write().unwrap();
+        let _locked3 = vnodes3[&vnode].write().unwrap();
+
+        db1.do_cmd(0, &[b"SET", b"test", b"value", b"", All]);
+        let report = inflight(&db1);
+        assert_eq!(report[&b"count"[..]], 1);
+        assert!(report[&b"oldest_ms"[..]] < 200);
+        assert_eq!(report[&b"gt_1000ms"[..]], 0);
+
+        // the timeout sweep clears them
+        assert_eq!(
+            db1.response_resp(0),
+            RespValue::Error("TIMEOUT Request timed out".into())
+        );
+        assert_eq!(inflight(&db1)[&b"count"[..]], 0);
+    }
+
+    #[test]
+    fn test_selftest() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"DEBUG", b"SELFTEST", b"run1"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+        // test keys are cleaned up
+        db.do_cmd(1, &[b"GET", b"sucredb-selftest:run1:0", One]);
+        assert!(db.response_values(1).0.is_empty());
+        // a random run id by default
+        db.do_cmd(1, &[b"DEBUG", b"SELFTEST"]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+    }
+
+    #[test]
+    fn test_selftest_divergent_replica() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let all = |config: &mut config::Config| {
+            config.consistency_read = ConsistencyLevel::All;
+            config.consistency_write = ConsistencyLevel::All;
+        };
+        let db1 = TestDatabase::with_config("127.0.0.1:9000".parse().unwrap(), "t/db1", true, all);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        db1.do_cmd(1, &[b"DEBUG", b"SELFTEST", b"run1"]);
+        assert_eq!(db1.response_resp(1), RespValue::Status("OK".into()));
+
+        // bulk loaded writes aren't replicated, so db2 diverges from the others
+        db2.do_cmd(1, &[b"CLUSTER", b"BULKLOAD", b"START"]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+        db2.do_cmd(1, &[b"SET", b"sucredb-selftest:run1:0", b"divergent", b"", One]);
+        assert_eq!(db2.response_resp(1), RespValue::Status("OK".into()));
+
+        db1.do_cmd(1, &[b"DEBUG", b"SELFTEST", b"run1"]);
+        match db1.response_resp(1) {
+            RespValue::Array(ref failures) if failures.len() == 1 => match failures[0] {
+                RespValue::Data(ref f) => assert!(f.starts_with(b"sucredb-selftest:run1:0: ")),
+                ref r => panic!("Unexpected failure {:?}", r),
+            },
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cluster_down() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        drop(db2);
+        drop(db3);
+        sleep_ms(200);
+
+        db1.do_cmd(0, &[b"SET", b"key", b"value", b"", Quorum]);
+        assert_eq!(
+            db1.response_resp(0),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        db1.do_cmd(0, &[b"GET", b"key", Quorum]);
+        assert_eq!(
+            db1.response_resp(0),
+            RespValue::Error("CLUSTERDOWN Not enough replicas available".into())
+        );
+        // the write wasn't partially applied
+        db1.do_cmd(0, &[b"GET", b"key", One]);
+        assert_eq!(db1.response_values(0).0.len(), 0);
+    }
+
     #[test]
     fn test_consistency_level() {
         let _ = fs::remove_dir_all("t/");
@@ -1124,9 +6386,9 @@ mod tests {
         }
         for &cl in &[All] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("CLUSTERDOWN Not enough replicas available".into()));
             db1.do_cmd(0, &[b"GETSET", b"other", b"", b"", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("CLUSTERDOWN Not enough replicas available".into()));
         }
 
         drop(db2);
@@ -1138,9 +6400,9 @@ mod tests {
         }
         for &cl in &[Quorum, All] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("CLUSTERDOWN Not enough replicas available".into()));
             db1.do_cmd(0, &[b"GETSET", b"other", b"", b"", cl]);
-            assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+            assert_eq!(db1.response_resp(0), RespValue::Error("CLUSTERDOWN Not enough replicas available".into()));
         }
     }
 
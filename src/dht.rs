@@ -16,13 +16,14 @@ use serde::Serialize;
 use config::Config;
 use database::{NodeId, VNodeId};
 use fabric::{Fabric, FabricMsg, FabricMsgRef, FabricMsgType};
-use hash::{hash_slot, HASH_SLOTS};
-use types::PhysicalNodeId;
+use hash::{hash_slot, raw_hash_slot, HASH_SLOTS};
+use types::{PhysicalNodeId, RoutingPolicy};
 use utils::{split_u64, GenericError, IdHashMap, IdHashSet};
 use version_vector::VersionVector;
 
 // can be called by the network thread or a worker doing a dht mutation
-pub type DHTChangeFn = Box<Fn() + Send + Sync>;
+// called with the ring epoch after every ring change
+pub type DHTChangeFn = Box<Fn(u64) + Send + Sync>;
 
 // rwlock needs metadata to be sync, as it's read concurrently by multiple threads
 pub trait Metadata:
@@ -49,6 +50,8 @@ const DHT_AAE_TRIGGER_INTERVAL_MS: u64 = 1_000;
 pub struct DHT<T: Metadata> {
     node: NodeId,
     inner: Arc<RwLock<Inner<T>>>,
+    // longest prefixes first
+    routing_overrides: Vec<(Vec<u8>, RoutingPolicy)>,
 }
 
 struct Inner<T: Metadata> {
@@ -59,6 +62,12 @@ struct Inner<T: Metadata> {
     next_req_broadcast: Instant,
     sync_on_connect: bool,
     sync_aae: bool,
+    // failure detection
+    next_heartbeat: Instant,
+    heartbeat_interval: Duration,
+    failure_timeout: Duration,
+    last_seen: IdHashMap<NodeId, Instant>,
+    down_nodes: IdHashSet<NodeId>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
@@ -74,7 +83,7 @@ enum NodeStatus {
 use self::NodeStatus::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
-enum VNodeNodeStatus {
+pub enum VNodeNodeStatus {
     // Takes read/write traffic
     Owner,
     // Takes write traffic while it bootstraps from an Owner
@@ -127,6 +136,30 @@ impl RingDescription {
             partitions: partitions,
         }
     }
+
+    /// The partition count must be a power of 2 between 32 and 1024 so the
+    /// hash slots split evenly between partitions (see `DHT::key_vnode`).
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if !self.partitions.is_power_of_two() {
+            return Err(format!(
+                "Partition count must be a power of 2, got {}",
+                self.partitions
+            ).into());
+        }
+        if self.partitions < 32 || self.partitions > 1024 {
+            return Err(format!(
+                "Partition count must be between 32 and 1024, got {}",
+                self.partitions
+            ).into());
+        }
+        if self.replication_factor < 1 || self.replication_factor > 6 {
+            return Err(format!(
+                "Replication factor must be between 1 and 6, got {}",
+                self.replication_factor
+            ).into());
+        }
+        Ok(())
+    }
 }
 
 impl<T: Metadata> Ring<T> {
@@ -214,6 +247,14 @@ impl<T: Metadata> Ring<T> {
         Ok(())
     }
 
+    // Returns the address of a valid node already using `node` id, if it isn't `addr`
+    fn node_id_conflict(&self, node: NodeId, addr: SocketAddr) -> Option<SocketAddr> {
+        match self.nodes.get(&node) {
+            Some(n) if n.status == Valid && n.addr != addr => Some(n.addr),
+            _ => None,
+        }
+    }
+
     fn remove_node(&mut self, this: NodeId, removed: NodeId) -> Result<(), GenericError> {
         self.version.event(this);
         if let Some(node) = self.nodes.get_mut(&removed) {
@@ -544,6 +585,46 @@ impl<T: Metadata> Ring<T> {
         self.is_valid()
     }
 
+    fn epoch(&self) -> u64 {
+        self.version.iter().map(|(_, v)| v).sum()
+    }
+
+    // True if all vnodes are fully replicated and no node is doing
+    // more work than the rebalance target, in which case rebalance wouldn't move anything.
+    fn is_balanced(&self) -> bool {
+        let valid_nodes_count = self.valid_nodes_count();
+        if valid_nodes_count == 0 {
+            return true;
+        }
+        let desired_replicas = min(valid_nodes_count, self.replication_factor);
+        let vnpn = ((self.vnodes.len() * self.replication_factor) as f64 / valid_nodes_count as f64)
+            .ceil() as usize;
+        let mut node_map: IdHashMap<NodeId, usize> = self.nodes
+            .iter()
+            .filter(|&(_, n)| n.status == Valid)
+            .map(|(&n, _)| (n, 0))
+            .collect();
+        for vn in &self.vnodes {
+            let mut replicas = 0;
+            for (node, &status) in &vn.owners {
+                if status == Retiring {
+                    continue;
+                }
+                if let Some(count) = node_map.get_mut(node) {
+                    *count += 1;
+                } else {
+                    // owned by a node that is leaving or invalid
+                    return false;
+                }
+                replicas += 1;
+            }
+            if replicas != desired_replicas {
+                return false;
+            }
+        }
+        node_map.values().all(|&count| count <= vnpn)
+    }
+
     #[cfg(test)]
     fn finish_rebalance(&mut self, this: NodeId) -> Result<(), GenericError> {
         self.is_valid().unwrap();
@@ -624,16 +705,9 @@ impl<T: Metadata> DHT<T> {
         old_node: Option<NodeId>,
     ) -> Result<DHT<T>, GenericError> {
         let addr = fabric.addr();
+        ring.validate()?;
         let partitions = ring.partitions;
         let replication_factor = ring.replication_factor;
-        assert!(
-            partitions.is_power_of_two(),
-            "Partition count must be a power of 2"
-        );
-        assert!(partitions >= 32, "Partition count must be >= 32");
-        assert!(partitions <= 1024, "Partition count must be <= 1024");
-        assert!(replication_factor >= 1, "Replication factor must be >= 1");
-        assert!(replication_factor <= 6, "Replication factor must be <= 6");
 
         let dht = Self::new(fabric, config);
         let ring = Ring::new(&config.cluster_name, partitions, replication_factor);
@@ -659,6 +733,7 @@ impl<T: Metadata> DHT<T> {
         let addr = fabric.addr();
         let dht = Self::new(fabric, config);
         let ring = Ring::deserialize(serialized_ring)?;
+        RingDescription::new(ring.replication_factor as _, ring.vnodes.len() as _).validate()?;
         dht.inner.write().unwrap().ring = ring;
 
         if let Some(old_node) = old_node {
@@ -708,6 +783,11 @@ impl<T: Metadata> DHT<T> {
         if dht.inner.write().unwrap().ring.vnodes.is_empty() {
             return Err("Didn't receive seed ring".into());
         }
+        // two nodes with the same id would stamp dots with the same actor
+        let conflict = dht.inner.read().unwrap().ring.node_id_conflict(dht.node, addr);
+        if let Some(other_addr) = conflict {
+            return Err(format!("Node id {} is already in use by {}", dht.node, other_addr).into());
+        }
 
         if let Some(old_node) = old_node {
             dht.replace_node(old_node, dht.node, addr, meta).unwrap();
@@ -726,6 +806,11 @@ impl<T: Metadata> DHT<T> {
             next_req_broadcast: Instant::now(),
             sync_aae: config.dht_sync_aae,
             sync_on_connect: config.dht_sync_on_connect,
+            next_heartbeat: Instant::now(),
+            heartbeat_interval: Duration::from_millis(config.dht_heartbeat_interval as _),
+            failure_timeout: Duration::from_millis(config.dht_failure_timeout as _),
+            last_seen: Default::default(),
+            down_nodes: Default::default(),
         }));
 
         // TODO: move this to Database
@@ -744,9 +829,17 @@ impl<T: Metadata> DHT<T> {
         fabric.register_msg_handler(FabricMsgType::DHT, Box::new(msg_cb));
         fabric.register_con_handler(Box::new(con_cb));
 
+        let mut routing_overrides: Vec<_> = config
+            .routing_overrides
+            .iter()
+            .map(|&(ref prefix, policy)| (prefix.as_bytes().to_vec(), policy))
+            .collect();
+        routing_overrides.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
         DHT {
             node: fabric.node(),
             inner: inner,
+            routing_overrides: routing_overrides,
         }
     }
 
@@ -760,6 +853,11 @@ impl<T: Metadata> DHT<T> {
     }
 
     fn on_message(inner: &mut Inner<T>, from: NodeId, msg: FabricMsg) {
+        inner.last_seen.insert(from, Instant::now());
+        if inner.down_nodes.remove(&from) {
+            info!("Node {} is back up", from);
+            Self::call_callback(inner);
+        }
         match msg {
             FabricMsg::DHTAE(version) => if inner.ring.vnodes.is_empty() {
                 warn!("Can't reply DHTAE while starting up");
@@ -790,7 +888,7 @@ impl<T: Metadata> DHT<T> {
 
     fn call_callback(inner: &Inner<T>) {
         if let Some(callback) = inner.callback.as_ref() {
-            callback();
+            callback(inner.ring.epoch());
         }
     }
 
@@ -828,21 +926,65 @@ impl<T: Metadata> DHT<T> {
         }
     }
 
+    // DHTAE messages double as heartbeats, peers reply only if their ring is newer
+    fn heartbeat(inner: &mut Inner<T>, time: Instant) {
+        let mut changed = false;
+        for (&node_id, node) in inner.ring.nodes.iter() {
+            if node_id == inner.node || node.status != NodeStatus::Valid {
+                continue;
+            }
+            let _ = inner
+                .fabric
+                .send_msg(node_id, FabricMsgRef::DHTAE(&inner.ring.version));
+
+            // nodes we never heard from get a full timeout from now
+            let last_seen = *inner.last_seen.entry(node_id).or_insert(time);
+            if time > last_seen + inner.failure_timeout && inner.down_nodes.insert(node_id) {
+                warn!(
+                    "Node {} is down, last seen {:?} ago",
+                    node_id,
+                    time - last_seen
+                );
+                changed = true;
+            }
+        }
+        inner.next_heartbeat = time + inner.heartbeat_interval;
+        if changed {
+            Self::call_callback(inner);
+        }
+    }
+
     pub fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
         Self::on_message(&mut *self.inner.write().unwrap(), from, msg);
     }
 
     pub fn handler_tick(&self, time: Instant) {
         let r_inner = self.inner.read().unwrap();
+        let heartbeat = time >= r_inner.next_heartbeat;
         if r_inner.sync_aae && time >= r_inner.next_req_broadcast {
             trace!("Triggered AAE");
             Self::broadcast_req(&*r_inner);
             drop(r_inner);
             self.inner.write().unwrap().next_req_broadcast +=
                 Duration::from_millis(DHT_AAE_TRIGGER_INTERVAL_MS);
+        } else {
+            drop(r_inner);
+        }
+        if heartbeat {
+            Self::heartbeat(&mut *self.inner.write().unwrap(), time);
         }
     }
 
+    /// Whether the node was heard from within the failure timeout
+    pub fn is_node_up(&self, node: NodeId) -> bool {
+        let inner = self.inner.read().unwrap();
+        node == inner.node || !inner.down_nodes.contains(&node)
+    }
+
+    pub fn down_nodes(&self) -> Vec<NodeId> {
+        self.inner.read().unwrap().down_nodes.iter().cloned().collect()
+    }
+
     pub fn set_callback(&self, callback: DHTChangeFn) {
         self.inner.write().unwrap().callback = Some(callback);
     }
@@ -861,8 +1003,17 @@ impl<T: Metadata> DHT<T> {
     }
 
     pub fn key_vnode(&self, key: &[u8]) -> VNodeId {
+        let partitions = self.partitions() as VNodeId;
+        let slot = match self.routing_overrides
+            .iter()
+            .find(|&&(ref prefix, _)| key.starts_with(prefix))
+        {
+            Some(&(_, RoutingPolicy::VNode(vnode))) => return vnode % partitions,
+            Some(&(_, RoutingPolicy::Spread)) => raw_hash_slot(key),
+            None => hash_slot(key),
+        };
         // use / instead of % to get continuous hash slots for each vnode
-        (hash_slot(key) / (HASH_SLOTS / self.partitions() as VNodeId)) as VNodeId
+        (slot / (HASH_SLOTS / partitions)) as VNodeId
     }
 
     pub fn vnodes_for_node(&self, node: NodeId) -> (Vec<VNodeId>, Vec<VNodeId>) {
@@ -935,6 +1086,47 @@ impl<T: Metadata> DHT<T> {
         Ring::serialize(&self.inner.read().unwrap().ring).expect("Can't serialize ring")
     }
 
+    /// Replaces the ring with one returned by `save_ring`, to recover a cluster
+    /// that lost its metadata. Unlike a DHTSync it isn't merged with the current ring.
+    /// `check` runs under the ring lock with whether this node is alone in the current ring
+    /// and whether the loaded one has the same partitions and replication factor,
+    /// the ring is only replaced if it returns Ok.
+    pub fn load_ring<E, F>(
+        &self,
+        serialized_ring: &[u8],
+        check: F,
+    ) -> Result<Result<(), E>, GenericError>
+    where
+        F: FnOnce(bool, bool) -> Result<(), E>,
+    {
+        let ring = Ring::deserialize(serialized_ring)?;
+        RingDescription::new(ring.replication_factor as _, ring.vnodes.len() as _).validate()?;
+        if ring.valid_nodes_count() == 0 {
+            return Err("Ring doesn't have any valid node".into());
+        }
+        let mut inner = self.inner.write().unwrap();
+        if ring.cluster != inner.ring.cluster {
+            return Err(format!(
+                "Cluster name differs {:?} != {:?}",
+                inner.ring.cluster, ring.cluster
+            ).into());
+        }
+        let alone = inner
+            .ring
+            .nodes
+            .iter()
+            .all(|(&id, n)| id == self.node || n.status == Invalid);
+        let same_layout = ring.replication_factor == inner.ring.replication_factor
+            && ring.vnodes.len() == inner.ring.vnodes.len();
+        if let Err(e) = check(alone, same_layout) {
+            return Ok(Err(e));
+        }
+        info!("Loading ring version {:?}", ring.version);
+        inner.ring = ring;
+        Self::call_callback(&*inner);
+        Ok(Ok(()))
+    }
+
     pub fn members(&self) -> IdHashMap<NodeId, SocketAddr> {
         let inner = self.inner.read().unwrap();
         inner
@@ -946,6 +1138,30 @@ impl<T: Metadata> DHT<T> {
             .collect()
     }
 
+    /// Ring version as a single number, it increases with every change to the ring
+    pub fn epoch(&self) -> u64 {
+        self.inner.read().unwrap().ring.epoch()
+    }
+
+    /// Owners of each vnode (including pending and retiring) and their metadata
+    pub fn ring(&self) -> Vec<Vec<(NodeId, VNodeNodeStatus, T)>> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .vnodes
+            .iter()
+            .map(|vn| {
+                vn.owners
+                    .iter()
+                    .map(|(&node_id, &status)| {
+                        let node = inner.ring.nodes.get(&node_id).unwrap();
+                        (node_id, status, node.meta.clone())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
     pub fn slots(&self) -> BTreeMap<(u16, u16), Vec<(NodeId, (SocketAddr, T))>> {
         let slots_per_partition = HASH_SLOTS / self.partitions() as u16;
         let mut result = BTreeMap::new();
@@ -979,6 +1195,19 @@ impl<T: Metadata> DHT<T> {
         })
     }
 
+    /// Rebalances the ring unless it's already balanced, checked under the same lock
+    /// so concurrent changes can't get in between. Returns false if it was balanced.
+    pub fn rebalance_if_unbalanced(&self) -> Result<bool, GenericError> {
+        self.propose_if(|mut ring| {
+            if ring.is_balanced() {
+                return Ok(None);
+            }
+            info!("Rebalancing ring");
+            ring.rebalance(self.node)?;
+            Ok(Some(ring))
+        })
+    }
+
     #[cfg(test)]
     pub fn finish_rebalance(&self) -> Result<(), GenericError> {
         info!("Finish Rebalancing ring");
@@ -1037,14 +1266,26 @@ impl<T: Metadata> DHT<T> {
     fn propose<C>(&self, proposal: C) -> Result<(), GenericError>
     where
         C: FnOnce(Ring<T>) -> Result<Ring<T>, GenericError>,
+    {
+        self.propose_if(|ring| proposal(ring).map(Some)).map(|_| ())
+    }
+
+    // like propose but the proposal can return None to leave the ring as is,
+    // returns if the ring changed
+    fn propose_if<C>(&self, proposal: C) -> Result<bool, GenericError>
+    where
+        C: FnOnce(Ring<T>) -> Result<Option<Ring<T>>, GenericError>,
     {
         let mut inner = self.inner.write().unwrap();
         debug!("Executing proposal");
-        inner.ring = proposal(inner.ring.clone())?;
+        match proposal(inner.ring.clone())? {
+            Some(ring) => inner.ring = ring,
+            None => return Ok(false),
+        }
         info!("Proposing new ring version {:?}", inner.ring.version);
         Self::broadcast(&mut *inner);
         Self::call_callback(&mut *inner);
-        Ok(())
+        Ok(true)
     }
 }
 
@@ -1055,6 +1296,7 @@ mod tests {
     use env_logger;
     use fabric::Fabric;
     use rand::{thread_rng, Rng};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use utils::join_u64;
     use utils::sleep_ms;
 
@@ -1095,6 +1337,19 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn test_dht_init_partitions() {
+        let _ = env_logger::try_init();
+        let config: Config = Default::default();
+
+        let cases = [(100, false), (16, false), (2048, false), (64, true), (128, true)];
+        for &(partitions, valid) in &cases {
+            let fabric = Arc::new(Fabric::new(0, &config).unwrap());
+            let dht = DHT::init(fabric, &config, (), RingDescription::new(3, partitions), None);
+            assert_eq!(dht.is_ok(), valid, "partitions {}", partitions);
+        }
+    }
+
     #[test]
     fn test_dht_init() {
         let _ = env_logger::try_init();
@@ -1113,6 +1368,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_key_vnode_hashtag() {
+        let _ = env_logger::try_init();
+        let config: Config = Default::default();
+        let fabric = Arc::new(Fabric::new(0, &config).unwrap());
+        let dht = DHT::init(fabric, &config, (), RingDescription::new(1, 64), None).unwrap();
+
+        assert_eq!(dht.key_vnode(b"{user1}:a"), dht.key_vnode(b"{user1}:b"));
+        assert_eq!(dht.key_vnode(b"{user1}:a"), dht.key_vnode(b"user1"));
+        // different tags should spread over the vnodes
+        let distinct = (0..100)
+            .map(|i| dht.key_vnode(format!("{{user{}}}:a", i).as_bytes()))
+            .collect::<IdHashSet<_>>();
+        assert!(distinct.len() > 32, "{:?}", distinct);
+    }
+
+    #[test]
+    fn test_key_vnode_routing_overrides() {
+        let _ = env_logger::try_init();
+        let mut config: Config = Default::default();
+        config.routing_overrides = vec![
+            ("{hot}".into(), RoutingPolicy::Spread),
+            ("pinned:".into(), RoutingPolicy::VNode(5)),
+            ("pinned:other:".into(), RoutingPolicy::VNode(64 + 7)),
+        ];
+        let fabric = Arc::new(Fabric::new(0, &config).unwrap());
+        let dht = DHT::init(fabric, &config, (), RingDescription::new(1, 64), None).unwrap();
+        let default_vnode = |key: &[u8]| (hash_slot(key) / (HASH_SLOTS / 64)) as VNodeId;
+
+        // the hashtag would put all of them in the same vnode
+        let keys: Vec<_> = (0..100).map(|i| format!("{{hot}}:{}", i)).collect();
+        let distinct = keys.iter()
+            .map(|k| dht.key_vnode(k.as_bytes()))
+            .collect::<IdHashSet<_>>();
+        assert!(distinct.len() > 32, "{:?}", distinct);
+        assert!(keys.iter().all(|k| default_vnode(k.as_bytes()) == default_vnode(b"hot")));
+
+        // pinned, the longest prefix wins
+        for i in 0..100 {
+            let key = format!("pinned:{}", i);
+            assert_eq!(dht.key_vnode(key.as_bytes()), 5);
+            let key = format!("pinned:other:{}", i);
+            assert_eq!(dht.key_vnode(key.as_bytes()), 7);
+        }
+        assert!((0..100).any(|i| default_vnode(format!("pinned:{}", i).as_bytes()) != 5));
+
+        // other keys aren't affected
+        for &key in &[&b"{cold}:1"[..], b"user1", b"hot", b"pinned"] {
+            assert_eq!(dht.key_vnode(key), default_vnode(key));
+        }
+    }
+
     #[test]
     fn test_dht_join() {
         let _ = env_logger::try_init();
@@ -1188,6 +1495,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dht_join_dup_node_id() {
+        let _ = env_logger::try_init();
+        let config1: Config = Config {
+            fabric_addr: "127.0.0.1:3331".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2: Config = Config {
+            fabric_addr: "127.0.0.1:3332".parse().unwrap(),
+            ..Default::default()
+        };
+        let config3: Config = Config {
+            fabric_addr: "127.0.0.1:3333".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let fabric1 = Arc::new(Fabric::new(join_u64(0, 0), &config1).unwrap());
+        let _dht1 = DHT::init(fabric1, &config1, (), RingDescription::new(2, 32), None).unwrap();
+
+        let fabric2 = Arc::new(Fabric::new(join_u64(1, 0), &config2).unwrap());
+        let _dht2 = DHT::join_cluster(fabric2, &config2, (), &[config1.fabric_addr], None).unwrap();
+
+        sleep_ms(100);
+        // node 3 claims the id of node 2
+        let fabric3 = Arc::new(Fabric::new(join_u64(1, 0), &config3).unwrap());
+        assert!(DHT::join_cluster(fabric3, &config3, (), &[config1.fabric_addr], None).is_err());
+    }
+
+    #[test]
+    fn test_dht_failure_detection() {
+        let _ = env_logger::try_init();
+        let config1: Config = Config {
+            fabric_addr: "127.0.0.1:3331".parse().unwrap(),
+            dht_heartbeat_interval: 50,
+            dht_failure_timeout: 300,
+            ..Default::default()
+        };
+        let config2: Config = Config {
+            fabric_addr: "127.0.0.1:3332".parse().unwrap(),
+            dht_heartbeat_interval: 50,
+            dht_failure_timeout: 300,
+            ..Default::default()
+        };
+
+        let fabric1 = Arc::new(Fabric::new(join_u64(0, 0), &config1).unwrap());
+        let dht1 = DHT::init(fabric1, &config1, (), RingDescription::new(2, 32), None).unwrap();
+
+        let fabric2 = Arc::new(Fabric::new(join_u64(1, 0), &config2).unwrap());
+        let dht2 = DHT::join_cluster(fabric2, &config2, (), &[config1.fabric_addr], None).unwrap();
+
+        for _ in 0..10 {
+            dht1.handler_tick(Instant::now());
+            dht2.handler_tick(Instant::now());
+            sleep_ms(50);
+        }
+        assert!(dht1.is_node_up(join_u64(1, 0)));
+        assert!(dht1.down_nodes().is_empty());
+        let changes = Arc::new(AtomicUsize::new(0));
+        let changes_cb = changes.clone();
+        dht1.set_callback(Box::new(move |_| {
+            changes_cb.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        // node 2 stops heartbeating
+        drop(dht2);
+        let start = Instant::now();
+        while dht1.is_node_up(join_u64(1, 0)) {
+            assert!(start.elapsed() < Duration::from_millis(300 + 200));
+            dht1.handler_tick(Instant::now());
+            sleep_ms(10);
+        }
+        assert_eq!(dht1.down_nodes(), &[join_u64(1, 0)]);
+        // the node going down is a cluster change too
+        assert_eq!(changes.load(Ordering::SeqCst), 1);
+    }
+
     #[test]
     #[should_panic]
     fn test_dht_join_wrong_cluster() {
@@ -1277,6 +1660,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rebalance_skewed() {
+        let _ = env_logger::try_init();
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let mut ring = Ring::new("", 32, 1);
+        ring.join_node(0, join_u64(0, 0), addr, ()).unwrap();
+        ring.rebalance(0).unwrap();
+        ring.finish_rebalance(0).unwrap();
+        assert!(ring.is_balanced());
+
+        // node 0 owns everything
+        for i in 1..4 {
+            ring.join_node(0, join_u64(i, 0), addr, ()).unwrap();
+        }
+        assert!(!ring.is_balanced());
+
+        ring.rebalance(0).unwrap();
+        assert!(ring.is_balanced());
+        ring.finish_rebalance(0).unwrap();
+        assert!(ring.is_balanced());
+        for i in 0..4 {
+            let owned = ring.vnodes
+                .iter()
+                .filter(|vn| vn.owners.contains_key(&join_u64(i, 0)))
+                .count();
+            assert_eq!(owned, 8);
+        }
+    }
+
     #[test]
     fn test_rebalance() {
         let _ = env_logger::try_init();
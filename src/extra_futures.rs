@@ -0,0 +1,501 @@
+use std::cmp::min;
+use std::io;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use futures::{Async, Future, Poll, Stream};
+use rand::{thread_rng, Rng};
+use tokio_core::reactor::{self, Handle};
+use tokio_io::{io as tokio_io, AsyncRead};
+
+use utils::duration_millis;
+
+/// How many times and how often an operation is retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // total number of attempts, including the first
+    pub attempts: u32,
+    // delay before the first retry, doubles on each retry
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+    // a random delay up to this is added to each backoff
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        let mut delay = self.backoff;
+        for _ in 1..retry {
+            delay = delay * 2;
+            if delay >= self.backoff_max {
+                break;
+            }
+        }
+        delay = min(delay, self.backoff_max);
+        let jitter_ms = duration_millis(self.jitter);
+        if jitter_ms != 0 {
+            delay += Duration::from_millis(thread_rng().gen_range(0, jitter_ms + 1));
+        }
+        delay
+    }
+}
+
+enum RetryState<A> {
+    Running(A),
+    Sleeping(reactor::Timeout),
+}
+
+/// Retries the future returned by `action` with backoff until it succeeds,
+/// errors with a non retryable error or all attempts are exhausted.
+/// Errors with the last error returned by `action`.
+pub struct Retry<A, F, R> {
+    handle: Handle,
+    policy: RetryPolicy,
+    action: F,
+    retryable: R,
+    attempt: u32,
+    state: RetryState<A>,
+}
+
+impl<A, F, R> Retry<A, F, R>
+where
+    A: Future,
+    F: FnMut() -> A,
+    R: Fn(&A::Error) -> bool,
+{
+    pub fn new(handle: &Handle, policy: RetryPolicy, mut action: F, retryable: R) -> Self {
+        let first = action();
+        Retry {
+            handle: handle.clone(),
+            policy: policy,
+            action: action,
+            retryable: retryable,
+            attempt: 1,
+            state: RetryState::Running(first),
+        }
+    }
+}
+
+impl<A, F, R> Future for Retry<A, F, R>
+where
+    A: Future,
+    F: FnMut() -> A,
+    R: Fn(&A::Error) -> bool,
+{
+    type Item = A::Item;
+    type Error = A::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self.state {
+                RetryState::Running(ref mut action) => match action.poll() {
+                    Ok(result) => return Ok(result),
+                    Err(e) => {
+                        if self.attempt >= self.policy.attempts || !(self.retryable)(&e) {
+                            return Err(e);
+                        }
+                        let delay = self.policy.delay(self.attempt);
+                        debug!("Retrying attempt {} in {:?}", self.attempt + 1, delay);
+                        match reactor::Timeout::new(delay, &self.handle) {
+                            Ok(timeout) => RetryState::Sleeping(timeout),
+                            // the event loop is gone
+                            Err(_) => return Err(e),
+                        }
+                    }
+                },
+                RetryState::Sleeping(ref mut timeout) => match timeout.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) | Err(_) => {
+                        self.attempt += 1;
+                        RetryState::Running((self.action)())
+                    }
+                },
+            };
+            self.state = next;
+        }
+    }
+}
+
+/// Fails with a TimedOut io error if `future` doesn't complete within `duration`
+pub struct Timeout<F> {
+    future: F,
+    timer: reactor::Timeout,
+}
+
+impl<F> Timeout<F>
+where
+    F: Future,
+    F::Error: From<io::Error>,
+{
+    pub fn new(future: F, duration: Duration, handle: &Handle) -> io::Result<Self> {
+        Ok(Timeout {
+            future: future,
+            timer: reactor::Timeout::new(duration, handle)?,
+        })
+    }
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future,
+    F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(result) = self.future.poll()? {
+            return Ok(Async::Ready(result));
+        }
+        match self.timer.poll()? {
+            Async::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out").into()),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Fails with a TimedOut io error if `stream` doesn't yield an item for `duration`
+pub struct IdleTimeout<S> {
+    stream: S,
+    duration: Duration,
+    timer: reactor::Timeout,
+}
+
+impl<S> IdleTimeout<S>
+where
+    S: Stream,
+    S::Error: From<io::Error>,
+{
+    pub fn new(stream: S, duration: Duration, handle: &Handle) -> io::Result<Self> {
+        Ok(IdleTimeout {
+            stream: stream,
+            duration: duration,
+            timer: reactor::Timeout::new(duration, handle)?,
+        })
+    }
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+    S: Stream,
+    S::Error: From<io::Error>,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::Ready(item) = self.stream.poll()? {
+            self.timer.reset(Instant::now() + self.duration);
+            return Ok(Async::Ready(item));
+        }
+        match self.timer.poll()? {
+            Async::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, "Idle timeout").into()),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Reads a u32(le) length prefixed frame, which may arrive in pieces,
+/// frames longer than `max_len` fail with an InvalidData error.
+pub fn read_frame<R: AsyncRead + 'static>(
+    reader: R,
+    max_len: usize,
+) -> Box<Future<Item = (R, Vec<u8>), Error = io::Error>> {
+    let fut = tokio_io::read_exact(reader, [0u8; 4]).and_then(move |(reader, prefix)| {
+        let len = (&prefix[..]).read_u32::<LittleEndian>()? as usize;
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame of {} bytes exceeds max of {}", len, max_len),
+            ));
+        }
+        Ok(tokio_io::read_exact(reader, vec![0u8; len]))
+    });
+    Box::new(fut.flatten())
+}
+
+/// Accumulates the items of `stream` yielding them in batches once `count_max` items
+/// or `size_max` bytes (as measured by `size_fn`) are accumulated.
+/// Like SignaledChan a partial batch is yielded whenever the stream is momentarily drained.
+pub struct Batch<S: Stream, F> {
+    stream: S,
+    size_fn: F,
+    count_max: usize,
+    size_max: usize,
+    items: Vec<S::Item>,
+    size: usize,
+    done: bool,
+}
+
+impl<S, F> Batch<S, F>
+where
+    S: Stream,
+    F: Fn(&S::Item) -> usize,
+{
+    pub fn new(stream: S, count_max: usize, size_max: usize, size_fn: F) -> Self {
+        assert!(count_max > 0);
+        Batch {
+            stream: stream,
+            size_fn: size_fn,
+            count_max: count_max,
+            size_max: size_max,
+            items: Vec::new(),
+            size: 0,
+            done: false,
+        }
+    }
+
+    fn take(&mut self) -> Option<Vec<S::Item>> {
+        self.size = 0;
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(::std::mem::replace(&mut self.items, Vec::new()))
+        }
+    }
+}
+
+impl<S, F> Stream for Batch<S, F>
+where
+    S: Stream,
+    F: Fn(&S::Item) -> usize,
+{
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        loop {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    self.size += (self.size_fn)(&item);
+                    self.items.push(item);
+                    if self.items.len() >= self.count_max || self.size >= self.size_max {
+                        return Ok(Async::Ready(self.take()));
+                    }
+                }
+                Async::Ready(None) => {
+                    self.done = true;
+                    return Ok(Async::Ready(self.take()));
+                }
+                Async::NotReady => {
+                    return Ok(match self.take() {
+                        Some(items) => Async::Ready(Some(items)),
+                        None => Async::NotReady,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Whether an io error is worth retrying
+pub fn io_error_retryable(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::NotFound => {
+            false
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc;
+    use futures::{future, stream};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts: attempts,
+            backoff: Duration::from_millis(1),
+            backoff_max: Duration::from_millis(10),
+            jitter: Duration::from_millis(1),
+        }
+    }
+
+    fn failing_action(
+        calls: Rc<Cell<u32>>,
+        failures: u32,
+        kind: io::ErrorKind,
+    ) -> impl FnMut() -> future::FutureResult<u32, io::Error> {
+        move || {
+            calls.set(calls.get() + 1);
+            if calls.get() <= failures {
+                future::err(kind.into())
+            } else {
+                future::ok(calls.get())
+            }
+        }
+    }
+
+    #[test]
+    fn test_retry_success() {
+        let mut core = Core::new().unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let action = failing_action(calls.clone(), 2, io::ErrorKind::ConnectionRefused);
+        let retry = Retry::new(&core.handle(), policy(3), action, io_error_retryable);
+        assert_eq!(core.run(retry).unwrap(), 3);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        let mut core = Core::new().unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let action = failing_action(calls.clone(), 10, io::ErrorKind::ConnectionRefused);
+        let retry = Retry::new(&core.handle(), policy(3), action, io_error_retryable);
+        assert_eq!(
+            core.run(retry).unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_retry_not_retryable() {
+        let mut core = Core::new().unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let action = failing_action(calls.clone(), 10, io::ErrorKind::InvalidData);
+        let retry = Retry::new(&core.handle(), policy(3), action, io_error_retryable);
+        assert_eq!(core.run(retry).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut core = Core::new().unwrap();
+        let never = future::empty::<(), io::Error>();
+        let timeout = Timeout::new(never, Duration::from_millis(10), &core.handle()).unwrap();
+        assert_eq!(core.run(timeout).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        let quick = future::ok::<_, io::Error>(1);
+        let timeout = Timeout::new(quick, Duration::from_millis(10), &core.handle()).unwrap();
+        assert_eq!(core.run(timeout).unwrap(), 1);
+
+        let quick_err = future::err::<(), _>(io::Error::from(io::ErrorKind::InvalidData));
+        let timeout = Timeout::new(quick_err, Duration::from_millis(10), &core.handle()).unwrap();
+        assert_eq!(core.run(timeout).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // returns at most one chunk per read
+    struct ChunkedReader(Vec<Vec<u8>>);
+
+    impl io::Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let len = min(buf.len(), self.0[0].len());
+            buf[..len].copy_from_slice(&self.0[0][..len]);
+            let rest = self.0[0].split_off(len);
+            self.0[0] = rest;
+            if self.0[0].is_empty() {
+                self.0.remove(0);
+            }
+            Ok(len)
+        }
+    }
+
+    impl AsyncRead for ChunkedReader {}
+
+    #[test]
+    fn test_read_frame() {
+        let reader = ChunkedReader(vec![
+            vec![5],
+            vec![0, 0],
+            vec![0, b'h', b'e'],
+            vec![b'l'],
+            vec![b'l', b'o', 2, 0, 0, 0, b'!'],
+            vec![b'!'],
+        ]);
+        let (reader, frame) = read_frame(reader, 5).wait().unwrap();
+        assert_eq!(frame, b"hello");
+        let (reader, frame) = read_frame(reader, 5).wait().unwrap();
+        assert_eq!(frame, b"!!");
+        // eof
+        let error = read_frame(reader, 5).wait().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let reader = ChunkedReader(vec![vec![6, 0, 0, 0], b"hello!".to_vec()]);
+        let error = read_frame(reader, 5).wait().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_batch_count() {
+        let items = stream::iter_ok::<_, ()>(1..8);
+        let batches: Vec<_> = Batch::new(items, 3, usize::max_value(), |_| 1)
+            .wait()
+            .map(|b| b.unwrap())
+            .collect();
+        assert_eq!(batches, vec![vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let items = stream::iter_ok::<_, ()>(vec!["ab", "cd", "ef", "g", "hijkl"]);
+        let batches: Vec<_> = Batch::new(items, 100, 5, |s: &&str| s.len())
+            .wait()
+            .map(|b| b.unwrap())
+            .collect();
+        assert_eq!(batches, vec![vec!["ab", "cd", "ef"], vec!["g", "hijkl"]]);
+    }
+
+    #[test]
+    fn test_batch_drain() {
+        let (tx, rx) = mpsc::unbounded();
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+        let mut batches = Batch::new(rx, 100, usize::max_value(), |_| 1).wait();
+        // the channel is momentarily drained
+        assert_eq!(batches.next(), Some(Ok(vec![1, 2])));
+        tx.unbounded_send(3).unwrap();
+        drop(tx);
+        assert_eq!(batches.next(), Some(Ok(vec![3])));
+        assert_eq!(batches.next(), None);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (tx, rx) = mpsc::unbounded::<u32>();
+        let rx = rx.map_err(|_| io::Error::from(io::ErrorKind::Other));
+        let idle = IdleTimeout::new(rx, Duration::from_millis(50), &handle).unwrap();
+        // items arriving more often than the timeout keep the stream alive
+        let feed = stream::iter_ok::<_, ()>(0..5).for_each(move |i| {
+            tx.unbounded_send(i).unwrap();
+            reactor::Timeout::new(Duration::from_millis(20), &handle)
+                .unwrap()
+                .map_err(|_| ())
+        });
+        core.handle().spawn(feed.map(|_| ()));
+        let result = core.run(idle.take(5).collect()).unwrap();
+        assert_eq!(result, vec![0, 1, 2, 3, 4]);
+
+        let never = stream::poll_fn(|| -> Poll<Option<()>, io::Error> { Ok(Async::NotReady) });
+        let idle = IdleTimeout::new(never, Duration::from_millis(10), &core.handle()).unwrap();
+        assert_eq!(core.run(idle.collect()).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            backoff: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(50),
+            jitter: Duration::from_millis(0),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(20));
+        assert_eq!(policy.delay(3), Duration::from_millis(40));
+        assert_eq!(policy.delay(4), Duration::from_millis(50));
+        assert_eq!(policy.delay(20), Duration::from_millis(50));
+    }
+}
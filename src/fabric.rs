@@ -21,7 +21,9 @@ use tokio_io::{io as tokio_io, AsyncRead};
 
 use config::Config;
 use database::NodeId;
+use extra_futures::{IdleTimeout, Timeout};
 pub use fabric_msg::*;
+use hash::stable_hash;
 use utils::{into_io_error, GenericError, IdHashMap};
 
 // u32(le) payload len + bincode payload
@@ -78,10 +80,14 @@ pub type FabricMsgFn = Box<FnMut(NodeId, FabricMsg) + Send>;
 pub type FabricConFn = Box<FnMut(NodeId) + Send>;
 
 type SenderChan = fmpsc::UnboundedSender<Bytes>;
+// msgs sent to a connection that its writer didn't pick up yet
+type QueueDepth = Arc<AtomicUsize>;
 type InitType = io::Result<(Arc<SharedContext>, foneshot::Sender<()>)>;
 
 const FABRIC_KEEPALIVE_MS: u64 = 1000;
 const FABRIC_RECONNECT_INTERVAL_MS: u64 = 1000;
+// connections ping every fabric timeout and are dropped after this many without a msg
+const FABRIC_IDLE_TIMEOUTS: u32 = 5;
 
 /// The messaging network that encompasses all nodes of the cluster
 /// using the fabric you can send messages (best-effort delivery)
@@ -122,8 +128,14 @@ struct SharedContext {
     con_handlers: Mutex<Vec<FabricConFn>>,
     // TODO: unify nodes_addr and connections maps
     nodes_addr: RwLock<IdHashMap<NodeId, SocketAddr>>,
-    connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChan)>>>,
+    connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChan, QueueDepth)>>>,
     connection_gen: AtomicUsize,
+    // msgs that couldn't be handed to any connection
+    dropped: AtomicUsize,
+    // bounds the handshake with a peer
+    timeout: Duration,
+    // peers must route keys the same way, see routing_fingerprint
+    routing: u64,
 }
 
 impl SharedContext {
@@ -135,7 +147,7 @@ impl SharedContext {
         self.nodes_addr.write().unwrap().remove(&peer)
     }
 
-    fn register_connection(&self, peer: NodeId, sender: SenderChan) -> usize {
+    fn register_connection(&self, peer: NodeId, sender: SenderChan, queued: QueueDepth) -> usize {
         let connection_id = self.connection_gen.fetch_add(1, Ordering::Relaxed);
         debug!(
             "register_connection peer: {}, id: {:?}",
@@ -145,7 +157,7 @@ impl SharedContext {
             let mut locked = self.connections.write().unwrap();
             let entry = locked.entry(peer).or_insert_with(Default::default);
             let is_new = entry.is_empty();
-            entry.push((connection_id, sender));
+            entry.push((connection_id, sender, queued));
             is_new
         };
         if is_new {
@@ -184,6 +196,9 @@ impl ReaderContext {
     }
 
     fn dispatch(&self, msg: FabricMsg) {
+        if let FabricMsg::Ping = msg {
+            return;
+        }
         let msg_type = msg.get_type();
         if let Some(handler) = self.context
             .msg_handlers
@@ -200,8 +215,13 @@ impl ReaderContext {
 }
 
 impl WriterContext {
-    fn new(context: Arc<SharedContext>, peer: NodeId, sender: SenderChan) -> Self {
-        let connection_id = context.register_connection(peer, sender);
+    fn new(
+        context: Arc<SharedContext>,
+        peer: NodeId,
+        sender: SenderChan,
+        queued: QueueDepth,
+    ) -> Self {
+        let connection_id = context.register_connection(peer, sender, queued);
         WriterContext {
             context: context,
             peer: peer,
@@ -229,10 +249,11 @@ impl Fabric {
             .for_each(move |(socket, addr)| {
                 debug!("Accepting connection from {:?}", addr);
                 let context_cloned = context.clone();
+                let handle_cloned = handle.clone();
                 handle.spawn(
-                    Self::handshake(socket, context_cloned)
+                    Self::handshake(socket, context_cloned, &handle)
                         .and_then(move |(s, peer_id, context)| {
-                            Self::steady_connection(s, peer_id, context)
+                            Self::steady_connection(s, peer_id, context, &handle_cloned)
                         })
                         .then(|_| Ok(())),
                 );
@@ -252,6 +273,8 @@ impl Fabric {
         let context1 = context.clone();
         let handle1 = handle.clone();
         let handle2 = handle.clone();
+        let handle3 = handle.clone();
+        let handle4 = handle.clone();
 
         let fut = tokio::net::TcpStream::connect(&addr, &handle)
             .select2(
@@ -265,8 +288,10 @@ impl Fabric {
                 Ok(Either::B(_)) => Err(io::ErrorKind::TimedOut.into()),
                 Err(either) => Err(either.split().0),
             })
-            .and_then(move |s| Self::handshake(s, context))
-            .and_then(move |(s, peer_id, context)| Self::steady_connection(s, peer_id, context))
+            .and_then(move |s| Self::handshake(s, context, &handle3))
+            .and_then(move |(s, peer_id, context)| {
+                Self::steady_connection(s, peer_id, context, &handle4)
+            })
             .then(move |_| {
                 tokio::reactor::Timeout::new(
                     Duration::from_millis(FABRIC_RECONNECT_INTERVAL_MS),
@@ -296,20 +321,45 @@ impl Fabric {
     fn handshake(
         socket: tokio::net::TcpStream,
         context: Arc<SharedContext>,
+        handle: &tokio::reactor::Handle,
     ) -> Box<Future<Item = (tokio::net::TcpStream, NodeId, Arc<SharedContext>), Error = io::Error>>
     {
         debug!("Stablished connection with {:?}", socket.peer_addr());
         let _ = socket.set_nodelay(true);
         let _ = socket.set_keepalive(Some(Duration::from_millis(FABRIC_KEEPALIVE_MS)));
-        let mut buffer = [0u8; 8];
-        (&mut buffer[..])
+        let mut buffer = [0u8; 16];
+        (&mut buffer[..8])
             .write_u64::<LittleEndian>(context.node)
             .unwrap();
-        let fut = tokio_io::write_all(socket, buffer)
-            .and_then(|(s, b)| tokio_io::read_exact(s, b))
+        (&mut buffer[8..])
+            .write_u64::<LittleEndian>(context.routing)
+            .unwrap();
+        // a silent peer can't hang the handshake
+        let timeout = context.timeout;
+        let exchange = tokio_io::write_all(socket, buffer)
+            .and_then(|(s, b)| tokio_io::read_exact(s, b));
+        let fut = Timeout::new(exchange, timeout, handle)
+            .expect("Can't create handshake timeout")
             .and_then(move |(s, b)| {
-                let peer_id = (&b[..]).read_u64::<LittleEndian>().unwrap();
+                let peer_id = (&b[..8]).read_u64::<LittleEndian>().unwrap();
+                let peer_routing = (&b[8..]).read_u64::<LittleEndian>().unwrap();
                 debug!("Identified connection to node {}", peer_id);
+                if peer_id == context.node {
+                    error!(
+                        "Refusing connection from {:?}, it claims this node id {}",
+                        s.peer_addr(),
+                        peer_id
+                    );
+                    return Err(io::Error::new(io::ErrorKind::Other, "Duplicated node id"));
+                }
+                // they'd disagree on the vnodes of the overridden keys
+                if peer_routing != context.routing {
+                    error!(
+                        "Refusing connection from node {}, its routing_overrides differ",
+                        peer_id
+                    );
+                    return Err(io::Error::new(io::ErrorKind::Other, "Routing mismatch"));
+                }
                 Ok((s, peer_id, context))
             });
 
@@ -320,11 +370,20 @@ impl Fabric {
         socket: tokio::net::TcpStream,
         peer: NodeId,
         context: Arc<SharedContext>,
+        handle: &tokio::reactor::Handle,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         let (socket_rx, socket_tx) = socket.split();
         let socket_tx = codec::FramedWrite::new(socket_tx, FramedBincodeCodec);
-        let socket_rx = codec::FramedRead::new(socket_rx, FramedBincodeCodec);
+        // a peer that went silent is dropped and reconnected
+        let socket_rx = IdleTimeout::new(
+            codec::FramedRead::new(socket_rx, FramedBincodeCodec),
+            context.timeout * FABRIC_IDLE_TIMEOUTS,
+            handle,
+        ).expect("Can't create idle timeout");
+        let pings = tokio::reactor::Interval::new(context.timeout, handle)
+            .expect("Can't create ping interval");
         let (chan_tx, chan_rx) = fmpsc::unbounded();
+        let queued = QueueDepth::default();
 
         let ctx_rx = ReaderContext::new(context.clone(), peer);
         let fut_rx = socket_rx.for_each(move |msg| {
@@ -332,9 +391,19 @@ impl Fabric {
             Ok(())
         });
 
-        let ctx_tx = WriterContext::new(context, peer, chan_tx);
+        let ctx_tx = WriterContext::new(context, peer, chan_tx, queued.clone());
+        let chan_rx = chan_rx.map(move |msg| {
+            queued.fetch_sub(1, Ordering::Relaxed);
+            msg
+        });
+        let ping = FramedBincodeCodec::serialize(FabricMsgRef::Ping);
+        let pings = pings.map(move |_| ping.clone());
         let fut_tx = socket_tx
-            .send_all(chan_rx.map_err(|_| -> io::Error { io::ErrorKind::Other.into() }))
+            .send_all(
+                chan_rx
+                    .map_err(|_| -> io::Error { io::ErrorKind::Other.into() })
+                    .select(pings),
+            )
             .then(move |r| {
                 // hold onto ctx_tx until the stream is done
                 drop(ctx_tx);
@@ -358,6 +427,9 @@ impl Fabric {
             con_handlers: Default::default(),
             connections: Default::default(),
             connection_gen: Default::default(),
+            dropped: Default::default(),
+            timeout: Duration::from_millis(config.fabric_timeout as _),
+            routing: routing_fingerprint(&config),
         });
 
         let listener = tokio::net::TcpListener::bind(&context.addr, &handle)?;
@@ -429,6 +501,23 @@ impl Fabric {
             .collect()
     }
 
+    /// Msgs waiting to be written to each connected node
+    pub fn queue_depths(&self) -> Vec<(NodeId, usize)> {
+        let writers = self.context.connections.read().unwrap();
+        writers
+            .iter()
+            .map(|(&n, c)| {
+                let depth = c.iter().map(|&(_, _, ref q)| q.load(Ordering::Relaxed)).sum();
+                (n, depth)
+            })
+            .collect()
+    }
+
+    /// Msgs dropped since the start as no connection could take them
+    pub fn dropped_msgs(&self) -> usize {
+        self.context.dropped.load(Ordering::Relaxed)
+    }
+
     pub fn set_nodes<I>(&self, it: I)
     where
         I: Iterator<Item = (NodeId, SocketAddr)>,
@@ -487,8 +576,12 @@ impl Fabric {
         let serialized_msg = FramedBincodeCodec::serialize(msg);
         let connections = self.context.connections.read().unwrap();
         if let Some(o) = connections.get(&node) {
-            if let Some(&(connection_id, ref chan)) = thread_rng().choose::<(_, _)>(o) {
+            if let Some(&(connection_id, ref chan, ref queued)) =
+                thread_rng().choose::<(_, _, _)>(o)
+            {
+                queued.fetch_add(1, Ordering::Relaxed);
                 if let Err(_) = chan.unbounded_send(serialized_msg) {
+                    queued.fetch_sub(1, Ordering::Relaxed);
                     warn!("Can't send to fabric {}-{} chan", node, connection_id,);
                 } else {
                     return Ok(());
@@ -500,6 +593,7 @@ impl Fabric {
             warn!("DROPING MSG - No entry for node {:?}", node);
         }
 
+        self.context.dropped.fetch_add(1, Ordering::Relaxed);
         Err(FabricError::NoRoute)
     }
 }
@@ -514,12 +608,25 @@ impl Drop for Fabric {
     }
 }
 
+/// Hash of the routing_overrides, the same for any order they're listed in
+fn routing_fingerprint(config: &Config) -> u64 {
+    let mut overrides: Vec<_> = config
+        .routing_overrides
+        .iter()
+        .map(|&(ref prefix, policy)| format!("{:?}={:?};", prefix, policy))
+        .collect();
+    overrides.sort();
+    let parts: Vec<_> = overrides.iter().map(|o| o.as_bytes()).collect();
+    stable_hash(&parts)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use config::Config;
     use env_logger;
     use std::sync::{atomic, Arc};
+    use types::RoutingPolicy;
     use std::thread;
     use std::time::Duration;
 
@@ -563,4 +670,97 @@ mod tests {
         thread::sleep(Duration::from_millis(10));
         assert_eq!(counter.load(atomic::Ordering::Relaxed), 3);
     }
+
+    #[test]
+    fn test_queue_depth() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6483".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6484".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6484".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6483".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+        let depth = |fabric: &Fabric| -> usize {
+            fabric
+                .queue_depths()
+                .into_iter()
+                .filter(|&(n, _)| n == 2)
+                .map(|(_, d)| d)
+                .sum()
+        };
+        assert_eq!(depth(&fabric1), 0);
+
+        // the slow peer stops reading for a while
+        let stalled = Arc::new(atomic::AtomicBool::new(false));
+        let stalled_ = stalled.clone();
+        fabric2.register_msg_handler(
+            FabricMsgType::DHT,
+            Box::new(move |_, _| {
+                if !stalled_.swap(true, atomic::Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(500));
+                }
+            }),
+        );
+        // enough to go past the socket buffers
+        let payload = Bytes::from(vec![0u8; 256 * 1024]);
+        for _ in 0..64 {
+            fabric1.send_msg(2, &FabricMsg::DHTSync(payload.clone())).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(depth(&fabric1) > 0);
+
+        // drains once the peer catches up
+        for _ in 0..100 {
+            if depth(&fabric1) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(depth(&fabric1), 0);
+
+        assert_eq!(fabric1.dropped_msgs(), 0);
+        assert!(fabric1.send_msg(3, &FabricMsg::DHTSync(payload)).is_err());
+        assert_eq!(fabric1.dropped_msgs(), 1);
+    }
+
+    #[test]
+    fn test_routing_mismatch() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6485".parse().unwrap(),
+            routing_overrides: vec![
+                ("tenant1:".into(), RoutingPolicy::Spread),
+                ("tenant2:".into(), RoutingPolicy::VNode(3)),
+            ],
+            ..Default::default()
+        };
+        let mut config2 = Config {
+            fabric_addr: "127.0.0.1:6486".parse().unwrap(),
+            ..Default::default()
+        };
+        // listed in another order
+        config2.routing_overrides = config1.routing_overrides.iter().rev().cloned().collect();
+        let config3 = Config {
+            fabric_addr: "127.0.0.1:6487".parse().unwrap(),
+            routing_overrides: vec![("tenant1:".into(), RoutingPolicy::Spread)],
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        let fabric3 = Fabric::new(3, &config3).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6486".parse().unwrap());
+        fabric1.register_node(3, "127.0.0.1:6487".parse().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        assert!(fabric1.connections().contains(&2));
+        assert!(!fabric1.connections().contains(&3));
+        assert!(fabric2.connections().contains(&1));
+        assert!(fabric3.connections().is_empty());
+    }
 }
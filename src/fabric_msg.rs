@@ -19,6 +19,8 @@ pub enum FabricError {
     NotReady,
     SyncInterrupted,
     StorageError,
+    Timeout,
+    Disabled,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,6 +35,10 @@ pub enum FabricMsg {
     SyncFin(MsgSyncFin),
     DHTAE(VersionVector),
     DHTSync(Bytes),
+    Flush(MsgFlush),
+    FlushAck(MsgFlushAck),
+    // keeps idle connections from timing out, never dispatched
+    Ping,
     Unknown,
 }
 
@@ -48,6 +54,9 @@ pub enum FabricMsgRef<'a> {
     SyncFin(&'a MsgSyncFin),
     DHTAE(&'a VersionVector),
     DHTSync(&'a Bytes),
+    Flush(&'a MsgFlush),
+    FlushAck(&'a MsgFlushAck),
+    Ping,
     Unknown,
 }
 
@@ -57,7 +66,9 @@ impl FabricMsg {
             FabricMsg::RemoteGet(..)
             | FabricMsg::RemoteGetAck(..)
             | FabricMsg::RemoteSet(..)
-            | FabricMsg::RemoteSetAck(..) => FabricMsgType::Crud,
+            | FabricMsg::RemoteSetAck(..)
+            | FabricMsg::Flush(..)
+            | FabricMsg::FlushAck(..) => FabricMsgType::Crud,
             FabricMsg::SyncStart(..)
             | FabricMsg::SyncSend(..)
             | FabricMsg::SyncAck(..)
@@ -66,6 +77,21 @@ impl FabricMsg {
             _ => unreachable!(),
         }
     }
+
+    /// The vnode the message is about, if any
+    pub fn vnode(&self) -> Option<VNodeId> {
+        match *self {
+            FabricMsg::RemoteGet(ref m) => Some(m.vnode),
+            FabricMsg::RemoteGetAck(ref m) => Some(m.vnode),
+            FabricMsg::RemoteSet(ref m) => Some(m.vnode),
+            FabricMsg::RemoteSetAck(ref m) => Some(m.vnode),
+            FabricMsg::SyncStart(ref m) => Some(m.vnode),
+            FabricMsg::SyncSend(ref m) => Some(m.vnode),
+            FabricMsg::SyncAck(ref m) => Some(m.vnode),
+            FabricMsg::SyncFin(ref m) => Some(m.vnode),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> FabricMsgRef<'a> {
@@ -74,7 +100,9 @@ impl<'a> FabricMsgRef<'a> {
             FabricMsgRef::RemoteGet(..)
             | FabricMsgRef::RemoteGetAck(..)
             | FabricMsgRef::RemoteSet(..)
-            | FabricMsgRef::RemoteSetAck(..) => FabricMsgType::Crud,
+            | FabricMsgRef::RemoteSetAck(..)
+            | FabricMsgRef::Flush(..)
+            | FabricMsgRef::FlushAck(..) => FabricMsgType::Crud,
             FabricMsgRef::SyncStart(..)
             | FabricMsgRef::SyncSend(..)
             | FabricMsgRef::SyncAck(..)
@@ -85,11 +113,13 @@ impl<'a> FabricMsgRef<'a> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MsgRemoteGet {
     pub vnode: VNodeId,
     pub cookie: Cookie,
     pub keys: Vec<Bytes>,
+    // millis since epoch, past it the coordinator already gave up on the request
+    pub deadline: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,6 +135,10 @@ pub struct MsgRemoteSet {
     pub cookie: Cookie,
     pub writes: Vec<(Bytes, Cube, bool)>,
     pub reply: bool,
+    // restore the values even if the receiver clocks already have the dots
+    pub repair: bool,
+    // millis since epoch, past it the coordinator already gave up on the request
+    pub deadline: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -122,20 +156,29 @@ pub struct MsgSyncStart {
     pub target: Option<NodeId>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncSummary {
+    pub clocks: BitmappedVersionVector,
+    // number of distinct keys sent (seq numbers 0..count)
+    pub count: u64,
+    // order independent digest of all (seq, key) pairs sent
+    pub digest: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsgSyncFin {
     pub vnode: VNodeId,
     pub cookie: Cookie,
-    pub result: Result<BitmappedVersionVector, FabricError>,
+    pub result: Result<SyncSummary, FabricError>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsgSyncSend {
     pub vnode: VNodeId,
     pub cookie: Cookie,
+    // seq of the first write, the following ones take the next seqs
     pub seq: u64,
-    pub key: Bytes,
-    pub value: Cube,
+    pub writes: Vec<(Bytes, Cube)>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,6 +188,17 @@ pub struct MsgSyncAck {
     pub seq: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgFlush {
+    pub cookie: Cookie,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgFlushAck {
+    pub cookie: Cookie,
+    pub result: Result<(), FabricError>,
+}
+
 impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
     fn into(self) -> FabricMsgRef<'a> {
         match self {
@@ -158,6 +212,8 @@ impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
             &FabricMsg::SyncFin(ref a) => FabricMsgRef::SyncFin(a),
             &FabricMsg::DHTSync(ref a) => FabricMsgRef::DHTSync(a),
             &FabricMsg::DHTAE(ref a) => FabricMsgRef::DHTAE(a),
+            &FabricMsg::Flush(ref a) => FabricMsgRef::Flush(a),
+            &FabricMsg::FlushAck(ref a) => FabricMsgRef::FlushAck(a),
             _ => unreachable!(),
         }
     }
@@ -186,3 +242,5 @@ impl_into!(SyncAck, MsgSyncAck);
 impl_into!(SyncSend, MsgSyncSend);
 impl_into!(SyncFin, MsgSyncFin);
 impl_into!(SyncStart, MsgSyncStart);
+impl_into!(Flush, MsgFlush);
+impl_into!(FlushAck, MsgFlushAck);
@@ -1,4 +1,6 @@
 use crc16;
+use siphasher::sip::SipHasher;
+use std::hash::Hasher;
 
 pub const HASH_SLOTS: u16 = 16384;
 
@@ -13,26 +15,44 @@ pub fn hash_slot(mut key: &[u8]) -> u16 {
             }
         }
     }
+    raw_hash_slot(key)
+}
+
+/// Slot of the whole key, ignoring hashtags
+pub fn raw_hash_slot(key: &[u8]) -> u16 {
     crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS
 }
 
+/// SipHash with fixed keys of the parts as if they were concatenated.
+/// Unlike DefaultHasher it doesn't change with the compiler, so nodes can compare it.
+pub fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = SipHasher::new_with_keys(0, 0);
+    for part in parts {
+        hasher.write(part);
+    }
+    hasher.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn raw_hash(key: &[u8]) -> u16 {
-        crc16::State::<crc16::XMODEM>::calculate(key) % HASH_SLOTS
+    #[test]
+    fn test_hash_slot() {
+        assert_eq!(hash_slot(b"{}"), raw_hash_slot(b"{}"));
+        assert_eq!(hash_slot(b"_{abc}"), raw_hash_slot(b"abc"));
+        assert_eq!(hash_slot(b"{abc}_"), raw_hash_slot(b"abc"));
+        assert_eq!(hash_slot(b"_{abc}_"), raw_hash_slot(b"abc"));
+        assert_eq!(hash_slot(b"{abc}{def}"), raw_hash_slot(b"abc"));
+        assert_eq!(hash_slot(b"{}{abc}"), raw_hash_slot(b"{}{abc}"));
+        assert_eq!(hash_slot(b"{abc}{}"), raw_hash_slot(b"abc"));
+        assert_eq!(hash_slot(b"{{abc}}"), raw_hash_slot(b"{abc"));
     }
 
     #[test]
-    fn test_hash_slot() {
-        assert_eq!(hash_slot(b"{}"), raw_hash(b"{}"));
-        assert_eq!(hash_slot(b"_{abc}"), raw_hash(b"abc"));
-        assert_eq!(hash_slot(b"{abc}_"), raw_hash(b"abc"));
-        assert_eq!(hash_slot(b"_{abc}_"), raw_hash(b"abc"));
-        assert_eq!(hash_slot(b"{abc}{def}"), raw_hash(b"abc"));
-        assert_eq!(hash_slot(b"{}{abc}"), raw_hash(b"{}{abc}"));
-        assert_eq!(hash_slot(b"{abc}{}"), raw_hash(b"abc"));
-        assert_eq!(hash_slot(b"{{abc}}"), raw_hash(b"{abc"));
+    fn test_stable_hash() {
+        assert_eq!(stable_hash(&[]), 0x1e924b9d737700d7);
+        assert_eq!(stable_hash(&[b"sucredb"]), 0xd06a4228048dfa18);
+        assert_eq!(stable_hash(&[b"sucr", b"edb"]), 0xd06a4228048dfa18);
     }
 }
@@ -32,6 +32,11 @@ impl<K: Hash + Eq + Copy + fmt::Debug, V, T: Ord + Copy, H: BuildHasher + Defaul
         self.map.remove(key)
     }
 
+    /// Keeps only the entries for which `f` returns true
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, f: F) {
+        self.map.retain(f)
+    }
+
     pub fn entry_with_timeout(&mut self, key: K, expire: T) -> Entry<K, V> {
         self.heap.push(Pair(expire, key));
         self.map.entry(key)
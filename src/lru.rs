@@ -0,0 +1,107 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map holding up to `capacity` entries, evicting the least recently used ones
+#[derive(Debug)]
+pub struct LruCache<K: Hash + Eq + Clone, V> {
+    map: HashMap<K, (V, u64)>,
+    // last use of each key, the first one is evicted next
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            map: Default::default(),
+            order: Default::default(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    pub fn get<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.map.get_mut(key) {
+            Some(&mut (ref value, ref mut used)) => {
+                let key = self.order.remove(used).expect("Missing lru order");
+                self.order.insert(tick, key);
+                *used = tick;
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.map.len() >= self.capacity {
+            let oldest = *self.order.keys().next().expect("Missing lru order");
+            let evicted = self.order.remove(&oldest).unwrap();
+            self.map.remove(&evicted);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.map.insert(key, (value, self.tick));
+    }
+
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        match self.map.remove(key) {
+            Some((value, used)) => {
+                self.order.remove(&used);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        // 1 is used, so 2 is evicted
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.insert(3, "c");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+
+        // replacing doesn't evict
+        cache.insert(3, "d");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"d"));
+        assert_eq!(cache.remove(&1), Some("a"));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.remove(&1), None);
+    }
+
+    #[test]
+    fn test_lru_zero_capacity() {
+        let mut cache = LruCache::new(0);
+        cache.insert(1, "a");
+        assert_eq!(cache.get(&1), None);
+    }
+}
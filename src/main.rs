@@ -11,6 +11,7 @@
 // #[global_allocator]
 // static A: System = System;
 
+extern crate base64;
 extern crate bincode;
 extern crate byteorder;
 extern crate bytes;
@@ -22,8 +23,10 @@ extern crate lazy_static;
 extern crate linear_map;
 #[macro_use]
 extern crate log;
+extern crate libc;
 extern crate log4rs;
 extern crate metrics as rust_metrics;
+extern crate net2;
 extern crate num_cpus;
 extern crate rand;
 extern crate roaring;
@@ -31,7 +34,9 @@ extern crate rocksdb;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
+extern crate siphasher;
 extern crate tokio_core;
 extern crate tokio_io;
 
@@ -45,16 +50,19 @@ mod version_vector;
 // mod gossip;
 mod cubes;
 mod dht;
+mod extra_futures;
 mod fabric;
 mod fabric_msg;
 mod hash;
 mod inflightmap;
+mod lru;
 mod storage;
 #[macro_use]
 mod database;
 mod command;
 mod config;
 mod metrics;
+mod msgpack;
 mod resp;
 mod server;
 mod vnode;
@@ -131,8 +139,9 @@ fn configure() -> config::Config {
                         .short("p")
                         .help("Number of partitions")
                         .long_help(
-                            "Number of partitions, the recommended value is \
-                             `expected node count * 10` rounded up to the next power of 2.",
+                            "Number of partitions, must be a power of 2 between 32 and 1024. \
+                             The recommended value is `expected node count * 10` rounded up \
+                             to the next power of 2.",
                         )
                         .default_value(DEFAULT_PARTITIONS),
                 )
@@ -185,8 +194,38 @@ fn configure() -> config::Config {
     config
 }
 
+/// Resolves on SIGINT or SIGTERM. Must be called before any other thread is spawned,
+/// as they inherit the signal mask that leaves the signals to the waiting thread.
+#[cfg(not(test))]
+fn shutdown_signal() -> futures::sync::oneshot::Receiver<()> {
+    use std::{mem, ptr, thread};
+
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    };
+    let (tx, rx) = futures::sync::oneshot::channel();
+    thread::Builder::new()
+        .name("Signals".into())
+        .spawn(move || {
+            let mut signal = 0;
+            unsafe { libc::sigwait(&set, &mut signal) };
+            info!("Received signal {}, shutting down", signal);
+            let _ = tx.send(());
+        })
+        .unwrap();
+    rx
+}
+
 #[cfg(not(test))]
 fn main() {
+    use futures::Future;
+
+    let shutdown = shutdown_signal();
     let server = server::Server::new(configure());
-    server.run();
+    server.run(shutdown.map_err(|_| ()));
 }
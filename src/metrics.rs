@@ -13,4 +13,7 @@ lazy_static! {
     pub static ref SYNC_RESEND: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_OUTGOING: Arc<StdGauge> = { StdGauge::new() };
     pub static ref SYNC_INCOMING: Arc<StdGauge> = { StdGauge::new() };
+    pub static ref SCRUB_REPAIR: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref READ_REPAIR: Arc<StdMeter> = { StdMeter::new() };
+    pub static ref SIBLINGS_CREATED: Arc<StdMeter> = { StdMeter::new() };
 }
@@ -0,0 +1,152 @@
+//! Minimal MessagePack encoding of the value context,
+//! an alternative to bincode for clients outside of Rust.
+//!
+//! A context is encoded as a map of node id to version, both unsigned integers.
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use version_vector::VersionVector;
+
+const FIXMAP: u8 = 0x80;
+const FIXMAP_MAX: usize = 0x0f;
+const POSITIVE_FIXINT_MAX: u64 = 0x7f;
+const UINT8: u8 = 0xcc;
+const UINT16: u8 = 0xcd;
+const UINT32: u8 = 0xce;
+const UINT64: u8 = 0xcf;
+const MAP16: u8 = 0xde;
+const MAP32: u8 = 0xdf;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MsgPackError;
+
+fn write_map_len(buffer: &mut Vec<u8>, len: usize) {
+    if len <= FIXMAP_MAX {
+        buffer.push(FIXMAP | len as u8);
+    } else if len <= u16::max_value() as usize {
+        buffer.push(MAP16);
+        buffer.write_u16::<BigEndian>(len as u16).unwrap();
+    } else {
+        buffer.push(MAP32);
+        buffer.write_u32::<BigEndian>(len as u32).unwrap();
+    }
+}
+
+fn write_uint(buffer: &mut Vec<u8>, n: u64) {
+    if n <= POSITIVE_FIXINT_MAX {
+        buffer.push(n as u8);
+    } else if n <= u8::max_value() as u64 {
+        buffer.push(UINT8);
+        buffer.push(n as u8);
+    } else if n <= u16::max_value() as u64 {
+        buffer.push(UINT16);
+        buffer.write_u16::<BigEndian>(n as u16).unwrap();
+    } else if n <= u32::max_value() as u64 {
+        buffer.push(UINT32);
+        buffer.write_u32::<BigEndian>(n as u32).unwrap();
+    } else {
+        buffer.push(UINT64);
+        buffer.write_u64::<BigEndian>(n).unwrap();
+    }
+}
+
+fn read_map_len(bytes: &mut &[u8]) -> Result<usize, MsgPackError> {
+    let marker = bytes.read_u8().map_err(|_| MsgPackError)?;
+    match marker {
+        m if m & 0xf0 == FIXMAP => Ok((m & 0x0f) as usize),
+        MAP16 => bytes
+            .read_u16::<BigEndian>()
+            .map(|l| l as usize)
+            .map_err(|_| MsgPackError),
+        MAP32 => bytes
+            .read_u32::<BigEndian>()
+            .map(|l| l as usize)
+            .map_err(|_| MsgPackError),
+        _ => Err(MsgPackError),
+    }
+}
+
+fn read_uint(bytes: &mut &[u8]) -> Result<u64, MsgPackError> {
+    let marker = bytes.read_u8().map_err(|_| MsgPackError)?;
+    match marker {
+        m if m as u64 <= POSITIVE_FIXINT_MAX => Ok(m as u64),
+        UINT8 => bytes.read_u8().map(|n| n as u64).map_err(|_| MsgPackError),
+        UINT16 => bytes
+            .read_u16::<BigEndian>()
+            .map(|n| n as u64)
+            .map_err(|_| MsgPackError),
+        UINT32 => bytes
+            .read_u32::<BigEndian>()
+            .map(|n| n as u64)
+            .map_err(|_| MsgPackError),
+        UINT64 => bytes.read_u64::<BigEndian>().map_err(|_| MsgPackError),
+        _ => Err(MsgPackError),
+    }
+}
+
+pub fn serialize_vv(vv: &VersionVector) -> Vec<u8> {
+    let entries: Vec<_> = vv.iter().collect();
+    let mut buffer = Vec::with_capacity(1 + entries.len() * 18);
+    write_map_len(&mut buffer, entries.len());
+    for (id, version) in entries {
+        write_uint(&mut buffer, id);
+        write_uint(&mut buffer, version);
+    }
+    buffer
+}
+
+pub fn deserialize_vv(mut bytes: &[u8]) -> Result<VersionVector, MsgPackError> {
+    let len = read_map_len(&mut bytes)?;
+    let mut vv = VersionVector::new();
+    for _ in 0..len {
+        let id = read_uint(&mut bytes)?;
+        let version = read_uint(&mut bytes)?;
+        vv.add(id, version);
+    }
+    if !bytes.is_empty() {
+        return Err(MsgPackError);
+    }
+    Ok(vv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vv_round_trip() {
+        let mut vv = VersionVector::new();
+        let empty = serialize_vv(&vv);
+        assert_eq!(empty, vec![0x80]);
+        assert_eq!(deserialize_vv(&empty).unwrap(), vv);
+
+        for &(id, version) in &[
+            (1, 1),
+            (200, 300),
+            (70_000, 5_000_000_000),
+            (u64::max_value(), u64::max_value()),
+        ] {
+            vv.add(id, version);
+        }
+        let bytes = serialize_vv(&vv);
+        assert_eq!(bytes[0], 0x84);
+        assert_eq!(deserialize_vv(&bytes).unwrap(), vv);
+
+        // a map16 header with fixint entries is accepted as well
+        let bytes = [MAP16, 0x00, 0x01, 0x05, 0x07];
+        let mut expected = VersionVector::new();
+        expected.add(5, 7);
+        assert_eq!(deserialize_vv(&bytes).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_vv_invalid() {
+        // truncated
+        assert!(deserialize_vv(&[]).is_err());
+        assert!(deserialize_vv(&[0x81, 0x01]).is_err());
+        assert!(deserialize_vv(&[0x81, UINT16, 0x01]).is_err());
+        // trailing bytes
+        assert!(deserialize_vv(&[0x81, 0x01, 0x01, 0x00]).is_err());
+        // not a map, signed integers aren't valid versions
+        assert!(deserialize_vv(&[0x91, 0x01]).is_err());
+        assert!(deserialize_vv(&[0x81, 0xff, 0x01]).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 use bytes::Bytes;
 use std::error::Error;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
 use std::{fmt, str};
 use utils::assume_str;
 
@@ -296,6 +298,51 @@ impl Parser {
     }
 }
 
+/// A blocking connection to a RESP server, sending one command at a time
+pub struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    pub fn connect(addr: &SocketAddr, timeout: Duration) -> io::Result<Client> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            stream: stream,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Sends the command and waits for its reply
+    pub fn cmd(&mut self, args: &[&[u8]]) -> io::Result<RespValue> {
+        let cmd = RespValue::Array(args.iter().map(|&a| RespValue::Data(a.into())).collect());
+        let mut request = Vec::with_capacity(cmd.serialized_size());
+        cmd.serialize_into(&mut request)?;
+        self.stream.write_all(&request)?;
+        let mut chunk = [0u8; 4096];
+        loop {
+            let parsed = Parser::new(&self.buffer)
+                .and_then(|mut p| p.parse().map(|value| (p.consumed(), value)));
+            match parsed {
+                Ok((consumed, value)) => {
+                    self.buffer.drain(..consumed);
+                    return Ok(value);
+                }
+                Err(RespError::Incomplete) => (),
+                Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+            }
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Parser, RespError, RespResult, RespValue};
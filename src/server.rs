@@ -1,16 +1,20 @@
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::io;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::{io, net, thread};
 
 use bytes::{BufMut, BytesMut};
-use database::{Context as DbContext, Database, Token};
+use database::{Context as DbContext, Database, DatabaseResponseFn, Token};
+use futures::future::{self, Either};
 use futures::sync::mpsc as fmpsc;
-use futures::{Future, Sink, Stream};
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Sink, Stream};
+use net2::TcpBuilder;
+use rand::{thread_rng, Rng};
 use tokio_core as tokio;
 use tokio_io::{codec, AsyncRead};
-use workers::{WorkerMsg, WorkerSender};
+use workers::WorkerSender;
 
 use config::Config;
 use metrics::{self, Gauge};
@@ -55,12 +59,16 @@ struct Context {
     token: Token,
     requests: VecDeque<RespValue>,
     db_context: Option<DbContext>,
+    // reader task waiting for the requests queue to drain
+    read_task: Option<Task>,
 }
 
+type TokenChans = Arc<Mutex<IdHashMap<Token, fmpsc::UnboundedSender<DbContext>>>>;
+
 struct SharedContext {
     database: Arc<Database>,
     db_sender: RefCell<WorkerSender>,
-    token_chans: Arc<Mutex<IdHashMap<Token, fmpsc::UnboundedSender<DbContext>>>>,
+    token_chans: TokenChans,
 }
 
 pub struct Server {
@@ -68,29 +76,33 @@ pub struct Server {
 }
 
 impl Context {
-    fn new(
-        context: Rc<SharedContext>,
-        token: Token,
-        chan_tx: fmpsc::UnboundedSender<DbContext>,
-    ) -> Self {
+    // the token was already registered in token_chans by the acceptor
+    fn new(context: Rc<SharedContext>, token: Token) -> Self {
         metrics::CLIENT_CONNECTION.inc();
-        context.token_chans.lock().unwrap().insert(token, chan_tx);
         Context {
             context: context,
             token: token,
             db_context: Some(DbContext::new(token)),
             requests: VecDeque::new(),
+            read_task: None,
         }
     }
 
+    fn is_full(&self) -> bool {
+        self.requests.len() >= self.context.database.config().client_pipeline_max as usize
+    }
+
     fn dispatch(&mut self, req: RespValue) {
         if let Some(mut db_context) = self.db_context.take() {
-            debug!("Dispatched request ({}) {:?}", self.token, req);
+            db_context.request_id = thread_rng().gen();
+            debug!(
+                "Dispatched request ({}) req:{:016X} {:?}",
+                self.token, db_context.request_id, req
+            );
             db_context.commands.push(req);
             self.context
-                .db_sender
-                .borrow_mut()
-                .send(WorkerMsg::Command(db_context));
+                .database
+                .send_cmd(&mut *self.context.db_sender.borrow_mut(), db_context);
         } else {
             debug!("Enqueued request ({}) {:?}", self.token, req);
             self.requests.push_back(req);
@@ -103,21 +115,57 @@ impl Context {
             "can't cycle if there's nothing inflight"
         );
         if let Some(req) = self.requests.pop_front() {
-            debug!("Dispatched request ({}) {:?}", self.token, req);
+            if let Some(task) = self.read_task.take() {
+                task.notify();
+            }
+            db_context.request_id = thread_rng().gen();
+            debug!(
+                "Dispatched request ({}) req:{:016X} {:?}",
+                self.token, db_context.request_id, req
+            );
             db_context.commands.push(req);
             self.context
-                .db_sender
-                .borrow_mut()
-                .send(WorkerMsg::Command(db_context));
+                .database
+                .send_cmd(&mut *self.context.db_sender.borrow_mut(), db_context);
         } else {
             self.db_context = Some(db_context);
         }
     }
 }
 
+/// Reads requests from the connection into the Context,
+/// stops reading once client_pipeline_max requests are queued.
+struct ReadRequests<S> {
+    context: Rc<RefCell<Context>>,
+    requests: S,
+}
+
+impl<S: Stream<Item = RespValue, Error = io::Error>> Future for ReadRequests<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            {
+                let mut context = self.context.borrow_mut();
+                if context.is_full() {
+                    context.read_task = Some(task::current());
+                    return Ok(Async::NotReady);
+                }
+            }
+            match self.requests.poll()? {
+                Async::Ready(Some(request)) => self.context.borrow_mut().dispatch(request),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
 impl Drop for Context {
     fn drop(&mut self) {
         self.context.token_chans.lock().unwrap().remove(&self.token);
+        self.context.database.client_disconnected(self.token);
         metrics::CLIENT_CONNECTION.dec();
     }
 }
@@ -131,19 +179,22 @@ impl Server {
         context: Rc<SharedContext>,
         token: Token,
         socket: tokio::net::TcpStream,
+        chan_rx: fmpsc::UnboundedReceiver<DbContext>,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         socket.set_nodelay(true).expect("Failed to set nodelay");
+        context
+            .database
+            .client_connected(token, socket.peer_addr().ok());
         let (sock_rx, sock_tx) = socket.split();
         let sock_tx = codec::FramedWrite::new(sock_tx, RespCodec);
         let sock_rx = codec::FramedRead::new(sock_rx, RespCodec);
-        let (chan_tx, chan_rx) = fmpsc::unbounded();
-        let ctx_rx = Rc::new(RefCell::new(Context::new(context, token, chan_tx)));
+        let ctx_rx = Rc::new(RefCell::new(Context::new(context, token)));
         let ctx_tx = ctx_rx.clone();
 
-        let fut_rx = sock_rx.for_each(move |request| {
-            ctx_rx.borrow_mut().dispatch(request);
-            Ok(())
-        });
+        let fut_rx = ReadRequests {
+            context: ctx_rx,
+            requests: sock_rx,
+        };
 
         let fut_tx = sock_tx
             .send_all(
@@ -160,24 +211,45 @@ impl Server {
         Box::new(fut_rx.select(fut_tx).map(|_| ()).map_err(|(e, _)| e))
     }
 
-    pub fn run(self) {
-        let mut core = tokio::reactor::Core::new().unwrap();
-
-        let token_chans: Arc<Mutex<IdHashMap<Token, fmpsc::UnboundedSender<_>>>> =
-            Default::default();
-        let token_chans_cloned = token_chans.clone();
-        let response_fn = Box::new(move |context: DbContext| {
+    // routes the database responses to the connection of each token
+    fn response_fn(token_chans: TokenChans) -> DatabaseResponseFn {
+        Box::new(move |context: DbContext| {
             let token = context.token;
-            if let Some(chan) = token_chans_cloned.lock().unwrap().get_mut(&token) {
+            if let Some(chan) = token_chans.lock().unwrap().get_mut(&token) {
                 if let Err(e) = chan.unbounded_send(context) {
                     warn!("Can't send to token {} chan: {:?}", token, e);
                 }
             } else {
                 debug!("Can't find response channel for token {:?}", token);
             }
-        });
+        })
+    }
 
-        let database = Database::new(&self.config, response_fn);
+    fn bind(config: &Config) -> io::Result<net::TcpListener> {
+        let builder = match config.listen_addr {
+            net::SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            net::SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+        };
+        builder.reuse_address(true)?;
+        builder.bind(&config.listen_addr)?;
+        builder.listen(config.client_listen_backlog as i32)
+    }
+
+    /// Accepts and serves client connections until `shutdown` resolves.
+    /// Tokens go `first_token`, `first_token + token_step`, ... so each acceptor
+    /// thread uses distinct ones.
+    fn accept<F: Future<Item = (), Error = ()>>(
+        listener: net::TcpListener,
+        database: Arc<Database>,
+        token_chans: TokenChans,
+        first_token: Token,
+        token_step: Token,
+        shutdown: F,
+    ) {
+        let mut core = tokio::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let addr = listener.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::from_listener(listener, &addr, &handle).unwrap();
 
         let context = Rc::new(SharedContext {
             db_sender: RefCell::new(database.sender()),
@@ -185,32 +257,239 @@ impl Server {
             token_chans: token_chans,
         });
 
-        let mut next_token = 0;
-        let handle = core.handle();
-        let listener =
-            tokio::net::TcpListener::bind(&self.config.listen_addr, &core.handle()).unwrap();
+        let mut next_token = first_token;
         let listener_fut = listener.incoming().for_each(|(socket, addr)| {
-            if context.token_chans.lock().unwrap().len()
-                >= context.database.config.client_connection_max as usize
+            let (chan_tx, chan_rx) = fmpsc::unbounded();
             {
-                info!(
-                    "Refusing connection from {:?}, connection limit reached",
-                    addr
-                );
-                return Ok(());
+                // checked and reserved under the same lock, acceptors can't overshoot the max
+                let mut token_chans = context.token_chans.lock().unwrap();
+                if token_chans.len() >= context.database.config().client_connection_max as usize {
+                    info!(
+                        "Refusing connection from {:?}, connection limit reached",
+                        addr
+                    );
+                    return Ok(());
+                }
+                token_chans.insert(next_token, chan_tx);
             }
             info!("Token {} accepting connection from {:?}", next_token, addr);
             let conn_ctx = context.clone();
-            handle.spawn(
-                Self::connection(conn_ctx, next_token, socket).then(move |r| {
-                    info!("Token {} disconnected {:?}", next_token, r);
-                    Ok(())
-                }),
-            );
-            next_token = next_token.wrapping_add(1);
+            let token = next_token;
+            let conn = Self::connection(conn_ctx, token, socket, chan_rx);
+            handle.spawn(conn.then(move |r| {
+                info!("Token {} disconnected {:?}", token, r);
+                Ok(())
+            }));
+            next_token = next_token.wrapping_add(token_step);
             Ok(())
         });
 
-        core.run(listener_fut).unwrap();
+        if let Err(Either::A((e, _))) = core.run(listener_fut.select2(shutdown)) {
+            panic!("Client listener failed: {}", e);
+        }
+    }
+
+    /// Serves clients until `shutdown` resolves, then shuts the database down
+    pub fn run<F: Future<Item = (), Error = ()>>(self, shutdown: F) {
+        let token_chans: TokenChans = Default::default();
+        let database = Database::new(&self.config, Self::response_fn(token_chans.clone()));
+        let listener = Self::bind(&self.config).expect("Can't bind the client listener");
+
+        // all acceptors share the listener, each with its own event loop
+        let acceptors = self.config.client_acceptors.max(1) as Token;
+        for i in 1..acceptors {
+            let listener = listener
+                .try_clone()
+                .expect("Can't clone the client listener");
+            let database = database.clone();
+            let token_chans = token_chans.clone();
+            thread::Builder::new()
+                .name(format!("Client:{}", i))
+                .spawn(move || {
+                    Self::accept(
+                        listener,
+                        database,
+                        token_chans,
+                        i,
+                        acceptors,
+                        future::empty(),
+                    )
+                })
+                .unwrap();
+        }
+        Self::accept(
+            listener,
+            database.clone(),
+            token_chans,
+            0,
+            acceptors,
+            shutdown,
+        );
+        database.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::InitCommand;
+    use env_logger;
+    use futures::future::Either;
+    use futures::stream;
+    use std::cell::Cell;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pipeline_backpressure() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let config = Config {
+            data_dir: "t/db".into(),
+            fabric_addr: "127.0.0.1:9000".parse().unwrap(),
+            cluster_name: "test".into(),
+            sync_auto: false,
+            client_pipeline_max: 10,
+            cmd_init: Some(InitCommand {
+                replication_factor: 1,
+                partitions: 64,
+            }),
+            ..Default::default()
+        };
+        // responses are dropped, so the first request stays inflight
+        let database = Database::new(&config, Box::new(|_| ()));
+        let shared = Rc::new(SharedContext {
+            db_sender: RefCell::new(database.sender()),
+            database: database,
+            token_chans: Default::default(),
+        });
+        let context = Rc::new(RefCell::new(Context::new(shared, 1)));
+
+        let read = Rc::new(Cell::new(0));
+        let read_cloned = read.clone();
+        let requests = stream::iter_ok::<_, io::Error>((0..100).map(move |_| {
+            read_cloned.set(read_cloned.get() + 1);
+            RespValue::Array(vec![
+                RespValue::Data("ECHO".into()),
+                RespValue::Data("hello".into()),
+            ])
+        }));
+        let mut reader = ReadRequests {
+            context: context.clone(),
+            requests: requests,
+        };
+
+        let mut core = tokio::reactor::Core::new().unwrap();
+        for i in 0..2 {
+            let timeout =
+                tokio::reactor::Timeout::new(Duration::from_millis(100), &core.handle()).unwrap();
+            reader = match core.run(reader.select2(timeout)) {
+                Ok(Either::B((_, reader))) => reader,
+                _ => panic!("reader shouldn't finish"),
+            };
+            // one inflight plus the queued ones
+            assert_eq!(read.get(), 11 + i);
+            assert_eq!(context.borrow().requests.len(), 10);
+            // a response frees a slot in the queue
+            context.borrow_mut().dispatch_next(DbContext::new(1));
+        }
+    }
+
+    fn test_client_acceptors_stub(connection_max: u32) {
+        use futures::sync::oneshot;
+
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let config = Config {
+            data_dir: "t/db".into(),
+            fabric_addr: "127.0.0.1:9000".parse().unwrap(),
+            listen_addr: "127.0.0.1:9010".parse().unwrap(),
+            cluster_name: "test".into(),
+            sync_auto: false,
+            client_connection_max: connection_max,
+            client_listen_backlog: 16,
+            cmd_init: Some(InitCommand {
+                replication_factor: 1,
+                partitions: 64,
+            }),
+            ..Default::default()
+        };
+        let token_chans: TokenChans = Default::default();
+        let database = Database::new(&config, Server::response_fn(token_chans.clone()));
+        let listener = Server::bind(&config).unwrap();
+
+        let acceptors = 4;
+        let mut shutdowns = Vec::new();
+        let mut threads = Vec::new();
+        for i in 0..acceptors {
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+            let listener = listener.try_clone().unwrap();
+            let database = database.clone();
+            let token_chans = token_chans.clone();
+            shutdowns.push(shutdown_tx);
+            threads.push(thread::spawn(move || {
+                Server::accept(
+                    listener,
+                    database,
+                    token_chans,
+                    i,
+                    acceptors,
+                    shutdown_rx.map_err(|_| ()),
+                )
+            }));
+        }
+
+        // connect concurrently, way past the backlog, and keep all connections open
+        let connectors: Vec<_> = (0..8)
+            .map(|_| {
+                let addr = config.listen_addr;
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|_| resp::Client::connect(&addr, Duration::from_secs(5)).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut clients = Vec::new();
+        for connector in connectors {
+            clients.extend(connector.join().unwrap());
+        }
+        // refused connections are closed right away
+        let expected = (connection_max as usize).min(400);
+        let mut served = 0;
+        for client in &mut clients {
+            if let Ok(response) = client.cmd(&[b"ECHO", b"hello"]) {
+                assert_eq!(
+                    response,
+                    RespValue::Array(vec![
+                        RespValue::Data("ECHO".into()),
+                        RespValue::Data("hello".into()),
+                    ])
+                );
+                served += 1;
+            }
+        }
+        // no connection was dropped past the max and all tokens are distinct
+        assert_eq!(served, expected);
+        assert_eq!(token_chans.lock().unwrap().len(), expected);
+
+        drop(clients);
+        for shutdown in shutdowns {
+            shutdown.send(()).unwrap();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(token_chans.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_client_acceptors() {
+        test_client_acceptors_stub(1000);
+    }
+
+    #[test]
+    fn test_client_acceptors_connection_max() {
+        test_client_acceptors_stub(100);
     }
 }
@@ -83,6 +83,8 @@ pub struct LogStorageIterator {
 }
 
 unsafe impl Send for StorageIterator {}
+// the rocksdb iterator is only touched through &mut self
+unsafe impl Sync for StorageIterator {}
 
 impl StorageManager {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
@@ -173,13 +175,18 @@ impl Drop for StorageManager {
 
 impl Storage {
     pub fn iterator(&self) -> StorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
+        self.iterator_from(b"")
+    }
+
+    /// Iterates the keys starting at `start` (inclusive)
+    pub fn iterator_from(&self, start: &[u8]) -> StorageIterator {
+        let mut buffer = [0u8; 512];
+        let start_key = build_key(&mut buffer, self.num, start);
         let mut ro = rocksdb::ReadOptions::new();
         ro.set_total_order_seek(false);
         ro.set_prefix_same_as_start(true);
         let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
+        iterator.seek(rocksdb::SeekKey::Key(start_key));
         StorageIterator {
             db: self.db.clone(),
             iterator: iterator,
@@ -188,6 +195,15 @@ impl Storage {
         }
     }
 
+    /// Iterates the keys following `key` (exclusive)
+    pub fn iterator_after(&self, key: &[u8]) -> StorageIterator {
+        // the smallest key following it is itself with a trailing zero
+        let mut start = Vec::with_capacity(key.len() + 1);
+        start.extend_from_slice(key);
+        start.push(0);
+        self.iterator_from(&start)
+    }
+
     pub fn log_iterator(&self, prefix: u64, start: u64) -> LogStorageIterator {
         let mut end_prefix = [0u8; 2 + 8];
         build_log_prefix(&mut end_prefix, self.num, prefix + 1);
@@ -291,6 +307,17 @@ impl Storage {
         }
     }
 
+    /// Compacts the key range of this vnode, dropping overwritten and deleted entries
+    pub fn compact(&self) {
+        trace!("compact");
+        let mut from = [0u8; 2];
+        let mut to = [0u8; 2];
+        (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
+        (&mut to[..]).write_u16::<BigEndian>(self.num + 1).unwrap();
+        self.db
+            .compact_range_cf(self.cf, Some(&from[..]), Some(&to[..]));
+    }
+
     pub fn sync(&self) -> Result<(), GenericError> {
         debug!("sync");
         Ok(self.db.sync_wal()?)
@@ -455,6 +482,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_iter_after() {
+        let _ = fs::remove_dir_all("t/test_iter_after");
+        let sm = StorageManager::new("t/test_iter_after").unwrap();
+        let storage = sm.open(1).unwrap();
+        for &k in &[&b"1"[..], b"2", b"2\0", b"3", b"4"] {
+            storage.set(k, b"").unwrap();
+        }
+        // keys of other storages aren't reached
+        sm.open(2).unwrap().set(b"5", b"").unwrap();
+        let keys_after = |key: &[u8]| -> Vec<Vec<u8>> {
+            storage
+                .iterator_after(key)
+                .iter()
+                .map(|(k, _)| k.into())
+                .collect()
+        };
+        assert_eq!(keys_after(b""), [&b"1"[..], b"2", b"2\0", b"3", b"4"]);
+        assert_eq!(keys_after(b"2"), [&b"2\0"[..], b"3", b"4"]);
+        // the key doesn't need to exist anymore
+        storage.del(b"3").unwrap();
+        assert_eq!(keys_after(b"3"), [b"4"]);
+        assert_eq!(keys_after(b"4"), Vec::<Vec<u8>>::new());
+    }
+
     #[test]
     fn test_iter_log() {
         let _ = fs::remove_dir_all("t/test_iter_log");
@@ -9,6 +9,8 @@ pub type NodeId = u64;
 pub type PhysicalNodeId = u32;
 /// Identifier for connection with client
 pub type Token = u64;
+/// Identifier of a client request, to trace it across the nodes involved
+pub type RequestId = u64;
 /// Identifier for a vnode
 pub type VNodeId = u16;
 
@@ -20,6 +22,16 @@ impl Cookie {
     pub fn new(a: u64, b: u64) -> Self {
         Cookie(a, b)
     }
+
+    /// The first half, for syncs the generation of the receiving vnode
+    pub fn generation(&self) -> u64 {
+        self.0
+    }
+
+    /// The first half, for client requests the id of the request
+    pub fn request_id(&self) -> RequestId {
+        self.0
+    }
 }
 
 impl fmt::Debug for Cookie {
@@ -61,6 +73,237 @@ impl FromStr for ConsistencyLevel {
     }
 }
 
+/// How a vnode chooses the peer for the next anti-entropy sync
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncPeerStrategy {
+    Random,
+    RoundRobin,
+    // peer with the clocks furthest behind ours, as last reported by it
+    MostStaleFirst,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SyncPeerStrategyParseError;
+
+impl FromStr for SyncPeerStrategy {
+    type Err = SyncPeerStrategyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "random" => Ok(SyncPeerStrategy::Random),
+            "round_robin" => Ok(SyncPeerStrategy::RoundRobin),
+            "most_stale_first" => Ok(SyncPeerStrategy::MostStaleFirst),
+            _ => Err(SyncPeerStrategyParseError),
+        }
+    }
+}
+
+/// What to do with requests that can't reach enough replicas for their consistency
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnavailablePolicy {
+    // reply CLUSTERDOWN without applying anything
+    Fail,
+    // attempt the request anyway, it may be partially applied
+    BestEffort,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct UnavailablePolicyParseError;
+
+impl FromStr for UnavailablePolicy {
+    type Err = UnavailablePolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "fail" => Ok(UnavailablePolicy::Fail),
+            "best_effort" => Ok(UnavailablePolicy::BestEffort),
+            _ => Err(UnavailablePolicyParseError),
+        }
+    }
+}
+
+/// When writes are acknowledged in relation to the storage commit.
+/// Either way the writes handled until the worker queue drains share a storage batch,
+/// and are acknowledged once it's written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CommitPolicy {
+    // ack once the batch is written to the storage, without waiting for the log sync
+    Async,
+    // ack only after the batch is also synced to the storage log
+    Sync,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CommitPolicyParseError;
+
+impl FromStr for CommitPolicy {
+    type Err = CommitPolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "async" => Ok(CommitPolicy::Async),
+            "sync" => Ok(CommitPolicy::Sync),
+            _ => Err(CommitPolicyParseError),
+        }
+    }
+}
+
+/// Which replica clients are redirected to when a node can't coordinate the vnode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoordinatorPolicy {
+    // the first replica of the preference list
+    First,
+    // any replica, spreading the load across them
+    Random,
+    // the replica with the fewest fabric msgs waiting to be written to it
+    LeastLoaded,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CoordinatorPolicyParseError;
+
+impl FromStr for CoordinatorPolicy {
+    type Err = CoordinatorPolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "first" => Ok(CoordinatorPolicy::First),
+            "random" => Ok(CoordinatorPolicy::Random),
+            "least_loaded" => Ok(CoordinatorPolicy::LeastLoaded),
+            _ => Err(CoordinatorPolicyParseError),
+        }
+    }
+}
+
+/// What a SET without context does with the values already stored in the key
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlindWritePolicy {
+    // keep them, the new value becomes a sibling
+    Sibling,
+    // supersede them, last write wins
+    Overwrite,
+    // refuse the write unless the key is empty
+    Reject,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BlindWritePolicyParseError;
+
+impl FromStr for BlindWritePolicy {
+    type Err = BlindWritePolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "sibling" => Ok(BlindWritePolicy::Sibling),
+            "overwrite" => Ok(BlindWritePolicy::Overwrite),
+            "reject" => Ok(BlindWritePolicy::Reject),
+            _ => Err(BlindWritePolicyParseError),
+        }
+    }
+}
+
+/// How a node answers reads for the vnodes it's still bootstrapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BootstrapReadPolicy {
+    // ask the ready replicas instead
+    Redirect,
+    // use the local values if all keys are present, the ready replicas otherwise
+    ServeIfPresent,
+    // reply TRYAGAIN
+    Error,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BootstrapReadPolicyParseError;
+
+impl FromStr for BootstrapReadPolicy {
+    type Err = BootstrapReadPolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "redirect" => Ok(BootstrapReadPolicy::Redirect),
+            "serve_if_present" => Ok(BootstrapReadPolicy::ServeIfPresent),
+            "error" => Ok(BootstrapReadPolicy::Error),
+            _ => Err(BootstrapReadPolicyParseError),
+        }
+    }
+}
+
+/// How the value context is encoded in responses and parsed from requests
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContextEncoding {
+    // compact but Rust specific
+    Bincode,
+    // a map of node id to version, parseable by standard libraries
+    MsgPack,
+    // bincode as a printable base64 token
+    Base64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ContextEncodingParseError;
+
+impl FromStr for ContextEncoding {
+    type Err = ContextEncodingParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "bincode" => Ok(ContextEncoding::Bincode),
+            "msgpack" => Ok(ContextEncoding::MsgPack),
+            "base64" => Ok(ContextEncoding::Base64),
+            _ => Err(ContextEncodingParseError),
+        }
+    }
+}
+
+/// Whether reads write the merged value back to the stale replicas that replied
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadRepair {
+    Off,
+    // the read responds without waiting for the write-backs
+    Async,
+    // the read responds once the write-backs are acknowledged
+    Sync,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ReadRepairParseError;
+
+impl FromStr for ReadRepair {
+    type Err = ReadRepairParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "off" => Ok(ReadRepair::Off),
+            "async" => Ok(ReadRepair::Async),
+            "sync" => Ok(ReadRepair::Sync),
+            _ => Err(ReadRepairParseError),
+        }
+    }
+}
+
+/// Where the keys under a routing override prefix are placed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RoutingPolicy {
+    // hash the whole key, ignoring hashtags, so a tenant's keys use all vnodes
+    Spread,
+    // all keys in the vnode (modulo the partition count)
+    VNode(VNodeId),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RoutingPolicyParseError;
+
+impl FromStr for RoutingPolicy {
+    type Err = RoutingPolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        if s == "spread" {
+            return Ok(RoutingPolicy::Spread);
+        }
+        match s.split_at(s.find(' ').unwrap_or(s.len())) {
+            ("vnode", vnode) => vnode
+                .trim()
+                .parse()
+                .map(RoutingPolicy::VNode)
+                .map_err(|_| RoutingPolicyParseError),
+            _ => Err(RoutingPolicyParseError),
+        }
+    }
+}
+
 impl ConsistencyLevel {
     pub fn required(&self, replicas: u8) -> u8 {
         match *self {
@@ -69,4 +312,15 @@ impl ConsistencyLevel {
             ConsistencyLevel::All => replicas,
         }
     }
+
+    /// The level requiring exactly `required` of `replicas`, if any
+    pub fn with_required(required: u8, replicas: u8) -> Option<ConsistencyLevel> {
+        [
+            ConsistencyLevel::One,
+            ConsistencyLevel::Quorum,
+            ConsistencyLevel::All,
+        ].iter()
+            .cloned()
+            .find(|c| required <= replicas && c.required(replicas) == required)
+    }
 }
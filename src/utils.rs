@@ -1,7 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::hash::{BuildHasherDefault, Hasher};
-use std::{fmt, fs, io, path};
+use std::{fmt, fs, io, path, time};
 
 pub type GenericError = Box<Error + Send + Sync + 'static>;
 
@@ -64,6 +64,15 @@ pub fn join_u64(hi: u32, lo: u32) -> u64 {
     ((hi as u64) << 32) | (lo as u64)
 }
 
+pub fn duration_millis(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1_000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+// millis since epoch
+pub fn now_millis() -> u64 {
+    duration_millis(time::UNIX_EPOCH.elapsed().unwrap())
+}
+
 pub fn assume_str(bytes: &[u8]) -> &str {
     unsafe { ::std::str::from_utf8_unchecked(bytes) }
 }
@@ -125,6 +125,13 @@ impl BitmappedVersion {
         self.base >= version
     }
 
+    /// All the versions contained, in order, only meant for debugging as it's O(base)
+    pub fn values(&self) -> Vec<Version> {
+        let mut values: Vec<_> = (1..self.base + 1).collect();
+        values.extend(self.bitmap.iter().filter(|&v| v > self.base));
+        values
+    }
+
     /// self - other
     pub fn delta(&self, other: &Self) -> BitmappedVersionDelta {
         if self.base < other.base {
@@ -169,7 +176,7 @@ impl Iterator for BitmappedVersionDelta {
 }
 
 pub struct BitmappedVersionVectorDelta {
-    iter: Box<Iterator<Item = (Id, Version)> + Send>,
+    iter: Box<Iterator<Item = (Id, Version)> + Send + Sync>,
     min_versions: Vec<(Id, Version)>,
 }
 
@@ -328,9 +335,19 @@ impl BitmappedVersionVector {
             }
             LMEntry::Occupied(mut ocu) => {
                 let bv = ocu.get_mut();
-                debug_assert_eq!(bv.bitmap.len(), 0);
-                bv.base += 1;
-                bv.base
+                // an id issues its versions in order, so the ones past the base
+                // (ex: merged back from restored data) were already used.
+                // The holes stay open, they may still be synced from other nodes.
+                match bv.bitmap.max() {
+                    Some(max) => {
+                        bv.bitmap.insert(max + 1);
+                        max + 1
+                    }
+                    None => {
+                        bv.base += 1;
+                        bv.base
+                    }
+                }
             }
         }
     }
@@ -351,6 +368,11 @@ impl BitmappedVersionVector {
         self.0.iter()
     }
 
+    /// The versions contained of each id, see BitmappedVersion::values
+    pub fn values<'a>(&'a self) -> impl 'a + Iterator<Item = (Id, Vec<Version>)> {
+        self.0.iter().map(|(&id, bv)| (id, bv.values()))
+    }
+
     pub fn clone_if<F: FnMut(Id) -> bool>(&self, mut cond: F) -> Self {
         let mut result = Self::default();
         for (&id, bv) in self.0.iter() {
@@ -647,6 +669,10 @@ impl DotSet {
         result.0.insert(dot);
         result
     }
+
+    pub fn iter<'a>(&'a self) -> impl 'a + Iterator<Item = (Id, Version)> {
+        self.0.iter().cloned()
+    }
 }
 
 impl CausalValue for DotSet {
@@ -822,6 +848,14 @@ mod test_bv {
         assert!(a.delta(&b).collect::<Vec<Version>>().is_empty());
     }
 
+    #[test]
+    fn values() {
+        assert!(BitmappedVersion::new(0, 0).values().is_empty());
+        assert_eq!(BitmappedVersion::new(0, 0b11).values(), vec![1, 2]);
+        assert_eq!(BitmappedVersion::new(2, 0b101).values(), vec![1, 2, 3, 5]);
+        assert_eq!(BitmappedVersion::new(3, 0b1010).values(), vec![1, 2, 3, 5, 7]);
+    }
+
     #[test]
     fn norm() {
         let mut a = BitmappedVersion::new(1, 0b10);
@@ -849,6 +883,17 @@ mod test_bvv {
         assert_eq!(a.get(1).unwrap(), &BitmappedVersion::new(3, 0));
     }
 
+    #[test]
+    fn values() {
+        let mut a = BitmappedVersionVector::new();
+        a.add(1, 1);
+        a.add(1, 3);
+        a.add(2, 2);
+        let mut values: Vec<_> = a.values().collect();
+        values.sort();
+        assert_eq!(values, vec![(1, vec![1, 3]), (2, vec![2])]);
+    }
+
     #[test]
     fn delta() {
         let mut bvv1 = BitmappedVersionVector::new();
@@ -914,6 +959,13 @@ mod test_bvv {
 
         assert_eq!(a.event(1), 2);
         assert_eq!(a.get(1).unwrap(), &BitmappedVersion::new(2, 0));
+
+        // versions past the base were already issued, the holes are kept
+        a.add(1, 5);
+        assert_eq!(a.event(1), 6);
+        assert_eq!(a.get(1).unwrap(), &BitmappedVersion::new(2, 0b1100));
+        assert!(!a.contains(1, 3));
+        assert!(!a.contains(1, 4));
     }
 
 }
@@ -1133,16 +1185,4 @@ mod test_vv {
 //         d3e.vv.0.insert(3, 15);
 //         assert_eq!(d3, d3e);
 //     }
-//
-//     #[test]
-//     #[ignore]
-//     fn fill() {
-//         unimplemented!()
-//     }
-//
-//     #[test]
-//     #[ignore]
-//     fn strip() {
-//         unimplemented!()
-//     }
 // }
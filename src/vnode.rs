@@ -1,20 +1,35 @@
 use bincode;
 use bytes::Bytes;
-use command::CommandError;
+use command::{CommandError, ServerError};
 use cubes::*;
 use database::*;
 use fabric::*;
 use hash::hash_slot;
 use inflightmap::InFlightMap;
+use lru::LruCache;
+use metrics::{self, Meter};
 use rand::{thread_rng, Rng};
+use resp::RespValue;
+use serde_json;
 use std::collections::hash_map::Entry as HMEntry;
+use std::cmp::{max, min, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use storage::*;
-use utils::{replace_default, IdHashMap, IdHashSet, IdHasherBuilder};
+use utils::{duration_millis, now_millis, replace_default, GenericError, IdHashMap, IdHashSet, IdHasherBuilder};
 use version_vector::*;
 use vnode_sync::*;
 
-const ZOMBIE_TIMEOUT_MS: u64 = 60 * 1_000;
+// staged writes are flushed once the worker queue drains or this many writes accumulate
+const PENDING_WRITES_MAX: usize = 1_000;
+// keys per replication msg once a bulk load ends
+const BULK_LOAD_BATCH: usize = 100;
+// log entries a repair scans looking for a delete of the key
+const REPAIR_LOG_SCAN_MAX: usize = 10_000;
+// milliseconds the clocks of two nodes may differ when checking request deadlines
+pub const DEADLINE_SKEW_MARGIN: u64 = 500;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum VNodeStatus {
@@ -35,6 +50,29 @@ pub struct VNode {
     state: VNodeState,
     syncs: IdHashMap<Cookie, Synchronization>,
     requests: InFlightMap<Cookie, ReqState, Instant, IdHasherBuilder>,
+    // advanced on every sync attempt, used by the round robin peer strategy
+    sync_cursor: usize,
+    scrubs: IdHashMap<Cookie, ScrubState>,
+    // last key checked by the previous scrub, the next one resumes after it
+    scrub_cursor: Option<Bytes>,
+    next_scrub: Instant,
+    // last key checked by the previous expiration sweep
+    expire_cursor: Option<Bytes>,
+    next_expire_sweep: Instant,
+    // keys written locally during a bulk load, replicated once it ends
+    bulk_keys: HashSet<Bytes>,
+}
+
+/// A sample of local keys being checked against the other replicas
+struct ScrubState {
+    keys: Vec<Bytes>,
+    // empty when rebuilding corrupt keys, the local values are gone
+    cubes: Vec<Cube>,
+    rebuild: bool,
+    nodes: Vec<NodeId>,
+    // None if the replica replied with an error
+    replies: IdHashMap<NodeId, Option<Vec<Cube>>>,
+    expire: Instant,
 }
 
 pub struct VNodeState {
@@ -46,6 +84,108 @@ pub struct VNodeState {
     // state for syncs
     pub pending_bootstrap: bool,
     pub sync_nodes: IdHashSet<NodeId>,
+    // clocks reported by peers when they last started a sync with this node
+    pub peer_clocks: IdHashMap<NodeId, BitmappedVersionVector>,
+    pub stats: VNodeStats,
+    // writes staged for the next storage batch, flushed once the worker queue drains
+    // or the batch is full
+    pending: PendingWrites,
+    // acks of the staged writes, sent once they reach the storage
+    acks: Vec<StagedAck>,
+    // reads take the vnode lock shared, so the cache has its own
+    cache: Mutex<ReadCache>,
+    // changes every time the data is discarded, sync cookies carry it so messages
+    // of the previous syncs are told apart. Starts random as it isn't saved.
+    generation: u64,
+    // keys dropped as corrupt, fetched again from the other replicas
+    rebuild: Vec<Bytes>,
+}
+
+/// Decoded cubes of recently read keys with their stored size, writes invalidate them
+struct ReadCache {
+    entries: LruCache<Bytes, (Cube, usize)>,
+    hits: u64,
+}
+
+impl ReadCache {
+    fn new(db: &Database) -> Mutex<Self> {
+        Mutex::new(ReadCache {
+            entries: LruCache::new(db.config().read_cache_size as usize),
+            hits: 0,
+        })
+    }
+}
+
+#[derive(Default)]
+struct PendingWrites {
+    // None means the key is deleted
+    data: HashMap<Bytes, Option<Vec<u8>>>,
+    log: HashMap<(u64, u64), Bytes>,
+}
+
+/// An ack held until the staged writes reach the storage, see `VNode::storage_persist_staged`
+pub enum StagedAck {
+    // the local write of a request coordinated by this node
+    Local(Cookie, Vec<Option<Cube>>),
+    RemoteSet(NodeId, MsgRemoteSetAck),
+    Sync(NodeId, MsgSyncAck),
+}
+
+/// Storage counters for a vnode, in bytes of key + value.
+/// These are kept in memory and start from zero when the vnode is loaded.
+#[derive(Debug, Default, Clone)]
+pub struct VNodeStats {
+    pub bytes_written: u64,
+    pub live_bytes: u64,
+    // overwritten or deleted bytes not yet reclaimed by a compaction
+    pub dead_bytes: u64,
+    pub compactions: u64,
+    pub bytes_reclaimed: u64,
+    pub storage_batches: u64,
+    // writes leaving a register with more concurrent values than before,
+    // usually clients not passing back the context
+    pub siblings_created: u64,
+    // storage reads served by the read cache
+    pub cache_hits: u64,
+    // versions the most lagging replica didn't have as of its last sync with this one
+    pub replication_lag: u64,
+    // sync and bootstrap messages received and the keys in them
+    pub sync_msgs_received: u64,
+    pub sync_keys_received: u64,
+}
+
+/// Upper bounds of the value size buckets reported by `VNode::value_stats`
+pub const VALUE_SIZE_BUCKETS: [u64; 6] = [64, 256, 1024, 4096, 16384, 65536];
+
+/// Distribution of the stored value sizes in a vnode, in bytes of the serialized record
+#[derive(Debug, Default, Clone)]
+pub struct ValueStats {
+    pub keys: u64,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    // values above the previous bound and up to each of VALUE_SIZE_BUCKETS,
+    // the last one counts the values larger than all of them
+    pub buckets: [u64; 7],
+}
+
+/// A key and its cube, exported as one json object per line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportRecord {
+    pub key: Bytes,
+    pub cube: Cube,
+}
+
+/// Everything a node knows about a key, see DEBUG KEYDUMP
+#[derive(Debug, Serialize)]
+pub struct KeyDump {
+    pub key: String,
+    pub vnode: VNodeId,
+    // "pending" while the write is staged in memory, "storage" once it's flushed
+    pub location: &'static str,
+    // bytes of the serialized cube, None if the key isn't stored
+    pub size: Option<usize>,
+    pub ttl_ms: Option<u64>,
+    pub cube: CubeDump,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,6 +200,19 @@ struct ReqState {
     required: u8,
     total: u8,
     context: Context,
+    started: Instant,
+    // replicas not asked by a read, tried if a target can't serve it
+    spare: Vec<NodeId>,
+    spare_msg: Option<MsgRemoteGet>,
+    // replies of a read, if the merged values are written back to stale replicas
+    read_repair: Option<ReadRepairState>,
+    // the response of a read is rendered, only the read repair acks are missing
+    repair_wait: bool,
+}
+
+struct ReadRepairState {
+    keys: Vec<Bytes>,
+    replies: Vec<(NodeId, Vec<Cube>)>,
 }
 
 #[cfg(test)]
@@ -104,6 +257,26 @@ macro_rules! forward {
     };
 }
 
+impl VNodeStats {
+    fn on_write(&mut self, old_len: Option<usize>, new_len: Option<usize>) {
+        if let Some(old_len) = old_len {
+            self.dead_bytes += old_len as u64;
+            self.live_bytes = self.live_bytes.saturating_sub(old_len as u64);
+        }
+        if let Some(new_len) = new_len {
+            self.bytes_written += new_len as u64;
+            self.live_bytes += new_len as u64;
+        }
+    }
+
+    fn on_merge(&mut self, siblings_before: usize, siblings_after: usize) {
+        if siblings_after > 1 && siblings_after > siblings_before {
+            self.siblings_created += 1;
+            metrics::SIBLINGS_CREATED.mark(1);
+        }
+    }
+}
+
 impl ReqState {
     fn new(context: Context, nodes: usize, consistency: ConsistencyLevel) -> Self {
         ReqState {
@@ -112,9 +285,26 @@ impl ReqState {
             replies: 0,
             succesfull: 0,
             context,
+            started: Instant::now(),
+            spare: Vec::new(),
+            spare_msg: None,
+            read_repair: None,
+            repair_wait: false,
         }
     }
 
+    // like new but only `targets` of the `nodes` replicas are asked
+    fn with_targets(
+        context: Context,
+        nodes: usize,
+        targets: usize,
+        consistency: ConsistencyLevel,
+    ) -> Self {
+        let mut state = Self::new(context, nodes, consistency);
+        state.total = targets as u8;
+        state
+    }
+
     fn done(&self) -> bool {
         self.satisfied() || self.replies == self.total
     }
@@ -122,17 +312,61 @@ impl ReqState {
     fn satisfied(&self) -> bool {
         self.succesfull >= self.required
     }
+
+    // asks a spare replica in place of one that failed, if any is left
+    fn ask_spare(&mut self, db: &Database) {
+        if let Some(ref msg) = self.spare_msg {
+            while let Some(node) = self.spare.pop() {
+                if db.fabric.send_msg(node, msg).is_ok() {
+                    self.total += 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl ReadRepairState {
+    // the writes bringing each replica that replied up to date with the merged `reads`
+    fn writes(self, reads: &[ContextRead]) -> Vec<(NodeId, Vec<(Bytes, Cube, bool)>)> {
+        let ReadRepairState { keys, replies } = self;
+        let writes: Vec<_> = replies
+            .into_iter()
+            .filter_map(|(node, cubes)| {
+                let writes: Vec<_> = keys.iter()
+                    .zip(&cubes)
+                    .zip(reads)
+                    .filter(|&((_, cube), read)| !cube_contains(cube, &read.cube))
+                    .map(|((key, _), read)| (key.clone(), read.cube.clone(), false))
+                    .collect();
+                if writes.is_empty() {
+                    None
+                } else {
+                    Some((node, writes))
+                }
+            })
+            .collect();
+        writes
+    }
 }
 
 impl VNode {
     pub fn new(db: &Database, num: u16, status: VNodeStatus) -> VNode {
-        let state = VNodeState::load(num, db, status);
+        let mut state = VNodeState::load(num, db, status);
         state.save(db, false);
 
         let mut vnode = VNode {
             state: state,
             requests: InFlightMap::new(),
             syncs: Default::default(),
+            sync_cursor: thread_rng().gen(),
+            scrubs: Default::default(),
+            scrub_cursor: None,
+            next_scrub: Instant::now() + Duration::from_millis(db.config().scrub_interval as _),
+            expire_cursor: None,
+            next_expire_sweep: Instant::now()
+                + Duration::from_millis(db.config().expire_sweep_interval as _),
+            bulk_keys: Default::default(),
         };
 
         match vnode.status() {
@@ -151,15 +385,288 @@ impl VNode {
         self.state.save(db, shutdown);
     }
 
+    /// Whether the node saved the state of vnode `num`, so it held data at some point
+    pub fn has_saved_state(db: &Database, num: u16) -> bool {
+        db.meta_storage
+            .get_vec(num.to_string().as_bytes())
+            .expect("Can't read saved vnode state")
+            .is_some()
+    }
+
+    /// Saves and loads the vnode again from storage, only possible if it's idle.
+    pub fn reload(&mut self, db: &Database) -> bool {
+        let status = self.status();
+        match status {
+            VNodeStatus::Ready | VNodeStatus::Absent => (),
+            _ => return false,
+        }
+        if !self.requests.is_empty() || !self.syncs.is_empty() || !self.scrubs.is_empty() {
+            return false;
+        }
+        info!("Reloading vnode {}", self.state.num);
+        self.save(db, true);
+        let num = self.state.num;
+        *self = VNode::new(db, num, status);
+        true
+    }
+
     pub fn status(&self) -> VNodeStatus {
         self.state.status
     }
 
+    // drops the writes not yet committed to storage, like a crash would
+    #[cfg(test)]
+    pub fn _discard_pending(&mut self) {
+        self.state.pending = Default::default();
+        self.state.acks.clear();
+    }
+
+    #[cfg(test)]
+    pub fn _clocks(&self) -> BitmappedVersionVector {
+        self.state.clocks.clone()
+    }
+
     #[cfg(test)]
-    pub fn _log_len(&self, node: NodeId) -> usize {
+    pub fn _log_len(&mut self, node: NodeId) -> usize {
+        self.state.storage_flush();
         self.state.storage.log_iterator(node, 0).iter().count()
     }
 
+    #[cfg(test)]
+    pub fn _storage_get_vec(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.state.storage.get_vec(key).unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn _storage_set_vec(&mut self, key: &[u8], value: &[u8]) {
+        self.state.storage_flush();
+        self.state.cache.get_mut().unwrap().entries.remove(key);
+        self.state.storage.set(key, value).unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn _set_status(&mut self, db: &Database, status: VNodeStatus) {
+        self.state.set_status(db, status);
+    }
+
+    #[cfg(test)]
+    pub fn _storage_del(&mut self, key: &[u8]) {
+        self.state.storage_flush();
+        self.state.cache.get_mut().unwrap().entries.remove(key);
+        self.state.storage.del(key).unwrap()
+    }
+
+    pub fn storage_flush(&mut self) {
+        self.state.storage_flush();
+    }
+
+    /// Writes the staged writes to the storage in one batch, then sends the acks waiting
+    /// for them. Called once the worker queue drains, so all the writes handled meanwhile
+    /// share the batch (and the sync of the storage log with the sync commit policy).
+    pub fn storage_persist_staged(&mut self, db: &Database) {
+        let result = self.state.storage_persist(db);
+        if let Err(ref e) = result {
+            error!("Can't persist vnode {} staged writes: {:?}", self.state.num, e);
+        }
+        for ack in replace_default(&mut self.state.acks) {
+            match ack {
+                StagedAck::Local(cookie, cubes) => {
+                    let response = result
+                        .as_ref()
+                        .map(|_| cubes)
+                        .map_err(|_| FabricError::StorageError);
+                    self.process_set(db, cookie, response);
+                }
+                StagedAck::RemoteSet(to, mut ack) => {
+                    if result.is_err() {
+                        ack.result = Err(FabricError::StorageError);
+                    }
+                    let _ = db.fabric.send_msg(to, &ack);
+                }
+                // unacked sync msgs are resent by the sender
+                StagedAck::Sync(to, ack) => if result.is_ok() {
+                    let _ = db.fabric.send_msg(to, &ack);
+                },
+            }
+        }
+    }
+
+    /// Whether some writes are staged for the next storage batch
+    pub fn has_pending_writes(&self) -> bool {
+        !self.state.pending.data.is_empty() || !self.state.pending.log.is_empty()
+    }
+
+    /// Whether the vnode doesn't store any key, not even tombstones
+    pub fn is_storage_empty(&mut self) -> bool {
+        self.state.storage_flush();
+        self.state.storage.iterator().iter().next().is_none()
+    }
+
+    /// Writes a record that can't be deserialized under `key`, bypassing the clocks and log
+    #[cfg(debug_assertions)]
+    pub fn corrupt(&mut self, key: &[u8]) -> Result<(), GenericError> {
+        warn!("Corrupting key {:?} of vnode {}", key, self.state.num);
+        self.state.storage_flush();
+        self.state.cache.get_mut().unwrap().entries.remove(key);
+        // not a valid Cube variant
+        self.state.storage.set(key, &[0xff; 4])
+    }
+
+    /// Deletes all data and clocks of the vnode, used by FLUSHALL
+    pub fn reset(&mut self, db: &Database) {
+        info!("Resetting vnode {}", self.state.num);
+        self.bulk_keys.clear();
+        self.state.clear();
+        self.state.save(db, false);
+    }
+
+    /// Reads the local copy of the key, never asking other replicas
+    pub fn local_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        self.state.storage_get_raw(key)
+    }
+
+    pub fn dump_key(&self, key: &[u8]) -> Result<KeyDump, ()> {
+        let location = if self.state.pending.data.contains_key(key) {
+            "pending"
+        } else {
+            "storage"
+        };
+        let raw = self.state.storage_get_raw(key)?;
+        let cube = match raw {
+            Some(ref bytes) => deserialize_cube(bytes).map_err(|_| ())?,
+            None => Cube::default(),
+        };
+        Ok(KeyDump {
+            key: String::from_utf8_lossy(key).into_owned(),
+            vnode: self.state.num,
+            location,
+            size: raw.as_ref().map(|b| b.len()),
+            ttl_ms: cube.expire().map(|at| at.saturating_sub(now_millis())),
+            cube: cube.dump(),
+        })
+    }
+
+    /// Drops the inflight coordinated requests of a client, their replies are ignored.
+    /// Returns the contexts of the requests dropped.
+    pub fn cancel_requests(&mut self, token: Token) -> Vec<Context> {
+        let cookies: Vec<_> = self.requests
+            .iter()
+            .filter(|&(_, req)| req.context.token == token)
+            .map(|(&cookie, _)| cookie)
+            .collect();
+        cookies
+            .into_iter()
+            .filter_map(|cookie| self.requests.remove(&cookie))
+            .map(|req| req.context)
+            .collect()
+    }
+
+    fn insert_request(&mut self, db: &Database, cookie: Cookie, req: ReqState, expire: Instant) {
+        db.client_request(req.context.token, self.state.num);
+        self.requests.insert(cookie, req, expire);
+    }
+
+    /// Age in millis of each inflight coordinated request
+    pub fn requests_ages(&self, now: Instant) -> Vec<u64> {
+        self.requests
+            .values()
+            .map(|req| duration_millis(now.duration_since(req.started)))
+            .collect()
+    }
+
+    pub fn stats(&self, db: &Database) -> VNodeStats {
+        let mut stats = self.state.stats.clone();
+        stats.cache_hits = self.state.cache.lock().unwrap().hits;
+        stats.replication_lag = self.replication_lag(db);
+        stats
+    }
+
+    /// Versions in this vnode that the replicas weren't confirmed to have, taking the most
+    /// lagging one. Replicas confirm their clocks when syncing, until then they lag all of them.
+    pub fn replication_lag(&self, db: &Database) -> u64 {
+        let empty = BitmappedVersionVector::new();
+        db.dht
+            .nodes_for_vnode(self.state.num, false, true)
+            .into_iter()
+            .filter(|&node| node != db.dht.node())
+            .map(|node| {
+                let peer_clocks = self.state.peer_clocks.get(&node).unwrap_or(&empty);
+                clocks_lag(&self.state.clocks, peer_clocks)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn compact(&mut self) {
+        self.state.compact();
+    }
+
+    /// Writes every key in the vnode to `writer` as newline delimited json
+    pub fn export_ndjson<W: Write>(&mut self, mut writer: W) -> Result<usize, GenericError> {
+        self.state.storage_flush();
+        let mut count = 0;
+        let mut iterator = self.state.storage.iterator();
+        for (key, value) in iterator.iter() {
+            let record = ExportRecord {
+                key: Bytes::from(key),
+                cube: deserialize_cube(value)?,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Sizes of the values stored in the vnode, tombstones included
+    pub fn value_stats(&mut self) -> ValueStats {
+        self.state.storage_flush();
+        let mut stats = ValueStats::default();
+        let mut iterator = self.state.storage.iterator();
+        for (_, value) in iterator.iter() {
+            let size = value.len() as u64;
+            stats.keys += 1;
+            stats.bytes += size;
+            stats.max_bytes = max(stats.max_bytes, size);
+            let bucket = VALUE_SIZE_BUCKETS
+                .iter()
+                .position(|&b| size <= b)
+                .unwrap_or(VALUE_SIZE_BUCKETS.len());
+            stats.buckets[bucket] += 1;
+        }
+        stats
+    }
+
+    /// Keys stored in the vnode, optionally only those of type `type_name`,
+    /// tombstones are skipped and values are never deserialized.
+    /// Adds the keys following `after` (or from the first one) until `keys` has `count`
+    /// of them, returns false if the vnode was exhausted first.
+    /// Staged writes aren't seen, see `has_pending_writes`.
+    pub fn scan_keys(
+        &self,
+        after: Option<&[u8]>,
+        count: usize,
+        type_name: Option<&str>,
+        keys: &mut Vec<Bytes>,
+    ) -> bool {
+        let mut iterator = match after {
+            Some(after) => self.state.storage.iterator_after(after),
+            None => self.state.storage.iterator(),
+        };
+        for (key, value) in iterator.iter() {
+            match Cube::serialized_type_name(value) {
+                Some("none") | None => continue,
+                Some(t) if type_name.map_or(false, |n| n != t) => continue,
+                _ => keys.push(Bytes::from(key)),
+            }
+            if keys.len() >= count {
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn syncs_inflight(&self) -> (usize, usize) {
         let pend = if self.state.pending_bootstrap { 1 } else { 0 };
         self.syncs
@@ -178,12 +685,31 @@ impl VNode {
         Cookie::new(rng.gen(), rng.gen())
     }
 
+    // cookie for the fabric msgs of a client request, tracing it in the replicas logs
+    fn gen_request_cookie(&self, context: &Context) -> Cookie {
+        Cookie::new(context.request_id, thread_rng().gen())
+    }
+
+    // cookie for syncs received by this vnode
+    fn gen_sync_cookie(&self) -> Cookie {
+        Cookie::new(self.state.generation, thread_rng().gen())
+    }
+
+    #[cfg(test)]
+    pub fn _generation(&self) -> u64 {
+        self.state.generation
+    }
+
     // DHT Changes
     pub fn handler_dht_change(&mut self, db: &Database, x_status: VNodeStatus) {
         match x_status {
             VNodeStatus::Absent | VNodeStatus::Ready => (),
             status => panic!("Invalid final status {:?}", status),
         }
+        // forget the clocks of nodes that are no longer replicas
+        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
+        self.state.peer_clocks.retain(|node, _| nodes.contains(node));
+
         let status = self.status();
         match (status, x_status) {
             (VNodeStatus::Ready, VNodeStatus::Absent)
@@ -241,6 +767,11 @@ impl VNode {
 
     // TICK
     pub fn handler_tick(&mut self, db: &Database, _time: Instant) {
+        // a busy worker queue may not drain for a while, don't hold the acks past a tick
+        if !self.state.acks.is_empty() {
+            self.storage_persist_staged(db);
+        }
+
         let terminated_syncs = {
             let state = &mut self.state;
             self.syncs
@@ -267,8 +798,45 @@ impl VNode {
                 "Request cookie:{:?} token:{} timed out",
                 cookie, req.context.token
             );
-            req.context.clear();
-            db.respond_error(&mut req.context, CommandError::Timeout);
+            if req.repair_wait {
+                // the read itself succeeded, only the write-backs are missing
+                db.respond(&mut req.context);
+            } else {
+                req.context.clear();
+                db.respond_error(&mut req.context, ServerError::Timeout);
+            }
+        }
+
+        if self.status() == VNodeStatus::Ready && db.config().scrub_interval != 0
+            && now >= self.next_scrub
+        {
+            self.next_scrub = now + Duration::from_millis(db.config().scrub_interval as _);
+            if self.scrubs.is_empty() {
+                self.start_scrub(db);
+            }
+        }
+        if self.status() == VNodeStatus::Ready && !self.state.rebuild.is_empty()
+            && self.scrubs.is_empty()
+        {
+            self.start_rebuild(db);
+        }
+        let expired_scrubs: Vec<_> = self.scrubs
+            .iter()
+            .filter(|&(_, s)| s.expire <= now)
+            .map(|(&cookie, _)| cookie)
+            .collect();
+        for cookie in expired_scrubs {
+            debug!("Scrub cookie:{:?} timed out", cookie);
+            let scrub = self.scrubs.remove(&cookie).unwrap();
+            self.finish_scrub(db, scrub);
+        }
+
+        if self.status() == VNodeStatus::Ready && db.config().expire_sweep_interval != 0
+            && now >= self.next_expire_sweep
+        {
+            self.next_expire_sweep =
+                now + Duration::from_millis(db.config().expire_sweep_interval as _);
+            self.sweep_expired(db);
         }
 
         if self.state.pending_bootstrap {
@@ -276,13 +844,39 @@ impl VNode {
             self.start_bootstrap(db);
         } else if self.status() == VNodeStatus::Zombie && self.requests.is_empty()
             && self.syncs.is_empty()
-            && self.state.last_status_change.elapsed() > Duration::from_millis(ZOMBIE_TIMEOUT_MS)
+            && self.state.last_status_change.elapsed()
+                > Duration::from_millis(db.config().vnode_zombie_timeout as _)
+            && self.handoff_done(db)
         {
             // go absent when zombie timeout
             self.state.set_status(db, VNodeStatus::Absent);
         }
     }
 
+    // New owners are pending until they finish bootstrapping,
+    // zombies keep the data around until then as the bootstrap may need it.
+    fn handoff_done(&self, db: &Database) -> bool {
+        let owners = db.dht.nodes_for_vnode(self.state.num, false, false);
+        let pending = db.dht.nodes_for_vnode(self.state.num, true, false);
+        if owners.len() != pending.len() {
+            debug!(
+                "vnode:{} zombie waiting for pending nodes {:?}",
+                self.state.num, pending
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    // the pending owners may still bootstrap from a zombie past its timeout
+    fn is_handoff_target(&self, db: &Database, node: NodeId) -> bool {
+        db.dht.nodes_for_vnode(self.state.num, true, false).contains(&node)
+            && !db.dht
+                .nodes_for_vnode(self.state.num, false, false)
+                .contains(&node)
+    }
+
     // CLIENT CRUD
     pub fn do_get(
         &mut self,
@@ -293,9 +887,10 @@ impl VNode {
         response_fn: ResponseFn,
     ) -> Result<(), CommandError> {
         debug!(
-            "vnode:{:?} do_get ({:?}) {:?}",
+            "vnode:{:?} do_get ({:?}) req:{:016X} {:?}",
             self.state.num(),
             context.token,
+            context.request_id,
             consistency
         );
         let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
@@ -303,9 +898,39 @@ impl VNode {
             debug!("vnode:{:?} no nodes", self.state.num());
             return Err(CommandError::Unavailable);
         }
-        let participate = nodes.contains(&db.dht.node());
-        let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
+        self.check_available(db, &nodes, consistency)?;
+        let participate = match self.status() {
+            VNodeStatus::Ready => nodes.contains(&db.dht.node()),
+            // the local data is incomplete until the bootstrap finishes
+            VNodeStatus::Bootstrap => match db.config().bootstrap_read_policy {
+                BootstrapReadPolicy::Redirect => false,
+                BootstrapReadPolicy::ServeIfPresent => keys.iter().all(|k| {
+                    self.state.storage_get_len(k).ok().map_or(false, |l| l.is_some())
+                }),
+                BootstrapReadPolicy::Error => return Err(CommandError::TryAgain),
+            },
+            _ => false,
+        };
+        // speculative reads, ask a few more replicas than required
+        // and complete with the first responses
+        let fanout = consistency.required(nodes.len() as u8) as usize
+            + db.config().read_fanout_extra as usize;
+        let connections = db.fabric.connections();
+        let down = db.dht.down_nodes();
+        let mut targets: Vec<_> = nodes
+            .iter()
+            .cloned()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        thread_rng().shuffle(&mut targets);
+        // the ones that stopped heartbeating are only asked if there aren't enough others
+        targets.sort_by_key(|n| !connections.contains(n) || down.contains(n));
+        let asked = min(targets.len(), fanout.saturating_sub(participate as usize));
+        let mut spare = targets.split_off(asked);
+        // spares are popped from the back, keep the preferred ones there
+        spare.reverse();
+        let cookie = self.gen_request_cookie(context);
+        let expire = Instant::now() + Duration::from_millis(db.config().request_timeout as _);
 
         let mut response_fn = Some(response_fn);
         for key in keys {
@@ -320,33 +945,97 @@ impl VNode {
             });
         }
 
-        let req = ReqState::new(replace_default(context), nodes.len(), consistency);
-        self.requests.insert(cookie, req, expire);
+        let msg = MsgRemoteGet {
+            cookie: cookie,
+            vnode: self.state.num,
+            keys: keys.iter().map(|&x| x.clone()).collect(),
+            deadline: Some(now_millis() + db.config().request_timeout as u64),
+        };
+        let mut req = ReqState::with_targets(
+            replace_default(context),
+            nodes.len(),
+            targets.len() + participate as usize,
+            consistency,
+        );
+        if !spare.is_empty() {
+            req.spare = spare;
+            req.spare_msg = Some(msg.clone());
+        }
+        if db.config().read_repair != ReadRepair::Off {
+            let mut replies = Vec::new();
+            if participate {
+                let cubes: Vec<_> = req.context.reads.iter().map(|r| r.cube.clone()).collect();
+                replies.push((db.dht.node(), cubes));
+            }
+            req.read_repair = Some(ReadRepairState {
+                keys: msg.keys.clone(),
+                replies: replies,
+            });
+        }
+        self.insert_request(db, cookie, req, expire);
 
         if participate {
             // register the results added above
-            if self.process_get::<Option<_>>(db, cookie, Ok(None)) {
+            if self.process_get::<Option<_>>(db, cookie, db.dht.node(), Ok(None)) {
                 return Ok(());
             }
         }
 
-        let msg = MsgRemoteGet {
-            cookie: cookie,
-            vnode: self.state.num,
-            keys: keys.iter().map(|&x| x.clone()).collect(),
-        };
-        for node in nodes {
-            if node != db.dht.node() {
-                if let Err(err) = db.fabric.send_msg(node, &msg) {
-                    if self.process_get::<Option<_>>(db, cookie, Err(err)) {
-                        return Ok(());
-                    }
+        for node in targets {
+            if let Err(err) = db.fabric.send_msg(node, &msg) {
+                if self.process_get::<Option<_>>(db, cookie, node, Err(err)) {
+                    return Ok(());
                 }
             }
         }
         Ok(())
     }
 
+    // Fails upfront if the reachable replicas can't satisfy the consistency level,
+    // otherwise the request could be partially applied before failing.
+    fn check_available(
+        &self,
+        db: &Database,
+        nodes: &[NodeId],
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        if db.config().unavailable_policy == UnavailablePolicy::BestEffort {
+            return Ok(());
+        }
+        let connections = db.fabric.connections();
+        let down = db.dht.down_nodes();
+        let available = nodes
+            .iter()
+            .filter(|&n| *n == db.dht.node() || connections.contains(n) && !down.contains(n))
+            .count();
+        if available < consistency.required(nodes.len() as u8) as usize {
+            debug!(
+                "vnode:{:?} only {} of {:?} replicas available for {:?}",
+                self.state.num(),
+                available,
+                nodes,
+                consistency
+            );
+            Err(CommandError::Unavailable)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Asks the client to send the write to one of the other replicas
+    fn respond_standby(&mut self, db: &Database, context: &mut Context) {
+        let hash_slot = hash_slot(&context.writes[0].key);
+        let mut nodes = db.dht.nodes_for_vnode_ex(self.state.num(), false, true);
+        thread_rng().shuffle(&mut nodes);
+        for (node, (_, addr)) in nodes {
+            if node != db.dht.node() {
+                return db.respond_ask(context, hash_slot, addr);
+            }
+        }
+
+        db.respond_error(context, ServerError::ClusterDown);
+    }
+
     fn respond_cant_coordinate(
         &mut self,
         db: &Database,
@@ -355,7 +1044,13 @@ impl VNode {
     ) {
         let hash_slot = hash_slot(&context.writes[0].key);
         let mut nodes = db.dht.nodes_for_vnode_ex(self.state.num(), true, false);
-        thread_rng().shuffle(&mut nodes);
+        let policy = db.config().coordinator_policy;
+        let queue_depths = if policy == CoordinatorPolicy::LeastLoaded {
+            db.fabric.queue_depths().into_iter().collect()
+        } else {
+            Default::default()
+        };
+        order_coordinators(policy, &mut nodes, &queue_depths);
         for (node, (_, addr)) in nodes {
             if node != db.dht.node() {
                 match status {
@@ -370,7 +1065,7 @@ impl VNode {
             }
         }
 
-        db.respond_error(context, CommandError::Unavailable);
+        db.respond_error(context, ServerError::ClusterDown);
     }
 
     pub fn do_flush(
@@ -380,17 +1075,33 @@ impl VNode {
         consistency: ConsistencyLevel,
     ) -> Result<(), CommandError> {
         match self.status() {
+            VNodeStatus::Ready if db.is_standby() => {
+                return Ok(self.respond_standby(db, context));
+            }
             VNodeStatus::Ready => (),
             status => return Ok(self.respond_cant_coordinate(db, context, status)),
         }
 
+        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
+        let bulk_load = db.is_bulk_loading();
+        if !bulk_load {
+            self.check_available(db, &nodes, consistency)?;
+        }
+
         let mut error = None;
+        let now = now_millis();
+        // stored sizes of the keys, so the write stats don't read them again
+        let mut old_lens = Vec::with_capacity(context.writes.len());
         for write in &mut context.writes {
             let old_cube = match self.state
-                .storage_get(&write.key)
+                .storage_get_sized(&write.key)
                 .map_err(|_| CommandError::StorageError)
             {
-                Ok(old_cube) => old_cube,
+                // writes start over on expired keys
+                Ok((old_cube, old_len)) => {
+                    old_lens.push(old_len);
+                    old_cube.clear_expired(db.dht.node(), now)
+                }
                 Err(e) => {
                     error = Some(e);
                     break;
@@ -399,8 +1110,10 @@ impl VNode {
 
             write.version = self.state.clocks.event(db.dht.node());
             let mutator = write.mutator_fn.take().expect("No MutatorFn");
+            let siblings = old_cube.siblings();
             match mutator(db.dht.node(), write.version, old_cube) {
                 Ok((cube, opt_resp)) => {
+                    self.state.stats.on_merge(siblings, cube.siblings());
                     write.cube = cube;
                     write.response = opt_resp;
                 }
@@ -415,20 +1128,29 @@ impl VNode {
             return Err(e);
         }
 
-        let cookie = self.gen_cookie();
-        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
-        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
+        let cookie = self.gen_request_cookie(context);
+        debug!(
+            "vnode:{:?} do_flush ({:?}) req:{:016X} {} writes {:?}",
+            self.state.num(),
+            context.token,
+            context.request_id,
+            context.writes.len(),
+            consistency
+        );
+        let expire = Instant::now() + Duration::from_millis(db.config().request_timeout as _);
 
-        match self.state.storage_set_local(
+        self.state.storage_set_local(
             db,
             context
                 .writes
                 .iter()
-                .map(|w| (w.version, &w.key[..], &w.cube)),
-        ) {
-            Ok(()) => (),
-            Err(e) => return Err(e),
-        };
+                .zip(old_lens)
+                .map(|(w, old_len)| (w.version, &w.key[..], &w.cube, old_len)),
+        )?;
+
+        if bulk_load {
+            self.bulk_keys.extend(context.writes.iter().map(|w| w.key.clone()));
+        }
 
         // The code bellow is carefully ordered to move Cubes around without cloning
 
@@ -442,13 +1164,140 @@ impl VNode {
                 .map(|w| (w.key.clone(), replace_default(&mut w.cube), w.reply_result))
                 .collect(),
             reply: consistency != ConsistencyLevel::One,
+            repair: false,
+            deadline: Some(now_millis() + db.config().request_timeout as u64),
         };
 
         // 2. create reqstate, note that writes have have nil cubes at this point
+        // bulk loads only wait for the local write
+        let req = if bulk_load {
+            ReqState::new(replace_default(context), 1, ConsistencyLevel::One)
+        } else {
+            ReqState::new(replace_default(context), nodes.len(), consistency)
+        };
+        self.insert_request(db, cookie, req, expire);
+
+        // 3. send the msgs, bulk loads replicate once they end
+        for &node in &nodes {
+            if node != db.dht.node() && !bulk_load {
+                if let Err(err) = db.fabric.send_msg(node, &msg) {
+                    if self.process_set::<Option<_>>(db, cookie, Err(err)) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        // 4. get back the cubes from msg, the local write counts once it's in the storage
+        let cubes = msg.writes.into_iter().map(|w| Some(w.1)).collect();
+        self.state.ack_once_stored(db, StagedAck::Local(cookie, cubes));
+
+        Ok(())
+    }
+
+    /// Sends the keys written during a bulk load to the other replicas in batches,
+    /// returns the number of keys replicated.
+    pub fn bulk_load_replicate(&mut self, db: &Database) -> usize {
+        let keys: Vec<_> = self.bulk_keys.drain().collect();
+        let nodes: Vec<_> = db.dht
+            .nodes_for_vnode(self.state.num, true, true)
+            .into_iter()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        for batch in keys.chunks(BULK_LOAD_BATCH) {
+            let mut writes = Vec::with_capacity(batch.len());
+            for key in batch {
+                match self.state.storage_get(key) {
+                    Ok(cube) => writes.push((key.clone(), cube, false)),
+                    Err(_) => warn!(
+                        "Can't read bulk loaded key {:?} of vnode {}",
+                        key, self.state.num
+                    ),
+                }
+            }
+            let msg = MsgRemoteSet {
+                cookie: self.gen_cookie(),
+                vnode: self.state.num,
+                writes: writes,
+                reply: false,
+                repair: false,
+                deadline: None,
+            };
+            for &node in &nodes {
+                if let Err(e) = db.fabric.send_msg(node, &msg) {
+                    warn!("Can't send bulk load to node {}: {:?}", node, e);
+                }
+            }
+        }
+        keys.len()
+    }
+
+    /// Merges an imported key into this vnode replicas, like a replicated write
+    /// the causal context of the cube is kept and concurrent values become siblings.
+    pub fn do_import(&mut self, db: &Database, key: Bytes, cube: Cube) -> Result<(), CommandError> {
+        let msg = MsgRemoteSet {
+            cookie: self.gen_cookie(),
+            vnode: self.state.num,
+            writes: vec![(key, cube, false)],
+            reply: false,
+            repair: false,
+            deadline: None,
+        };
+        let mut local = false;
+        for node in db.dht.nodes_for_vnode(self.state.num, true, true) {
+            if node == db.dht.node() {
+                local = true;
+            } else if let Err(e) = db.fabric.send_msg(node, &msg) {
+                warn!("Can't send import to node {}: {:?}", node, e);
+            }
+        }
+
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Bootstrap if local => self.state
+                .storage_set_remote(db, msg.writes)
+                .map(|_| ())
+                .map_err(|_| CommandError::StorageError),
+            _ => Ok(()),
+        }
+    }
+
+    /// Coordinates merging restored cubes into this vnode replicas, like replicated writes
+    /// the causal context of the cubes is kept and concurrent values become siblings.
+    /// The request is responded once `consistency` replicas acknowledged the writes.
+    pub fn do_restore(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        consistency: ConsistencyLevel,
+    ) -> Result<(), CommandError> {
+        match self.status() {
+            VNodeStatus::Ready if db.is_standby() => {
+                return Ok(self.respond_standby(db, context));
+            }
+            VNodeStatus::Ready => (),
+            status => return Ok(self.respond_cant_coordinate(db, context, status)),
+        }
+
+        let nodes = db.dht.nodes_for_vnode(self.state.num, true, true);
+        self.check_available(db, &nodes, consistency)?;
+
+        let cookie = self.gen_request_cookie(context);
+        let expire = Instant::now() + Duration::from_millis(db.config().request_timeout as _);
+        let msg = MsgRemoteSet {
+            cookie: cookie,
+            vnode: self.state.num,
+            writes: context
+                .writes
+                .iter_mut()
+                .map(|w| (w.key.clone(), replace_default(&mut w.cube), false))
+                .collect(),
+            reply: true,
+            repair: false,
+            deadline: Some(now_millis() + db.config().request_timeout as u64),
+        };
         let req = ReqState::new(replace_default(context), nodes.len(), consistency);
-        self.requests.insert(cookie, req, expire);
+        self.insert_request(db, cookie, req, expire);
 
-        // 3. send the msgs
         for &node in &nodes {
             if node != db.dht.node() {
                 if let Err(err) = db.fabric.send_msg(node, &msg) {
@@ -459,10 +1308,320 @@ impl VNode {
             }
         }
 
-        // 4. get back the cubes from msg and process_set
-        self.process_set(db, cookie, Ok(msg.writes.into_iter().map(|w| Some(w.1))));
+        // same as a remote set from another coordinator
+        match self.state.storage_set_remote(db, msg.writes) {
+            Ok(results) => self.state
+                .ack_once_stored(db, StagedAck::Local(cookie, results)),
+            Err(()) => {
+                self.process_set::<Option<_>>(db, cookie, Err(FabricError::StorageError));
+            }
+        }
+        Ok(())
+    }
+
+    // SCRUB
+    fn start_scrub(&mut self, db: &Database) {
+        let nodes: Vec<_> = db.dht
+            .nodes_for_vnode(self.state.num, false, false)
+            .into_iter()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        if nodes.is_empty() {
+            return;
+        }
+
+        let sample_size = db.config().scrub_sample_size as usize;
+        let mut keys = Vec::with_capacity(sample_size);
+        let mut cubes = Vec::with_capacity(sample_size);
+        self.state.storage_flush();
+        {
+            let start = self.scrub_cursor.take().unwrap_or_default();
+            let mut iterator = self.state.storage.iterator_from(&start);
+            for (key, value) in iterator.iter() {
+                if keys.len() >= sample_size {
+                    break;
+                }
+                if !start.is_empty() && key == &start[..] {
+                    continue;
+                }
+                match deserialize_cube(value) {
+                    Ok(Cube::Void(_)) => (),
+                    Ok(cube) => {
+                        keys.push(Bytes::from(key));
+                        cubes.push(cube);
+                    }
+                    Err(_) => warn!("Can't deserialize key in vnode {}", self.state.num),
+                }
+            }
+        }
+        // start over once the end is reached
+        if keys.len() >= sample_size {
+            self.scrub_cursor = keys.last().cloned();
+        }
+        if keys.is_empty() {
+            return;
+        }
+
+        let cookie = self.gen_cookie();
+        debug!(
+            "Starting scrub {:?} of vnode {} with {} keys",
+            cookie,
+            self.state.num,
+            keys.len()
+        );
+        let msg = MsgRemoteGet {
+            cookie: cookie,
+            vnode: self.state.num,
+            keys: keys.clone(),
+            deadline: None,
+        };
+        let mut replies = IdHashMap::default();
+        for &node in &nodes {
+            if let Err(e) = db.fabric.send_msg(node, &msg) {
+                replies.insert(node, None);
+                debug!("Can't send scrub to node {}: {:?}", node, e);
+            }
+        }
+        self.scrubs.insert(
+            cookie,
+            ScrubState {
+                keys: keys,
+                cubes: cubes,
+                rebuild: false,
+                nodes: nodes,
+                replies: replies,
+                expire: Instant::now() + Duration::from_millis(db.config().request_timeout as _),
+            },
+        );
+    }
+
+    fn process_scrub(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetAck) {
+        let done = {
+            let scrub = self.scrubs.get_mut(&msg.cookie).unwrap();
+            let reply = match msg.result {
+                Ok(cubes) => if cubes.len() == scrub.keys.len() {
+                    Some(cubes)
+                } else {
+                    None
+                },
+                Err(e) => {
+                    debug!("Scrub {:?} error from node {}: {:?}", msg.cookie, from, e);
+                    None
+                }
+            };
+            scrub.replies.insert(from, reply);
+            scrub.replies.len() >= scrub.nodes.len()
+        };
+        if done {
+            let scrub = self.scrubs.remove(&msg.cookie).unwrap();
+            self.finish_scrub(db, scrub);
+        }
+    }
+
+    // fetches the keys dropped as corrupt from the other replicas, like a scrub in reverse
+    fn start_rebuild(&mut self, db: &Database) {
+        let nodes: Vec<_> = db.dht
+            .nodes_for_vnode(self.state.num, false, false)
+            .into_iter()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        if nodes.is_empty() {
+            warn!(
+                "No replicas to rebuild {} corrupt keys of vnode {} from",
+                self.state.rebuild.len(),
+                self.state.num
+            );
+            self.state.rebuild.clear();
+            return;
+        }
+
+        let batch = max(db.config().scrub_sample_size as usize, 1);
+        let count = min(self.state.rebuild.len(), batch);
+        let keys: Vec<_> = self.state.rebuild.drain(..count).collect();
+        let cookie = self.gen_cookie();
+        debug!(
+            "Starting rebuild {:?} of vnode {} with {} keys",
+            cookie,
+            self.state.num,
+            keys.len()
+        );
+        let msg = MsgRemoteGet {
+            cookie: cookie,
+            vnode: self.state.num,
+            keys: keys.clone(),
+            deadline: None,
+        };
+        let mut replies = IdHashMap::default();
+        for &node in &nodes {
+            if let Err(e) = db.fabric.send_msg(node, &msg) {
+                replies.insert(node, None);
+                debug!("Can't send rebuild to node {}: {:?}", node, e);
+            }
+        }
+        self.scrubs.insert(
+            cookie,
+            ScrubState {
+                keys: keys,
+                cubes: Vec::new(),
+                rebuild: true,
+                nodes: nodes,
+                replies: replies,
+                expire: Instant::now() + Duration::from_millis(db.config().request_timeout as _),
+            },
+        );
+    }
+
+    fn finish_rebuild(
+        &mut self,
+        db: &Database,
+        keys: Vec<Bytes>,
+        replies: IdHashMap<NodeId, Option<Vec<Cube>>>,
+    ) {
+        let mut replies: Vec<_> = replies.into_iter().filter_map(|(_, r)| r).collect();
+        if replies.is_empty() {
+            // retried on the next tick
+            self.state.rebuild.extend(keys);
+            return;
+        }
+        for (i, key) in keys.into_iter().enumerate() {
+            let cube = replies
+                .iter_mut()
+                .fold(Cube::default(), |cube, r| cube.merge(replace_default(&mut r[i])));
+            // also deleted in the other replicas
+            if let Cube::Void(_) = cube {
+                continue;
+            }
+            info!("Rebuilding corrupt key {:?} of vnode {}", key, self.state.num);
+            if self.state.storage_repair(db, key, cube).is_err() {
+                warn!("Can't rebuild key in vnode {}", self.state.num);
+            }
+        }
+    }
+
+    fn finish_scrub(&mut self, db: &Database, scrub: ScrubState) {
+        let ScrubState {
+            keys,
+            cubes,
+            rebuild,
+            replies,
+            ..
+        } = scrub;
+        if rebuild {
+            return self.finish_rebuild(db, keys, replies);
+        }
+        let mut repairs = 0;
+        for (i, (key, cube)) in keys.into_iter().zip(cubes).enumerate() {
+            let mut missing = Vec::new();
+            for (&node, reply) in &replies {
+                match *reply {
+                    Some(ref cubes) if !cube_contains(&cubes[i], &cube) => missing.push(node),
+                    _ => (),
+                }
+            }
+            // a missing key could also be a delete this replica didn't see yet,
+            // the target tells them apart from its log, see `storage_repair`
+            if missing.is_empty() {
+                continue;
+            }
+            let msg = MsgRemoteSet {
+                cookie: self.gen_cookie(),
+                vnode: self.state.num,
+                writes: vec![(key, cube, false)],
+                reply: false,
+                repair: true,
+                deadline: None,
+            };
+            for node in missing {
+                if repairs >= db.config().scrub_repair_max {
+                    return;
+                }
+                info!(
+                    "Scrub repairing key {:?} of vnode {} in node {}",
+                    msg.writes[0].0, self.state.num, node
+                );
+                metrics::SCRUB_REPAIR.mark(1);
+                let _ = db.fabric.send_msg(node, &msg);
+                repairs += 1;
+            }
+        }
+    }
+
+    // EXPIRE
+    fn sweep_expired(&mut self, db: &Database) {
+        // a single replica sweeps, the others get the tombstones from it
+        let owners = db.dht.nodes_for_vnode(self.state.num, false, false);
+        if owners.first() != Some(&db.dht.node()) {
+            return;
+        }
+
+        let sweep_size = db.config().expire_sweep_size as usize;
+        let now = now_millis();
+        let mut expired = Vec::new();
+        self.state.storage_flush();
+        {
+            let start = self.expire_cursor.take().unwrap_or_default();
+            let mut iterator = self.state.storage.iterator_from(&start);
+            let mut checked = 0;
+            for (key, value) in iterator.iter() {
+                if checked >= sweep_size {
+                    break;
+                }
+                if !start.is_empty() && key == &start[..] {
+                    continue;
+                }
+                checked += 1;
+                // start over once the end is reached
+                if checked == sweep_size {
+                    self.expire_cursor = Some(Bytes::from(key));
+                }
+                match deserialize_cube(value) {
+                    Ok(cube) => if cube.is_expired(now) {
+                        expired.push((Bytes::from(key), cube, key.len() + value.len()));
+                    },
+                    Err(_) => warn!("Can't deserialize key in vnode {}", self.state.num),
+                }
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Sweeping {} expired keys of vnode {}",
+            expired.len(),
+            self.state.num
+        );
+        let node = db.dht.node();
+        let mut writes = Vec::with_capacity(expired.len());
+        for (key, cube, len) in expired {
+            let version = self.state.clocks.event(node);
+            let mut cube = cube.clear_expired(node, now);
+            cube.del(node, version, &VersionVector::new());
+            writes.push((version, key, cube, len));
+        }
+        if let Err(e) = self.state.storage_set_local(
+            db,
+            writes
+                .iter()
+                .map(|&(v, ref k, ref c, len)| (v, &k[..], c, Some(len))),
+        ) {
+            warn!("Can't sweep vnode {}: {:?}", self.state.num, e);
+            return;
+        }
 
-        Ok(())
+        let msg = MsgRemoteSet {
+            cookie: self.gen_cookie(),
+            vnode: self.state.num,
+            writes: writes.into_iter().map(|(_, k, c, _)| (k, c, false)).collect(),
+            reply: false,
+            repair: false,
+            deadline: None,
+        };
+        for replica in db.dht.nodes_for_vnode(self.state.num, true, false) {
+            if replica != node {
+                let _ = db.fabric.send_msg(replica, &msg);
+            }
+        }
     }
 
     // OTHER
@@ -470,19 +1629,47 @@ impl VNode {
         &mut self,
         db: &Database,
         cookie: Cookie,
+        from: NodeId,
         response: Result<I, FabricError>,
     ) -> bool {
-        if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
-            debug!("process_get {:?}", cookie);
+        // read repairs can only be sent once the request is released
+        let mut repair = None;
+        let done = if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
+            debug!(
+                "process_get {:?} req:{:016X} from {}",
+                cookie,
+                cookie.request_id(),
+                from
+            );
             let done = {
                 let state = o.get_mut();
                 state.replies += 1;
-                if let Ok(response) = response {
-                    state.succesfull += 1;
-                    for (response, read) in response.into_iter().zip(&mut state.context.reads) {
-                        let cube = replace_default(&mut read.cube);
-                        read.cube = cube.merge(response);
+                match response {
+                    Ok(response) => {
+                        state.succesfull += 1;
+                        let mut cubes = Vec::new();
+                        for (response, read) in response.into_iter().zip(&mut state.context.reads)
+                        {
+                            if state.read_repair.is_some() {
+                                cubes.push(response.clone());
+                            }
+                            let cube = replace_default(&mut read.cube);
+                            read.cube = cube.merge(response);
+                        }
+                        if let Some(ref mut read_repair) = state.read_repair {
+                            // the local reply is registered upfront without cubes
+                            if cubes.len() == read_repair.keys.len() {
+                                read_repair.replies.push((from, cubes));
+                            }
+                        }
+                    }
+                    // the replica can't serve the vnode, skip it and try another one
+                    Err(FabricError::BadVNodeStatus) | Err(FabricError::NoRoute) => {
+                        if !state.satisfied() {
+                            state.ask_spare(db);
+                        }
                     }
+                    Err(_) => (),
                 }
                 state.done()
             };
@@ -491,23 +1678,108 @@ impl VNode {
                 if !state.satisfied() {
                     debug!("get {:?} done but not satisfied", cookie);
                     state.context.clear();
-                    db.respond_error(&mut state.context, CommandError::Unavailable);
+                    db.respond_error(&mut state.context, ServerError::ClusterDown);
                 } else {
-                    let ReqState { mut context, .. } = state;
+                    let ReqState {
+                        mut context,
+                        read_repair,
+                        ..
+                    } = state;
+                    let writes = read_repair.map_or_else(Vec::new, |r| r.writes(&context.reads));
                     let mut render_fn = None;
-                    context.response.extend(context.reads.drain(..).map(|r| {
-                        if render_fn.is_none() {
-                            render_fn = r.response;
+                    let now = now_millis();
+                    let rendered: Vec<_> = context
+                        .reads
+                        .drain(..)
+                        .map(|r| {
+                            if render_fn.is_none() {
+                                render_fn = r.response;
+                            }
+                            render_fn.as_mut().expect("No ResponseFn")(r.cube.without_expired(now))
+                        })
+                        .collect();
+                    // keys with too many siblings could produce replies clients can't handle
+                    let size: usize = rendered.iter().map(RespValue::serialized_size).sum();
+                    if size > db.config().response_size_max as usize {
+                        debug!("get {:?} response too large {}", cookie, size);
+                        context.clear();
+                        db.respond_error(&mut context, CommandError::ResponseTooLarge);
+                        if !writes.is_empty() {
+                            repair = Some((writes, None));
                         }
-                        render_fn.as_mut().expect("No ResponseFn")(r.cube)
-                    }));
-                    db.respond(&mut context);
+                    } else {
+                        context.response.extend(rendered);
+                        if writes.is_empty() {
+                            db.respond(&mut context);
+                        } else {
+                            repair = Some((writes, Some(context)));
+                        }
+                    }
                 }
             }
             done
         } else {
             debug!("process_get cookie not found {:?}", cookie);
             true
+        };
+        if let Some((writes, context)) = repair {
+            self.read_repair(db, writes, context);
+        }
+        done
+    }
+
+    // Writes the merged values of a read back to the stale replicas, as regular
+    // causal writes. With read_repair sync the read is responded once they're acknowledged.
+    fn read_repair(
+        &mut self,
+        db: &Database,
+        repairs: Vec<(NodeId, Vec<(Bytes, Cube, bool)>)>,
+        context: Option<Context>,
+    ) {
+        let wait = context.is_some() && db.config().read_repair == ReadRepair::Sync;
+        let cookie = match context {
+            Some(ref context) => self.gen_request_cookie(context),
+            None => self.gen_cookie(),
+        };
+        let mut sent = 0;
+        for (node, writes) in repairs {
+            debug!(
+                "Read repairing {} keys of vnode {} in node {}",
+                writes.len(),
+                self.state.num,
+                node
+            );
+            metrics::READ_REPAIR.mark(writes.len() as _);
+            if node == db.dht.node() {
+                if self.state.storage_set_remote(db, writes).is_err() {
+                    warn!("Can't read repair vnode {} locally", self.state.num);
+                }
+                continue;
+            }
+            let msg = MsgRemoteSet {
+                cookie: cookie,
+                vnode: self.state.num,
+                writes: writes,
+                reply: wait,
+                repair: false,
+                deadline: Some(now_millis() + db.config().request_timeout as u64),
+            };
+            match db.fabric.send_msg(node, &msg) {
+                Ok(()) => sent += 1,
+                Err(e) => debug!("Can't send read repair to node {}: {:?}", node, e),
+            }
+        }
+
+        if let Some(mut context) = context {
+            if wait && sent != 0 {
+                let mut req = ReqState::new(context, sent, ConsistencyLevel::All);
+                req.repair_wait = true;
+                let expire =
+                    Instant::now() + Duration::from_millis(db.config().request_timeout as _);
+                self.insert_request(db, cookie, req, expire);
+            } else {
+                db.respond(&mut context);
+            }
         }
     }
 
@@ -518,7 +1790,7 @@ impl VNode {
         response: Result<I, FabricError>,
     ) -> bool {
         if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
-            debug!("process_set {:?}", cookie);
+            debug!("process_set {:?} req:{:016X}", cookie, cookie.request_id());
             let done = {
                 let state = o.get_mut();
                 state.replies += 1;
@@ -535,10 +1807,13 @@ impl VNode {
             };
             if done {
                 let mut state = o.remove();
-                if !state.satisfied() {
+                if state.repair_wait {
+                    // the read response was rendered before the write-backs
+                    db.respond(&mut state.context);
+                } else if !state.satisfied() {
                     debug!("set {:?} done but not satisfied", cookie);
                     state.context.clear();
-                    db.respond_error(&mut state.context, CommandError::Unavailable);
+                    db.respond_error(&mut state.context, ServerError::ClusterDown);
                 } else {
                     let ReqState { mut context, .. } = state;
                     context.response.extend(context.writes.drain(..).map(|w| {
@@ -561,11 +1836,14 @@ impl VNode {
     }
 
     // CRUD HANDLERS
-    pub fn handler_get_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteGetAck) {
-        self.process_get(db, msg.cookie, msg.result);
+    pub fn handler_get_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetAck) {
+        if self.scrubs.contains_key(&msg.cookie) {
+            return self.process_scrub(db, from, msg);
+        }
+        self.process_get(db, msg.cookie, from, msg.result);
     }
 
-    pub fn handler_get_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
+    pub fn handler_get_remote(&self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
         // accept zombie to reduce chance of timeouts due to races on cluster change
         check_status!(
             self,
@@ -576,6 +1854,17 @@ impl VNode {
             MsgRemoteGetAck,
             inflight_get
         );
+        if deadline_passed(msg.deadline) {
+            debug!("Skipping expired remote get {:?}", msg.cookie);
+            let _ = fabric_send_error!(db, from, msg, MsgRemoteGetAck, FabricError::Timeout);
+            return;
+        }
+        debug!(
+            "vnode:{} remote get req:{:016X} from {}",
+            msg.vnode,
+            msg.cookie.request_id(),
+            from
+        );
         let mut result = Vec::with_capacity(msg.keys.len());
         for key in &msg.keys {
             let value = self.state
@@ -595,48 +1884,72 @@ impl VNode {
     }
 
     pub fn handler_set_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteSet) {
-        check_status!(
-            self,
-            VNodeStatus::Ready | VNodeStatus::Bootstrap,
-            db,
-            from,
-            msg,
-            MsgRemoteSetAck,
-            inflight_set
-        );
-        let MsgRemoteSet {
-            writes,
-            vnode,
-            cookie,
-            reply,
-        } = msg;
-        // Is this really ok?
-        // This optimization prevents a class of errors (storage errrors..)
-        // from propagating to the coordinator
-        // if reply && !reply_result {
-        //     let _ = db.fabric.send_msg(
-        //         from,
-        //         &MsgRemoteSetAck {
-        //             vnode: vnode,
-        //             cookie: cookie,
-        //             result: Ok(None),
-        //         },
-        //     );
-        // }
-        let result = self.state
-            .storage_set_remote(db, writes)
-            .map_err(|_| FabricError::StorageError);
-        if
-        /*reply_result && */
-        reply {
-            let _ = db.fabric.send_msg(
-                from,
-                &MsgRemoteSetAck {
-                    vnode: vnode,
-                    cookie: cookie,
-                    result: result,
-                },
+        self.handler_set_remote_batch(db, vec![(from, msg)]);
+    }
+
+    /// Applies the remote sets in order, committing once for all of them
+    pub fn handler_set_remote_batch(&mut self, db: &Database, msgs: Vec<(NodeId, MsgRemoteSet)>) {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Bootstrap => (),
+            state => {
+                for (from, msg) in msgs {
+                    debug!("Incorrect state for remote set {:?} was {:?}", msg.cookie, state);
+                    let _ = fabric_send_error!(
+                        db,
+                        from,
+                        msg,
+                        MsgRemoteSetAck,
+                        FabricError::BadVNodeStatus
+                    );
+                }
+                return;
+            }
+        }
+        for (from, msg) in msgs {
+            // sets without a reply are still applied, there's no one waiting to time out
+            if msg.reply && deadline_passed(msg.deadline) {
+                debug!("Skipping expired remote set {:?}", msg.cookie);
+                let _ = fabric_send_error!(db, from, msg, MsgRemoteSetAck, FabricError::Timeout);
+                continue;
+            }
+            debug!(
+                "vnode:{} remote set req:{:016X} from {}",
+                msg.vnode,
+                msg.cookie.request_id(),
+                from
             );
+            let MsgRemoteSet {
+                writes,
+                vnode,
+                cookie,
+                reply,
+                repair,
+                ..
+            } = msg;
+            if repair {
+                for (key, cube, _) in writes {
+                    if self.state.storage_repair(db, key, cube).is_err() {
+                        warn!("Can't repair key in vnode {}", vnode);
+                    }
+                }
+                continue;
+            }
+            let result = self.state
+                .storage_set_remote(db, writes)
+                .map_err(|_| FabricError::StorageError);
+            if !reply {
+                continue;
+            }
+            let ack = MsgRemoteSetAck {
+                vnode: vnode,
+                cookie: cookie,
+                result: result,
+            };
+            if ack.result.is_ok() {
+                self.state.ack_once_stored(db, StagedAck::RemoteSet(from, ack));
+            } else {
+                let _ = db.fabric.send_msg(from, &ack);
+            }
         }
     }
 
@@ -648,13 +1961,14 @@ impl VNode {
     pub fn handler_sync_start(&mut self, db: &Database, from: NodeId, msg: MsgSyncStart) {
         if !(self.state.status == VNodeStatus::Ready
             || (self.state.status == VNodeStatus::Zombie
-                && self.state.last_status_change.elapsed()
-                    < Duration::from_millis(ZOMBIE_TIMEOUT_MS)))
+                && (self.state.last_status_change.elapsed()
+                    < Duration::from_millis(db.config().vnode_zombie_timeout as _)
+                    || self.is_handoff_target(db, from))))
         {
             debug!("Can't start sync when {:?}", self.state.status);
             let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::BadVNodeStatus);
         } else if !self.syncs.contains_key(&msg.cookie) {
-            if !db.signal_sync_start(SyncDirection::Outgoing) {
+            if !db.signal_sync_start(SyncDirection::Outgoing, msg.target.is_none()) {
                 debug!("Refusing remote sync request, limit exceeded");
                 let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::NotReady);
                 return;
@@ -669,6 +1983,9 @@ impl VNode {
                 Some(target) => {
                     assert_eq!(target, db.dht.node());
                     info!("Starting sync sender {:?} peer:{}", cookie, from);
+                    self.state
+                        .peer_clocks
+                        .insert(from, msg.clocks_in_peer.clone());
                     Synchronization::new_sync_sender(db, &mut self.state, from, msg)
                 }
             };
@@ -682,6 +1999,11 @@ impl VNode {
     }
 
     pub fn handler_sync_send(&mut self, db: &Database, from: NodeId, msg: MsgSyncSend) {
+        if msg.cookie.generation() != self.state.generation {
+            debug!("Ignoring sync send {:?} of a previous generation", msg.cookie);
+            let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::CookieNotFound);
+            return;
+        }
         forward!(
             self,
             VNodeStatus::Ready | VNodeStatus::Bootstrap,
@@ -749,6 +2071,10 @@ impl VNode {
                 info!("Retrying bootstrap");
                 self.start_bootstrap(db);
             }
+            SyncResult::Done if self.is_owner(db) => {
+                // rebuilt, the node already owned the vnode
+                self.state.set_status(db, VNodeStatus::Ready);
+            }
             SyncResult::Done => {
                 match db.dht.promote_pending_node(db.dht.node(), self.state.num()) {
                     Ok(_) => {
@@ -772,6 +2098,25 @@ impl VNode {
         }
     }
 
+    // whether the node is a ready (not pending) owner of the vnode in the dht
+    fn is_owner(&self, db: &Database) -> bool {
+        db.dht
+            .nodes_for_vnode(self.state.num, false, true)
+            .contains(&db.dht.node())
+    }
+
+    /// Discards the local data and bootstraps the vnode again from the other replicas,
+    /// only possible if it's ready and not syncing.
+    pub fn rebuild(&mut self, db: &Database) -> bool {
+        if self.status() != VNodeStatus::Ready || !self.syncs.is_empty() {
+            return false;
+        }
+        warn!("Rebuilding vnode {} from the replicas", self.state.num);
+        self.state.set_status(db, VNodeStatus::Bootstrap);
+        self.start_bootstrap(db);
+        true
+    }
+
     fn start_bootstrap(&mut self, db: &Database) {
         debug!(
             "start_bootstrap vn:{} p:{:?}",
@@ -780,11 +2125,11 @@ impl VNode {
         assert_eq!(self.state.status, VNodeStatus::Bootstrap);
         assert_eq!(self.syncs.len(), 0);
         self.state.pending_bootstrap = false;
-        let cookie = self.gen_cookie();
+        let cookie = self.gen_sync_cookie();
         let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         let connected_nodes = db.fabric.connections();
-        nodes.retain(|x| connected_nodes.contains(x));
-        if nodes.is_empty() || nodes == &[db.dht.node()] {
+        nodes.retain(|x| *x != db.dht.node() && connected_nodes.contains(x));
+        if nodes.is_empty() {
             // nothing to boostrap from
             self.handle_bootstrap_result(db, SyncResult::Done);
             return;
@@ -792,10 +2137,7 @@ impl VNode {
 
         thread_rng().shuffle(&mut nodes);
         for node in nodes {
-            if node == db.dht.node() {
-                continue;
-            }
-            if !db.signal_sync_start(SyncDirection::Incomming) {
+            if !db.signal_sync_start(SyncDirection::Incomming, true) {
                 debug!("Bootstrap not allowed to start, go pending");
                 self.state.pending_bootstrap = true;
                 return;
@@ -820,6 +2162,21 @@ impl VNode {
         }
     }
 
+    #[cfg(test)]
+    pub fn _sync_cookies(&self) -> Vec<Cookie> {
+        self.syncs.keys().cloned().collect()
+    }
+
+    #[cfg(test)]
+    pub fn _peer_clocks_nodes(&self) -> Vec<NodeId> {
+        self.state.peer_clocks.keys().cloned().collect()
+    }
+
+    #[cfg(test)]
+    pub fn _sync_nodes(&self) -> Vec<NodeId> {
+        self.state.sync_nodes.iter().cloned().collect()
+    }
+
     #[cfg(test)]
     pub fn _start_sync(&mut self, db: &Database) -> bool {
         assert_any!(self.state.status, VNodeStatus::Ready);
@@ -830,18 +2187,22 @@ impl VNode {
         trace!("do_start_sync vn:{}", self.state.num);
         let mut nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         let connected_nodes = db.fabric.connections();
-        nodes.retain(|x| connected_nodes.contains(x));
-        thread_rng().shuffle(&mut nodes);
+        nodes.retain(|x| *x != db.dht.node() && connected_nodes.contains(x));
+        self.sync_cursor = self.sync_cursor.wrapping_add(1);
+        order_sync_peers(
+            db.config().sync_peer_strategy,
+            &mut nodes,
+            &self.state.clocks,
+            &self.state.peer_clocks,
+            self.sync_cursor,
+        );
         for node in nodes {
-            if node == db.dht.node() {
-                continue;
-            }
-            if !db.signal_sync_start(SyncDirection::Incomming) {
+            if !db.signal_sync_start(SyncDirection::Incomming, false) {
                 debug!("Refusing start sync, limit exceeded");
                 continue;
             }
 
-            let cookie = self.gen_cookie();
+            let cookie = self.gen_sync_cookie();
             self.state.sync_nodes.insert(node);
             info!("Starting sync receiver {:?} peer:{}", cookie, node);
             let sync = Synchronization::new_sync_receiver(db, &mut self.state, node, cookie);
@@ -857,12 +2218,99 @@ impl VNode {
     }
 }
 
+// the coordinator times out requests past their deadline, so there's no point doing the work.
+// Deadlines come from the coordinator clock, so they're only enforced past a skew margin.
+fn deadline_passed(deadline: Option<u64>) -> bool {
+    deadline.map_or(false, |d| now_millis() >= d + DEADLINE_SKEW_MARGIN)
+}
+
+/// Whether `cube` has all the values (dots) of `other`
+fn cube_contains(cube: &Cube, other: &Cube) -> bool {
+    if let Cube::Void(_) = *other {
+        return true;
+    }
+    if let Cube::Void(_) = *cube {
+        return false;
+    }
+    let mut dots = HashSet::new();
+    cube.for_each_dot(|i, v| {
+        dots.insert((i, v));
+    });
+    let mut contains = true;
+    other.for_each_dot(|i, v| contains &= dots.contains(&(i, v)));
+    contains
+}
+
+/// Orders the candidate peers for an anti-entropy sync according to the strategy
+fn order_sync_peers(
+    strategy: SyncPeerStrategy,
+    nodes: &mut Vec<NodeId>,
+    clocks: &BitmappedVersionVector,
+    peer_clocks: &IdHashMap<NodeId, BitmappedVersionVector>,
+    cursor: usize,
+) {
+    match strategy {
+        SyncPeerStrategy::Random => thread_rng().shuffle(nodes),
+        SyncPeerStrategy::RoundRobin => {
+            nodes.sort();
+            if !nodes.is_empty() {
+                let split = cursor % nodes.len();
+                let mut rotated = nodes.split_off(split);
+                rotated.extend(nodes.drain(..));
+                *nodes = rotated;
+            }
+        }
+        SyncPeerStrategy::MostStaleFirst => {
+            // peers that never reported their clocks are considered the most stale
+            nodes.sort_by_key(|node| {
+                Reverse(
+                    peer_clocks
+                        .get(node)
+                        .map_or(u64::max_value(), |pc| clocks_lag(clocks, pc)),
+                )
+            });
+        }
+    }
+}
+
+/// Orders the replicas clients are redirected to, the first one is picked.
+/// Nodes without a connection count as the most loaded.
+fn order_coordinators<T>(
+    policy: CoordinatorPolicy,
+    nodes: &mut Vec<(NodeId, T)>,
+    queue_depths: &IdHashMap<NodeId, usize>,
+) {
+    match policy {
+        CoordinatorPolicy::First => (),
+        CoordinatorPolicy::Random => thread_rng().shuffle(nodes),
+        CoordinatorPolicy::LeastLoaded => {
+            // ties in random order
+            thread_rng().shuffle(nodes);
+            nodes.sort_by_key(|&(ref node, _)| {
+                queue_depths.get(node).cloned().unwrap_or(usize::max_value())
+            });
+        }
+    }
+}
+
+/// Roughly how many versions `other` is behind `clocks`
+fn clocks_lag(clocks: &BitmappedVersionVector, other: &BitmappedVersionVector) -> u64 {
+    clocks
+        .iter()
+        .map(|(&id, bv)| {
+            bv.base()
+                .saturating_sub(other.get(id).map_or(0, |obv| obv.base()))
+        })
+        .sum()
+}
+
 impl Drop for VNode {
     fn drop(&mut self) {
         info!("Droping vnode {:?}", self.state.num);
         // clean up any references to the storage
         self.requests.clear();
         self.syncs.clear();
+        self.state.storage_flush();
     }
 }
 
@@ -877,7 +2325,21 @@ impl VNodeState {
 
     pub fn clear(&mut self) {
         self.clocks.clear();
+        self.pending = Default::default();
+        self.acks.clear();
+        self.cache.lock().unwrap().entries.clear();
         self.storage.clear();
+        self.stats.dead_bytes = 0;
+        self.stats.live_bytes = 0;
+    }
+
+    pub fn compact(&mut self) {
+        info!("Compacting vnode {} storage", self.num);
+        self.storage_flush();
+        self.storage.compact();
+        self.stats.compactions += 1;
+        self.stats.bytes_reclaimed += self.stats.dead_bytes;
+        self.stats.dead_bytes = 0;
     }
 
     pub fn set_status(&mut self, db: &Database, new: VNodeStatus) {
@@ -893,10 +2355,12 @@ impl VNodeState {
                 assert!(!self.pending_bootstrap);
                 assert_eq!(self.sync_nodes.len(), 0);
                 self.clear();
+                self.generation = self.generation.wrapping_add(1);
             }
             VNodeStatus::Absent => {
                 assert_eq!(self.sync_nodes.len(), 0);
                 self.clear();
+                self.generation = self.generation.wrapping_add(1);
             }
             VNodeStatus::Ready | VNodeStatus::Zombie => {}
         }
@@ -923,6 +2387,13 @@ impl VNodeState {
             storage: storage,
             pending_bootstrap: false,
             sync_nodes: Default::default(),
+            peer_clocks: Default::default(),
+            stats: Default::default(),
+            pending: Default::default(),
+            acks: Vec::new(),
+            cache: ReadCache::new(db),
+            generation: thread_rng().gen(),
+            rebuild: Vec::new(),
         }
     }
 
@@ -955,15 +2426,51 @@ impl VNodeState {
             storage: storage,
             sync_nodes: Default::default(),
             pending_bootstrap: false,
+            peer_clocks: Default::default(),
+            stats: Default::default(),
+            pending: Default::default(),
+            acks: Vec::new(),
+            cache: ReadCache::new(db),
+            generation: thread_rng().gen(),
+            rebuild: Vec::new(),
         };
 
         if !clean_shutdown {
             info!("Unclean shutdown, recovering from the storage");
             state.recover_dots();
+            // a full scan of the storage, so it's opt-in
+            if db.config().storage_verify_on_recovery {
+                state.verify_storage();
+            }
         }
+        state.recover_local_clock(db.dht.node());
         state
     }
 
+    // drops the records that can't be deserialized, their dots are still in the clocks
+    // so they're rebuilt from the other replicas instead of synced
+    fn verify_storage(&mut self) {
+        let corrupt: Vec<Bytes> = {
+            let mut iterator = self.storage.iterator();
+            let corrupt = iterator
+                .iter()
+                .filter(|&(_, value)| deserialize_cube(value).is_err())
+                .map(|(key, _)| Bytes::from(key))
+                .collect();
+            corrupt
+        };
+        for key in corrupt {
+            warn!("Dropping corrupt key {:?} of vnode {}", key, self.num);
+            match self.storage.del(&key) {
+                Ok(()) => self.rebuild.push(key),
+                Err(e) => error!(
+                    "Can't drop corrupt key {:?} of vnode {}: {:?}",
+                    key, self.num, e
+                ),
+            }
+        }
+    }
+
     fn recover_dots(&mut self) {
         for (&node, bv) in self.clocks.iter_mut() {
             let mut iterator = self.storage.log_iterator(node, bv.base() + 1);
@@ -973,7 +2480,40 @@ impl VNodeState {
         }
     }
 
-    pub fn save(&self, db: &Database, shutdown: bool) {
+    // the saved clocks may be older than the storage (ex: restored from an older backup),
+    // skip past the local versions in the log so the node never issues a dot twice
+    fn recover_local_clock(&mut self, node: NodeId) {
+        let base = self.clocks.get(node).map_or(0, |bv| bv.base());
+        let dots: Vec<_> = {
+            let mut iterator = self.storage.log_iterator(node, base + 1);
+            let dots = iterator.iter().map(|((_, dot), _)| dot).collect();
+            dots
+        };
+        // only the logged versions, the holes between them may still be synced
+        let mut recovered = 0;
+        for dot in dots {
+            if self.clocks.add(node, dot) {
+                recovered += 1;
+            }
+        }
+        if recovered != 0 {
+            warn!(
+                "Vnode {} clock for {} was behind the storage, recovered {} versions past {}",
+                self.num, node, recovered, base
+            );
+        }
+    }
+
+    pub fn save(&mut self, db: &Database, shutdown: bool) {
+        // the saved clocks can't be ahead of the storage
+        self.storage_flush();
+        if self.status == VNodeStatus::Absent {
+            // nothing to load, so the vnode isn't created on startup
+            db.meta_storage
+                .del(self.num.to_string().as_bytes())
+                .expect("Can't del vnode state");
+            return;
+        }
         let saved_state = SavedVNodeState {
             clocks: self.clocks.clone(),
             clean_shutdown: shutdown,
@@ -988,81 +2528,370 @@ impl VNodeState {
 
     // STORAGE
     pub fn storage_get(&self, key: &[u8]) -> Result<Cube, ()> {
-        let result = self.storage.get(key, |v| bincode::deserialize::<Cube>(v));
+        self.storage_get_sized(key).map(|(cube, _)| cube)
+    }
+
+    /// Like storage_get but also returns the stored size of the entry, if any
+    fn storage_get_sized(&self, key: &[u8]) -> Result<(Cube, Option<usize>), ()> {
+        let from_storage = !self.pending.data.contains_key(key);
+        if from_storage {
+            let mut cache = self.cache.lock().unwrap();
+            let hit = cache.entries.get(key).cloned();
+            if let Some((cube, len)) = hit {
+                cache.hits += 1;
+                return Ok((cube, Some(len)));
+            }
+        }
+        let result = if let Some(pending) = self.pending.data.get(key) {
+            Ok(pending.as_ref().map(|v| {
+                (deserialize_cube(v), key.len() + v.len())
+            }))
+        } else {
+            self.storage.get(key, |v| {
+                (deserialize_cube(v), key.len() + v.len())
+            })
+        };
         match result {
-            Ok(Some(Ok(cube))) => Ok(cube),
-            Ok(Some(Err(_de))) => Err(()),
-            Ok(None) => Ok(Cube::new(&self.clocks)),
+            Ok(Some((Ok(cube), len))) => {
+                if from_storage {
+                    let mut cache = self.cache.lock().unwrap();
+                    cache.entries.insert(Bytes::from(key), (cube.clone(), len));
+                }
+                Ok((cube, Some(len)))
+            }
+            Ok(Some((Err(_de), _))) => Err(()),
+            Ok(None) => Ok((Cube::new(&self.clocks), None)),
             Err(_se) => Err(()),
         }
     }
 
-    pub fn storage_set_local<'a, I: Iterator<Item = (Version, &'a [u8], &'a Cube)>>(
+    pub fn storage_log_get(&self, log_key: (u64, u64)) -> Result<Option<Bytes>, ()> {
+        if let Some(key) = self.pending.log.get(&log_key) {
+            return Ok(Some(key.clone()));
+        }
+        self.storage
+            .log_get(log_key, |x| Bytes::from(x))
+            .map_err(|_| ())
+    }
+
+    /// The serialized cube as stored locally, including staged writes
+    pub fn storage_get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        if let Some(pending) = self.pending.data.get(key) {
+            return Ok(pending.as_ref().map(|v| v.to_vec()));
+        }
+        self.storage.get_vec(key).map_err(|_| ())
+    }
+
+    fn storage_get_len(&self, key: &[u8]) -> Result<Option<usize>, ()> {
+        if let Some(pending) = self.pending.data.get(key) {
+            return Ok(pending.as_ref().map(|v| key.len() + v.len()));
+        }
+        self.storage
+            .get(key, |v| key.len() + v.len())
+            .map_err(|_| ())
+    }
+
+    /// Like storage_set_remote but also restores a key missing from the storage,
+    /// even if the clocks already have its dots, unless the log shows it was deleted.
+    pub fn storage_repair(&mut self, db: &Database, key: Bytes, proposed: Cube) -> Result<(), ()> {
+        let (old, old_len) = self.storage_get_sized(&key)?;
+        let lost = old_len.is_none() && !self.storage_log_has_newer(&key, &proposed);
+        let mut new_dots = Vec::new();
+        {
+            let clocks = &mut self.clocks;
+            proposed.for_each_dot(|i, v| {
+                if clocks.add(i, v) {
+                    new_dots.push((i, v));
+                }
+            });
+        }
+        if new_dots.is_empty() && !lost {
+            return Ok(());
+        }
+        for dot in new_dots {
+            self.storage_stage_log(db, dot, &key);
+        }
+
+        // merging with the clocks of a lost key would discard the values as deleted,
+        // otherwise the merge drops the values with already seen dots
+        let new = if lost { proposed } else { old.merge(proposed) };
+        if new.is_subsumed(&self.clocks) {
+            self.storage_stage(db, &key, old_len, None);
+        } else {
+            let serialized = serialize_cube(&new);
+            self.storage_stage(db, &key, old_len, Some(serialized));
+        }
+        self.storage_flush_if_full().map_err(|_| ())
+    }
+
+    /// Whether the log has a dot for `key` newer than the ones in `cube`, like a delete's.
+    /// Gives up after REPAIR_LOG_SCAN_MAX entries, assuming there's one.
+    fn storage_log_has_newer(&self, key: &[u8], cube: &Cube) -> bool {
+        let mut known: IdHashMap<Id, Version> = IdHashMap::default();
+        cube.for_each_dot(|i, v| {
+            let known = known.entry(i).or_insert(0);
+            *known = max(*known, v);
+        });
+        let pending_newer = self.pending.log.iter().any(|(&(i, v), logged)| {
+            &logged[..] == key && v > known.get(&i).cloned().unwrap_or(0)
+        });
+        if pending_newer {
+            return true;
+        }
+        let mut scanned = 0;
+        for (&node, _) in self.clocks.iter() {
+            let start = known.get(&node).cloned().unwrap_or(0) + 1;
+            let mut iterator = self.storage.log_iterator(node, start);
+            for (_, logged) in iterator.iter() {
+                scanned += 1;
+                if logged == key || scanned >= REPAIR_LOG_SCAN_MAX {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Writes all staged writes to the storage in a single batch
+    pub fn storage_flush(&mut self) {
+        if let Err(e) = self.storage_try_flush() {
+            // staged writes are kept so the next flush retries them
+            error!("Can't flush vnode {} storage batch: {:?}", self.num, e);
+        }
+    }
+
+    /// Flushes the staged writes and syncs the storage log, so they survive a crash
+    pub fn storage_commit(&mut self) -> Result<(), GenericError> {
+        self.storage_try_flush()?;
+        self.storage.sync()
+    }
+
+    /// Writes the staged writes to the storage, also syncing the storage log
+    /// with the sync commit policy
+    pub fn storage_persist(&mut self, db: &Database) -> Result<(), GenericError> {
+        if db.config().commit_policy == CommitPolicy::Sync {
+            self.storage_commit()
+        } else {
+            self.storage_try_flush()
+        }
+    }
+
+    fn storage_try_flush(&mut self) -> Result<(), GenericError> {
+        if self.pending.data.is_empty() && self.pending.log.is_empty() {
+            return Ok(());
+        }
+        {
+            let pending = &self.pending;
+            let mut batch = self.storage
+                .batch_new(pending.data.len() + pending.log.len());
+            for (key, value) in &pending.data {
+                if let Some(ref value) = *value {
+                    batch.set(key, value);
+                } else {
+                    batch.del(key);
+                }
+            }
+            for (&log_key, key) in &pending.log {
+                batch.log_set(log_key, key);
+            }
+            self.storage.batch_write(batch)?;
+        }
+        self.pending = Default::default();
+        self.stats.storage_batches += 1;
+        Ok(())
+    }
+
+    fn storage_stage(
         &mut self,
         db: &Database,
-        writes: I,
-    ) -> Result<(), CommandError> {
-        let mut batch = self.storage.batch_new(0);
-        for (version, key, cube) in writes {
+        key: &[u8],
+        old_len: Option<usize>,
+        value: Option<Vec<u8>>,
+    ) {
+        if self.pending.data.is_empty() && self.pending.log.is_empty() {
+            db.signal_pending_flush(self.num);
+        }
+        self.stats
+            .on_write(old_len, value.as_ref().map(|v| key.len() + v.len()));
+        self.cache.get_mut().unwrap().entries.remove(key);
+        self.pending.data.insert(Bytes::from(key), value);
+    }
+
+    fn storage_stage_log(&mut self, db: &Database, log_key: (u64, u64), key: &[u8]) {
+        if self.pending.data.is_empty() && self.pending.log.is_empty() {
+            db.signal_pending_flush(self.num);
+        }
+        self.pending.log.insert(log_key, Bytes::from(key));
+    }
+
+    /// Holds `ack` until the staged writes reach the storage
+    pub fn ack_once_stored(&mut self, db: &Database, ack: StagedAck) {
+        db.signal_pending_flush(self.num);
+        self.acks.push(ack);
+    }
+
+    fn storage_flush_if_full(&mut self) -> Result<(), GenericError> {
+        if self.pending.data.len() + self.pending.log.len() >= PENDING_WRITES_MAX {
+            self.storage_try_flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stages the writes coordinated by this node, along with the stored sizes
+    /// of the keys they replace
+    pub fn storage_set_local<'a, I>(&mut self, db: &Database, writes: I) -> Result<(), CommandError>
+    where
+        I: Iterator<Item = (Version, &'a [u8], &'a Cube, Option<usize>)>,
+    {
+        for (version, key, cube, old_len) in writes {
             // TODO: integrate is_subsumed logic into the result of merge and MutatorFn
             if cube.is_subsumed(&self.clocks) {
-                batch.del(key);
+                self.storage_stage(db, key, old_len, None);
             } else {
-                let bytes = bincode::serialize(cube).expect("Can't serialize Cube");
-                batch.set(key, &bytes);
+                let bytes = serialize_cube(cube);
+                self.storage_stage(db, key, old_len, Some(bytes));
             }
 
-            batch.log_set((db.dht.node(), version), key);
+            self.storage_stage_log(db, (db.dht.node(), version), key);
         }
-        self.storage
-            .batch_write(batch)
-            .map_err(|_| CommandError::StorageError)?;
-
-        Ok(())
+        self.storage_flush_if_full()
+            .map_err(|_| CommandError::StorageError)
     }
 
     pub fn storage_set_remote(
         &mut self,
-        _db: &Database,
+        db: &Database,
         writes: Vec<(Bytes, Cube, bool)>,
     ) -> Result<Vec<Option<Cube>>, ()> {
-        let mut batch = self.storage.batch_new(0);
         let mut results = Vec::with_capacity(writes.len());
+        let mut new_dots = Vec::new();
         for (key, proposed, reply_result) in writes {
             // need to fetch old before adding any dot
             // otherwise the dots might be added to Void cubes
-            let old = self.storage_get(&key).map_err(|_| ())?;
+            let (old, old_len) = self.storage_get_sized(&key).map_err(|_| ())?;
 
-            let mut empty = true;
             {
                 let clocks = &mut self.clocks;
                 proposed.for_each_dot(|i, v| {
                     if clocks.add(i, v) {
-                        empty = false;
-                        batch.log_set((i, v), &key);
+                        new_dots.push((i, v));
                     }
                 });
             }
+            let empty = new_dots.is_empty();
+            for dot in new_dots.drain(..) {
+                self.storage_stage_log(db, dot, &key);
+            }
 
             if empty && !reply_result {
                 results.push(None);
                 continue;
             }
 
+            let siblings = max(old.siblings(), proposed.siblings());
             let new = old.merge(proposed);
+            self.stats.on_merge(siblings, new.siblings());
 
             if !empty {
                 if new.is_subsumed(&self.clocks) {
-                    batch.del(&key);
+                    self.storage_stage(db, &key, old_len, None);
                 } else {
-                    let serialized = bincode::serialize(&new).expect("Can't serialize Cube");
-                    batch.set(&key, &serialized);
+                    let serialized = serialize_cube(&new);
+                    self.storage_stage(db, &key, old_len, Some(serialized));
                 }
             }
 
             results.push(if reply_result { Some(new) } else { None });
         }
-        self.storage.batch_write(batch).map_err(|_| ())?;
+        self.storage_flush_if_full().map_err(|_| ())?;
         Ok(results)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_peers_most_stale_first() {
+        let mut clocks = BitmappedVersionVector::new();
+        for v in 1..11 {
+            clocks.add(1, v);
+            clocks.add(2, v);
+        }
+        let mut peer_clocks: IdHashMap<NodeId, BitmappedVersionVector> = Default::default();
+        peer_clocks.insert(10, clocks.clone());
+        let mut lagging = BitmappedVersionVector::new();
+        for v in 1..4 {
+            lagging.add(1, v);
+            lagging.add(2, v);
+        }
+        peer_clocks.insert(20, lagging);
+
+        for cursor in 0..3 {
+            let mut nodes = vec![10, 20];
+            order_sync_peers(
+                SyncPeerStrategy::MostStaleFirst,
+                &mut nodes,
+                &clocks,
+                &peer_clocks,
+                cursor,
+            );
+            assert_eq!(nodes, vec![20, 10]);
+        }
+
+        // peers with unknown clocks come first
+        let mut nodes = vec![10, 20, 30];
+        order_sync_peers(
+            SyncPeerStrategy::MostStaleFirst,
+            &mut nodes,
+            &clocks,
+            &peer_clocks,
+            0,
+        );
+        assert_eq!(nodes, vec![30, 20, 10]);
+    }
+
+    #[test]
+    fn test_sync_peers_round_robin() {
+        let clocks = BitmappedVersionVector::new();
+        let peer_clocks = Default::default();
+        let firsts: Vec<_> = (0..4)
+            .map(|cursor| {
+                let mut nodes = vec![30, 10, 20];
+                order_sync_peers(
+                    SyncPeerStrategy::RoundRobin,
+                    &mut nodes,
+                    &clocks,
+                    &peer_clocks,
+                    cursor,
+                );
+                nodes[0]
+            })
+            .collect();
+        assert_eq!(firsts, vec![10, 20, 30, 10]);
+    }
+
+    #[test]
+    fn test_coordinators_least_loaded() {
+        let mut queue_depths: IdHashMap<NodeId, usize> = Default::default();
+        queue_depths.insert(10, 5);
+        queue_depths.insert(20, 0);
+        queue_depths.insert(30, 0);
+        let mut firsts = IdHashSet::default();
+        for _ in 0..100 {
+            // 40 isn't connected
+            let mut nodes = vec![(10, ()), (20, ()), (30, ()), (40, ())];
+            order_coordinators(CoordinatorPolicy::LeastLoaded, &mut nodes, &queue_depths);
+            assert_eq!(nodes[2..].to_vec(), vec![(10, ()), (40, ())]);
+            firsts.insert(nodes[0].0);
+        }
+        // spread across the least loaded ones
+        assert_eq!(firsts.len(), 2);
+
+        let mut nodes = vec![(10, ()), (20, ())];
+        order_coordinators(CoordinatorPolicy::First, &mut nodes, &queue_depths);
+        assert_eq!(nodes, [(10, ()), (20, ())]);
+    }
+}
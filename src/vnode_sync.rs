@@ -1,15 +1,17 @@
-use bincode;
 use bytes::Bytes;
-use cubes::Cube;
+use cubes::{deserialize_cube, Cube};
 use database::*;
 use fabric::*;
+use hash::stable_hash;
 use inflightmap::InFlightMap;
 use metrics::{self, Meter};
+use rand::{thread_rng, Rng};
+use roaring::RoaringTreemap;
 use std::collections::{hash_set, HashSet};
 use std::time::{Duration, Instant};
 use utils::{split_u64, IdHasherBuilder};
 use version_vector::*;
-use vnode::VNodeState;
+use vnode::{StagedAck, VNodeState};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[must_use]
@@ -46,7 +48,8 @@ pub enum SyncDirection {
     Outgoing,
 }
 
-type IteratorFn = Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, ()> + Send>;
+type IteratorFn =
+    Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, ()> + Send + Sync>;
 
 type InFlightSyncMsgMap = InFlightMap<u64, MsgSyncSend, Instant, IdHasherBuilder>;
 
@@ -71,6 +74,8 @@ pub enum Synchronization {
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // digest of sent keys (includes inflight)
+        digest: u64,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -81,6 +86,9 @@ pub enum Synchronization {
         peer: NodeId,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // distinct seqs received, used to verify against the sender summary
+        recv_seqs: RoaringTreemap,
+        recv_digest: u64,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -92,6 +100,8 @@ pub enum Synchronization {
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // digest of sent keys (includes inflight)
+        digest: u64,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -100,6 +110,9 @@ pub enum Synchronization {
         peer: NodeId,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // distinct seqs received, used to verify against the sender summary
+        recv_seqs: RoaringTreemap,
+        recv_digest: u64,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -124,10 +137,7 @@ impl SyncKeysIterator {
             // consider up to 90% of the actual capacity as an alternative limit
             let limit = (keys.capacity() * 9 / 10).max(1_000);
             for (n, v) in self.dots_delta.by_ref() {
-                let key = state
-                    .storage
-                    .log_get((n, v), |x| Bytes::from(x))
-                    .map_err(|_| ())?;
+                let key = state.storage_log_get((n, v))?;
                 if let Some(key) = key {
                     keys.insert(key);
                     if keys.len() >= limit {
@@ -146,6 +156,11 @@ impl SyncKeysIterator {
     }
 }
 
+// order independent digest component for a (seq, key) pair
+fn sync_digest(seq: u64, key: &[u8]) -> u64 {
+    stable_hash(&[&seq.to_le_bytes(), key])
+}
+
 use self::Synchronization::*;
 
 impl Synchronization {
@@ -159,6 +174,8 @@ impl Synchronization {
             cookie: cookie,
             peer: peer,
             recv_count: 0,
+            recv_seqs: RoaringTreemap::new(),
+            recv_digest: 0,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -170,12 +187,14 @@ impl Synchronization {
         peer: NodeId,
         msg: MsgSyncStart,
     ) -> Self {
+        // the iterator only sees writes already in the storage
+        state.storage_flush();
         let mut storage_iterator = state.storage.iterator();
         let iterator_fn: IteratorFn = Box::new(move |_| {
             let next = storage_iterator
                 .iter()
                 .map(|(k, v)| {
-                    let cube = bincode::deserialize::<Cube>(v).map_err(|_| ())?;
+                    let cube = deserialize_cube(v).map_err(|_| ())?;
                     Ok((Bytes::from(k), cube))
                 })
                 .next();
@@ -194,6 +213,7 @@ impl Synchronization {
             inflight: InFlightMap::new(),
             peer: peer,
             count: 0,
+            digest: 0,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -210,6 +230,8 @@ impl Synchronization {
             peer: peer,
             cookie: cookie,
             recv_count: 0,
+            recv_seqs: RoaringTreemap::new(),
+            recv_digest: 0,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -261,6 +283,7 @@ impl Synchronization {
             cookie: cookie,
             peer: peer,
             count: 0,
+            digest: 0,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -358,6 +381,8 @@ impl Synchronization {
             SyncSender {
                 peer,
                 cookie,
+                count,
+                digest,
                 ref clocks_snapshot,
                 ref mut last_send,
                 ..
@@ -365,6 +390,8 @@ impl Synchronization {
             | BootstrapSender {
                 peer,
                 cookie,
+                count,
+                digest,
                 ref clocks_snapshot,
                 ref mut last_send,
                 ..
@@ -376,7 +403,11 @@ impl Synchronization {
                         &MsgSyncFin {
                             cookie: cookie,
                             vnode: state.num(),
-                            result: Ok(clocks_snapshot.clone()),
+                            result: Ok(SyncSummary {
+                                clocks: clocks_snapshot.clone(),
+                                count: count,
+                                digest: digest,
+                            }),
                         },
                     )
                     .into()
@@ -389,13 +420,14 @@ impl Synchronization {
     // (also takes care of expired SyncSend)
     fn send_next(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
         let now = Instant::now();
-        let timeout = now + Duration::from_millis(db.config.sync_msg_timeout as _);
+        let timeout = now + Duration::from_millis(db.config().sync_msg_timeout as _);
         let (error, inflight_empty) = match *self {
             SyncSender {
                 peer,
                 cookie,
                 ref mut iterator,
                 ref mut count,
+                ref mut digest,
                 ref mut inflight,
                 ref mut last_send,
                 ..
@@ -405,6 +437,7 @@ impl Synchronization {
                 cookie,
                 ref mut iterator,
                 ref mut count,
+                ref mut digest,
                 ref mut inflight,
                 ref mut last_send,
                 ..
@@ -414,32 +447,42 @@ impl Synchronization {
                     let _ = stry!(db.fabric.send_msg(peer, msg,));
                     metrics::SYNC_RESEND.mark(1);
                 }
+                let batch_size = db.config().sync_msg_batch.max(1) as usize;
                 let mut error = false;
-                while inflight.len() < db.config.sync_msg_inflight as usize {
-                    match iterator(state) {
-                        Ok(Some((k, v))) => {
-                            let msg = MsgSyncSend {
-                                cookie: cookie,
-                                vnode: state.num(),
-                                seq: *count,
-                                key: k.clone(),
-                                value: v.clone(),
-                            };
-                            let _ = stry!(db.fabric.send_msg(peer, &msg,));
-                            inflight.insert(*count, msg, timeout);
-                            *count += 1;
-                            *last_send = now;
-                            metrics::SYNC_SEND.mark(1);
-                            continue;
-                        }
-                        Ok(None) => {
-                            break;
-                        }
-                        Err(_) => {
-                            error = true;
-                            break;
+                let mut done = false;
+                while !done && inflight.len() < db.config().sync_msg_inflight as usize {
+                    let mut writes = Vec::new();
+                    while writes.len() < batch_size {
+                        match iterator(state) {
+                            Ok(Some(kv)) => writes.push(kv),
+                            Ok(None) => {
+                                done = true;
+                                break;
+                            }
+                            Err(_) => {
+                                error = true;
+                                break;
+                            }
                         }
                     }
+                    if error || writes.is_empty() {
+                        break;
+                    }
+                    let msg = MsgSyncSend {
+                        cookie: cookie,
+                        vnode: state.num(),
+                        seq: *count,
+                        writes: writes,
+                    };
+                    let _ = stry!(db.fabric.send_msg(peer, &msg,));
+                    for (i, &(ref k, _)) in msg.writes.iter().enumerate() {
+                        *digest ^= sync_digest(*count + i as u64, k);
+                    }
+                    let sent = msg.writes.len() as u64;
+                    inflight.insert(*count, msg, timeout);
+                    *count += sent;
+                    *last_send = now;
+                    metrics::SYNC_SEND.mark(sent as _);
                 }
                 (error, inflight.is_empty())
             }
@@ -477,7 +520,7 @@ impl Synchronization {
             _ => (),
         }
 
-        db.signal_sync_end(self.direction());
+        db.signal_sync_end(self.direction(), self.is_bootstrap());
     }
 
     pub fn on_tick(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
@@ -487,7 +530,7 @@ impl Synchronization {
             }
             | BootstrapSender {
                 last_recv, cookie, ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            } => if last_recv.elapsed() > Duration::from_millis(db.config().sync_timeout as _) {
                 warn!("sync/boostrap sender timed out {:?}", cookie);
                 SyncResult::Error
             } else {
@@ -506,11 +549,11 @@ impl Synchronization {
                 last_send,
                 cookie,
                 ..
-            } => if last_recv.elapsed() > Duration::from_millis(db.config.sync_timeout as _) {
+            } => if last_recv.elapsed() > Duration::from_millis(db.config().sync_timeout as _) {
                 warn!("sync/boostrap receiver timed out {:?}", cookie);
                 SyncResult::Error
             } else if recv_count == 0
-                && last_send.elapsed() > Duration::from_millis(db.config.sync_msg_timeout as _)
+                && last_send.elapsed() > Duration::from_millis(db.config().sync_msg_timeout as _)
             {
                 self.send_start(db, state)
             } else {
@@ -533,24 +576,64 @@ impl Synchronization {
         state: &mut VNodeState,
         msg: MsgSyncFin,
     ) -> SyncResult {
-        match *self {
-            SyncReceiver { peer, .. } | BootstrapReceiver { peer, .. } => {
-                if msg.result.is_ok() {
-                    state.clocks.merge(msg.result.as_ref().unwrap());
-                    state.save(db, false);
-                    // send it back as a form of ack-ack
-                    let _ = db.fabric.send_msg(peer, &msg);
-                    SyncResult::Done
-                } else if msg.result.err() == Some(FabricError::NotReady) {
-                    SyncResult::Continue
-                } else {
-                    SyncResult::Error
-                }
+        let (peer, complete) = match *self {
+            SyncReceiver {
+                peer,
+                cookie,
+                ref recv_seqs,
+                recv_digest,
+                ..
             }
-            SyncSender { .. } | BootstrapSender { .. } => {
+            | BootstrapReceiver {
+                peer,
+                cookie,
+                ref recv_seqs,
+                recv_digest,
+                ..
+            } => match msg.result {
+                // reconcile what we got with what the sender reported as sent
+                Ok(ref summary) => {
+                    let complete =
+                        summary.count == recv_seqs.len() && summary.digest == recv_digest;
+                    if !complete {
+                        warn!(
+                            "sync/bootstrap {:?} incomplete: sender reported {} keys, received {}",
+                            cookie,
+                            summary.count,
+                            recv_seqs.len()
+                        );
+                    }
+                    (peer, complete)
+                }
+                Err(_) => (peer, true),
+            },
+            SyncSender { peer, .. } => {
+                // the receiver merged the clocks sent, now they're confirmed in the peer
+                if let Ok(ref summary) = msg.result {
+                    state
+                        .peer_clocks
+                        .entry(peer)
+                        .or_insert_with(BitmappedVersionVector::new)
+                        .merge(&summary.clocks);
+                }
                 // Senders are always Done on SyncFin messages
-                SyncResult::Done
+                return SyncResult::Done;
             }
+            BootstrapSender { .. } => return SyncResult::Done,
+        };
+
+        if !complete {
+            self.send_error_fin(db, state, FabricError::SyncInterrupted)
+        } else if msg.result.is_ok() {
+            state.clocks.merge(&msg.result.as_ref().unwrap().clocks);
+            state.save(db, false);
+            // send it back as a form of ack-ack
+            let _ = db.fabric.send_msg(peer, &msg);
+            SyncResult::Done
+        } else if msg.result.err() == Some(FabricError::NotReady) {
+            SyncResult::Continue
+        } else {
+            SyncResult::Error
         }
     }
 
@@ -559,6 +642,8 @@ impl Synchronization {
             SyncReceiver {
                 peer,
                 ref mut recv_count,
+                ref mut recv_seqs,
+                ref mut recv_digest,
                 ref mut last_recv,
                 ref mut last_send,
                 ..
@@ -566,29 +651,65 @@ impl Synchronization {
             | BootstrapReceiver {
                 peer,
                 ref mut recv_count,
+                ref mut recv_seqs,
+                ref mut recv_digest,
                 ref mut last_recv,
                 ref mut last_send,
                 ..
             } => {
-                // TODO: what to do with errors here?
-                state
-                    .storage_set_remote(db, vec![(msg.key, msg.value, false)])
-                    .unwrap();
+                // simulates keys lost by the receiver after being acked
+                let lost = cfg!(test) && {
+                    let sync_drop = ::std::env::var("SYNC_DROP_RECV")
+                        .ok()
+                        .map(|s| s.parse::<f64>().expect("Can't parse SYNC_DROP_RECV"))
+                        .unwrap_or(0.0);
+                    sync_drop > 0.0 && thread_rng().gen::<f64>() < sync_drop
+                };
+                let MsgSyncSend {
+                    cookie,
+                    seq,
+                    writes,
+                    ..
+                } = msg;
+                let recv = writes.len() as u64;
+                if lost {
+                    warn!("Sync msg lost due to SYNC_DROP_RECV: {:?}", seq);
+                } else {
+                    let digests: Vec<_> = writes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &(ref key, _))| {
+                            (seq + i as u64, sync_digest(seq + i as u64, key))
+                        })
+                        .collect();
+                    let batch = writes.into_iter().map(|(k, v)| (k, v, false)).collect();
+                    if state.storage_set_remote(db, batch).is_err() {
+                        warn!("Can't store sync msg {:?} seq {}", cookie, seq);
+                        return;
+                    }
+                    for (s, digest) in digests {
+                        if recv_seqs.insert(s) {
+                            *recv_digest ^= digest;
+                        }
+                    }
+                }
 
-                let _ = db.fabric.send_msg(
-                    peer,
-                    &MsgSyncAck {
-                        cookie: msg.cookie,
-                        vnode: state.num(),
-                        seq: msg.seq,
-                    },
-                );
+                // the msg is only acked once it reaches the storage,
+                // otherwise the sender times out and resends it
+                let ack = MsgSyncAck {
+                    cookie: cookie,
+                    vnode: state.num(),
+                    seq: seq,
+                };
+                state.ack_once_stored(db, StagedAck::Sync(peer, ack));
 
-                *recv_count += 1;
+                *recv_count += recv;
+                state.stats.sync_msgs_received += 1;
+                state.stats.sync_keys_received += recv;
                 let now = Instant::now();
                 *last_recv = now;
                 *last_send = now;
-                metrics::SYNC_RECV.mark(1);
+                metrics::SYNC_RECV.mark(recv as _);
             }
             _ => unreachable!(),
         }
@@ -620,4 +741,11 @@ impl Synchronization {
             BootstrapSender { .. } | SyncSender { .. } => SyncDirection::Outgoing,
         }
     }
+
+    pub fn is_bootstrap(&self) -> bool {
+        match *self {
+            BootstrapReceiver { .. } | BootstrapSender { .. } => true,
+            SyncReceiver { .. } | SyncSender { .. } => false,
+        }
+    }
 }
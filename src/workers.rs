@@ -1,82 +1,201 @@
-use database::{Context, NodeId};
+use database::{Context, NodeId, VNodeId};
 use fabric::FabricMsg;
 use rand::{thread_rng, Rng};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::{thread, time};
+use utils::duration_millis;
 
 pub enum WorkerMsg {
     Fabric(NodeId, FabricMsg),
     Command(Context),
     Tick(time::Instant),
     DHTFabric(NodeId, FabricMsg),
-    DHTChange,
+    DHTChange(u64),
     Exit,
 }
 
+type WorkerFn = Box<FnMut(mpsc::Receiver<WorkerMsg>) + Send>;
+
+// the channels of the regular lane, replaced when the manager is resized
+struct SharedChannels {
+    // bumped on every resize so senders know their copy is stale
+    generation: AtomicUsize,
+    channels: Mutex<Vec<mpsc::Sender<WorkerMsg>>>,
+}
+
 /// A Sender attached to a WorkerManager
 /// messages are distributed to threads in a Round-Robin manner.
 pub struct WorkerSender {
     cursor: usize,
     channels: Vec<mpsc::Sender<WorkerMsg>>,
+    shared: Arc<SharedChannels>,
+    generation: usize,
+    // the background lane, see `send_background`
+    background_channels: Vec<mpsc::Sender<WorkerMsg>>,
+    // set once the manager refuses new messages
+    closed: Arc<RwLock<bool>>,
+    vnode_affinity: bool,
+}
+
+/// Wraps a Receiver signaling (with a None) whenever the queue is momentarily drained
+/// after receiving one or more messages.
+/// Signals are at least `interval` apart, drains in between are coalesced
+/// and signaled once the interval elapses.
+pub struct SignaledChan<T> {
+    chan: mpsc::Receiver<T>,
+    received: bool,
+    interval: time::Duration,
+    last_signal: Option<time::Instant>,
+}
+
+impl<T> SignaledChan<T> {
+    pub fn new(chan: mpsc::Receiver<T>) -> Self {
+        Self::with_interval(chan, time::Duration::from_millis(0))
+    }
+
+    pub fn with_interval(chan: mpsc::Receiver<T>, interval: time::Duration) -> Self {
+        SignaledChan {
+            chan: chan,
+            received: false,
+            interval: interval,
+            last_signal: None,
+        }
+    }
+}
+
+impl<T> SignaledChan<T> {
+    /// Receives a message only if one is already queued, it doesn't affect the signaling
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.chan.try_recv().ok()
+    }
+}
+
+impl<T> Iterator for SignaledChan<T> {
+    type Item = Option<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.received {
+            match self.chan.try_recv() {
+                Ok(msg) => return Some(Some(msg)),
+                Err(mpsc::TryRecvError::Empty) => {
+                    let now = time::Instant::now();
+                    if let Some(last_signal) = self.last_signal {
+                        if now < last_signal + self.interval {
+                            // keep receiving until the interval elapses
+                            match self.chan.recv_timeout(last_signal + self.interval - now) {
+                                Ok(msg) => return Some(Some(msg)),
+                                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                            }
+                        }
+                    }
+                    self.received = false;
+                    self.last_signal = Some(time::Instant::now());
+                    return Some(None);
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return None,
+            }
+        }
+        let msg = self.chan.recv().ok()?;
+        self.received = true;
+        Some(Some(msg))
+    }
 }
 
 /// A thread pool containing threads prepared to receive WorkerMsg's
 pub struct WorkerManager {
-    ticker_interval: time::Duration,
+    // in millis, can change while the ticker runs
+    ticker_interval: Arc<AtomicUsize>,
     ticker_thread: Option<thread::JoinHandle<()>>,
     ticker_chan: Option<mpsc::Sender<()>>,
     thread_count: usize,
+    // threads only taking the background lane
+    background_count: usize,
     threads: Vec<thread::JoinHandle<()>>,
     channels: Vec<mpsc::Sender<WorkerMsg>>,
+    shared: Arc<SharedChannels>,
+    background_channels: Vec<mpsc::Sender<WorkerMsg>>,
+    closed: Arc<RwLock<bool>>,
+    // creates the workers of the threads started by a resize
+    worker_fn_gen: Option<Box<FnMut() -> WorkerFn + Send>>,
     node: NodeId,
+    // if set senders start from this cursor instead of a random one
+    cursor_seed: Option<usize>,
+    vnode_affinity: bool,
 }
 
 impl WorkerManager {
     pub fn new(node: NodeId, thread_count: usize, ticker_interval: time::Duration) -> Self {
         assert!(thread_count > 0);
         WorkerManager {
-            ticker_interval: ticker_interval,
+            ticker_interval: Arc::new(AtomicUsize::new(duration_millis(ticker_interval) as usize)),
             ticker_thread: None,
             ticker_chan: None,
             thread_count: thread_count,
+            background_count: 0,
             threads: Default::default(),
             channels: Default::default(),
+            shared: Arc::new(SharedChannels {
+                generation: Default::default(),
+                channels: Default::default(),
+            }),
+            background_channels: Default::default(),
+            closed: Default::default(),
+            worker_fn_gen: None,
             node: node,
+            cursor_seed: None,
+            vnode_affinity: false,
         }
     }
 
-    pub fn start<F>(&mut self, mut worker_fn_gen: F)
+    /// Makes all senders created afterwards start from the same cursor,
+    /// so message distribution among threads is reproducible.
+    #[cfg(test)]
+    pub fn set_cursor_seed(&mut self, seed: usize) {
+        self.cursor_seed = Some(seed);
+    }
+
+    /// Makes the senders created afterwards send the messages of each vnode
+    /// to the same thread (see `WorkerSender::send_for_vnode`).
+    pub fn set_vnode_affinity(&mut self, vnode_affinity: bool) {
+        self.vnode_affinity = vnode_affinity;
+    }
+
+    /// Adds `background_count` threads for the background lane, started along the others
+    pub fn set_background_threads(&mut self, background_count: usize) {
+        assert!(self.channels.is_empty());
+        self.background_count = background_count;
+    }
+
+    pub fn start<F>(&mut self, worker_fn_gen: F)
     where
-        F: FnMut() -> Box<FnMut(mpsc::Receiver<WorkerMsg>) + Send>,
+        F: FnMut() -> WorkerFn + Send + 'static,
     {
         assert!(self.channels.is_empty());
+        self.worker_fn_gen = Some(Box::new(worker_fn_gen));
         for i in 0..self.thread_count {
-            // since neither closure cloning or Box<FnOnce> are stable use Box<FnMut>
-            let mut worker_fn = worker_fn_gen();
-            let (tx, rx) = mpsc::channel();
-            self.threads.push(
-                thread::Builder::new()
-                    .name(format!("Worker:{}:{}", i, self.node))
-                    .spawn(move || {
-                        worker_fn(rx);
-                        info!("Exiting worker");
-                    })
-                    .unwrap(),
-            );
+            let tx = self.spawn_worker(format!("Worker:{}:{}", i, self.node));
             self.channels.push(tx);
         }
+        for i in 0..self.background_count {
+            let tx = self.spawn_worker(format!("BackgroundWorker:{}:{}", i, self.node));
+            self.background_channels.push(tx);
+        }
+        *self.shared.channels.lock().unwrap() = self.channels.clone();
 
         let (ticker_tx, ticker_rx) = mpsc::channel();
         self.ticker_chan = Some(ticker_tx);
-        let ticker_interval = self.ticker_interval;
+        let ticker_interval = self.ticker_interval.clone();
         let mut sender = self.sender();
         self.ticker_thread = Some(
             thread::Builder::new()
                 .name(format!("WorkerTicker:{}", self.node))
                 .spawn(move || loop {
-                    thread::sleep(ticker_interval);
-                    match ticker_rx.try_recv() {
-                        Err(mpsc::TryRecvError::Empty) => (),
+                    // returns early once signaled to stop (or the manager is gone)
+                    let interval = ticker_interval.load(Ordering::Relaxed) as u64;
+                    match ticker_rx.recv_timeout(time::Duration::from_millis(interval)) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => (),
                         _ => break,
                     }
                     let _ = sender.try_send(WorkerMsg::Tick(time::Instant::now()));
@@ -85,13 +204,101 @@ impl WorkerManager {
         );
     }
 
+    fn spawn_worker(&mut self, name: String) -> mpsc::Sender<WorkerMsg> {
+        // since neither closure cloning or Box<FnOnce> are stable use Box<FnMut>
+        let mut worker_fn = (self.worker_fn_gen.as_mut().unwrap())();
+        let (tx, rx) = mpsc::channel();
+        self.threads.push(
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || {
+                    worker_fn(rx);
+                    info!("Exiting worker");
+                })
+                .unwrap(),
+        );
+        tx
+    }
+
+    /// Starts or stops threads so `thread_count` take the messages outside the
+    /// background lane. Stopped threads still handle the messages queued to them,
+    /// senders switch to the new threads with their next message, so
+    /// with the vnode affinity most vnodes move to another thread.
+    pub fn resize(&mut self, thread_count: usize) {
+        assert!(thread_count > 0 && !self.channels.is_empty());
+        // no sender is between picking a channel and sending to it meanwhile,
+        // the stopped threads get their Exit after anything sent to them
+        let closed = self.closed.clone();
+        let closed = closed.write().unwrap();
+        if *closed || thread_count == self.channels.len() {
+            return;
+        }
+        info!(
+            "Resizing workers from {} to {} threads",
+            self.channels.len(),
+            thread_count
+        );
+        while self.channels.len() < thread_count {
+            let name = format!("Worker:{}:{}", self.threads.len(), self.node);
+            let tx = self.spawn_worker(name);
+            self.channels.push(tx);
+        }
+        let stopped = self.channels.split_off(thread_count);
+        *self.shared.channels.lock().unwrap() = self.channels.clone();
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        for c in stopped {
+            let _ = c.send(WorkerMsg::Exit);
+        }
+        self.thread_count = thread_count;
+    }
+
+    /// Takes effect after the next tick
+    pub fn set_ticker_interval(&self, ticker_interval: time::Duration) {
+        self.ticker_interval
+            .store(duration_millis(ticker_interval) as usize, Ordering::Relaxed);
+    }
+
     pub fn sender(&self) -> WorkerSender {
         assert!(!self.channels.is_empty());
         WorkerSender {
-            cursor: thread_rng().gen(),
+            cursor: self.cursor_seed.unwrap_or_else(|| thread_rng().gen()),
             channels: self.channels.clone(),
+            shared: self.shared.clone(),
+            generation: self.shared.generation.load(Ordering::SeqCst),
+            background_channels: self.background_channels.clone(),
+            closed: self.closed.clone(),
+            vnode_affinity: self.vnode_affinity,
         }
     }
+
+    /// Stops the ticker and the worker threads. If `drain` new messages are refused
+    /// and every worker handles the messages already queued before exiting,
+    /// otherwise messages sent during the shutdown may be lost.
+    pub fn shutdown(&mut self, drain: bool) {
+        for t in self.stop(drain) {
+            let _ = t.join();
+        }
+    }
+
+    /// Like `shutdown` but returns the worker threads instead of joining them,
+    /// so workers using the manager (ex: for senders) can finish meanwhile.
+    pub fn stop(&mut self, drain: bool) -> Vec<thread::JoinHandle<()>> {
+        // stop the ticker first so no Tick is queued after the Exit
+        if let Some(c) = self.ticker_chan.take() {
+            let _ = c.send(());
+        }
+        if let Some(t) = self.ticker_thread.take() {
+            let _ = t.join();
+        }
+        if drain {
+            // senders hold the lock while sending, so the Exit is the last message
+            *self.closed.write().unwrap() = true;
+        }
+        for c in self.channels.iter().chain(&self.background_channels) {
+            let _ = c.send(WorkerMsg::Exit);
+        }
+        self.threads.drain(..).collect()
+    }
 }
 
 impl WorkerSender {
@@ -100,24 +307,408 @@ impl WorkerSender {
         let _ = self.try_send(msg);
     }
     pub fn try_send(&mut self, msg: WorkerMsg) -> Result<(), mpsc::SendError<WorkerMsg>> {
+        self.send_regular(None, msg)
+    }
+
+    /// Sends to the background lane, for bulk traffic like syncs that shouldn't
+    /// delay the requests handled by the other threads.
+    /// Without background threads it's the same as `send`.
+    pub fn send_background(&mut self, msg: WorkerMsg) {
+        if self.background_channels.is_empty() {
+            return self.send(msg);
+        }
         self.cursor = self.cursor.wrapping_add(1);
-        self.channels[self.cursor % self.channels.len()].send(msg)
+        let index = self.cursor % self.background_channels.len();
+        let _ = self.send_to(&self.background_channels[index], msg);
+    }
+
+    /// Like `send_for_vnode` but within the background lane
+    pub fn send_background_for_vnode(&mut self, vnode: VNodeId, msg: WorkerMsg) {
+        if self.background_channels.is_empty() {
+            return self.send_for_vnode(vnode, msg);
+        }
+        if self.vnode_affinity {
+            let index = vnode as usize % self.background_channels.len();
+            let _ = self.send_to(&self.background_channels[index], msg);
+        } else {
+            self.send_background(msg);
+        }
+    }
+
+    /// With the vnode affinity enabled messages for the same vnode always go to the
+    /// same thread, so they don't contend for the vnode lock and its data stays in
+    /// that thread cache. Otherwise it's the same as `send`.
+    pub fn send_for_vnode(&mut self, vnode: VNodeId, msg: WorkerMsg) {
+        if self.vnode_affinity {
+            let _ = self.send_regular(Some(vnode), msg);
+        } else {
+            self.send(msg);
+        }
+    }
+
+    pub fn vnode_affinity(&self) -> bool {
+        self.vnode_affinity
+    }
+
+    // outside the background lane, which a resize can change, so the thread is
+    // picked while holding the lock and after taking the channels of the last resize
+    fn send_regular(
+        &mut self,
+        vnode: Option<VNodeId>,
+        msg: WorkerMsg,
+    ) -> Result<(), mpsc::SendError<WorkerMsg>> {
+        let closed = self.closed.clone();
+        let closed = closed.read().unwrap();
+        if *closed {
+            return Err(mpsc::SendError(msg));
+        }
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        if generation != self.generation {
+            self.channels = self.shared.channels.lock().unwrap().clone();
+            self.generation = generation;
+        }
+        let index = match vnode {
+            Some(vnode) => vnode as usize % self.channels.len(),
+            None => {
+                self.cursor = self.cursor.wrapping_add(1);
+                self.cursor % self.channels.len()
+            }
+        };
+        self.channels[index].send(msg)
+    }
+
+    fn send_to(
+        &self,
+        channel: &mpsc::Sender<WorkerMsg>,
+        msg: WorkerMsg,
+    ) -> Result<(), mpsc::SendError<WorkerMsg>> {
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            return Err(mpsc::SendError(msg));
+        }
+        channel.send(msg)
     }
 }
 
 impl Drop for WorkerManager {
     fn drop(&mut self) {
-        for c in &*self.channels {
-            let _ = c.send(WorkerMsg::Exit);
+        self.shutdown(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_cursor_seed() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 4, time::Duration::from_millis(10));
+        manager.set_cursor_seed(0);
+        let mut next_index = 0;
+        manager.start(move || {
+            let index = next_index;
+            next_index += 1;
+            let tx = tx.clone();
+            Box::new(move |chan| {
+                for wm in chan {
+                    match wm {
+                        WorkerMsg::DHTChange(..) => tx.send(index).unwrap(),
+                        WorkerMsg::Exit => break,
+                        _ => (),
+                    }
+                }
+            })
+        });
+
+        for _ in 0..2 {
+            let mut sender = manager.sender();
+            sender.send(WorkerMsg::DHTChange(0));
+            assert_eq!(rx.recv().unwrap(), 1);
+            sender.send(WorkerMsg::DHTChange(0));
+            assert_eq!(rx.recv().unwrap(), 2);
         }
-        if let Some(c) = self.ticker_chan.take() {
-            let _ = c.send(());
+    }
+
+    #[test]
+    fn test_vnode_affinity() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 4, time::Duration::from_secs(60));
+        manager.set_vnode_affinity(true);
+        let mut next_index = 0;
+        manager.start(move || {
+            let index = next_index;
+            next_index += 1;
+            let tx = tx.clone();
+            Box::new(move |chan| for wm in chan {
+                match wm {
+                    WorkerMsg::DHTChange(vnode) => tx.send((vnode, index)).unwrap(),
+                    WorkerMsg::Exit => break,
+                    _ => (),
+                }
+            })
+        });
+
+        let mut channels = HashMap::new();
+        for _ in 0..3 {
+            // senders don't share a cursor, the vnode alone picks the thread
+            let mut sender = manager.sender();
+            sender.send(WorkerMsg::Tick(time::Instant::now()));
+            for vnode in 0..16 {
+                sender.send_for_vnode(vnode, WorkerMsg::DHTChange(vnode as _));
+                let (received, index) = rx.recv().unwrap();
+                assert_eq!(received, vnode as u64);
+                assert_eq!(*channels.entry(vnode).or_insert(index), index);
+            }
         }
-        if let Some(t) = self.ticker_thread.take() {
-            let _ = t.join();
+        // and they're spread over all threads
+        let mut indexes: Vec<_> = channels.values().cloned().collect();
+        indexes.sort();
+        indexes.dedup();
+        assert_eq!(indexes, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_background_lane() {
+        let (tx, rx) = mpsc::channel();
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+        let block_rx = Arc::new(Mutex::new(block_rx));
+        let mut manager = WorkerManager::new(0, 2, time::Duration::from_secs(60));
+        manager.set_background_threads(1);
+        manager.start(move || {
+            let tx = tx.clone();
+            let block_rx = block_rx.clone();
+            Box::new(move |chan| for wm in chan {
+                match wm {
+                    // stands for a sync message that takes until released
+                    WorkerMsg::DHTChange(..) => {
+                        let _ = block_rx.lock().unwrap().recv();
+                    }
+                    WorkerMsg::Command(..) => tx.send(()).unwrap(),
+                    WorkerMsg::Exit => break,
+                    _ => (),
+                }
+            })
+        });
+
+        let mut sender = manager.sender();
+        for i in 0..10 {
+            sender.send_background(WorkerMsg::DHTChange(i));
         }
-        for t in self.threads.drain(..) {
-            let _ = t.join();
+        // the commands don't wait behind the blocked background work
+        for token in 0..10 {
+            sender.send(WorkerMsg::Command(Context::new(token)));
+            rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+        }
+        drop(block_tx);
+    }
+
+    #[test]
+    fn test_background_lane_affinity() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 2, time::Duration::from_secs(60));
+        manager.set_background_threads(3);
+        manager.set_vnode_affinity(true);
+        let mut next_index = 0;
+        manager.start(move || {
+            let index = next_index;
+            next_index += 1;
+            let tx = tx.clone();
+            Box::new(move |chan| for wm in chan {
+                match wm {
+                    WorkerMsg::DHTChange(vnode) => tx.send((vnode, index)).unwrap(),
+                    WorkerMsg::Exit => break,
+                    _ => (),
+                }
+            })
+        });
+
+        let mut sender = manager.sender();
+        for _ in 0..10 {
+            for vnode in 0..6 {
+                sender.send_background_for_vnode(vnode, WorkerMsg::DHTChange(vnode as u64));
+            }
+        }
+        manager.shutdown(true);
+        let mut threads = HashMap::new();
+        for (vnode, index) in rx.try_iter() {
+            // the first 2 threads are the regular ones
+            assert!(index >= 2);
+            assert_eq!(*threads.entry(vnode).or_insert(index), index);
+        }
+        assert_eq!(threads.len(), 6);
+    }
+
+    #[test]
+    fn test_no_tick_after_exit() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 1, time::Duration::from_millis(1));
+        manager.start(move || {
+            let tx = tx.clone();
+            Box::new(move |chan| {
+                for wm in chan.iter() {
+                    match wm {
+                        WorkerMsg::Tick(..) => tx.send(false).unwrap(),
+                        WorkerMsg::Exit => break,
+                        _ => (),
+                    }
+                }
+                tx.send(true).unwrap();
+                // give a late tick time to arrive
+                thread::sleep(time::Duration::from_millis(20));
+                while let Ok(wm) = chan.try_recv() {
+                    if let WorkerMsg::Tick(..) = wm {
+                        tx.send(false).unwrap();
+                    }
+                }
+            })
+        });
+        thread::sleep(time::Duration::from_millis(50));
+        // along with the senders in the worker generator
+        drop(manager);
+        let events: Vec<bool> = rx.iter().collect();
+        let exits = events.iter().position(|&exit| exit).unwrap();
+        assert!(exits > 0, "no ticks");
+        assert!(events[exits..].iter().all(|&exit| exit), "tick after exit");
+
+        // the ticker doesn't hold the shutdown for its whole interval
+        let mut manager = WorkerManager::new(0, 1, time::Duration::from_secs(60));
+        manager.start(move || {
+            Box::new(|chan| for wm in chan {
+                if let WorkerMsg::Exit = wm {
+                    break;
+                }
+            })
+        });
+        let start = time::Instant::now();
+        drop(manager);
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let processed_ = processed.clone();
+        let mut manager = WorkerManager::new(0, 4, time::Duration::from_secs(60));
+        manager.start(move || {
+            let processed = processed_.clone();
+            Box::new(move |chan| for wm in chan {
+                match wm {
+                    WorkerMsg::DHTChange(..) => {
+                        // slow enough for the queues to back up
+                        thread::sleep(time::Duration::from_millis(1));
+                        processed.fetch_add(1, Ordering::SeqCst);
+                    }
+                    WorkerMsg::Exit => break,
+                    _ => (),
+                }
+            })
+        });
+        let mut sender = manager.sender();
+        for i in 0..200 {
+            sender.send(WorkerMsg::DHTChange(i));
+        }
+        manager.shutdown(true);
+        assert_eq!(processed.load(Ordering::SeqCst), 200);
+        // messages sent afterwards are refused instead of lost
+        assert!(sender.try_send(WorkerMsg::DHTChange(0)).is_err());
+    }
+
+    #[test]
+    fn test_resize() {
+        let (tx, rx) = mpsc::channel();
+        let mut manager = WorkerManager::new(0, 2, time::Duration::from_secs(60));
+        let mut next_index = 0;
+        manager.start(move || {
+            let index = next_index;
+            next_index += 1;
+            let tx = tx.clone();
+            Box::new(move |chan| for wm in chan {
+                match wm {
+                    WorkerMsg::DHTChange(..) => tx.send(index).unwrap(),
+                    WorkerMsg::Exit => break,
+                    _ => (),
+                }
+            })
+        });
+        let threads_used = |sender: &mut WorkerSender| {
+            for i in 0..20 {
+                sender.send(WorkerMsg::DHTChange(i));
+            }
+            let mut indexes: Vec<usize> = rx.iter().take(20).collect();
+            indexes.sort();
+            indexes.dedup();
+            indexes
+        };
+
+        // senders created before a resize follow it
+        let mut sender = manager.sender();
+        assert_eq!(threads_used(&mut sender), [0, 1]);
+        manager.resize(4);
+        assert_eq!(threads_used(&mut sender), [0, 1, 2, 3]);
+
+        // the messages queued to the stopped threads are still handled
+        for i in 0..100 {
+            sender.send(WorkerMsg::DHTChange(i));
+        }
+        manager.resize(1);
+        assert_eq!(rx.iter().take(100).count(), 100);
+        assert_eq!(threads_used(&mut sender), [0]);
+        manager.resize(3);
+        assert_eq!(threads_used(&mut sender), [0, 4, 5]);
+    }
+
+    #[test]
+    fn test_signaled_chan() {
+        let (tx, rx) = mpsc::channel();
+        let mut chan = SignaledChan::new(rx);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(chan.next(), Some(Some(1)));
+        assert_eq!(chan.next(), Some(Some(2)));
+        assert_eq!(chan.next(), Some(None));
+        tx.send(3).unwrap();
+        assert_eq!(chan.next(), Some(Some(3)));
+        assert_eq!(chan.next(), Some(None));
+        drop(tx);
+        assert_eq!(chan.next(), None);
+    }
+
+    #[test]
+    fn test_signaled_chan_interval() {
+        let interval = time::Duration::from_millis(100);
+        let (tx, rx) = mpsc::channel();
+        let mut chan = SignaledChan::with_interval(rx, interval);
+        tx.send(1).unwrap();
+        assert_eq!(chan.next(), Some(Some(1)));
+        assert_eq!(chan.next(), Some(None));
+
+        // the next signal waits for the interval
+        let start = time::Instant::now();
+        tx.send(2).unwrap();
+        assert_eq!(chan.next(), Some(Some(2)));
+        assert_eq!(chan.next(), Some(None));
+        assert!(start.elapsed() >= time::Duration::from_millis(90));
+        assert!(start.elapsed() < time::Duration::from_millis(200));
+
+        // a burst of drains over ~400ms is coalesced into a few signals
+        let producer = thread::spawn(move || {
+            for i in 0..40 {
+                thread::sleep(time::Duration::from_millis(10));
+                tx.send(i).unwrap();
+            }
+        });
+        let (mut msgs, mut signals) = (0, 0);
+        for msg in chan {
+            match msg {
+                Some(_) => msgs += 1,
+                None => signals += 1,
+            }
         }
+        producer.join().unwrap();
+        assert_eq!(msgs, 40);
+        assert!(signals >= 2 && signals <= 5, "{} signals", signals);
     }
 }